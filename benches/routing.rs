@@ -0,0 +1,243 @@
+//! Benchmarks of the bus, all through the in-memory transport so that they're deterministic.
+//!
+//! Run them with `cargo bench`, and compare against a baseline with
+//! `cargo bench -- --save-baseline main` and `cargo bench -- --baseline main`.
+
+#[cfg(unix)]
+use criterion::criterion_main;
+
+// The in-memory transport is only available on Unix.
+#[cfg(unix)]
+mod unix {
+    use std::{collections::HashSet, sync::Arc, time::Duration};
+
+    use busd::{
+        bus::{BusHandle, MEMORY_ADDRESS},
+        bus_builder::BusBuilder,
+        limits::{Limits, SharedLimits},
+        name_registry::NameRegistry,
+    };
+    use criterion::{criterion_group, BenchmarkId, Criterion};
+    use futures_util::stream::StreamExt;
+    use tokio::{runtime::Runtime, sync::broadcast, sync::Mutex};
+    use zbus::{
+        dbus_interface,
+        fdo::DBusProxy,
+        names::{BusName, OwnedUniqueName, OwnedWellKnownName, UniqueName, WellKnownName},
+        CacheProperties, Connection, MatchRule, MessageBuilder, MessageStream,
+    };
+
+    struct Echo;
+
+    #[dbus_interface(name = "org.busd.Echo")]
+    impl Echo {
+        fn echo(&self, word: &str) -> String {
+            word.to_string()
+        }
+    }
+
+    fn runtime() -> Runtime {
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()
+            .unwrap()
+    }
+
+    async fn spawn_bus() -> BusHandle {
+        BusBuilder::new()
+            .address(MEMORY_ADDRESS)
+            .build()
+            .await
+            .unwrap()
+            .spawn()
+    }
+
+    async fn connect(bus: &BusHandle) -> Connection {
+        bus.memory_connector().unwrap().connect().await.unwrap()
+    }
+
+    async fn add_match_rule(conn: &Connection, rule: MatchRule<'_>) {
+        DBusProxy::builder(conn)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await
+            .unwrap()
+            .add_match_rule(rule)
+            .await
+            .unwrap();
+    }
+
+    /// A method call from one peer to another, up to the reply.
+    fn method_call(c: &mut Criterion) {
+        let rt = runtime();
+        let (bus, service, client) = rt.block_on(async {
+            let bus = spawn_bus().await;
+            let service = connect(&bus).await;
+            service
+                .object_server()
+                .at("/org/busd/Echo", Echo)
+                .await
+                .unwrap();
+            service.request_name("org.busd.Echo").await.unwrap();
+            let client = connect(&bus).await;
+
+            (bus, service, client)
+        });
+
+        c.bench_function("method_call", |b| {
+            b.to_async(&rt).iter(|| async {
+                client
+                    .call_method(
+                        Some("org.busd.Echo"),
+                        "/org/busd/Echo",
+                        Some("org.busd.Echo"),
+                        "Echo",
+                        &("hello",),
+                    )
+                    .await
+                    .unwrap()
+            })
+        });
+
+        drop((service, client));
+        rt.block_on(bus.shutdown()).unwrap();
+    }
+
+    /// A signal broadcasted to an increasing number of subscribers, until they all received it.
+    fn signal_fanout(c: &mut Criterion) {
+        let rt = runtime();
+        let mut group = c.benchmark_group("signal_fanout");
+        for subscribers in [1, 10, 100] {
+            let (bus, emitter, streams) = rt.block_on(async {
+                let bus = spawn_bus().await;
+                let emitter = connect(&bus).await;
+                let mut streams = vec![];
+                for _ in 0..subscribers {
+                    let conn = connect(&bus).await;
+                    let stream = MessageStream::from(&conn);
+                    add_match_rule(&conn, MatchRule::builder().member("Tick").unwrap().build())
+                        .await;
+                    streams.push((conn, stream));
+                }
+
+                (bus, emitter, Mutex::new(streams))
+            });
+
+            group.bench_with_input(
+                BenchmarkId::from_parameter(subscribers),
+                &subscribers,
+                |b, _| {
+                    b.to_async(&rt).iter(|| async {
+                        emitter
+                            .emit_signal(
+                                None::<BusName<'_>>,
+                                "/org/busd/Bench",
+                                "org.busd.Bench",
+                                "Tick",
+                                &(),
+                            )
+                            .await
+                            .unwrap();
+                        for (_, stream) in streams.lock().await.iter_mut() {
+                            while let Some(msg) = stream.next().await {
+                                if msg.unwrap().member().as_deref() == Some("Tick") {
+                                    break;
+                                }
+                            }
+                        }
+                    })
+                },
+            );
+
+            drop((emitter, streams));
+            rt.block_on(bus.shutdown()).unwrap();
+        }
+        group.finish();
+    }
+
+    /// Connecting to the bus, up to the reply to `Hello`.
+    fn connection_setup(c: &mut Criterion) {
+        let rt = runtime();
+        let bus = rt.block_on(spawn_bus());
+
+        c.bench_function("connection_setup", |b| {
+            b.to_async(&rt).iter(|| connect(&bus))
+        });
+
+        rt.block_on(bus.shutdown()).unwrap();
+    }
+
+    /// Matching a signal against the rules of a peer, none of which but the last match it.
+    fn match_rules(c: &mut Criterion) {
+        let msg = MessageBuilder::signal("/org/busd/Bench", "org.busd.Bench", "Tick")
+            .unwrap()
+            .sender(":busd.1")
+            .unwrap()
+            .build(&("hello",))
+            .unwrap();
+        let mut group = c.benchmark_group("match_rules");
+        for count in [1, 10, 100] {
+            let mut rules: Vec<MatchRule<'static>> = (1..count)
+                .map(|i| {
+                    MatchRule::try_from(format!("type='signal',member='Tock{i}'").as_str())
+                        .unwrap()
+                        .into_owned()
+                })
+                .collect();
+            rules.push(
+                MatchRule::try_from(
+                    "type='signal',interface='org.busd.Bench',member='Tick',arg0='hello'",
+                )
+                .unwrap(),
+            );
+
+            group.bench_with_input(BenchmarkId::from_parameter(count), &rules, |b, rules| {
+                b.iter(|| rules.iter().any(|rule| rule.matches(&msg).unwrap()))
+            });
+        }
+        group.finish();
+    }
+
+    /// Two peers handing a name over to each other, through its queue.
+    fn name_registry(c: &mut Criterion) {
+        let (events, _) = broadcast::channel(1024);
+        let limits = Arc::new(SharedLimits::new(Limits::default()));
+        let registry = NameRegistry::new(events, limits, HashSet::new(), Duration::ZERO);
+        let name = OwnedWellKnownName::from(WellKnownName::try_from("org.busd.Bench").unwrap());
+        let owner = OwnedUniqueName::from(UniqueName::try_from(":busd.1").unwrap());
+        let queued = OwnedUniqueName::from(UniqueName::try_from(":busd.2").unwrap());
+        registry
+            .request_name(name.clone(), queued.clone(), Default::default())
+            .unwrap();
+
+        c.bench_function("name_registry", |b| {
+            b.iter(|| {
+                // Each peer queues up and is promoted as the other releases the name.
+                registry
+                    .request_name(name.clone(), owner.clone(), Default::default())
+                    .unwrap();
+                registry.release_name(name.clone().into(), (&*queued).into());
+                registry
+                    .request_name(name.clone(), queued.clone(), Default::default())
+                    .unwrap();
+                registry.release_name(name.clone().into(), (&*owner).into());
+            })
+        });
+    }
+
+    criterion_group!(
+        benches,
+        method_call,
+        signal_fanout,
+        connection_setup,
+        match_rules,
+        name_registry
+    );
+}
+
+#[cfg(unix)]
+criterion_main!(unix::benches);
+
+#[cfg(not(unix))]
+fn main() {}
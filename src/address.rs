@@ -0,0 +1,197 @@
+//! Parsing and formatting of D-Bus server addresses, e.g. `unix:path=/run/bus`.
+//!
+//! Values are percent-escaped, so they can contain characters that are otherwise part of the
+//! syntax (e.g. `,` in a socket path).
+
+use std::{borrow::Cow, fmt::Write};
+#[cfg(unix)]
+use std::{
+    env,
+    ffi::OsString,
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::PathBuf,
+};
+
+use anyhow::{anyhow, bail, Result};
+#[cfg(unix)]
+use xdg_home::home_dir;
+
+/// A parsed address: a transport and its key-value parameters.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct ServerAddress {
+    transport: String,
+    params: Vec<Param>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Param {
+    key: String,
+    value: Vec<u8>,
+    // Still escaped, for expanding paths.
+    raw: String,
+}
+
+impl ServerAddress {
+    pub fn parse(address: &str) -> Result<Self> {
+        let (transport, params) = address
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Address `{}` has no transport", address))?;
+        if transport.is_empty() {
+            bail!("Address `{}` has no transport", address);
+        }
+        let params = params
+            .split(',')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (key, value) = pair
+                    .split_once('=')
+                    .ok_or_else(|| anyhow!("Parameter `{}` has no value", pair))?;
+                if key.is_empty() {
+                    bail!("Parameter `{}` has no key", pair);
+                }
+
+                Ok(Param {
+                    key: key.to_string(),
+                    value: unescape(value)?,
+                    raw: value.to_string(),
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            transport: transport.to_string(),
+            params,
+        })
+    }
+
+    pub fn transport(&self) -> &str {
+        &self.transport
+    }
+
+    /// The unescaped value of the `key` parameter, if present.
+    pub fn get(&self, key: &str) -> Option<&[u8]> {
+        self.param(key).map(|param| param.value.as_slice())
+    }
+
+    /// Same as [`ServerAddress::get`], for values that must be valid UTF-8 (e.g. hosts).
+    pub fn get_str(&self, key: &str) -> Result<Option<&str>> {
+        self.get(key)
+            .map(|value| {
+                std::str::from_utf8(value)
+                    .map_err(|_| anyhow!("Value of `{}` is not valid UTF-8", key))
+            })
+            .transpose()
+    }
+
+    /// The value of the `key` parameter as a path, with a leading `~` and environment variables
+    /// (`$VAR` or `${VAR}`) expanded, e.g. `$XDG_RUNTIME_DIR/bus`.
+    ///
+    /// Expansion happens before unescaping, so an escaped `%24` is a literal `$`. Fails if a
+    /// variable isn't set.
+    #[cfg(unix)]
+    pub fn get_path(&self, key: &str) -> Result<Option<PathBuf>> {
+        let param = match self.param(key) {
+            Some(param) => param,
+            None => return Ok(None),
+        };
+        let expanded = expand(&param.raw)?;
+
+        Ok(Some(OsString::from_vec(unescape(&expanded)?).into()))
+    }
+
+    fn param(&self, key: &str) -> Option<&Param> {
+        self.params.iter().find(|param| param.key == key)
+    }
+}
+
+/// Expand a leading `~` and the environment variables in the escaped `value`.
+///
+/// The expansions are escaped in turn, so they're taken literally once `value` is unescaped.
+#[cfg(unix)]
+fn expand(value: &str) -> Result<String> {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+    if let Some(after) = rest.strip_prefix('~') {
+        if after.is_empty() || after.starts_with('/') {
+            let home = home_dir()
+                .ok_or_else(|| anyhow!("No home directory to expand `~` in `{}`", value))?;
+            expanded.push_str(&escape(home.as_os_str().as_bytes()));
+            rest = after;
+        }
+    }
+    while let Some(i) = rest.find('$') {
+        expanded.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+        let (name, after) = match rest.strip_prefix('{') {
+            Some(braced) => braced
+                .split_once('}')
+                .ok_or_else(|| anyhow!("Unterminated `${{` in `{}`", value))?,
+            None => {
+                let end = rest
+                    .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                    .unwrap_or(rest.len());
+
+                rest.split_at(end)
+            }
+        };
+        if name.is_empty() {
+            bail!("Missing variable name after `$` in `{}`", value);
+        }
+        let var = env::var_os(name)
+            .ok_or_else(|| anyhow!("Variable `{}` in `{}` is not set", name, value))?;
+        expanded.push_str(&escape(var.as_bytes()));
+        rest = after;
+    }
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+/// Escape `value` for use in an address.
+///
+/// Only the bytes the specification allows unescaped are left as is.
+pub(crate) fn escape(value: &[u8]) -> Cow<'_, str> {
+    if value.iter().copied().all(is_optionally_escaped) {
+        // Only ASCII, so valid UTF-8.
+        return String::from_utf8_lossy(value);
+    }
+
+    let mut escaped = String::with_capacity(value.len() * 3);
+    for &byte in value {
+        if is_optionally_escaped(byte) {
+            escaped.push(char::from(byte));
+        } else {
+            write!(escaped, "%{:02x}", byte).unwrap();
+        }
+    }
+
+    escaped.into()
+}
+
+/// Unescape a value of an address.
+///
+/// Bytes that should have been escaped but weren't are accepted as is, like other
+/// implementations do.
+pub(crate) fn unescape(value: &str) -> Result<Vec<u8>> {
+    let mut unescaped = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte != b'%' {
+            unescaped.push(byte);
+
+            continue;
+        }
+
+        let hex: Vec<u8> = bytes.by_ref().take(2).collect();
+        match hex::decode(hex).as_deref() {
+            Ok([byte]) => unescaped.push(*byte),
+            _ => bail!("Invalid escape sequence in `{}`", value),
+        }
+    }
+
+    Ok(unescaped)
+}
+
+fn is_optionally_escaped(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"-_/.\\*".contains(&byte)
+}
@@ -1,6 +1,13 @@
 extern crate busd;
 
-use busd::bus;
+use busd::bus_builder::BusBuilder;
+#[cfg(unix)]
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    os::unix::io::{AsRawFd, FromRawFd},
+};
+use std::{path::PathBuf, time::Duration};
 
 use anyhow::Result;
 use clap::{Parser, ValueEnum};
@@ -9,6 +16,7 @@ use tokio::{select, signal::unix::SignalKind};
 use tracing::error;
 #[cfg(unix)]
 use tracing::{info, warn};
+use zbus::Guid;
 
 /// A simple D-Bus broker.
 #[derive(Parser, Debug)]
@@ -22,6 +30,110 @@ struct Args {
     #[clap(long)]
     #[arg(value_enum, default_value_t = AuthMechanism::External)]
     auth_mechanism: AuthMechanism,
+
+    /// Capture all routed messages to the given file, in the pcap format.
+    #[clap(long, value_parser)]
+    message_log: Option<PathBuf>,
+
+    /// Rotate the message log once it reaches the given size in bytes.
+    #[clap(long, value_parser)]
+    message_log_max_size: Option<u64>,
+
+    /// The number of rotated message logs to keep around.
+    #[clap(long, value_parser, default_value_t = 5)]
+    message_log_max_files: usize,
+
+    /// The GUID of the bus, as 32 hexadecimal digits. By default, a random one is generated.
+    #[clap(long, value_parser)]
+    guid: Option<String>,
+
+    /// Fork into the background once the bus is ready. By default, busd stays in the foreground.
+    #[cfg(unix)]
+    #[clap(long)]
+    fork: bool,
+
+    /// Serve the single connection inherited on stdin from inetd and the like, rather than
+    /// listening, and exit once it closes. Since stdout and stderr may be the same connection,
+    /// nothing is logged there.
+    #[cfg(unix)]
+    #[clap(long, conflicts_with_all = ["address", "fork"])]
+    inetd: bool,
+
+    /// Log to the system log, using the given facility (e.g. `daemon`), rather than stderr.
+    #[cfg(feature = "syslog")]
+    #[clap(long, value_parser)]
+    syslog: Option<String>,
+
+    /// Exit once the last client disconnects.
+    #[clap(long)]
+    exit_on_idle: bool,
+
+    /// Write the process ID of the bus to the given file.
+    #[clap(long, value_parser)]
+    pid_file: Option<PathBuf>,
+
+    /// Warn whenever routing a single message takes longer than the given number of milliseconds.
+    #[clap(long, value_parser)]
+    slow_routing_threshold: Option<u64>,
+
+    /// Slow down accepting new connections while the bus is congested, by up to the given number
+    /// of milliseconds per connection.
+    #[clap(long, value_parser)]
+    max_accept_delay: Option<u64>,
+
+    /// Stop reading from peers while more than the given number of messages are waiting to be
+    /// sent to peers, until the backlog drains.
+    #[clap(long, value_parser)]
+    max_routing_backlog: Option<usize>,
+
+    /// Hold the names of disconnected owners unowned for the given number of milliseconds, before
+    /// promoting the next owners queued for them.
+    #[clap(long, value_parser)]
+    name_promotion_grace: Option<u64>,
+
+    /// Disconnect peers that haven't called `Hello` within the given number of milliseconds after
+    /// authenticating. Defaults to 30 seconds.
+    #[clap(long, value_parser)]
+    hello_timeout: Option<u64>,
+
+    /// Stop awaiting replies to method calls after the given number of milliseconds. Defaults to
+    /// 25 seconds.
+    #[clap(long, value_parser)]
+    reply_timeout: Option<u64>,
+
+    /// Refuse all peers authenticating through `ANONYMOUS`, whatever the auth mechanism of the
+    /// listeners.
+    #[clap(long)]
+    deny_anonymous: bool,
+
+    /// Replace the socket file of a `unix:path=` address if no one is listening on it anymore,
+    /// e.g. after a crash. By default, busd fails to start if the file exists.
+    #[clap(long)]
+    replace_stale_socket: bool,
+
+    /// The permissions of the socket files of `unix:` addresses, in octal (e.g. `660`), whatever
+    /// the umask. By default, they're left to the umask.
+    #[cfg(unix)]
+    #[clap(long, value_parser = parse_mode)]
+    socket_mode: Option<u32>,
+
+    /// The SELinux file context of the socket files of `unix:` addresses. Ignored if SELinux is
+    /// disabled.
+    #[cfg(all(target_os = "linux", feature = "selinux"))]
+    #[clap(long, value_parser)]
+    socket_context: Option<String>,
+
+    /// Restrict busd to the syscalls it needs, through a seccomp filter, once it's listening. Any
+    /// other syscall fails.
+    #[cfg(all(target_os = "linux", feature = "seccomp"))]
+    #[clap(long)]
+    seccomp: bool,
+
+    /// Serve a control socket at the given path, answering `stats` with the statistics of the bus
+    /// as `key=value` lines.
+    #[cfg(unix)]
+    #[clap(long, value_parser)]
+    control_socket: Option<PathBuf>,
 }
 
 #[derive(Copy, Clone, Debug, ValueEnum)]
@@ -47,14 +159,126 @@ impl From<AuthMechanism> for zbus::AuthMechanism {
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    // Writing to a peer that went away must result in an error, not kill the bus. The Rust
+    // runtime already ignores SIGPIPE before `main` but we don't want to rely on that.
+    #[cfg(unix)]
+    {
+        use nix::sys::signal::{signal, SigHandler, Signal};
+
+        // SAFETY: We're not replacing any handler, just ignoring the signal.
+        unsafe { signal(Signal::SIGPIPE, SigHandler::SigIgn) }?;
+    }
+
+    // This must happen before any threads are spawned, including the tokio runtime's.
+    #[cfg(unix)]
+    let ready_tx = if args.fork { Some(daemonize()?) } else { None };
+    #[cfg(not(unix))]
+    let ready_tx = None;
+    #[cfg(unix)]
+    if args.inetd {
+        silence_stdio()?;
+    }
+
+    #[cfg(feature = "syslog")]
+    if let Some(facility) = &args.syslog {
+        let facility = facility
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Invalid syslog facility `{facility}`"))?;
+        busd::tracing_subscriber::init_syslog(facility)?;
+    } else {
+        busd::tracing_subscriber::init();
+    }
+    #[cfg(not(feature = "syslog"))]
     busd::tracing_subscriber::init();
 
-    let args = Args::parse();
+    tokio::runtime::Runtime::new()?.block_on(run(args, ready_tx))
+}
 
-    let mut bus =
-        bus::Bus::for_address(args.address.as_deref(), args.auth_mechanism.into()).await?;
+async fn run(args: Args, ready_tx: Option<File>) -> Result<()> {
+    let mut builder = BusBuilder::new()
+        .auth_mechanism(args.auth_mechanism.into())
+        .exit_on_idle(args.exit_on_idle)
+        .deny_anonymous(args.deny_anonymous)
+        .replace_stale_socket(args.replace_stale_socket);
+    if let Some(address) = &args.address {
+        builder = builder.address(address);
+    }
+    #[cfg(unix)]
+    if args.inetd {
+        builder = builder
+            .address(busd::bus::INHERITED_ADDRESS)
+            .exit_on_idle(true);
+    }
+    if let Some(path) = args.message_log {
+        builder = builder.message_log_path(path);
+    }
+    if let Some(max_size) = args.message_log_max_size {
+        builder = builder.message_log_rotation(max_size, args.message_log_max_files);
+    }
+    if let Some(guid) = &args.guid {
+        builder = builder.guid(Guid::try_from(guid.as_str())?);
+    }
+    if let Some(max_delay) = args.max_accept_delay {
+        builder = builder.max_accept_delay(Duration::from_millis(max_delay));
+    }
+    if let Some(max) = args.max_routing_backlog {
+        builder = builder.max_routing_backlog(max);
+    }
+    if let Some(grace) = args.name_promotion_grace {
+        builder = builder.name_promotion_grace(Duration::from_millis(grace));
+    }
+    if let Some(timeout) = args.hello_timeout {
+        builder = builder.hello_timeout(Duration::from_millis(timeout));
+    }
+    if let Some(timeout) = args.reply_timeout {
+        builder = builder.reply_timeout(Duration::from_millis(timeout));
+    }
+    if let Some(threshold) = args.slow_routing_threshold {
+        builder = builder.slow_routing_threshold(Duration::from_millis(threshold));
+    }
+    #[cfg(unix)]
+    if let Some(mode) = args.socket_mode {
+        builder = builder.socket_mode(mode);
+    }
+    #[cfg(all(target_os = "linux", feature = "selinux"))]
+    if let Some(context) = &args.socket_context {
+        builder = builder.socket_context(context);
+    }
+    #[cfg(unix)]
+    if let Some(path) = args.control_socket {
+        builder = builder.control_socket(path);
+    }
+    let mut bus = builder.build().await?;
+    if let Some(pid_file) = &args.pid_file {
+        tokio::fs::write(pid_file, format!("{}\n", std::process::id())).await?;
+        // Readable by anyone, like the PID files of other daemons, whatever the umask.
+        #[cfg(unix)]
+        {
+            use std::{fs::Permissions, os::unix::fs::PermissionsExt};
+
+            tokio::fs::set_permissions(pid_file, Permissions::from_mode(0o644)).await?;
+        }
+    }
+    // Let the original process know that we're listening, so it can exit.
+    if let Some(mut ready_tx) = ready_tx {
+        ready_tx.write_all(&[1])?;
+    }
+    #[cfg(all(target_os = "linux", feature = "systemd"))]
+    {
+        busd::systemd::notify_ready()?;
+        if let Some(period) = busd::systemd::watchdog_interval() {
+            tokio::spawn(busd::systemd::run_watchdog(period, bus.liveness()));
+        }
+    }
+
+    // Only once all the listeners are bound and we're done setting up.
+    #[cfg(all(target_os = "linux", feature = "seccomp"))]
+    if args.seccomp {
+        busd::seccomp::apply()?;
+    }
 
     // FIXME: How to handle this gracefully on Windows?
     #[cfg(unix)]
@@ -77,6 +301,82 @@ async fn main() -> Result<()> {
     if let Err(e) = bus.cleanup().await {
         error!("Failed to clean up: {}", e);
     }
+    if let Some(pid_file) = &args.pid_file {
+        if let Err(e) = tokio::fs::remove_file(pid_file).await {
+            error!("Failed to remove PID file: {}", e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Parse file permissions given in octal.
+#[cfg(unix)]
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    match u32::from_str_radix(mode, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err(format!("`{mode}` is not an octal mode between 0 and 777")),
+    }
+}
+
+/// Point stdout and stderr to `/dev/null`, leaving stdin alone.
+#[cfg(unix)]
+fn silence_stdio() -> Result<()> {
+    use nix::unistd::dup2;
+
+    let dev_null = OpenOptions::new().write(true).open("/dev/null")?;
+    for fd in 1..3 {
+        dup2(dev_null.as_raw_fd(), fd)?;
+    }
 
     Ok(())
 }
+
+/// Detach from the controlling terminal and continue in a (grand)child process.
+///
+/// The original process only exits once the daemon writes to the returned pipe, which should
+/// happen after the bus is listening. Its exit status is non-zero if the daemon exits before that.
+#[cfg(unix)]
+fn daemonize() -> Result<File> {
+    use nix::{
+        sys::wait::waitpid,
+        unistd::{dup2, fork, pipe, setsid, ForkResult},
+    };
+
+    let (ready_rx, ready_tx) = pipe()?;
+    // SAFETY: We just created these and nothing else owns them.
+    let (mut ready_rx, ready_tx) =
+        unsafe { (File::from_raw_fd(ready_rx), File::from_raw_fd(ready_tx)) };
+
+    // SAFETY: We're still single-threaded.
+    if let ForkResult::Parent { child } = unsafe { fork() }? {
+        drop(ready_tx);
+        waitpid(child, None)?;
+        let mut buf = [0u8; 1];
+        let status = match ready_rx.read(&mut buf) {
+            Ok(1) => 0,
+            _ => 1,
+        };
+
+        std::process::exit(status);
+    }
+    drop(ready_rx);
+
+    setsid()?;
+    // Fork again so that the daemon isn't a session leader and can't acquire a controlling
+    // terminal. SAFETY: We're still single-threaded.
+    if let ForkResult::Parent { .. } = unsafe { fork() }? {
+        std::process::exit(0);
+    }
+
+    // We don't change the working directory, so that relative paths in arguments keep working.
+    let dev_null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    for fd in 0..3 {
+        dup2(dev_null.as_raw_fd(), fd)?;
+    }
+
+    Ok(ready_tx)
+}
@@ -1,173 +1,1869 @@
 use anyhow::{anyhow, Result};
+use futures_util::{future, stream, Stream, StreamExt};
 use rand::Rng;
 #[cfg(unix)]
+use rand::{
+    distributions::{Alphanumeric, DistString},
+    thread_rng,
+};
+#[cfg(unix)]
 use std::{
     env,
     fs::Permissions,
-    os::unix::prelude::PermissionsExt,
+    os::unix::{ffi::OsStrExt, fs::FileTypeExt, prelude::PermissionsExt},
     path::{Path, PathBuf},
 };
 use std::{
-    io,
+    fmt,
+    future::Future,
+    io, iter,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
     str::FromStr,
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 #[cfg(unix)]
 use tokio::fs::set_permissions;
 use tokio::{
     fs::{create_dir_all, metadata, remove_file, rename, File, OpenOptions},
     io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    time::sleep,
+    net::{lookup_host, TcpSocket},
+    select,
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc, oneshot, watch, OwnedSemaphorePermit,
+    },
+    task::JoinHandle,
+    time::{interval, sleep},
 };
-use tracing::{debug, info, instrument, trace, warn};
+use tracing::{debug, info, info_span, instrument, trace, warn, Instrument};
 use xdg_home::home_dir;
-use zbus::{Address, AuthMechanism, Guid, Socket, TcpAddress};
+#[cfg(unix)]
+use zbus::fdo::{RequestNameFlags, RequestNameReply};
+use zbus::{
+    names::{OwnedUniqueName, OwnedWellKnownName, UniqueName, WellKnownName},
+    AuthMechanism, Guid, Socket,
+};
 
-use crate::{name_registry::NameRegistry, peer::Peer, peers::Peers};
+#[cfg(unix)]
+use crate::control::ControlSocket;
+use crate::{
+    address::{escape, ServerAddress},
+    bus_builder::BusBuilder,
+    credentials::Credentials,
+    error::BusError,
+    event::{BusEvent, NameOwnerChange, EVENT_QUEUE_SIZE},
+    limits::{Limit, Limits, SharedLimits},
+    machine_id::read_machine_id,
+    message_log::MessageLog,
+    name_registry::NameRegistry,
+    peer::Peer,
+    peers::{PeerInfo, Peers},
+    stats::BusStats,
+};
 
 /// The bus.
 #[derive(Debug)]
 pub struct Bus {
     peers: Peers,
-    listener: Listener,
+    listeners: Vec<Listener>,
+    address: String,
     guid: Guid,
     next_id: usize,
-    name_registry: NameRegistry,
+    events: broadcast::Sender<BusEvent>,
+    machine_id: String,
+    exit_on_idle: bool,
+    lifecycle_signals: bool,
+    // If we're accepting connections.
+    accepting: watch::Sender<bool>,
+    // When the bus was last seen alive while running, see `Bus::liveness`.
+    alive: Arc<watch::Sender<Instant>>,
+    max_accept_delay: Option<Duration>,
+    accept_filter: Option<AcceptFilter>,
+    deny_anonymous: bool,
+    bind_options: BindOptions,
+    // Requests from the `BusHandle`, if the bus was spawned.
+    listener_requests: Option<mpsc::Receiver<ListenerRequest>>,
+    #[cfg(unix)]
+    control_socket: Option<ControlSocket>,
+}
+
+/// Decides which peers are allowed to connect, by their credentials.
+///
+/// See [`Bus::set_accept_filter`].
+struct AcceptFilter(Box<dyn Fn(&Credentials) -> bool + Send + Sync>);
+
+impl fmt::Debug for AcceptFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AcceptFilter")
+    }
+}
+
+/// How to bind listeners, as set through the [`BusBuilder`].
+#[derive(Clone, Debug)]
+struct BindOptions {
+    replace_stale_socket: bool,
+    // Of socket files, if not left to the umask.
+    socket_mode: Option<u32>,
+    // The SELinux file context of socket files, if not left to the policy.
+    #[cfg(all(target_os = "linux", feature = "selinux"))]
+    socket_context: Option<String>,
+}
+
+/// A change to the listeners, requested through a [`BusHandle`].
+#[derive(Debug)]
+enum ListenerRequest {
+    Add {
+        address: String,
+        auth_mechanism: AuthMechanism,
+        guid: Option<Guid>,
+        // With the address of the new listeners, and then of all of them.
+        reply: oneshot::Sender<Result<(String, String)>>,
+    },
+    Remove {
+        address: String,
+        // With the address of the remaining listeners.
+        reply: oneshot::Sender<Result<String>>,
+    },
+}
+
+/// A listening socket, along with the authentication mechanism peers must use on it.
+#[derive(Debug)]
+struct Listener {
+    transport: Transport,
     auth_mechanism: AuthMechanism,
+    // Advertised to the peers connecting through this listener, instead of the GUID of the bus.
+    guid: Option<Guid>,
 }
 
 #[derive(Debug)]
-enum Listener {
+enum Transport {
     #[cfg(unix)]
     Unix {
         listener: tokio::net::UnixListener,
         socket_path: PathBuf,
     },
+    // A socket in the abstract namespace, which has no file to clean up.
+    #[cfg(target_os = "linux")]
+    Abstract {
+        listener: tokio::net::UnixListener,
+        name: Vec<u8>,
+    },
     Tcp {
         listener: tokio::net::TcpListener,
+        // The host to advertise, if it differs from the one we're bound to.
+        host: Option<String>,
+    },
+    #[cfg(unix)]
+    Memory {
+        rx: mpsc::Receiver<tokio::net::UnixStream>,
+        connector: MemoryConnector,
     },
+    // A single connection inherited on stdin, e.g. from inetd. Once it's accepted, there's
+    // nothing left to accept.
+    #[cfg(unix)]
+    Inherited { stream: Option<InheritedStream> },
+    // A socket systemd is listening on for us, which is its to clean up.
+    #[cfg(all(target_os = "linux", feature = "systemd"))]
+    Activated {
+        listener: ActivatedListener,
+        name: String,
+    },
+}
+
+#[cfg(all(target_os = "linux", feature = "systemd"))]
+#[derive(Debug)]
+enum ActivatedListener {
+    Unix(tokio::net::UnixListener),
+    Tcp(tokio::net::TcpListener),
+}
+
+#[cfg(unix)]
+#[derive(Debug)]
+enum InheritedStream {
+    Unix(tokio::net::UnixStream),
+    Tcp(tokio::net::TcpStream),
+}
+
+/// Connects clients to a bus listening on the `memory:` address.
+///
+/// This transport is entirely in-process and doesn't create any files, which makes it ideal for
+/// tests.
+#[cfg(unix)]
+#[derive(Clone, Debug)]
+pub struct MemoryConnector {
+    tx: mpsc::Sender<tokio::net::UnixStream>,
+}
+
+#[cfg(unix)]
+impl MemoryConnector {
+    /// Connect a new client to the bus.
+    pub async fn connect(&self) -> Result<zbus::Connection> {
+        let (client, server) = tokio::net::UnixStream::pair()?;
+        self.tx
+            .send(server)
+            .await
+            .map_err(|_| anyhow!("The bus is gone."))?;
+
+        zbus::ConnectionBuilder::socket(client)
+            .build()
+            .await
+            .map_err(Into::into)
+    }
+}
+
+/// A handle to a bus running on its own task.
+///
+/// See [`Bus::spawn`]. Dropping the handle shuts the bus down too, just without waiting for it.
+#[derive(Debug)]
+pub struct BusHandle {
+    address: String,
+    guid: Guid,
+    #[cfg(unix)]
+    memory_connector: Option<MemoryConnector>,
+    // Dropping it stops the bus.
+    stop: oneshot::Sender<()>,
+    listener_requests: mpsc::Sender<ListenerRequest>,
+    events: broadcast::Sender<BusEvent>,
+    task: JoinHandle<Result<(), BusError>>,
+}
+
+impl BusHandle {
+    /// The address the bus is listening on.
+    ///
+    /// See [`Bus::address`]. Kept up to date by [`BusHandle::add_listener`] and
+    /// [`BusHandle::remove_listener`], but not when a listener fails.
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// The GUID of the bus.
+    pub fn guid(&self) -> &Guid {
+        &self.guid
+    }
+
+    /// A connector for clients, if the bus is listening on the `memory:` address.
+    #[cfg(unix)]
+    pub fn memory_connector(&self) -> Option<&MemoryConnector> {
+        self.memory_connector.as_ref()
+    }
+
+    /// A stream of events on the bus.
+    ///
+    /// See [`Bus::event_stream`].
+    pub fn event_stream(&self) -> impl Stream<Item = BusEvent> + Unpin + 'static {
+        event_stream(&self.events)
+    }
+
+    /// Start listening on `address` while the bus runs.
+    ///
+    /// See [`Bus::add_listener`].
+    pub async fn add_listener(
+        &mut self,
+        address: &str,
+        auth_mechanism: AuthMechanism,
+    ) -> Result<String> {
+        self.add_listener_(address, auth_mechanism, None).await
+    }
+
+    /// Start listening on `address` while the bus runs, advertising `guid` there.
+    ///
+    /// See [`Bus::add_listener_with_guid`].
+    pub async fn add_listener_with_guid(
+        &mut self,
+        address: &str,
+        auth_mechanism: AuthMechanism,
+        guid: Guid,
+    ) -> Result<String> {
+        self.add_listener_(address, auth_mechanism, Some(guid))
+            .await
+    }
+
+    async fn add_listener_(
+        &mut self,
+        address: &str,
+        auth_mechanism: AuthMechanism,
+        guid: Option<Guid>,
+    ) -> Result<String> {
+        let (reply, response) = oneshot::channel();
+        self.request(ListenerRequest::Add {
+            address: address.to_string(),
+            auth_mechanism,
+            guid,
+            reply,
+        })
+        .await?;
+        let (added, address) = response.await.map_err(|_| anyhow!("The bus is gone."))??;
+        self.address = address;
+
+        Ok(added)
+    }
+
+    /// Stop listening on `address` while the bus runs.
+    ///
+    /// See [`Bus::remove_listener`].
+    pub async fn remove_listener(&mut self, address: &str) -> Result<()> {
+        let (reply, response) = oneshot::channel();
+        self.request(ListenerRequest::Remove {
+            address: address.to_string(),
+            reply,
+        })
+        .await?;
+        self.address = response.await.map_err(|_| anyhow!("The bus is gone."))??;
+
+        Ok(())
+    }
+
+    async fn request(&self, request: ListenerRequest) -> Result<()> {
+        self.listener_requests
+            .send(request)
+            .await
+            .map_err(|_| anyhow!("The bus is gone."))
+    }
+
+    /// Stop the bus and wait until it's cleaned up.
+    ///
+    /// Fails if the bus had already stopped with an error, or if cleaning it up failed.
+    pub async fn shutdown(self) -> Result<(), BusError> {
+        // It's fine if the bus already stopped on its own.
+        let _ = self.stop.send(());
+
+        self.task.await.map_err(|e| BusError::Other(e.into()))?
+    }
 }
 
 impl Bus {
-    pub async fn for_address(address: Option<&str>, auth_mechanism: AuthMechanism) -> Result<Self> {
-        let address = match address {
+    pub async fn for_address(
+        address: Option<&str>,
+        auth_mechanism: AuthMechanism,
+    ) -> Result<Self, BusError> {
+        let builder = BusBuilder::new().auth_mechanism(auth_mechanism);
+        match address {
+            Some(address) => builder.address(address),
+            None => builder,
+        }
+        .build()
+        .await
+    }
+
+    /// Serve the single connection inherited on stdin, inetd-style, rather than listening.
+    ///
+    /// The bus then returns from [`Bus::run`] once that connection closes.
+    #[cfg(unix)]
+    pub async fn for_inherited_connection(auth_mechanism: AuthMechanism) -> Result<Self, BusError> {
+        BusBuilder::new()
+            .address(INHERITED_ADDRESS)
+            .auth_mechanism(auth_mechanism)
+            .exit_on_idle(true)
+            .build()
+            .await
+    }
+
+    /// Accept connections on all the sockets systemd listens on for us, rather than binding an
+    /// address, with socket activation.
+    ///
+    /// Fails if we weren't socket-activated, i.e. `LISTEN_PID` and `LISTEN_FDS` aren't set, or
+    /// if the sockets are meant for another process. systemd owns the sockets and their files,
+    /// so [`Bus::cleanup`] leaves them be. Use a `systemd:name=` address to only take some of the
+    /// sockets.
+    #[cfg(all(target_os = "linux", feature = "systemd"))]
+    pub async fn for_activated_socket(auth_mechanism: AuthMechanism) -> Result<Self, BusError> {
+        BusBuilder::new()
+            .address(SYSTEMD_ADDRESS)
+            .auth_mechanism(auth_mechanism)
+            .build()
+            .await
+    }
+
+    pub(crate) async fn for_builder(builder: BusBuilder<'_>) -> Result<Self, BusError> {
+        let address = match builder.address {
             Some(address) => address.to_string(),
             None => default_address(),
         };
-        match Address::from_str(&address)? {
-            #[cfg(unix)]
-            Address::Unix(path) => {
-                let path = Path::new(&path);
-                info!("Listening on {}.", path.display());
+        let machine_id = read_machine_id(builder.machine_id_path.as_deref()).await?;
+        let bind_options = BindOptions {
+            replace_stale_socket: builder.replace_stale_socket,
+            socket_mode: builder.socket_mode,
+            #[cfg(all(target_os = "linux", feature = "selinux"))]
+            socket_context: builder.socket_context,
+        };
+        // Before binding anything, so there's nothing to clean up.
+        if iter::once(builder.auth_mechanism)
+            .chain(builder.additional_listeners.iter().map(|(_, auth)| *auth))
+            .any(|auth| matches!(auth, AuthMechanism::Cookie))
+            && home_dir().is_none()
+        {
+            return Err(BusError::Auth(
+                "`DBUS_COOKIE_SHA1` needs a home directory to keep its cookies in".to_string(),
+            ));
+        }
+        let mut listeners = Listener::bind(&address, builder.auth_mechanism, None, &bind_options)
+            .await
+            .map_err(|e| BusError::bind(&address, e))?;
+        for (address, auth_mechanism) in builder.additional_listeners {
+            match Listener::bind(address, auth_mechanism, None, &bind_options).await {
+                Ok(more) => listeners.extend(more),
+                Err(e) => {
+                    // Don't leave socket files behind.
+                    for listener in listeners {
+                        let _ = listener.cleanup().await;
+                    }
 
-                Self::unix_stream(path, auth_mechanism).await
+                    return Err(BusError::bind(address, e));
+                }
             }
-            #[cfg(not(unix))]
-            Address::Unix(_) => Err(anyhow!("`unix` transport on non-UNIX OS is not supported."))?,
-            Address::Tcp(address) => {
-                info!("Listening on `{}:{}`.", address.host(), address.port());
+        }
+        let guid = builder.guid.unwrap_or_else(Guid::generate);
+        let address = listeners_address(&listeners, &guid)?;
+        if builder.deny_anonymous {
+            warn_if_anonymous(&listeners)?;
+        }
+        let message_log = match builder.message_log_path {
+            Some(path) => Some(MessageLog::create(&path, builder.message_log_rotation).await?),
+            None => None,
+        };
+        let (events, _) = broadcast::channel(EVENT_QUEUE_SIZE);
+        let limits = Arc::new(SharedLimits::new(builder.limits));
+        let name_registry = NameRegistry::new(
+            events.clone(),
+            limits.clone(),
+            builder.reserved_names,
+            builder.name_promotion_grace,
+        );
+        let peers = Peers::new(
+            name_registry,
+            message_log,
+            builder.destination_rate_limit,
+            limits,
+            builder.max_completed_connections,
+            builder.slow_routing_threshold,
+            events.clone(),
+        );
+        #[cfg(unix)]
+        let control_socket = match builder.control_socket {
+            Some(path) => Some(
+                ControlSocket::bind(&path, peers.clone())
+                    .await
+                    .map_err(|e| BusError::bind(&path.display().to_string(), e))?,
+            ),
+            None => None,
+        };
 
-                Self::tcp_stream(&address, auth_mechanism).await
-            }
-            Address::NonceTcp { .. } => {
-                Err(anyhow!("`nonce-tcp` transport is not supported (yet)."))?
+        Ok(Self {
+            listeners,
+            address,
+            peers,
+            guid,
+            next_id: 0,
+            events,
+            machine_id,
+            exit_on_idle: builder.exit_on_idle,
+            lifecycle_signals: builder.lifecycle_signals,
+            accepting: watch::channel(false).0,
+            alive: Arc::new(watch::channel(Instant::now()).0),
+            max_accept_delay: builder.max_accept_delay,
+            accept_filter: None,
+            deny_anonymous: builder.deny_anonymous,
+            bind_options,
+            listener_requests: None,
+            #[cfg(unix)]
+            control_socket,
+        })
+    }
+
+    /// A stream of events on the bus.
+    ///
+    /// Only events that happen after this call are yielded. If the stream isn't polled often
+    /// enough to keep up with the bus, the oldest events are skipped.
+    pub fn event_stream(&self) -> impl Stream<Item = BusEvent> + Unpin + 'static {
+        event_stream(&self.events)
+    }
+
+    /// A stream of the changes of the primary owner of `name`.
+    ///
+    /// This covers the name being taken, released and replaced, whether explicitly or because
+    /// its owner disconnected. Like with [`Bus::event_stream`], only changes that happen after
+    /// this call are yielded and the oldest ones are skipped if the stream lags behind. Each
+    /// stream gets all the changes, however many watch the same name.
+    pub fn watch_name(
+        &self,
+        name: WellKnownName<'_>,
+    ) -> impl Stream<Item = NameOwnerChange> + Unpin + 'static {
+        let name = OwnedWellKnownName::from(name);
+
+        self.event_stream().filter_map(move |event| {
+            let change = match event {
+                BusEvent::NameOwnerChanged {
+                    name: changed_name,
+                    old_owner,
+                    new_owner,
+                } if changed_name == name => Some(NameOwnerChange {
+                    old_owner,
+                    new_owner,
+                }),
+                _ => None,
+            };
+
+            future::ready(change)
+        })
+    }
+
+    /// Only let peers for which `filter` returns `true` connect.
+    ///
+    /// The filter is called with the credentials of each peer as soon as it connects, so peers it
+    /// returns `false` for are disconnected before they even authenticate, let alone get any
+    /// messages routed. Since it only gets to see who the peer is, not what it sends, this is no
+    /// replacement for a policy on messages. Peers connected before the filter was set are not
+    /// affected. The supplementary groups of peers aren't resolved yet by the time it's called.
+    pub fn set_accept_filter<F>(&mut self, filter: F)
+    where
+        F: Fn(&Credentials) -> bool + Send + Sync + 'static,
+    {
+        self.accept_filter = Some(AcceptFilter(Box::new(filter)));
+    }
+
+    /// Resolves once [`Bus::run`] is accepting connections.
+    ///
+    /// The bus is already listening once it's built, so clients can connect before it runs:
+    /// their connections are queued (by the OS or, for the `memory:` transport, by the bus) and
+    /// accepted once it does. Hence this is only needed to know when the bus starts serving, e.g.
+    /// to not time out on connections made before [`Bus::run`] is spawned. It resolves right
+    /// away if the bus is already running.
+    pub fn ready(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut accepting = self.accepting.subscribe();
+
+        async move {
+            while !*accepting.borrow_and_update() {
+                if accepting.changed().await.is_err() {
+                    // The bus is gone.
+                    future::pending::<()>().await;
+                }
             }
-            Address::Autolaunch(_) => {
-                Err(anyhow!("`autolaunch` transport is not supported (yet)."))?
+        }
+    }
+
+    /// When the bus was last seen alive.
+    ///
+    /// While [`Bus::run`] runs, this is updated every second or so, from the task accepting
+    /// connections and only once the peers can be looked up for routing. So it stops being
+    /// updated if either is stuck, e.g. for a watchdog to tell. It starts out as when the bus was
+    /// built.
+    pub fn liveness(&self) -> watch::Receiver<Instant> {
+        self.alive.subscribe()
+    }
+
+    /// A connector for clients, if the bus is listening on the `memory:` address.
+    #[cfg(unix)]
+    pub fn memory_connector(&self) -> Option<MemoryConnector> {
+        self.listeners
+            .iter()
+            .find_map(|listener| match &listener.transport {
+                Transport::Memory { connector, .. } => Some(connector.clone()),
+                _ => None,
+            })
+    }
+
+    /// The address the bus is listening on.
+    ///
+    /// Unlike the address the bus was created for, this is fully resolved, e.g. with the path of
+    /// the socket for `unix:dir=` addresses, and carries the GUID clients are to expect there as
+    /// `guid=`, like the addresses `dbus-daemon` advertises, but for `memory:` and the inherited
+    /// connection. If the bus listens on multiple addresses, they're separated by `;`.
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// The GUID of the bus.
+    pub fn guid(&self) -> &Guid {
+        &self.guid
+    }
+
+    /// Also listen on `address`, requiring peers to authenticate through `auth_mechanism` there.
+    ///
+    /// The new listener shares the GUID and everything else with the existing ones, and
+    /// [`Bus::run`] accepts connections on it right away. Returns its address, resolved like
+    /// [`Bus::address`], which is what [`Bus::remove_listener`] takes. Use
+    /// [`BusHandle::add_listener`] to add listeners to a spawned bus.
+    pub async fn add_listener(
+        &mut self,
+        address: &str,
+        auth_mechanism: AuthMechanism,
+    ) -> Result<String> {
+        self.add_listener_(address, auth_mechanism, None).await
+    }
+
+    /// Like [`Bus::add_listener`], but advertising `guid` to the peers connecting through the new
+    /// listener, instead of the GUID of the bus.
+    ///
+    /// Both their authentication handshake and `GetId` give them `guid`, so that they can tell
+    /// the listener apart from the others, e.g. for a container having its own socket. Use
+    /// [`BusHandle::add_listener_with_guid`] to add listeners to a spawned bus.
+    pub async fn add_listener_with_guid(
+        &mut self,
+        address: &str,
+        auth_mechanism: AuthMechanism,
+        guid: Guid,
+    ) -> Result<String> {
+        self.add_listener_(address, auth_mechanism, Some(guid))
+            .await
+    }
+
+    async fn add_listener_(
+        &mut self,
+        address: &str,
+        auth_mechanism: AuthMechanism,
+        guid: Option<Guid>,
+    ) -> Result<String> {
+        let listeners = Listener::bind(address, auth_mechanism, guid, &self.bind_options).await?;
+        if self.deny_anonymous {
+            warn_if_anonymous(&listeners)?;
+        }
+        let added = listeners_address(&listeners, &self.guid)?;
+        for listener in listeners {
+            let address = listener.address()?;
+            self.listeners.push(listener);
+            let _ = self.events.send(BusEvent::ListenerAdded { address });
+        }
+        self.address = listeners_address(&self.listeners, &self.guid)?;
+
+        Ok(added)
+    }
+
+    /// Stop listening on `address`, as returned by [`Bus::address`] or [`Bus::add_listener`],
+    /// with or without its `guid=`.
+    ///
+    /// The listener is cleaned up, e.g. its socket file is removed, but peers that connected
+    /// through it stay connected. Fails if the bus isn't listening on `address`, and refuses to
+    /// remove the last listener, since the bus couldn't accept any connections anymore. Use
+    /// [`BusHandle::remove_listener`] to remove listeners of a spawned bus.
+    pub async fn remove_listener(&mut self, address: &str) -> Result<()> {
+        let mut found = None;
+        for (i, listener) in self.listeners.iter().enumerate() {
+            if listener.address()? == address || listener.advertised_address(&self.guid)? == address
+            {
+                found = Some(i);
+
+                break;
             }
-            _ => Err(anyhow!("Unsupported address `{}`.", address))?,
         }
+        let i = found.ok_or_else(|| anyhow!("Not listening on `{}`", address))?;
+        if self.listeners.len() == 1 {
+            return Err(anyhow!("Can't remove the last listener"));
+        }
+
+        let listener = self.listeners.remove(i);
+        self.address = listeners_address(&self.listeners, &self.guid)?;
+        info!("No longer listening on `{}`.", address);
+        let _ = self.events.send(BusEvent::ListenerRemoved {
+            address: address.to_string(),
+        });
+
+        listener.cleanup().await
+    }
+
+    /// A snapshot of the statistics of the bus.
+    pub async fn stats(&self) -> BusStats {
+        self.peers.stats().await
+    }
+
+    /// The current limits imposed on peers.
+    pub fn limits(&self) -> Limits {
+        self.peers.limits().get()
+    }
+
+    /// Change `limit` to `value`, without disconnecting anyone.
+    ///
+    /// The new value applies to what peers do from now on. For example, peers that already have
+    /// more match rules than a lowered [`Limit::MaxMatchRulesPerConnection`] keep them, but can't
+    /// add any more. Privileged peers can do the same through the `SetLimit` method of the
+    /// `org.busd.Limits` interface.
+    pub fn set_limit(&self, limit: Limit, value: usize) {
+        self.peers.limits().set(limit, value);
+    }
+
+    /// A snapshot of all connected peers, ordered by ID.
+    ///
+    /// IDs are assigned as connections are accepted and tag all the logs about each peer. Only
+    /// peers connected over TCP have a remote address. See [`PeerInfo`] for the details.
+    pub async fn peers(&self) -> Vec<PeerInfo> {
+        self.peers.infos().await
+    }
+
+    /// The unique names of all connected peers.
+    pub async fn peer_names(&self) -> Vec<OwnedUniqueName> {
+        self.peers.unique_names().await
+    }
+
+    /// Forcibly disconnect the peer with the given unique name.
+    ///
+    /// The peer is cleaned up just like when it disconnects on its own: its names are released
+    /// and its match rules go away with it. Nothing happens if no such peer is connected.
+    pub async fn disconnect_peer(&self, unique_name: UniqueName<'_>) {
+        self.peers.remove(unique_name).await
+    }
+
+    /// The machine ID, which is an ephemeral one if none could be found.
+    pub fn machine_id(&self) -> &str {
+        &self.machine_id
+    }
+
+    /// Serve `iface` at `path` from within the bus process, under the well-known name `name`.
+    ///
+    /// The service is a peer like any other, except that it's connected through an in-process
+    /// socket pair instead of the bus' listener. It lives as long as the returned connection, which
+    /// can also be used to emit signals. It takes up a connection slot like any other peer too,
+    /// waiting for one if [`BusBuilder::max_completed_connections`] is reached.
+    #[cfg(unix)]
+    pub async fn serve_at<I>(
+        &mut self,
+        name: &str,
+        path: &str,
+        iface: I,
+    ) -> Result<zbus::Connection>
+    where
+        I: zbus::Interface,
+    {
+        let slot = self.peers.reserve_slot().await?;
+        let (client, server) = tokio::net::UnixStream::pair()?;
+        let credentials = Credentials::from_unix_stream(&server)?;
+        let id = self.next_id;
+        self.next_id += 1;
+        let peer = Peer::new(
+            &self.guid,
+            id,
+            Box::new(server),
+            credentials,
+            Instant::now(),
+            self.peers.clone(),
+            // Always possible with socket pairs, regardless of the mechanism of the listener.
+            AuthMechanism::External,
+        );
+        let name = OwnedWellKnownName::from(WellKnownName::try_from(name)?);
+        let conn = async {
+            zbus::ConnectionBuilder::socket(client)
+                .serve_at(path, iface)?
+                .build()
+                .await
+                .map_err(anyhow::Error::from)
+        };
+        let (peer, conn) = future::try_join(peer, conn).await?;
+        let unique_name = peer.unique_name().clone();
+        self.peers.add(peer, slot).await;
+        // Requested on behalf of the service, since it may be reserved.
+        let flags = RequestNameFlags::ReplaceExisting | RequestNameFlags::DoNotQueue;
+        match self
+            .peers
+            .name_registry()
+            .claim_name(name.clone(), unique_name, flags)?
+        {
+            RequestNameReply::PrimaryOwner | RequestNameReply::AlreadyOwner => Ok(conn),
+            _ => Err(anyhow!("`{}` is already owned", name)),
+        }
+    }
+
+    /// Connect a peer from within the bus process, treating it as authenticated with `credentials`.
+    ///
+    /// The peer's real credentials are ignored, so that tests can exercise credential-dependent
+    /// behavior with controlled identities. Like [`Bus::serve_at`], it waits for a connection slot
+    /// if need be. Never use this in production.
+    #[cfg(all(unix, feature = "test-util"))]
+    pub async fn add_authenticated_peer(
+        &mut self,
+        credentials: Credentials,
+    ) -> Result<zbus::Connection> {
+        if !self.accepts(&credentials) {
+            return Err(anyhow!("Refused by the accept filter"));
+        }
+        let slot = self.peers.reserve_slot().await?;
+        let (client, server) = tokio::net::UnixStream::pair()?;
+        let id = self.next_id;
+        self.next_id += 1;
+        let peer = Peer::new(
+            &self.guid,
+            id,
+            Box::new(server),
+            credentials,
+            Instant::now(),
+            self.peers.clone(),
+            // The credentials are made up anyway.
+            AuthMechanism::Anonymous,
+        );
+        let conn = async {
+            zbus::ConnectionBuilder::socket(client)
+                .auth_mechanisms(&[AuthMechanism::Anonymous])
+                .build()
+                .await
+                .map_err(anyhow::Error::from)
+        };
+        let (peer, conn) = future::try_join(peer, conn).await?;
+        self.peers.add(peer, slot).await;
+
+        Ok(conn)
     }
 
-    pub async fn run(&mut self) -> Result<()> {
-        while let Ok(socket) = self.accept().await {
-            if self.auth_mechanism == AuthMechanism::Cookie {
-                sync_cookies().await?;
+    /// Accept and serve peers.
+    ///
+    /// Only returns once connections can't be accepted on any of the listeners anymore, unless
+    /// the bus was built with [`BusBuilder::exit_on_idle`], in which case it returns once the last
+    /// peer disconnects.
+    pub async fn run(&mut self) -> Result<(), BusError> {
+        let peers = self.peers.clone();
+        let signals = peers.emit_bus_signals(peers.subscribe_bus_signals());
+        let exit_on_idle = self.exit_on_idle;
+        // Only subscribing if needed, since routing doesn't create events no one listens to.
+        let idle_events = exit_on_idle.then(|| self.events.subscribe());
+        let idle = wait_until_idle(self.peers.clone(), idle_events);
+        let heartbeat = heartbeat(self.peers.clone(), self.alive.clone());
+        self.accepting.send_replace(true);
+        if self.lifecycle_signals {
+            peers.emit_lifecycle_signal("Starting").await;
+        }
+        let res = select! {
+            res = self.accept_peers() => res,
+            // Only another subscriber could stop the signals, while we hold `self`.
+            _ = signals => unreachable!("bus signals channel closed"),
+            _ = heartbeat => unreachable!("heartbeat stopped"),
+            _ = idle, if exit_on_idle => {
+                info!("No peers left, exiting..");
+
+                Ok(())
             }
-            match Peer::new(
-                &self.guid,
-                self.next_id,
-                socket,
-                self.name_registry.clone(),
-                self.auth_mechanism,
-            )
-            .await
+        };
+        self.accepting.send_replace(false);
+
+        res.map_err(Into::into)
+    }
+
+    async fn accept_peers(&mut self) -> Result<()> {
+        loop {
+            let slot = self.peers.reserve_slot().await?;
+            if let Some(max_delay) = self.max_accept_delay {
+                let pending_sends = self.peers.pending_sends();
+                let delay = ACCEPT_DELAY_PER_PENDING_SEND
+                    .saturating_mul(u32::try_from(pending_sends).unwrap_or(u32::MAX))
+                    .min(max_delay);
+                if !delay.is_zero() {
+                    debug!(
+                        "{} messages pending, delaying accept by {:?}",
+                        pending_sends, delay
+                    );
+                    sleep(delay).await;
+                }
+            }
+            let (socket, credentials, auth_mechanism, guid) = self.accept().await?;
+            self.add_peer(socket, credentials, auth_mechanism, guid, slot)
+                .await?;
+            // Otherwise we'd wait forever for a peer that never comes.
+            if self.exit_on_idle
+                && self.listeners.iter().all(Listener::is_exhausted)
+                && self.peers.count().await == 0
             {
-                Ok(peer) => self.peers.add(peer).await,
-                Err(e) => warn!("Failed to establish connection: {}", e),
+                info!("No peers left to accept, exiting..");
+
+                return Ok(());
             }
-            self.next_id += 1;
         }
+    }
+
+    /// Authenticate the peer on the other end of `socket` and start serving it.
+    ///
+    /// Peers that are rejected or fail to connect are only logged about. `guid` overrides the
+    /// GUID of the bus for the peer.
+    async fn add_peer(
+        &mut self,
+        socket: Box<dyn Socket + 'static>,
+        credentials: Credentials,
+        auth_mechanism: AuthMechanism,
+        guid: Option<Guid>,
+        slot: Option<OwnedSemaphorePermit>,
+    ) -> Result<()> {
+        let accepted_at = Instant::now();
+        // Dropping the socket fails the handshake.
+        if self.deny_anonymous && matches!(auth_mechanism, AuthMechanism::Anonymous) {
+            info!("Rejecting peer, `ANONYMOUS` authentication is denied.");
+
+            return Ok(());
+        }
+        if let AuthMechanism::Cookie = auth_mechanism {
+            sync_cookies().await?;
+        }
+        if !self.accepts(&credentials) {
+            info!("Rejecting peer refused by the accept filter.");
+
+            return Ok(());
+        }
+        match Peer::new(
+            guid.as_ref().unwrap_or(&self.guid),
+            self.next_id,
+            socket,
+            credentials,
+            accepted_at,
+            self.peers.clone(),
+            auth_mechanism,
+        )
+        .instrument(info_span!("peer", id = self.next_id))
+        .await
+        {
+            Ok(peer) => self.peers.add(peer, slot).await,
+            Err(e) => warn!("Failed to establish connection {}: {}", self.next_id, e),
+        }
+        self.next_id += 1;
 
         Ok(())
     }
 
-    // AsyncDrop would have been nice!
-    pub async fn cleanup(self) -> Result<()> {
-        match self.listener {
+    async fn handle_listener_request(&mut self, request: ListenerRequest) {
+        // The requester may have given up waiting, that's fine.
+        match request {
+            ListenerRequest::Add {
+                address,
+                auth_mechanism,
+                guid,
+                reply,
+            } => {
+                let res = self
+                    .add_listener_(&address, auth_mechanism, guid)
+                    .await
+                    .map(|added| (added, self.address.clone()));
+                let _ = reply.send(res);
+            }
+            ListenerRequest::Remove { address, reply } => {
+                let res = self
+                    .remove_listener(&address)
+                    .await
+                    .map(|_| self.address.clone());
+                let _ = reply.send(res);
+            }
+        }
+    }
+
+    /// If the accept filter, if any, lets a peer with the given credentials in.
+    fn accepts(&self, credentials: &Credentials) -> bool {
+        match &self.accept_filter {
+            Some(filter) => (filter.0)(credentials),
+            None => true,
+        }
+    }
+
+    /// Run the bus on a new task, until the returned handle is shut down or dropped.
+    ///
+    /// This takes care of running and then cleaning up the bus, e.g. in tests or when embedding
+    /// it in a larger application.
+    pub fn spawn(mut self) -> BusHandle {
+        let (stop, stopped) = oneshot::channel::<()>();
+        let (listener_requests, requests) = mpsc::channel(1);
+        self.listener_requests = Some(requests);
+        let address = self.address.clone();
+        let guid = self.guid.clone();
+        #[cfg(unix)]
+        let memory_connector = self.memory_connector();
+        let events = self.events.clone();
+        let task = tokio::spawn(async move {
+            // Also resolves if the handle is dropped.
+            let res = select! {
+                _ = stopped => Ok(()),
+                res = self.run() => res,
+            };
+            let cleaned_up = self.cleanup().await;
+
+            res.and(cleaned_up)
+        });
+
+        BusHandle {
+            address,
+            guid,
             #[cfg(unix)]
-            Listener::Unix { socket_path, .. } => {
-                remove_file(socket_path).await.map_err(Into::into)
+            memory_connector,
+            stop,
+            listener_requests,
+            events,
+            task,
+        }
+    }
+
+    // AsyncDrop would have been nice!
+    pub async fn cleanup(self) -> Result<(), BusError> {
+        if self.lifecycle_signals {
+            self.peers.emit_lifecycle_signal("ShuttingDown").await;
+        }
+        let mut res = Ok(());
+        for listener in self.listeners {
+            if let Err(e) = listener.cleanup().await {
+                res = Err(e.into());
+            }
+        }
+        #[cfg(unix)]
+        if let Some(control_socket) = self.control_socket {
+            if let Err(e) = control_socket.cleanup().await {
+                res = Err(e.into());
             }
-            Listener::Tcp { .. } => Ok(()),
         }
+
+        res
     }
 
-    fn new(listener: Listener, auth_mechanism: AuthMechanism) -> Self {
-        let name_registry = NameRegistry::default();
+    /// Accept a connection on any of the listeners.
+    ///
+    /// A listener that fails is removed, while the others keep accepting connections. Only fails
+    /// if no listeners are left.
+    // Along with the auth mechanism and the GUID override of the listener that accepted the peer.
+    async fn accept(
+        &mut self,
+    ) -> Result<(
+        Box<dyn Socket + 'static>,
+        Credentials,
+        AuthMechanism,
+        Option<Guid>,
+    )> {
+        loop {
+            if self.listeners.is_empty() {
+                return Err(anyhow!("No listeners left"));
+            }
+            let listener_requests = &mut self.listener_requests;
+            let requests = async {
+                match listener_requests {
+                    Some(requests) => requests.recv().await,
+                    None => None,
+                }
+            };
+            let accepts = self
+                .listeners
+                .iter_mut()
+                .map(|listener| Box::pin(listener.accept()));
+            let (res, i) = select! {
+                (res, i, _) = future::select_all(accepts) => (res, i),
+                Some(request) = requests => {
+                    self.handle_listener_request(request).await;
 
-        Self {
-            listener,
-            peers: Peers::new(name_registry.clone()),
-            guid: Guid::generate(),
-            next_id: 0,
-            name_registry,
-            auth_mechanism,
+                    continue;
+                }
+            };
+            let e = match res {
+                Ok((socket, credentials)) => {
+                    let listener = &self.listeners[i];
+
+                    return Ok((
+                        socket,
+                        credentials,
+                        listener.auth_mechanism,
+                        listener.guid.clone(),
+                    ));
+                }
+                Err(e) if is_transient(&e) => {
+                    warn!("Failed to accept a connection: {}", e);
+                    // Give e.g. file descriptors a chance to be freed up before retrying.
+                    sleep(ACCEPT_RETRY_DELAY).await;
+
+                    continue;
+                }
+                Err(e) => e,
+            };
+
+            let listener = self.listeners.remove(i);
+            let address = listener.address()?;
+            warn!("No longer listening on `{}`: {}", address, e);
+            if let Err(e) = listener.cleanup().await {
+                debug!("Failed to clean up listener on `{}`: {}", address, e);
+            }
+            self.address = listeners_address(&self.listeners, &self.guid)?;
+            let _ = self.events.send(BusEvent::ListenerRemoved { address });
         }
     }
+}
 
-    #[cfg(unix)]
-    async fn unix_stream(socket_path: &Path, auth_mechanism: AuthMechanism) -> Result<Self> {
-        let socket_path = socket_path.to_path_buf();
-        let listener = Listener::Unix {
-            listener: tokio::net::UnixListener::bind(&socket_path)?,
-            socket_path,
-        };
+impl Listener {
+    /// Bind to `address`, which takes multiple listeners if it resolves to multiple addresses.
+    ///
+    /// `address` can also be a `;`-separated list, like `DBUS_SESSION_BUS_ADDRESS`, in which case
+    /// all of them are bound. Semicolons in values are escaped, so they can't be mistaken for
+    /// separators.
+    async fn bind(
+        address: &str,
+        auth_mechanism: AuthMechanism,
+        guid: Option<Guid>,
+        options: &BindOptions,
+    ) -> Result<Vec<Self>> {
+        let mut listeners = vec![];
+        for address in address.split(';').filter(|address| !address.is_empty()) {
+            match Transport::bind(address, options).await {
+                Ok(transports) => listeners.extend(transports.into_iter().map(|transport| Self {
+                    transport,
+                    auth_mechanism,
+                    guid: guid.clone(),
+                })),
+                Err(e) => {
+                    // Don't leave socket files behind.
+                    for listener in listeners {
+                        let _ = listener.cleanup().await;
+                    }
+
+                    return Err(e);
+                }
+            }
+        }
+        if listeners.is_empty() {
+            return Err(BusError::address(address, anyhow!("Address is empty")));
+        }
 
-        Ok(Self::new(listener, auth_mechanism))
+        Ok(listeners)
     }
 
-    async fn tcp_stream(address: &TcpAddress, auth_mechanism: AuthMechanism) -> Result<Self> {
-        let address = (address.host(), address.port());
-        let listener = Listener::Tcp {
-            listener: tokio::net::TcpListener::bind(address).await?,
-        };
+    /// The address of the listener as advertised to clients, with the GUID they're to expect
+    /// there, the one of the listener if it has one, `bus_guid` otherwise.
+    ///
+    /// Only addresses clients can connect to from elsewhere get one.
+    fn advertised_address(&self, bus_guid: &Guid) -> Result<String> {
+        let address = self.address()?;
+        match &self.transport {
+            #[cfg(unix)]
+            Transport::Memory { .. } | Transport::Inherited { .. } => Ok(address),
+            _ => Ok(format!(
+                "{},guid={}",
+                address,
+                self.guid.as_ref().unwrap_or(bus_guid)
+            )),
+        }
+    }
+
+    /// The address of the listener, with any generated parts resolved.
+    fn address(&self) -> Result<String> {
+        match &self.transport {
+            #[cfg(unix)]
+            Transport::Unix { socket_path, .. } => Ok(format!(
+                "unix:path={}",
+                escape(socket_path.as_os_str().as_bytes())
+            )),
+            #[cfg(target_os = "linux")]
+            Transport::Abstract { name, .. } => Ok(format!("unix:abstract={}", escape(name))),
+            Transport::Tcp { listener, host } => {
+                let addr = listener.local_addr()?;
+                let host = host.clone().unwrap_or_else(|| addr.ip().to_string());
 
-        Ok(Self::new(listener, auth_mechanism))
+                Ok(format!(
+                    "tcp:host={},port={}",
+                    escape(host.as_bytes()),
+                    addr.port()
+                ))
+            }
+            #[cfg(unix)]
+            Transport::Memory { .. } => Ok(MEMORY_ADDRESS.to_string()),
+            #[cfg(unix)]
+            Transport::Inherited { .. } => Ok(INHERITED_ADDRESS.to_string()),
+            #[cfg(all(target_os = "linux", feature = "systemd"))]
+            Transport::Activated { listener, name } => match listener {
+                ActivatedListener::Unix(listener) => match listener.local_addr()?.as_pathname() {
+                    Some(path) => Ok(format!("unix:path={}", escape(path.as_os_str().as_bytes()))),
+                    // Abstract sockets can't be told apart from unnamed ones (yet).
+                    None => Ok(format!("systemd:name={}", escape(name.as_bytes()))),
+                },
+                ActivatedListener::Tcp(listener) => {
+                    let addr = listener.local_addr()?;
+
+                    Ok(format!(
+                        "tcp:host={},port={}",
+                        escape(addr.ip().to_string().as_bytes()),
+                        addr.port()
+                    ))
+                }
+            },
+        }
     }
 
-    async fn accept(&mut self) -> Result<Box<dyn Socket + 'static>> {
-        match &mut self.listener {
+    async fn accept(&mut self) -> Result<(Box<dyn Socket + 'static>, Credentials)> {
+        match &mut self.transport {
             #[cfg(unix)]
-            Listener::Unix {
+            Transport::Unix {
                 listener,
-                socket_path: _,
-            } => {
+                socket_path,
+            } => loop {
+                // Accepting doesn't fail if the socket file is removed, we just don't get any
+                // connections anymore.
+                let (unix_stream, addr) = select! {
+                    res = listener.accept() => res?,
+                    _ = socket_removed(socket_path) => {
+                        return Err(anyhow!("`{}` was removed", socket_path.display()));
+                    }
+                };
+                debug!("Accepted connection from {:?}", addr);
+                if let Some(peer) = unix_peer(unix_stream, self.auth_mechanism) {
+                    return Ok(peer);
+                }
+            },
+            #[cfg(target_os = "linux")]
+            Transport::Abstract { listener, .. } => loop {
                 let (unix_stream, addr) = listener.accept().await?;
                 debug!("Accepted connection from {:?}", addr);
+                if let Some(peer) = unix_peer(unix_stream, self.auth_mechanism) {
+                    return Ok(peer);
+                }
+            },
+            Transport::Tcp { listener, .. } => {
+                let (tcp_stream, addr) = listener.accept().await?;
+                debug!("Accepted connection from {:?}", addr);
+
+                Ok((Box::new(tcp_stream), Credentials::from_remote_address(addr)))
+            }
+            #[cfg(unix)]
+            Transport::Memory { rx, .. } => {
+                let unix_stream = rx.recv().await.ok_or_else(|| anyhow!("Channel closed"))?;
+                debug!("Accepted in-memory connection");
+                let credentials = Credentials::from_unix_stream(&unix_stream)?;
 
-                Ok(Box::new(unix_stream))
+                Ok((Box::new(unix_stream), credentials))
             }
-            Listener::Tcp { listener } => {
+            #[cfg(unix)]
+            Transport::Inherited { stream } => match stream.take() {
+                Some(InheritedStream::Unix(unix_stream)) => {
+                    debug!("Accepted inherited connection");
+
+                    unix_peer(unix_stream, self.auth_mechanism)
+                        .ok_or_else(|| anyhow!("Inherited peer rejected"))
+                }
+                Some(InheritedStream::Tcp(tcp_stream)) => {
+                    let addr = tcp_stream.peer_addr()?;
+                    debug!("Accepted inherited connection from {:?}", addr);
+
+                    Ok((Box::new(tcp_stream), Credentials::from_remote_address(addr)))
+                }
+                None => future::pending().await,
+            },
+            #[cfg(all(target_os = "linux", feature = "systemd"))]
+            Transport::Activated {
+                listener: ActivatedListener::Unix(listener),
+                ..
+            } => loop {
+                let (unix_stream, addr) = listener.accept().await?;
+                debug!("Accepted connection from {:?}", addr);
+                if let Some(peer) = unix_peer(unix_stream, self.auth_mechanism) {
+                    return Ok(peer);
+                }
+            },
+            #[cfg(all(target_os = "linux", feature = "systemd"))]
+            Transport::Activated {
+                listener: ActivatedListener::Tcp(listener),
+                ..
+            } => {
                 let (tcp_stream, addr) = listener.accept().await?;
                 debug!("Accepted connection from {:?}", addr);
 
-                Ok(Box::new(tcp_stream))
+                Ok((Box::new(tcp_stream), Credentials::from_remote_address(addr)))
             }
         }
     }
+
+    /// If the listener will never accept any more connections.
+    fn is_exhausted(&self) -> bool {
+        match &self.transport {
+            #[cfg(unix)]
+            Transport::Inherited { stream } => stream.is_none(),
+            _ => false,
+        }
+    }
+
+    async fn cleanup(self) -> Result<()> {
+        match self.transport {
+            #[cfg(unix)]
+            Transport::Unix { socket_path, .. } => {
+                remove_file(socket_path).await.map_err(Into::into)
+            }
+            // The socket goes away along with the listener.
+            #[cfg(target_os = "linux")]
+            Transport::Abstract { .. } => Ok(()),
+            Transport::Tcp { .. } => Ok(()),
+            #[cfg(unix)]
+            Transport::Memory { .. } => Ok(()),
+            // The connection is closed along with the peer.
+            #[cfg(unix)]
+            Transport::Inherited { .. } => Ok(()),
+            // systemd owns the socket, and any file it has.
+            #[cfg(all(target_os = "linux", feature = "systemd"))]
+            Transport::Activated { .. } => Ok(()),
+        }
+    }
 }
 
+impl Transport {
+    async fn bind(address: &str, options: &BindOptions) -> Result<Vec<Self>> {
+        #[cfg(unix)]
+        if address == MEMORY_ADDRESS {
+            info!("Listening in memory.");
+            let (tx, rx) = mpsc::channel(1);
+
+            return Ok(vec![Transport::Memory {
+                rx,
+                connector: MemoryConnector { tx },
+            }]);
+        }
+        #[cfg(unix)]
+        if address == INHERITED_ADDRESS {
+            info!("Serving the connection inherited on stdin.");
+
+            return Ok(vec![Transport::Inherited {
+                stream: Some(inherited_stream()?),
+            }]);
+        }
+
+        let invalid = |e| BusError::address(address, e);
+        let address = ServerAddress::parse(address).map_err(invalid)?;
+        match address.transport() {
+            #[cfg(unix)]
+            "unix" => {
+                if let Some(path) = address.get_path("path").map_err(invalid)? {
+                    info!("Listening on {}.", path.display());
+
+                    return Ok(vec![
+                        Self::unix(&path, options.replace_stale_socket, options).await?,
+                    ]);
+                }
+                #[cfg(target_os = "linux")]
+                if let Some(name) = address.get("abstract") {
+                    info!("Listening on abstract socket `{}`.", escape(name));
+
+                    return Ok(vec![Self::unix_abstract(name)?]);
+                }
+
+                let dir = match address.get_path("dir").map_err(invalid)? {
+                    Some(dir) => dir,
+                    None => address
+                        .get_path("tmpdir")
+                        .map_err(invalid)?
+                        .ok_or_else(|| {
+                            invalid(anyhow!(
+                                "`unix` address needs a `path`, `abstract`, `dir` or `tmpdir`."
+                            ))
+                        })?,
+                };
+                let name = format!("dbus-{}", Alphanumeric.sample_string(&mut thread_rng(), 10));
+                let path = dir.join(name);
+                info!("Listening on {}.", path.display());
+
+                // We just made the name up, so there's nothing to replace.
+                Ok(vec![Self::unix(&path, false, options).await?])
+            }
+            #[cfg(not(unix))]
+            "unix" => Err(invalid(anyhow!(
+                "`unix` transport on non-UNIX OS is not supported."
+            ))),
+            "tcp" => {
+                let host = address
+                    .get_str("host")
+                    .map_err(invalid)?
+                    .ok_or_else(|| invalid(anyhow!("`tcp` address needs a `host`.")))?;
+                let port = match address.get_str("port").map_err(invalid)? {
+                    Some(port) => port
+                        .parse()
+                        .map_err(|_| invalid(anyhow!("Invalid port `{}`.", port)))?,
+                    // Picked for us.
+                    None => 0,
+                };
+
+                // If present, we bind to `bind=` instead of `host=`, which is then only
+                // advertised to clients.
+                match address.get_str("bind").map_err(invalid)? {
+                    Some(bind) => {
+                        info!(
+                            "Listening on `{}:{}`, advertised as `{}`.",
+                            bind, port, host
+                        );
+
+                        Self::tcp(bind, port, Some(host.to_string())).await
+                    }
+                    None => {
+                        info!("Listening on `{}:{}`.", host, port);
+
+                        Self::tcp(host, port, None).await
+                    }
+                }
+            }
+            #[cfg(all(target_os = "linux", feature = "systemd"))]
+            "systemd" => {
+                let name = address.get_str("name").map_err(invalid)?;
+                let fds = crate::systemd::take_listen_fds(name)?;
+                info!("Listening on {} socket(s) passed by systemd.", fds.len());
+
+                fds.into_iter()
+                    .map(|(fd, name)| Self::activated(fd, name))
+                    .collect()
+            }
+            #[cfg(not(all(target_os = "linux", feature = "systemd")))]
+            "systemd" => Err(invalid(anyhow!(
+                "`systemd` transport is only supported on Linux, with the `systemd` feature."
+            ))),
+            "nonce-tcp" => Err(invalid(anyhow!(
+                "`nonce-tcp` transport is not supported (yet)."
+            ))),
+            "autolaunch" => Err(invalid(anyhow!(
+                "`autolaunch` transport is not supported (yet)."
+            ))),
+            transport => Err(invalid(anyhow!("Unsupported transport `{}`.", transport))),
+        }
+    }
+
+    /// Take over `fd`, a socket systemd is listening on for us.
+    #[cfg(all(target_os = "linux", feature = "systemd"))]
+    fn activated(fd: std::os::unix::io::RawFd, name: String) -> Result<Self> {
+        use nix::sys::socket::{getsockname, AddressFamily, SockaddrLike, SockaddrStorage};
+        use std::os::unix::io::FromRawFd;
+
+        let family = getsockname::<SockaddrStorage>(fd)
+            .map_err(|e| anyhow!("Socket `{}` passed by systemd is unusable: {}", name, e))?
+            .family();
+        // SAFETY: systemd passed `fd` to us, and `take_listen_fds` only hands it out once.
+        let listener = match family {
+            Some(AddressFamily::Unix) => {
+                let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+                listener.set_nonblocking(true)?;
+
+                ActivatedListener::Unix(tokio::net::UnixListener::from_std(listener)?)
+            }
+            Some(AddressFamily::Inet | AddressFamily::Inet6) => {
+                let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+                listener.set_nonblocking(true)?;
+
+                ActivatedListener::Tcp(tokio::net::TcpListener::from_std(listener)?)
+            }
+            family => {
+                return Err(anyhow!(
+                    "Socket `{}` passed by systemd has unsupported family {:?}",
+                    name,
+                    family
+                ))
+            }
+        };
+
+        Ok(Transport::Activated { listener, name })
+    }
+
+    #[cfg(unix)]
+    async fn unix(socket_path: &Path, replace_stale: bool, options: &BindOptions) -> Result<Self> {
+        let listener = match bind_socket_file(socket_path, options) {
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+                if !is_stale_socket(socket_path).await {
+                    return Err(anyhow!(
+                        "`{}` is already in use, and not by a stale socket",
+                        socket_path.display()
+                    ));
+                }
+                if !replace_stale {
+                    return Err(anyhow!(
+                        "`{}` is a stale socket, no one is listening on it anymore",
+                        socket_path.display()
+                    ));
+                }
+                // Only retried once, in case someone else is replacing it at the same time.
+                info!("Replacing stale socket {}.", socket_path.display());
+                remove_file(socket_path).await?;
+
+                bind_socket_file(socket_path, options)?
+            }
+            res => res?,
+        };
+
+        Ok(Transport::Unix {
+            listener,
+            socket_path: socket_path.to_path_buf(),
+        })
+    }
+
+    /// Bind to the socket named `name` in the abstract namespace.
+    ///
+    /// Unlike socket files, abstract sockets can't be left behind, so a name in use means a socket
+    /// is bound to it. Connecting to it tells us if it's another bus, or one that's still starting
+    /// (or stopping).
+    #[cfg(target_os = "linux")]
+    fn unix_abstract(name: &[u8]) -> Result<Self> {
+        let e = match abstract_listener(name) {
+            Ok(listener) => {
+                return Ok(Transport::Abstract {
+                    listener,
+                    name: name.to_vec(),
+                })
+            }
+            Err(e) => e,
+        };
+        if e.kind() != io::ErrorKind::AddrInUse {
+            return Err(e.into());
+        }
+
+        match connect_abstract(name) {
+            Ok(()) => Err(anyhow!(
+                "Another bus is already listening on abstract socket `{}`.",
+                escape(name)
+            )),
+            Err(e) => Err(anyhow!(
+                "Abstract socket `{}` is in use but not accepting connections ({}), try again \
+                 once its owner is done starting or stopping.",
+                escape(name),
+                e
+            )),
+        }
+    }
+
+    /// Bind to all the addresses `bind` resolves to, with `*` standing for all interfaces.
+    ///
+    /// Addresses that can't be bound to (e.g. IPv6 ones on a host without IPv6) are skipped, as
+    /// long as at least one can be.
+    async fn tcp(bind: &str, mut port: u16, host: Option<String>) -> Result<Vec<Self>> {
+        let addrs: Vec<SocketAddr> = if bind == "*" {
+            vec![
+                (Ipv4Addr::UNSPECIFIED, port).into(),
+                (Ipv6Addr::UNSPECIFIED, port).into(),
+            ]
+        } else {
+            lookup_host((bind, port)).await?.collect()
+        };
+
+        let mut transports = vec![];
+        let mut error = None;
+        for mut addr in addrs {
+            // If the port is picked for us, use the same one for all addresses.
+            addr.set_port(port);
+            match tcp_listener(addr) {
+                Ok(listener) => {
+                    port = listener.local_addr()?.port();
+                    transports.push(Transport::Tcp {
+                        listener,
+                        host: host.clone(),
+                    });
+                }
+                Err(e) => {
+                    debug!("Failed to listen on `{}`: {}", addr, e);
+                    error.get_or_insert(e);
+                }
+            }
+        }
+
+        match (transports.is_empty(), error) {
+            (true, Some(e)) => Err(e.into()),
+            (true, None) => Err(anyhow!("`{}` doesn't resolve to any address", bind)),
+            (false, _) => Ok(transports),
+        }
+    }
+}
+
+/// Listen on `addr`, only for IPv6 if it's an IPv6 address.
+///
+/// This allows listening on the same port for both IPv4 and IPv6.
+fn tcp_listener(addr: SocketAddr) -> io::Result<tokio::net::TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => {
+            let socket = TcpSocket::new_v6()?;
+            #[cfg(unix)]
+            {
+                use nix::sys::socket::{setsockopt, sockopt::Ipv6V6Only};
+                use std::os::unix::io::AsRawFd;
+
+                setsockopt(socket.as_raw_fd(), Ipv6V6Only, &true)?;
+            }
+
+            socket
+        }
+    };
+    // Same as `TcpListener::bind`.
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+
+    socket.listen(1024)
+}
+
+/// Listen on the socket named `name` in the abstract namespace.
+///
+/// Neither the standard library nor tokio support abstract addresses (yet).
+#[cfg(target_os = "linux")]
+fn abstract_listener(name: &[u8]) -> io::Result<tokio::net::UnixListener> {
+    use nix::sys::socket::{bind, listen, socket, AddressFamily, SockFlag, SockType, UnixAddr};
+    use std::os::unix::io::FromRawFd;
+
+    let fd = socket(
+        AddressFamily::Unix,
+        SockType::Stream,
+        SockFlag::SOCK_CLOEXEC | SockFlag::SOCK_NONBLOCK,
+        None,
+    )?;
+    // SAFETY: We just created `fd` and nothing else owns it, so it's closed on errors too.
+    let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+    bind(fd, &UnixAddr::new_abstract(name)?)?;
+    listen(fd, 1024)?;
+
+    tokio::net::UnixListener::from_std(listener)
+}
+
+/// Take ownership of the connected socket on stdin.
+///
+/// inetd hands over both unix and TCP sockets, so we have to ask which kind it is.
+#[cfg(unix)]
+fn inherited_stream() -> Result<InheritedStream> {
+    use nix::sys::socket::{getsockname, AddressFamily, SockaddrLike, SockaddrStorage};
+    use std::os::unix::io::{FromRawFd, RawFd};
+
+    const STDIN: RawFd = 0;
+    let family = getsockname::<SockaddrStorage>(STDIN)
+        .map_err(|e| anyhow!("stdin is not a socket: {}", e))?
+        .family();
+    // SAFETY: Nothing else uses stdin. It's closed along with the stream, once the peer is gone.
+    let stream = match family {
+        Some(AddressFamily::Unix) => {
+            let stream = unsafe { std::os::unix::net::UnixStream::from_raw_fd(STDIN) };
+            stream.set_nonblocking(true)?;
+
+            InheritedStream::Unix(tokio::net::UnixStream::from_std(stream)?)
+        }
+        Some(AddressFamily::Inet | AddressFamily::Inet6) => {
+            let stream = unsafe { std::net::TcpStream::from_raw_fd(STDIN) };
+            stream.set_nonblocking(true)?;
+
+            InheritedStream::Tcp(tokio::net::TcpStream::from_std(stream)?)
+        }
+        family => return Err(anyhow!("Unsupported socket family on stdin: {:?}", family)),
+    };
+
+    Ok(stream)
+}
+
+/// Connect to the socket named `name` in the abstract namespace, without blocking.
+#[cfg(target_os = "linux")]
+fn connect_abstract(name: &[u8]) -> io::Result<()> {
+    use nix::{
+        errno::Errno,
+        sys::socket::{connect, socket, AddressFamily, SockFlag, SockType, UnixAddr},
+        unistd::close,
+    };
+
+    let fd = socket(
+        AddressFamily::Unix,
+        SockType::Stream,
+        SockFlag::SOCK_CLOEXEC | SockFlag::SOCK_NONBLOCK,
+        None,
+    )?;
+    let res = UnixAddr::new_abstract(name).and_then(|addr| connect(fd, &addr));
+    let _ = close(fd);
+    match res {
+        // A full backlog still means someone is listening.
+        Ok(()) | Err(Errno::EAGAIN) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Warn about listeners that require `ANONYMOUS`, for when it's denied.
+fn warn_if_anonymous(listeners: &[Listener]) -> Result<()> {
+    for listener in listeners {
+        if matches!(listener.auth_mechanism, AuthMechanism::Anonymous) {
+            warn!(
+                "`{}` requires `ANONYMOUS`, which is denied, so all its connections will be \
+                 refused.",
+                listener.address()?
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// The addresses of all the listeners, in the format of `DBUS_SESSION_BUS_ADDRESS`, as advertised
+/// by a bus with the given `guid`.
+fn listeners_address(listeners: &[Listener], guid: &Guid) -> Result<String> {
+    Ok(listeners
+        .iter()
+        .map(|listener| listener.advertised_address(guid))
+        .collect::<Result<Vec<_>>>()?
+        .join(";"))
+}
+
+/// The peer on the other end of `unix_stream`, along with its credentials.
+///
+/// Returns `None` if the peer must be rejected.
+#[cfg(unix)]
+fn unix_peer(
+    unix_stream: tokio::net::UnixStream,
+    auth_mechanism: AuthMechanism,
+) -> Option<(Box<dyn Socket + 'static>, Credentials)> {
+    match Credentials::from_unix_stream(&unix_stream) {
+        Ok(credentials) => Some((Box::new(unix_stream), credentials)),
+        // Without them, nothing backs the user an `EXTERNAL` peer claims to be. Only this
+        // connection is affected.
+        Err(e) if matches!(auth_mechanism, AuthMechanism::External) => {
+            warn!("Rejecting peer, failed to get its credentials: {}", e);
+
+            None
+        }
+        // Other mechanisms don't rely on them.
+        Err(e) => {
+            debug!("Failed to get credentials of the peer: {}", e);
+
+            Some((Box::new(unix_stream), Credentials::default()))
+        }
+    }
+}
+
+/// Wait until the socket file at `path` is gone.
+#[cfg(unix)]
+async fn socket_removed(path: &Path) {
+    loop {
+        match metadata(path).await {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return,
+            _ => sleep(SOCKET_CHECK_INTERVAL).await,
+        }
+    }
+}
+
+/// Bind a listener to the socket file at `path`, with exactly `mode` as its permissions if set.
+///
+/// Otherwise, they depend on the umask of the process. Setting them after the fact would leave a
+/// window where anyone the umask lets in could connect, so the umask is changed around the bind
+/// instead. Since it's process-wide, files created by other threads in the meantime get it too.
+#[cfg(unix)]
+pub(crate) fn bind_unix_listener(
+    path: &Path,
+    mode: Option<u32>,
+) -> io::Result<tokio::net::UnixListener> {
+    use nix::sys::stat::{umask, Mode};
+
+    let mode = match mode {
+        Some(mode) => mode,
+        None => return tokio::net::UnixListener::bind(path),
+    };
+    let old_umask = umask(Mode::from_bits_truncate((!mode & 0o777) as _));
+    let res = tokio::net::UnixListener::bind(path);
+    umask(old_umask);
+
+    res
+}
+
+/// Bind to the socket file at `path`, with the permissions and SELinux file context of `options`.
+#[cfg(unix)]
+fn bind_socket_file(path: &Path, options: &BindOptions) -> io::Result<tokio::net::UnixListener> {
+    #[cfg(all(target_os = "linux", feature = "selinux"))]
+    if let Some(context) = &options.socket_context {
+        return crate::selinux::with_fscreate_context(context, || {
+            bind_unix_listener(path, options.socket_mode)
+        });
+    }
+
+    bind_unix_listener(path, options.socket_mode)
+}
+
+/// If `path` is a socket that no one is listening on anymore.
+#[cfg(unix)]
+async fn is_stale_socket(path: &Path) -> bool {
+    match metadata(path).await {
+        Ok(metadata) if metadata.file_type().is_socket() => (),
+        // Not ours to remove.
+        _ => return false,
+    }
+
+    match tokio::net::UnixStream::connect(path).await {
+        Err(e) => e.kind() == io::ErrorKind::ConnectionRefused,
+        Ok(_) => false,
+    }
+}
+
+/// If an error accepting a connection is only temporary, rather than affecting the listener for
+/// good.
+fn is_transient(e: &anyhow::Error) -> bool {
+    let e = match e.downcast_ref::<io::Error>() {
+        Some(e) => e,
+        None => return false,
+    };
+    if matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+    ) {
+        return true;
+    }
+
+    // Running out of resources, which should be resolved as peers go away.
+    #[cfg(unix)]
+    if let Some(errno) = e.raw_os_error() {
+        use nix::libc::{EMFILE, ENFILE, ENOBUFS, ENOMEM};
+
+        return matches!(errno, EMFILE | ENFILE | ENOBUFS | ENOMEM);
+    }
+
+    false
+}
+
+/// A stream of the events sent through `events`, skipping the ones it lagged behind on.
+fn event_stream(
+    events: &broadcast::Sender<BusEvent>,
+) -> impl Stream<Item = BusEvent> + Unpin + 'static {
+    let events = events.subscribe();
+
+    Box::pin(stream::unfold(events, |mut events| async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => return Some((event, events)),
+                Err(RecvError::Lagged(n)) => warn!("Event stream lagging, skipped {n} events."),
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }))
+}
+
+/// Wait until the last peer disconnects, if there are `events` to tell, or forever.
+async fn wait_until_idle(peers: Peers, events: Option<broadcast::Receiver<BusEvent>>) {
+    let mut events = match events {
+        Some(events) => events,
+        None => return future::pending().await,
+    };
+    loop {
+        match events.recv().await {
+            Ok(BusEvent::PeerDisconnected(_)) | Err(RecvError::Lagged(_)) => {
+                if peers.count().await == 0 {
+                    return;
+                }
+            }
+            Ok(_) => (),
+            // The bus is going away anyway.
+            Err(RecvError::Closed) => return future::pending().await,
+        }
+    }
+}
+
+/// Record that the bus is alive every `HEARTBEAT_INTERVAL`, as long as the peers can be looked up,
+/// since routing can't go on otherwise.
+///
+/// Meant to run on the task accepting connections, so that it stops along with it too.
+async fn heartbeat(peers: Peers, alive: Arc<watch::Sender<Instant>>) {
+    let mut interval = interval(HEARTBEAT_INTERVAL);
+    loop {
+        interval.tick().await;
+        peers.count().await;
+        alive.send_replace(Instant::now());
+    }
+}
+
+/// The address of the in-process transport.
+///
+/// See [`Bus::memory_connector`].
+pub const MEMORY_ADDRESS: &str = "memory:";
+
+/// The address of the connection inherited on stdin.
+///
+/// See [`Bus::for_inherited_connection`].
+#[cfg(unix)]
+pub const INHERITED_ADDRESS: &str = "inherited:";
+
+/// The address of all the sockets passed through systemd socket activation.
+///
+/// See [`Bus::for_activated_socket`].
+#[cfg(all(target_os = "linux", feature = "systemd"))]
+pub const SYSTEMD_ADDRESS: &str = "systemd:";
+
+// How often to check if the socket file of a unix listener is still there.
+#[cfg(unix)]
+const SOCKET_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// How often the bus records that it's alive while running, see `Bus::liveness`.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+// How much to delay accepts by, for each message waiting to be sent to a peer.
+const ACCEPT_DELAY_PER_PENDING_SEND: Duration = Duration::from_millis(1);
+// How long to wait before accepting connections again after a temporary failure.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
 #[cfg(unix)]
 fn default_address() -> String {
     let runtime_dir = env::var("XDG_RUNTIME_DIR")
@@ -181,7 +1877,7 @@ fn default_address() -> String {
         });
     let path = runtime_dir.join("busd-session");
 
-    format!("unix:path={}", path.display())
+    format!("unix:path={}", escape(path.as_os_str().as_bytes()))
 }
 
 #[cfg(not(unix))]
@@ -191,7 +1887,9 @@ fn default_address() -> String {
 
 #[instrument]
 async fn sync_cookies() -> Result<()> {
-    let cookie_dir_path = home_dir().unwrap().join(".dbus-keyrings");
+    let cookie_dir_path = home_dir()
+        .ok_or_else(|| anyhow!("No home directory to keep cookies in"))?
+        .join(".dbus-keyrings");
 
     // Ensure the cookie directory exists and has the correct permissions.
     match metadata(&cookie_dir_path).await {
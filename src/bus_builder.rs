@@ -0,0 +1,460 @@
+use std::{collections::HashSet, path::PathBuf, time::Duration};
+
+use zbus::{
+    names::{OwnedWellKnownName, WellKnownName},
+    AuthMechanism, Guid,
+};
+
+use crate::{
+    bus::Bus, error::BusError, limits::Limits, message_log::Rotation, rate_limiter::RateLimit,
+};
+
+/// A builder for [`Bus`].
+#[derive(Debug)]
+pub struct BusBuilder<'a> {
+    pub(crate) address: Option<&'a str>,
+    pub(crate) auth_mechanism: AuthMechanism,
+    pub(crate) message_log_path: Option<PathBuf>,
+    pub(crate) message_log_rotation: Option<Rotation>,
+    pub(crate) destination_rate_limit: Option<RateLimit>,
+    pub(crate) guid: Option<Guid>,
+    pub(crate) limits: Limits,
+    pub(crate) machine_id_path: Option<PathBuf>,
+    pub(crate) max_completed_connections: Option<usize>,
+    pub(crate) exit_on_idle: bool,
+    pub(crate) additional_listeners: Vec<(&'a str, AuthMechanism)>,
+    pub(crate) slow_routing_threshold: Option<Duration>,
+    pub(crate) replace_stale_socket: bool,
+    pub(crate) socket_mode: Option<u32>,
+    #[cfg(all(target_os = "linux", feature = "selinux"))]
+    pub(crate) socket_context: Option<String>,
+    pub(crate) max_accept_delay: Option<Duration>,
+    pub(crate) name_promotion_grace: Duration,
+    pub(crate) deny_anonymous: bool,
+    pub(crate) reserved_names: HashSet<OwnedWellKnownName>,
+    pub(crate) lifecycle_signals: bool,
+    #[cfg(unix)]
+    pub(crate) control_socket: Option<PathBuf>,
+}
+
+impl<'a> BusBuilder<'a> {
+    /// Create a builder for a bus listening on the default address, using the `EXTERNAL`
+    /// authentication mechanism.
+    pub fn new() -> Self {
+        Self {
+            address: None,
+            auth_mechanism: AuthMechanism::External,
+            message_log_path: None,
+            message_log_rotation: None,
+            destination_rate_limit: None,
+            guid: None,
+            limits: Limits::default(),
+            machine_id_path: None,
+            max_completed_connections: None,
+            exit_on_idle: false,
+            additional_listeners: vec![],
+            slow_routing_threshold: None,
+            replace_stale_socket: false,
+            socket_mode: None,
+            #[cfg(all(target_os = "linux", feature = "selinux"))]
+            socket_context: None,
+            max_accept_delay: None,
+            name_promotion_grace: Duration::ZERO,
+            deny_anonymous: false,
+            reserved_names: HashSet::new(),
+            lifecycle_signals: false,
+            #[cfg(unix)]
+            control_socket: None,
+        }
+    }
+
+    /// The address to listen on.
+    ///
+    /// This can also be a `;`-separated list of addresses, like `DBUS_SESSION_BUS_ADDRESS`, to
+    /// listen on all of them. In the paths of `unix` addresses, a leading `~` and environment
+    /// variables are expanded, e.g. `unix:path=$XDG_RUNTIME_DIR/bus`.
+    pub fn address(mut self, address: &'a str) -> Self {
+        self.address = Some(address);
+
+        self
+    }
+
+    /// Also listen on `address`, requiring peers to authenticate through `auth_mechanism` there.
+    ///
+    /// This allows e.g. `ANONYMOUS` authentication on a TCP address while the main address
+    /// requires `EXTERNAL`.
+    pub fn listen_on(mut self, address: &'a str, auth_mechanism: AuthMechanism) -> Self {
+        self.additional_listeners.push((address, auth_mechanism));
+
+        self
+    }
+
+    /// The authentication mechanism to use on the main address.
+    pub fn auth_mechanism(mut self, auth_mechanism: AuthMechanism) -> Self {
+        self.auth_mechanism = auth_mechanism;
+
+        self
+    }
+
+    /// Capture all the messages the bus delivers to the file at `path`.
+    ///
+    /// The file is written in the [pcap] format, using the D-Bus link-layer type, so it can be
+    /// opened in Wireshark. It's truncated if it already exists, and only readable by its owner.
+    /// See [`MessageLog`](crate::message_log::MessageLog) for what's captured.
+    ///
+    /// [pcap]: https://wiki.wireshark.org/Development/LibpcapFileFormat
+    pub fn message_log_path<P>(mut self, path: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.message_log_path = Some(path.into());
+
+        self
+    }
+
+    /// Rotate the message log once it reaches `max_size` bytes, keeping `max_files` old logs.
+    ///
+    /// Only applies if [`BusBuilder::message_log_path`] is set. See [`Rotation`] for how the old
+    /// logs are named. By default, the log grows without bounds.
+    pub fn message_log_rotation(mut self, max_size: u64, max_files: usize) -> Self {
+        self.message_log_rotation = Some(Rotation {
+            max_size,
+            max_files,
+        });
+
+        self
+    }
+
+    /// Limit the rate of method calls each peer can make to any given destination.
+    ///
+    /// Method calls exceeding the limit are replied to with a
+    /// `org.freedesktop.DBus.Error.LimitsExceeded` error. No limit is applied by default.
+    pub fn destination_rate_limit(mut self, limit: RateLimit) -> Self {
+        self.destination_rate_limit = Some(limit);
+
+        self
+    }
+
+    /// Limit the rate of messages each peer can send, regardless of the destination.
+    ///
+    /// Reading from peers exceeding the limit is paused until they're back within it, so that
+    /// they don't starve other peers. Peers held back without a break for longer than
+    /// [`BusBuilder::sender_throttle_timeout`] are disconnected. No limit is applied by default.
+    pub fn sender_rate_limit(mut self, limit: RateLimit) -> Self {
+        self.limits.sender_rate_limit = Some(limit);
+
+        self
+    }
+
+    /// How long peers can be held back by [`BusBuilder::sender_rate_limit`] without a break.
+    ///
+    /// Every message a peer sends while it's over the limit extends the time it's held back for,
+    /// and any message that doesn't need to wait ends it. Peers held back for longer are
+    /// disconnected, as persistently exceeding the limit. Defaults to 60 seconds.
+    pub fn sender_throttle_timeout(mut self, timeout: Duration) -> Self {
+        self.limits.sender_throttle_timeout = timeout;
+
+        self
+    }
+
+    /// The GUID of the bus.
+    ///
+    /// By default, a random GUID is generated.
+    pub fn guid(mut self, guid: Guid) -> Self {
+        self.guid = Some(guid);
+
+        self
+    }
+
+    /// The number of protocol violations after which a peer is disconnected.
+    ///
+    /// Malformed messages, messages the bus refuses to route and method calls rejected for
+    /// exceeding a limit all count as violations. Only the violations within
+    /// [`BusBuilder::protocol_violation_window`] count together. Defaults to 1000.
+    pub fn max_protocol_violations(mut self, max: u32) -> Self {
+        self.limits.max_protocol_violations = max;
+
+        self
+    }
+
+    /// The window protocol violations are counted in, against
+    /// [`BusBuilder::max_protocol_violations`].
+    ///
+    /// The count of each peer starts over once the window its first violation fell in is over,
+    /// so that long-lived peers aren't disconnected for the odd violation adding up over time.
+    /// Defaults to 60 seconds.
+    pub fn protocol_violation_window(mut self, window: Duration) -> Self {
+        self.limits.protocol_violation_window = window;
+
+        self
+    }
+
+    /// The maximum length of match rules peers can add, in bytes.
+    ///
+    /// Longer rules are rejected with a `org.freedesktop.DBus.Error.MatchRuleInvalid` error,
+    /// before even being parsed. Defaults to 1024.
+    pub fn max_match_rule_length(mut self, max: usize) -> Self {
+        self.limits.max_match_rule_length = max;
+
+        self
+    }
+
+    /// How long peers have to call `Hello` once authenticated.
+    ///
+    /// Peers that don't are disconnected, so that half-initialized connections don't linger.
+    /// Defaults to 30 seconds.
+    pub fn hello_timeout(mut self, timeout: Duration) -> Self {
+        self.limits.hello_timeout = timeout;
+
+        self
+    }
+
+    /// The maximum number of match rules a single peer can add.
+    ///
+    /// Rules beyond that are rejected with a `org.freedesktop.DBus.Error.LimitsExceeded` error.
+    /// Defaults to 50000.
+    pub fn max_match_rules_per_connection(mut self, max: usize) -> Self {
+        self.limits.max_match_rules_per_connection = max;
+
+        self
+    }
+
+    /// The maximum number of match rules all peers together can add.
+    ///
+    /// Once reached, further rules are rejected with a `org.freedesktop.DBus.Error.LimitsExceeded`
+    /// error, however many rules the peer adding them has. Defaults to 131072.
+    pub fn max_match_rules(mut self, max: usize) -> Self {
+        self.limits.max_match_rules = max;
+
+        self
+    }
+
+    /// The maximum number of Unix file descriptors a single message can carry.
+    ///
+    /// Messages with more are dropped, closing their file descriptors, and method calls are
+    /// replied to with a `org.freedesktop.DBus.Error.LimitsExceeded` error. Defaults to 16.
+    pub fn max_message_unix_fds(mut self, max: usize) -> Self {
+        self.limits.max_message_unix_fds = max;
+
+        self
+    }
+
+    /// The maximum number of method calls a single peer can have awaiting a reply.
+    ///
+    /// Calls beyond that are replied to with a `org.freedesktop.DBus.Error.LimitsExceeded` error,
+    /// until replies to the earlier ones arrive or they time out, so that peers calling services
+    /// that never reply can't grow the bus without bounds. Defaults to 128.
+    pub fn max_replies_per_connection(mut self, max: usize) -> Self {
+        self.limits.max_replies_per_connection = max;
+
+        self
+    }
+
+    /// How long method calls await a reply.
+    ///
+    /// Calls that time out stop counting against
+    /// [`BusBuilder::max_replies_per_connection`], and replies arriving after that are dropped.
+    /// Defaults to 25 seconds.
+    pub fn reply_timeout(mut self, timeout: Duration) -> Self {
+        self.limits.reply_timeout = timeout;
+
+        self
+    }
+
+    /// The maximum number of peers connected at the same time.
+    ///
+    /// Once the limit is reached, no new connections are accepted until a peer disconnects, so
+    /// that existing peers don't suffer. No limit is applied by default.
+    pub fn max_completed_connections(mut self, max: usize) -> Self {
+        self.max_completed_connections = Some(max);
+
+        self
+    }
+
+    /// Make [`Bus::run`] return once the last peer disconnects.
+    ///
+    /// This only kicks in after the first peer has connected. Disabled by default.
+    pub fn exit_on_idle(mut self, exit_on_idle: bool) -> Self {
+        self.exit_on_idle = exit_on_idle;
+
+        self
+    }
+
+    /// The maximum number of peers that can wait in the queue of a single name.
+    ///
+    /// Requests for the name beyond that are replied to with a
+    /// `org.freedesktop.DBus.Error.LimitsExceeded` error, rather than being queued. Defaults to
+    /// 1024.
+    pub fn max_queued_owners_per_name(mut self, max: usize) -> Self {
+        self.limits.max_queued_owners_per_name = max;
+
+        self
+    }
+
+    /// Log a warning whenever routing a single message takes longer than `threshold`.
+    ///
+    /// The warning includes the member, the number of peers the message was sent to and the time
+    /// it took, to help diagnose slow peers and match rule storms. Disabled by default.
+    pub fn slow_routing_threshold(mut self, threshold: Duration) -> Self {
+        self.slow_routing_threshold = Some(threshold);
+
+        self
+    }
+
+    /// Slow down accepting new connections while the bus is congested, by up to `max_delay` per
+    /// connection.
+    ///
+    /// The more messages are waiting to be sent to peers, the longer the delay, so that new peers
+    /// don't make matters worse for the established ones. Disabled by default.
+    pub fn max_accept_delay(mut self, max_delay: Duration) -> Self {
+        self.max_accept_delay = Some(max_delay);
+
+        self
+    }
+
+    /// Stop reading from peers while more than `max` messages are waiting to be sent to peers,
+    /// until the backlog drains.
+    ///
+    /// Once the messages zbus buffers are read, a paused peer's socket isn't read from anymore,
+    /// so producers are pushed back on at the transport level, through the kernel's socket
+    /// buffers, rather than having the bus buffer their messages. Peers that messages are
+    /// waiting to be sent to keep being read from, since they could be blocked sending to the
+    /// bus themselves. Disabled by default.
+    pub fn max_routing_backlog(mut self, max: usize) -> Self {
+        self.limits.max_routing_backlog = Some(max);
+
+        self
+    }
+
+    /// The maximum number of copies waiting to be sent to a single monitor.
+    ///
+    /// Monitors get their copies through a queue of their own, so that one slow to read them
+    /// can't hold up routing. Once it's full, monitors are disconnected, unless they asked for
+    /// the oldest copies to be dropped instead, see
+    /// [`DROP_OLDEST`](crate::monitoring::DROP_OLDEST). Defaults to 1024.
+    pub fn max_monitor_queue(mut self, max: usize) -> Self {
+        self.limits.max_monitor_queue = max;
+
+        self
+    }
+
+    /// Hold the names of a disconnected owner unowned for `grace`, before promoting the next
+    /// owners queued for them.
+    ///
+    /// This is for failover setups, where the owner may reconnect right away: whoever requests a
+    /// name first during the grace period gets it, ahead of the queue. Messages to the name are
+    /// refused like to any unowned name in the meantime. Disabled by default, i.e. the next owner
+    /// is promoted right away.
+    pub fn name_promotion_grace(mut self, grace: Duration) -> Self {
+        self.name_promotion_grace = grace;
+
+        self
+    }
+
+    /// Replace the socket files of `unix:path=` addresses if they're stale.
+    ///
+    /// A socket file is stale if no one is listening on it anymore, e.g. because the bus that
+    /// created it crashed. Without this, binding to the address fails if the file exists. Sockets
+    /// that are still in use are never replaced. Disabled by default.
+    pub fn replace_stale_socket(mut self, replace: bool) -> Self {
+        self.replace_stale_socket = replace;
+
+        self
+    }
+
+    /// The permissions of the socket files of `unix:path=` and `unix:dir=` addresses, e.g.
+    /// `0o660`.
+    ///
+    /// They're applied as the files are created, regardless of the umask of the process. By
+    /// default, they're left to the umask.
+    pub fn socket_mode(mut self, mode: u32) -> Self {
+        self.socket_mode = Some(mode);
+
+        self
+    }
+
+    /// The SELinux file context of the socket files of `unix:path=` and `unix:dir=` addresses,
+    /// e.g. `system_u:object_r:system_dbusd_var_run_t:s0`.
+    ///
+    /// They're labeled as they're created, like `setfscreatecon(3)` does, rather than relying on
+    /// `restorecon` afterwards. Does nothing if SELinux is disabled. By default, they're labeled as
+    /// the policy says.
+    #[cfg(all(target_os = "linux", feature = "selinux"))]
+    pub fn socket_context(mut self, context: &str) -> Self {
+        self.socket_context = Some(context.to_string());
+
+        self
+    }
+
+    /// Reject all peers authenticating through `ANONYMOUS`, whatever the listeners allow.
+    ///
+    /// This takes precedence over the authentication mechanism of every listener, as a safety net
+    /// against an address or configuration letting anonymous peers in by mistake. Listeners
+    /// requiring `ANONYMOUS` then refuse all connections. Disabled by default.
+    pub fn deny_anonymous(mut self, deny: bool) -> Self {
+        self.deny_anonymous = deny;
+
+        self
+    }
+
+    /// Emit the busd-specific `Starting` and `ShuttingDown` signals, as the bus starts running and
+    /// is cleaned up.
+    ///
+    /// They're signals of the [`LIFECYCLE_INTERFACE`](crate::peers::LIFECYCLE_INTERFACE), sent by
+    /// `org.freedesktop.DBus` to all peers with matching rules. Disabled by default, since standard
+    /// buses don't have them.
+    pub fn lifecycle_signals(mut self, enabled: bool) -> Self {
+        self.lifecycle_signals = enabled;
+
+        self
+    }
+
+    /// Reserve `name` for the bus, so that peers can't request it.
+    ///
+    /// Only services served from within the bus process, through [`Bus::serve_at`], can own
+    /// reserved names. Peers requesting one get an `AccessDenied` error.
+    pub fn reserve_name(mut self, name: WellKnownName<'_>) -> Self {
+        self.reserved_names.insert(name.into());
+
+        self
+    }
+
+    /// Read the machine ID from the file at `path`.
+    ///
+    /// The file must contain a 32-character hexadecimal ID. If it doesn't exist, an ephemeral ID is
+    /// generated for the lifetime of the bus instead, without creating the file. By default, the
+    /// ID is read from the standard locations (`/etc/machine-id` or `/var/lib/dbus/machine-id`).
+    pub fn machine_id_path<P>(mut self, path: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.machine_id_path = Some(path.into());
+
+        self
+    }
+
+    /// Also serve a control socket at `path`, for inspecting the bus from shell scripts.
+    ///
+    /// Only the user of the bus can connect to it. See the [`control`](crate::control) module for
+    /// the protocol.
+    #[cfg(unix)]
+    pub fn control_socket<P>(mut self, path: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.control_socket = Some(path.into());
+
+        self
+    }
+
+    /// Bind to the address and build the bus.
+    pub async fn build(self) -> Result<Bus, BusError> {
+        Bus::for_builder(self).await
+    }
+}
+
+impl Default for BusBuilder<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,110 @@
+//! The control socket, for inspecting the bus from shell scripts without a D-Bus client.
+//!
+//! The protocol is line-based: each line sent to the socket is a command, answered with
+//! `key=value` lines followed by an empty line. The only command for now is `stats`, returning
+//! the same statistics as [`Bus::stats`](crate::bus::Bus::stats), e.g.:
+//!
+//! ```text
+//! $ echo stats | socat - UNIX-CONNECT:/run/busd.control
+//! connections=3
+//! peak_connections=5
+//! ...
+//! ```
+//!
+//! Unknown commands are answered with a single `error=` line instead.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use tokio::{
+    fs::remove_file,
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    task::JoinHandle,
+};
+use tracing::{debug, warn};
+
+use crate::{bus::bind_unix_listener, peers::Peers, stats::micros};
+
+/// A control socket being served on its own task.
+#[derive(Debug)]
+pub(crate) struct ControlSocket {
+    path: PathBuf,
+    task: JoinHandle<()>,
+}
+
+impl ControlSocket {
+    /// Bind to `path` and serve the statistics of `peers` there.
+    ///
+    /// Only the user of the bus can connect, since it's meant for operators.
+    pub async fn bind(path: &Path, peers: Peers) -> Result<Self> {
+        let listener = bind_unix_listener(path, Some(0o600))?;
+        debug!("Serving the control socket on {}.", path.display());
+        let task = tokio::spawn(accept_clients(listener, peers));
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            task,
+        })
+    }
+
+    pub async fn cleanup(self) -> Result<()> {
+        self.task.abort();
+
+        remove_file(self.path).await.map_err(Into::into)
+    }
+}
+
+async fn accept_clients(listener: UnixListener, peers: Peers) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let peers = peers.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = serve_client(stream, peers).await {
+                        debug!("Control socket client failed: {}", e);
+                    }
+                });
+            }
+            Err(e) => warn!("Failed to accept a control socket client: {}", e),
+        }
+    }
+}
+
+async fn serve_client(stream: UnixStream, peers: Peers) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let reply = match line.trim() {
+            "stats" => stats(&peers).await,
+            command => format!("error=unknown command `{command}`\n"),
+        };
+        writer.write_all(reply.as_bytes()).await?;
+        writer.write_all(b"\n").await?;
+    }
+
+    Ok(())
+}
+
+async fn stats(peers: &Peers) -> String {
+    let stats = peers.stats().await;
+
+    [
+        ("connections", stats.connections as u64),
+        ("monitors", stats.monitors as u64),
+        ("peak_connections", stats.peak_connections as u64),
+        ("names", stats.names as u64),
+        ("peak_names", stats.peak_names as u64),
+        ("match_rules", stats.match_rules as u64),
+        ("messages_routed", stats.messages_routed),
+        ("bytes_routed", stats.bytes_routed),
+        ("self_sends", stats.self_sends),
+        ("connections_greeted", stats.connections_greeted),
+        ("mean_hello_latency_us", micros(stats.mean_hello_latency)),
+        ("max_hello_latency_us", micros(stats.max_hello_latency)),
+        ("uptime_seconds", stats.uptime.as_secs()),
+    ]
+    .into_iter()
+    .map(|(key, value)| format!("{key}={value}\n"))
+    .collect()
+}
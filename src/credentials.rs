@@ -0,0 +1,255 @@
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+
+use anyhow::Result;
+use parking_lot::Mutex;
+use tokio::sync::OnceCell;
+
+/// The credentials of a peer.
+///
+/// Only available for peers connected over Unix sockets, where the kernel vouches for them
+/// (through `SO_PEERCRED`, `getpeereid` or the like, depending on the platform). Peers
+/// authenticating through `EXTERNAL` are rejected if they're not available, since the user ID
+/// they claim can't be verified otherwise. Peers connected over TCP only have their remote
+/// address.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Credentials {
+    uid: Option<u32>,
+    gid: Option<u32>,
+    pid: Option<u32>,
+    // Shared by all the copies, since they're resolved once the peer is set up.
+    groups: Arc<OnceCell<Arc<Vec<u32>>>>,
+    security_label: Option<Vec<u8>>,
+    remote_address: Option<SocketAddr>,
+}
+
+impl Credentials {
+    /// Credentials of a made-up peer, running as `uid`, with `gid` as its only group.
+    ///
+    /// See [`Bus::add_authenticated_peer`](crate::bus::Bus::add_authenticated_peer).
+    #[cfg(feature = "test-util")]
+    pub fn new(uid: u32, gid: u32, pid: u32) -> Self {
+        Self {
+            uid: Some(uid),
+            gid: Some(gid),
+            pid: Some(pid),
+            groups: resolved_groups(vec![gid]),
+            security_label: None,
+            remote_address: None,
+        }
+    }
+
+    #[cfg(unix)]
+    pub(crate) fn from_unix_stream(stream: &tokio::net::UnixStream) -> Result<Self> {
+        let cred = stream.peer_cred()?;
+
+        Ok(Self {
+            uid: Some(cred.uid()),
+            gid: Some(cred.gid()),
+            pid: cred.pid().and_then(|pid| u32::try_from(pid).ok()),
+            groups: Arc::default(),
+            #[cfg(target_os = "linux")]
+            security_label: peer_security_label(stream),
+            #[cfg(not(target_os = "linux"))]
+            security_label: None,
+            remote_address: None,
+        })
+    }
+
+    pub(crate) fn from_remote_address(remote_address: SocketAddr) -> Self {
+        Self {
+            remote_address: Some(remote_address),
+            ..Self::default()
+        }
+    }
+
+    /// The credentials of the bus process itself.
+    pub(crate) fn of_bus() -> Self {
+        #[cfg(unix)]
+        {
+            use nix::unistd::{Gid, Uid};
+
+            let uid = Uid::current().as_raw();
+            let gid = Gid::current().as_raw();
+
+            Self {
+                uid: Some(uid),
+                gid: Some(gid),
+                pid: Some(std::process::id()),
+                groups: resolved_groups(group_list(uid, gid).unwrap_or_else(|_| vec![gid])),
+                security_label: None,
+                remote_address: None,
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            Self {
+                pid: Some(std::process::id()),
+                ..Self::default()
+            }
+        }
+    }
+
+    /// The user ID of the peer.
+    pub fn uid(&self) -> Option<u32> {
+        self.uid
+    }
+
+    /// The primary group ID of the peer.
+    pub fn gid(&self) -> Option<u32> {
+        self.gid
+    }
+
+    /// The process ID of the peer.
+    ///
+    /// This is the process that connected, as the kernel recorded it then, so it's still
+    /// reported once that process exits.
+    pub fn pid(&self) -> Option<u32> {
+        self.pid
+    }
+
+    /// The supplementary groups of the user of the peer.
+    ///
+    /// Only resolved for peers authenticated through the `EXTERNAL` mechanism, right after they
+    /// connect, on the task serving them. Empty until then.
+    pub fn groups(&self) -> &[u32] {
+        self.groups
+            .get()
+            .map(|groups| groups.as_slice())
+            .unwrap_or(&[])
+    }
+
+    /// The security label of the peer, as provided by the kernel's LSM (e.g. SELinux or AppArmor).
+    ///
+    /// Only available on Linux, if an LSM providing labels is active.
+    pub fn security_label(&self) -> Option<&[u8]> {
+        self.security_label.as_deref()
+    }
+
+    /// The IP address and port the peer connected from, for TCP connections.
+    ///
+    /// Unlike the other credentials, anyone on the path to the peer can make this up, so it's
+    /// only good for logging and rudimentary access control.
+    pub fn remote_address(&self) -> Option<SocketAddr> {
+        self.remote_address
+    }
+
+    /// If the peer runs as root or as the same user as the bus.
+    ///
+    /// Peers whose user is unknown (e.g. on TCP) are never privileged.
+    pub fn is_privileged(&self) -> bool {
+        #[cfg(unix)]
+        {
+            let bus_uid = nix::unistd::Uid::current().as_raw();
+
+            matches!(self.uid, Some(uid) if uid == 0 || uid == bus_uid)
+        }
+        #[cfg(not(unix))]
+        {
+            false
+        }
+    }
+}
+
+/// Get the security label of the peer through `SO_PEERSEC`.
+///
+/// Returns `None` if no LSM providing labels is active.
+#[cfg(target_os = "linux")]
+fn peer_security_label(stream: &tokio::net::UnixStream) -> Option<Vec<u8>> {
+    use nix::libc::{getsockopt, socklen_t, ERANGE, SOL_SOCKET, SO_PEERSEC};
+    use std::{io, os::unix::io::AsRawFd};
+
+    let mut label = vec![0u8; 256];
+    loop {
+        let mut len = label.len() as socklen_t;
+        // SAFETY: `label` is valid for writes of `len` bytes.
+        let ret = unsafe {
+            getsockopt(
+                stream.as_raw_fd(),
+                SOL_SOCKET,
+                SO_PEERSEC,
+                label.as_mut_ptr().cast(),
+                &mut len,
+            )
+        };
+        if ret == 0 {
+            label.truncate(len as usize);
+
+            return Some(label);
+        }
+        match io::Error::last_os_error().raw_os_error() {
+            // The kernel tells us how much space it needs.
+            Some(ERANGE) if len as usize > label.len() => label.resize(len as usize, 0),
+            _ => return None,
+        }
+    }
+}
+
+fn resolved_groups(groups: Vec<u32>) -> Arc<OnceCell<Arc<Vec<u32>>>> {
+    let cell = OnceCell::new();
+    // Can't fail, it's empty.
+    let _ = cell.set(Arc::new(groups));
+
+    Arc::new(cell)
+}
+
+/// Caches the supplementary groups of users, since resolving them can involve NSS lookups.
+///
+/// Keyed by both the user and the primary group, since the latter is part of the groups and
+/// processes of the same user can run with different ones.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct GroupsCache {
+    groups: Arc<Mutex<HashMap<(u32, u32), Arc<Vec<u32>>>>>,
+}
+
+impl GroupsCache {
+    /// Fill in the supplementary groups of the user in `credentials`, and in all its copies.
+    ///
+    /// Nothing to do if they're already known.
+    pub async fn resolve(&self, credentials: &Credentials) -> Result<()> {
+        let key = match (credentials.uid, credentials.gid) {
+            (Some(uid), Some(gid)) => (uid, gid),
+            _ => return Ok(()),
+        };
+        if credentials.groups.initialized() {
+            return Ok(());
+        }
+
+        let cached = self.groups.lock().get(&key).cloned();
+        let groups = match cached {
+            Some(groups) => groups,
+            None => {
+                let (uid, gid) = key;
+                let groups =
+                    Arc::new(tokio::task::spawn_blocking(move || group_list(uid, gid)).await??);
+                self.groups.lock().insert(key, groups.clone());
+
+                groups
+            }
+        };
+        // Only ever resolved from the task serving the peer.
+        let _ = credentials.groups.set(groups);
+
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn group_list(uid: u32, gid: u32) -> Result<Vec<u32>> {
+    use nix::unistd::{getgrouplist, Gid, Uid, User};
+    use std::ffi::CString;
+
+    let user = match User::from_uid(Uid::from_raw(uid))? {
+        Some(user) => user,
+        // No such user in the database so only the primary group applies.
+        None => return Ok(vec![gid]),
+    };
+    let name = CString::new(user.name)?;
+    let groups = getgrouplist(&name, Gid::from_raw(gid))?;
+
+    Ok(groups.into_iter().map(Gid::as_raw).collect())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn group_list(_uid: u32, gid: u32) -> Result<Vec<u32>> {
+    Ok(vec![gid])
+}
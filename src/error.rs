@@ -0,0 +1,70 @@
+use std::io;
+
+/// An error setting up, running or cleaning up the bus.
+///
+/// See [`Bus::for_address`](crate::bus::Bus::for_address), [`Bus::run`](crate::bus::Bus::run)
+/// and [`Bus::cleanup`](crate::bus::Bus::cleanup).
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum BusError {
+    /// `address` isn't a valid address to listen on, e.g. it lacks a required key or its
+    /// transport isn't supported.
+    #[error("Invalid address `{address}`: {source}")]
+    Address {
+        address: String,
+        #[source]
+        source: anyhow::Error,
+    },
+    /// Listening on `address` failed, e.g. since it's in use already.
+    #[error("Failed to listen on `{address}`: {source}")]
+    Bind {
+        address: String,
+        #[source]
+        source: anyhow::Error,
+    },
+    /// The authentication mechanisms of the bus can't be used as configured.
+    #[error("Invalid authentication configuration: {0}")]
+    Auth(String),
+    /// An I/O error, e.g. accepting connections or removing the socket files on cleanup.
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// Any other error.
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl BusError {
+    /// An error from binding to `address`, unless `address` itself was at fault.
+    pub(crate) fn bind(address: &str, source: anyhow::Error) -> Self {
+        match source.downcast::<BusError>() {
+            Ok(e) => e,
+            Err(source) => Self::Bind {
+                address: address.to_string(),
+                source,
+            },
+        }
+    }
+
+    /// An invalid `address`, as an [`anyhow::Error`] for the internals of binding.
+    pub(crate) fn address(address: &str, source: anyhow::Error) -> anyhow::Error {
+        Self::Address {
+            address: address.to_string(),
+            source,
+        }
+        .into()
+    }
+}
+
+impl From<anyhow::Error> for BusError {
+    fn from(e: anyhow::Error) -> Self {
+        // Internals pass errors on as `anyhow::Error`, so they're only classified here.
+        let e = match e.downcast::<BusError>() {
+            Ok(e) => return e,
+            Err(e) => e,
+        };
+        match e.downcast::<io::Error>() {
+            Ok(e) => Self::Io(e),
+            Err(e) => Self::Other(e),
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use tokio::sync::mpsc;
+use zbus::names::{OwnedBusName, OwnedUniqueName, OwnedWellKnownName};
+
+/// An event on the bus.
+///
+/// See [`Bus::event_stream`](crate::bus::Bus::event_stream).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BusEvent {
+    /// A peer connected to the bus.
+    PeerConnected(OwnedUniqueName),
+    /// A peer disconnected from the bus, or became a monitor, which can't be addressed anymore.
+    PeerDisconnected(OwnedUniqueName),
+    /// The primary owner of a name changed.
+    NameOwnerChanged {
+        name: OwnedWellKnownName,
+        old_owner: Option<OwnedUniqueName>,
+        new_owner: Option<OwnedUniqueName>,
+    },
+    /// A message was routed between peers.
+    ///
+    /// A `None` destination means the message was broadcasted.
+    MessageRouted {
+        sender: OwnedUniqueName,
+        destination: Option<OwnedBusName>,
+    },
+    /// The bus started listening on `address`, through
+    /// [`Bus::add_listener`](crate::bus::Bus::add_listener).
+    ListenerAdded { address: String },
+    /// A listener failed or was removed, so the bus isn't listening on `address` anymore.
+    ListenerRemoved { address: String },
+}
+
+/// A change of the primary owner of a name.
+///
+/// See [`Bus::watch_name`](crate::bus::Bus::watch_name).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NameOwnerChange {
+    /// The previous owner, `None` if the name wasn't owned.
+    pub old_owner: Option<OwnedUniqueName>,
+    /// The new owner, `None` if the name was released.
+    pub new_owner: Option<OwnedUniqueName>,
+}
+
+// Enough to handle bursts of events without subscribers lagging behind.
+pub(crate) const EVENT_QUEUE_SIZE: usize = 1024;
+
+/// The events the bus emits its own signals about, e.g. `NameOwnerChanged`.
+///
+/// Unlike the events of [`Bus::event_stream`](crate::bus::Bus::event_stream), none are ever
+/// skipped, however far behind emitting the signals falls, since the protocol requires them all.
+/// Events are only queued while subscribed to.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct BusSignals(Arc<Mutex<Option<mpsc::UnboundedSender<BusEvent>>>>);
+
+impl BusSignals {
+    /// Subscribe to the events from now on, in place of the previous subscriber, if any.
+    pub fn subscribe(&self) -> mpsc::UnboundedReceiver<BusEvent> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        *self.0.lock() = Some(tx);
+
+        rx
+    }
+
+    pub fn send(&self, event: BusEvent) {
+        if let Some(tx) = &*self.0.lock() {
+            // It's fine if the subscriber is gone.
+            let _ = tx.send(event);
+        }
+    }
+}
@@ -1,5 +1,28 @@
+mod address;
 pub mod bus;
+pub mod bus_builder;
+#[cfg(unix)]
+pub mod control;
+pub mod credentials;
+pub mod error;
+pub mod event;
+pub mod limits;
+pub mod machine_id;
+mod match_rule;
+pub mod message_log;
+pub mod monitoring;
 pub mod name_registry;
 pub mod peer;
 pub mod peers;
+pub mod protocol;
+pub mod rate_limiter;
+#[cfg(all(target_os = "linux", feature = "seccomp"))]
+pub mod seccomp;
+#[cfg(all(target_os = "linux", feature = "selinux"))]
+mod selinux;
+pub mod stats;
+#[cfg(all(target_os = "linux", feature = "systemd"))]
+pub mod systemd;
+#[cfg(feature = "test-util")]
+pub mod test_util;
 pub mod tracing_subscriber;
@@ -0,0 +1,358 @@
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use zbus::{
+    dbus_interface, fdo,
+    zvariant::{OwnedValue, Value},
+};
+
+use crate::{credentials::Credentials, peers::Peers, rate_limiter::RateLimit};
+
+/// Limits on what each peer can do, protecting the bus and other peers from misbehaving ones.
+///
+/// See [`BusBuilder`](crate::bus_builder::BusBuilder) for setting these, and
+/// [`Bus::set_limit`](crate::bus::Bus::set_limit) for changing them while the bus runs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Limits {
+    /// The number of protocol violations within [`Limits::protocol_violation_window`] after
+    /// which a peer is disconnected.
+    pub max_protocol_violations: u32,
+    /// The window protocol violations are counted in.
+    pub protocol_violation_window: Duration,
+    /// The maximum length of a match rule string, in bytes.
+    pub max_match_rule_length: usize,
+    /// The maximum number of peers waiting in the queue of a single name.
+    pub max_queued_owners_per_name: usize,
+    /// The maximum number of match rules of a single peer.
+    pub max_match_rules_per_connection: usize,
+    /// The maximum number of match rules of all peers together.
+    pub max_match_rules: usize,
+    /// The maximum number of Unix file descriptors attached to a single message.
+    pub max_message_unix_fds: usize,
+    /// The maximum number of method calls of a single peer awaiting a reply.
+    pub max_replies_per_connection: usize,
+    /// How long a method call awaits a reply, before no longer counting against
+    /// [`Limits::max_replies_per_connection`], and its reply being dropped.
+    pub reply_timeout: Duration,
+    /// The rate at which each peer can send messages, if limited.
+    pub sender_rate_limit: Option<RateLimit>,
+    /// How long a peer can be held back by [`Limits::sender_rate_limit`] without a break, before
+    /// being disconnected.
+    pub sender_throttle_timeout: Duration,
+    /// How long a peer has to call `Hello` once authenticated, before being disconnected.
+    pub hello_timeout: Duration,
+    /// The number of messages waiting to be sent to peers, all peers together, beyond which the
+    /// bus stops reading from peers until it drops, if limited.
+    pub max_routing_backlog: Option<usize>,
+    /// The maximum number of copies waiting to be sent to a single monitor, beyond which its
+    /// [`OverflowPolicy`](crate::monitoring::OverflowPolicy) applies.
+    pub max_monitor_queue: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            // High enough to never affect well-behaved clients.
+            max_protocol_violations: 1000,
+            // Long enough for a misbehaving peer to reach the limit, short enough for occasional
+            // violations over a long-lived connection not to.
+            protocol_violation_window: Duration::from_secs(60),
+            // Same as dbus-daemon. Real-world rules are much shorter.
+            max_match_rule_length: 1024,
+            // Far more than any legitimate use of queueing.
+            max_queued_owners_per_name: 1024,
+            // Same as the session bus of dbus-daemon.
+            max_match_rules_per_connection: 50000,
+            // Same as dbus-daemon.
+            max_match_rules: 131072,
+            // Real-world messages carry a few at most.
+            max_message_unix_fds: 16,
+            // Same as dbus-daemon, before the session bus raises it. With calls timing out, it only
+            // bounds the calls a peer has in flight at once.
+            max_replies_per_connection: 128,
+            // Same as the default timeout of libdbus and sd-bus clients.
+            reply_timeout: Duration::from_secs(25),
+            sender_rate_limit: None,
+            // A peer needing to be held back for that long is flooding the bus, not bursting.
+            sender_throttle_timeout: Duration::from_secs(60),
+            // Plenty of time even for a heavily loaded client.
+            hello_timeout: Duration::from_secs(30),
+            max_routing_backlog: None,
+            // Enough to ride out bursts, while a monitor that stopped reading holds on to little.
+            max_monitor_queue: 1024,
+        }
+    }
+}
+
+/// A limit that can be changed while the bus runs.
+///
+/// See [`Bus::set_limit`](crate::bus::Bus::set_limit). The names used by [`Limit::from_str`] are
+/// the ones of the corresponding `dbus-daemon` limits, where there's one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Limit {
+    /// See [`Limits::max_protocol_violations`].
+    MaxProtocolViolations,
+    /// See [`Limits::max_match_rule_length`].
+    MaxMatchRuleLength,
+    /// See [`Limits::max_queued_owners_per_name`].
+    MaxQueuedOwnersPerName,
+    /// See [`Limits::max_match_rules_per_connection`].
+    MaxMatchRulesPerConnection,
+    /// See [`Limits::max_match_rules`].
+    MaxMatchRules,
+    /// See [`Limits::max_message_unix_fds`].
+    MaxMessageUnixFds,
+    /// See [`Limits::max_replies_per_connection`].
+    MaxRepliesPerConnection,
+}
+
+impl FromStr for Limit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "max_protocol_violations" => Ok(Limit::MaxProtocolViolations),
+            "max_match_rule_length" => Ok(Limit::MaxMatchRuleLength),
+            "max_queued_owners_per_name" => Ok(Limit::MaxQueuedOwnersPerName),
+            "max_match_rules_per_connection" => Ok(Limit::MaxMatchRulesPerConnection),
+            "max_match_rules" => Ok(Limit::MaxMatchRules),
+            "max_message_unix_fds" => Ok(Limit::MaxMessageUnixFds),
+            "max_replies_per_connection" => Ok(Limit::MaxRepliesPerConnection),
+            _ => Err(anyhow!("Unknown limit `{}`", s)),
+        }
+    }
+}
+
+/// The current [`Limits`], shared by all the parts of the bus enforcing them.
+///
+/// The limits that can be changed at runtime are kept in atomics, so that checking them on the
+/// hot path is cheap. Checks involving more than one limit read them once, through
+/// [`SharedLimits::get`], rather than as they go, so a limit changed meanwhile can't apply to
+/// only part of a check.
+#[derive(Debug)]
+pub(crate) struct SharedLimits {
+    max_protocol_violations: AtomicU32,
+    protocol_violation_window: Duration,
+    max_match_rule_length: AtomicUsize,
+    max_queued_owners_per_name: AtomicUsize,
+    max_match_rules_per_connection: AtomicUsize,
+    max_match_rules: AtomicUsize,
+    max_message_unix_fds: AtomicUsize,
+    max_replies_per_connection: AtomicUsize,
+    reply_timeout: Duration,
+    sender_rate_limit: Option<RateLimit>,
+    sender_throttle_timeout: Duration,
+    hello_timeout: Duration,
+    max_routing_backlog: Option<usize>,
+    max_monitor_queue: usize,
+}
+
+impl SharedLimits {
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            max_protocol_violations: limits.max_protocol_violations.into(),
+            protocol_violation_window: limits.protocol_violation_window,
+            max_match_rule_length: limits.max_match_rule_length.into(),
+            max_queued_owners_per_name: limits.max_queued_owners_per_name.into(),
+            max_match_rules_per_connection: limits.max_match_rules_per_connection.into(),
+            max_match_rules: limits.max_match_rules.into(),
+            max_message_unix_fds: limits.max_message_unix_fds.into(),
+            max_replies_per_connection: limits.max_replies_per_connection.into(),
+            reply_timeout: limits.reply_timeout,
+            sender_rate_limit: limits.sender_rate_limit,
+            sender_throttle_timeout: limits.sender_throttle_timeout,
+            hello_timeout: limits.hello_timeout,
+            max_routing_backlog: limits.max_routing_backlog,
+            max_monitor_queue: limits.max_monitor_queue,
+        }
+    }
+
+    pub fn get(&self) -> Limits {
+        Limits {
+            max_protocol_violations: self.max_protocol_violations(),
+            protocol_violation_window: self.protocol_violation_window,
+            max_match_rule_length: self.max_match_rule_length(),
+            max_queued_owners_per_name: self.max_queued_owners_per_name(),
+            max_match_rules_per_connection: self.max_match_rules_per_connection(),
+            max_match_rules: self.max_match_rules(),
+            max_message_unix_fds: self.max_message_unix_fds(),
+            max_replies_per_connection: self.max_replies_per_connection(),
+            reply_timeout: self.reply_timeout,
+            sender_rate_limit: self.sender_rate_limit,
+            sender_throttle_timeout: self.sender_throttle_timeout,
+            hello_timeout: self.hello_timeout,
+            max_routing_backlog: self.max_routing_backlog,
+            max_monitor_queue: self.max_monitor_queue,
+        }
+    }
+
+    pub fn set(&self, limit: Limit, value: usize) {
+        match limit {
+            Limit::MaxProtocolViolations => self
+                .max_protocol_violations
+                .store(u32::try_from(value).unwrap_or(u32::MAX), Ordering::Relaxed),
+            Limit::MaxMatchRuleLength => self.max_match_rule_length.store(value, Ordering::Relaxed),
+            Limit::MaxQueuedOwnersPerName => self
+                .max_queued_owners_per_name
+                .store(value, Ordering::Relaxed),
+            Limit::MaxMatchRulesPerConnection => self
+                .max_match_rules_per_connection
+                .store(value, Ordering::Relaxed),
+            Limit::MaxMatchRules => self.max_match_rules.store(value, Ordering::Relaxed),
+            Limit::MaxMessageUnixFds => self.max_message_unix_fds.store(value, Ordering::Relaxed),
+            Limit::MaxRepliesPerConnection => self
+                .max_replies_per_connection
+                .store(value, Ordering::Relaxed),
+        }
+    }
+
+    pub fn max_protocol_violations(&self) -> u32 {
+        self.max_protocol_violations.load(Ordering::Relaxed)
+    }
+
+    pub fn protocol_violation_window(&self) -> Duration {
+        self.protocol_violation_window
+    }
+
+    pub fn max_match_rule_length(&self) -> usize {
+        self.max_match_rule_length.load(Ordering::Relaxed)
+    }
+
+    pub fn max_queued_owners_per_name(&self) -> usize {
+        self.max_queued_owners_per_name.load(Ordering::Relaxed)
+    }
+
+    pub fn max_match_rules_per_connection(&self) -> usize {
+        self.max_match_rules_per_connection.load(Ordering::Relaxed)
+    }
+
+    pub fn max_match_rules(&self) -> usize {
+        self.max_match_rules.load(Ordering::Relaxed)
+    }
+
+    pub fn max_message_unix_fds(&self) -> usize {
+        self.max_message_unix_fds.load(Ordering::Relaxed)
+    }
+
+    pub fn max_replies_per_connection(&self) -> usize {
+        self.max_replies_per_connection.load(Ordering::Relaxed)
+    }
+
+    pub fn reply_timeout(&self) -> Duration {
+        self.reply_timeout
+    }
+
+    pub fn sender_rate_limit(&self) -> Option<RateLimit> {
+        self.sender_rate_limit
+    }
+
+    pub fn sender_throttle_timeout(&self) -> Duration {
+        self.sender_throttle_timeout
+    }
+
+    pub fn hello_timeout(&self) -> Duration {
+        self.hello_timeout
+    }
+
+    pub fn max_routing_backlog(&self) -> Option<usize> {
+        self.max_routing_backlog
+    }
+
+    pub fn max_monitor_queue(&self) -> usize {
+        self.max_monitor_queue
+    }
+}
+
+/// The `org.busd.Limits` interface, for tuning limits without restarting the bus.
+#[derive(Debug)]
+pub(crate) struct LimitsInterface {
+    peers: Peers,
+    // Of the peer calling the methods.
+    credentials: Credentials,
+}
+
+impl LimitsInterface {
+    pub fn new(peers: Peers, credentials: Credentials) -> Self {
+        Self { peers, credentials }
+    }
+}
+
+#[dbus_interface(interface = "org.busd.Limits")]
+impl LimitsInterface {
+    /// Get the current limits, keyed by the names [`LimitsInterface::set_limit`] takes.
+    ///
+    /// Limits apply to all peers, so any of them can call this. The sender rate limit is only
+    /// included if one is set.
+    async fn get_limits(&self) -> HashMap<String, OwnedValue> {
+        let limits = self.peers.limits().get();
+        let to_u64 = |value: usize| u64::try_from(value).unwrap_or(u64::MAX);
+
+        let mut map = HashMap::new();
+        map.insert(
+            "max_protocol_violations".to_string(),
+            Value::from(limits.max_protocol_violations).into(),
+        );
+        map.insert(
+            "max_match_rule_length".to_string(),
+            Value::from(to_u64(limits.max_match_rule_length)).into(),
+        );
+        map.insert(
+            "max_queued_owners_per_name".to_string(),
+            Value::from(to_u64(limits.max_queued_owners_per_name)).into(),
+        );
+        map.insert(
+            "max_match_rules_per_connection".to_string(),
+            Value::from(to_u64(limits.max_match_rules_per_connection)).into(),
+        );
+        map.insert(
+            "max_match_rules".to_string(),
+            Value::from(to_u64(limits.max_match_rules)).into(),
+        );
+        map.insert(
+            "max_message_unix_fds".to_string(),
+            Value::from(to_u64(limits.max_message_unix_fds)).into(),
+        );
+        map.insert(
+            "max_replies_per_connection".to_string(),
+            Value::from(to_u64(limits.max_replies_per_connection)).into(),
+        );
+        if let Some(rate_limit) = limits.sender_rate_limit {
+            map.insert(
+                "sender_messages_per_second".to_string(),
+                Value::from(rate_limit.messages_per_second).into(),
+            );
+            map.insert(
+                "sender_burst".to_string(),
+                Value::from(rate_limit.burst).into(),
+            );
+        }
+
+        map
+    }
+
+    /// Change the limit named `name` to `value`.
+    ///
+    /// Only privileged peers (root or the user of the bus) are allowed to call this.
+    async fn set_limit(&self, name: &str, value: u64) -> fdo::Result<()> {
+        if !self.credentials.is_privileged() {
+            return Err(fdo::Error::AccessDenied(
+                "Only privileged peers can change limits".to_string(),
+            ));
+        }
+        let limit = name
+            .parse()
+            .map_err(|e: anyhow::Error| fdo::Error::InvalidArgs(e.to_string()))?;
+        self.peers
+            .limits()
+            .set(limit, usize::try_from(value).unwrap_or(usize::MAX));
+
+        Ok(())
+    }
+}
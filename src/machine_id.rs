@@ -0,0 +1,66 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use rand::random;
+use tokio::fs::{metadata, read_to_string};
+use tracing::{debug, warn};
+
+/// The standard locations of the machine ID, in the order of preference.
+const MACHINE_ID_PATHS: &[&str] = &["/etc/machine-id", "/var/lib/dbus/machine-id"];
+
+/// Read the machine ID from `path`, or from the standard locations if `path` is `None`.
+///
+/// A custom `path` must contain a valid ID if it exists. If it doesn't, or if none of the standard
+/// locations have one either, e.g. in minimal containers, an ephemeral ID is generated for the
+/// lifetime of the bus. Nothing is ever written, the machine ID being the system's to set up.
+pub(crate) async fn read_machine_id(path: Option<&Path>) -> Result<String> {
+    if let Some(path) = path {
+        match metadata(path).await {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            _ => return read_from(path).await,
+        }
+        warn!(
+            "`{}` doesn't exist, using an ephemeral machine ID.",
+            path.display()
+        );
+
+        return Ok(generate());
+    }
+
+    for path in MACHINE_ID_PATHS.iter().map(PathBuf::from) {
+        match read_from(&path).await {
+            Ok(id) => return Ok(id),
+            Err(e) => debug!("No machine ID in `{}`: {}", path.display(), e),
+        }
+    }
+    warn!(
+        "No machine ID in {}, using an ephemeral one.",
+        MACHINE_ID_PATHS.join(" or ")
+    );
+
+    Ok(generate())
+}
+
+/// A random machine ID.
+fn generate() -> String {
+    format!("{:032x}", random::<u128>())
+}
+
+async fn read_from(path: &Path) -> Result<String> {
+    let id = read_to_string(path).await.map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => anyhow!("`{}` doesn't exist", path.display()),
+        _ => e.into(),
+    })?;
+    let id = id.trim_end();
+    if id.len() != 32 || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(anyhow!(
+            "`{}` doesn't contain a valid machine ID",
+            path.display()
+        ));
+    }
+
+    Ok(id.to_string())
+}
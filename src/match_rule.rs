@@ -0,0 +1,166 @@
+use zbus::{fdo, names::BusName, MatchRule, Message, MessageType, OwnedMatchRule};
+
+use crate::name_registry::NameRegistry;
+
+/// Parse a match rule string, as passed to `AddMatch` and `RemoveMatch`.
+///
+/// Unlike a naive split on commas, this implements the tokenization of the D-Bus specification:
+/// values can be (partially) single-quoted, with commas inside quotes being part of the value,
+/// and `\'` outside quotes standing for a literal quote. So `arg0=','\'` matches `,'`.
+pub(crate) fn parse(rule: &str) -> fdo::Result<OwnedMatchRule> {
+    let mut builder = MatchRule::builder();
+    for (key, value) in tokenize(rule)? {
+        builder = match key.as_str() {
+            "type" => builder.msg_type(match value.as_str() {
+                "signal" => MessageType::Signal,
+                "method_call" => MessageType::MethodCall,
+                "method_return" => MessageType::MethodReturn,
+                "error" => MessageType::Error,
+                _ => return Err(invalid(format!("Unknown message type `{value}`"))),
+            }),
+            "sender" => builder.sender(value).map_err(invalid)?,
+            "interface" => builder.interface(value).map_err(invalid)?,
+            "member" => builder.member(value).map_err(invalid)?,
+            "path" => builder.path(value).map_err(invalid)?,
+            "path_namespace" => builder.path_namespace(value).map_err(invalid)?,
+            "destination" => builder.destination(value).map_err(invalid)?,
+            "arg0namespace" => builder.arg0namespace(value).map_err(invalid)?,
+            // We don't support eavesdropping but rules asking for it are still valid.
+            "eavesdrop" => builder,
+            key => match parse_arg_key(key) {
+                Some((idx, false)) => builder.arg(idx, value).map_err(invalid)?,
+                Some((idx, true)) => builder.arg_path(idx, value).map_err(invalid)?,
+                None => return Err(invalid(format!("Unknown key `{key}`"))),
+            },
+        };
+    }
+
+    Ok(builder.build().into())
+}
+
+/// If `msg` matches `rule`, with the well-known names in `rule` resolved through `name_registry`.
+///
+/// Messages without a sender never match rules with a well-known sender.
+///
+/// # Panics
+///
+/// if the header is not set.
+pub(crate) fn matches(rule: &OwnedMatchRule, msg: &Message, name_registry: &NameRegistry) -> bool {
+    // First make use of zbus API
+    match rule.matches(msg) {
+        Ok(false) => return false,
+        Ok(true) => (),
+        Err(e) => {
+            tracing::warn!("error matching rule: {}", e);
+
+            return false;
+        }
+    }
+
+    // Then match sender and destination involving well-known names, manually.
+    // Unique names are already taken care of by the zbus API.
+    if let Some(BusName::WellKnown(name)) = rule.sender() {
+        let hdr = msg.header().expect("received message without header");
+        let sender = match hdr.sender() {
+            Ok(Some(sender)) => sender,
+            _ => return false,
+        };
+        // The bus is the only owner of its name and it's also what it uses as the sender
+        // of its own signals. Peers can't use it, see `Peers::route_msg`.
+        let matches = if name.as_str() == "org.freedesktop.DBus" {
+            sender.as_str() == "org.freedesktop.DBus"
+        } else {
+            name_registry
+                .lookup(name.clone())
+                .map(|owner| *owner == *sender)
+                .unwrap_or(false)
+        };
+        if !matches {
+            return false;
+        }
+    }
+
+    // The destination.
+    if let Some(destination) = rule.destination() {
+        let hdr = msg.header().expect("received message without header");
+        match hdr.destination().ok().flatten().cloned() {
+            Some(BusName::WellKnown(name)) => match name_registry.lookup(name) {
+                Some(name) if name == *destination => (),
+                Some(_) => return false,
+                None => return false,
+            },
+            // Unique name is already taken care of by the zbus API.
+            Some(BusName::Unique(_)) => {}
+            // Messages without a destination (i.e. broadcasts) can't match.
+            None => return false,
+        }
+    }
+
+    true
+}
+
+/// Split `rule` into its key-value pairs, unquoting and unescaping the values.
+fn tokenize(rule: &str) -> fdo::Result<Vec<(String, String)>> {
+    let mut pairs = vec![];
+    let mut chars = rule.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_ascii_whitespace()).is_some() {}
+        if chars.peek().is_none() {
+            break;
+        }
+
+        let mut key = String::new();
+        loop {
+            match chars.next() {
+                Some('=') => break,
+                Some(c) => key.push(c),
+                None => return Err(invalid(format!("Missing value for `{key}`"))),
+            }
+        }
+        if key.is_empty() {
+            return Err(invalid("Empty key".to_string()));
+        }
+
+        let mut value = String::new();
+        let mut in_quotes = false;
+        loop {
+            match (chars.next(), in_quotes) {
+                (Some('\''), _) => in_quotes = !in_quotes,
+                (Some(c), true) => value.push(c),
+                (Some('\\'), false) if chars.peek() == Some(&'\'') => {
+                    chars.next();
+                    value.push('\'');
+                }
+                (Some(','), false) | (None, false) => break,
+                (Some(c), false) => value.push(c),
+                (None, true) => {
+                    return Err(invalid(format!("Unterminated quote in value of `{key}`")))
+                }
+            }
+        }
+        pairs.push((key, value));
+    }
+
+    Ok(pairs)
+}
+
+/// Parse an `argN` or `argNpath` key into `N` and if it's the path variant.
+fn parse_arg_key(key: &str) -> Option<(u8, bool)> {
+    let key = key.strip_prefix("arg")?;
+    let (idx, is_path) = match key.strip_suffix("path") {
+        Some(idx) => (idx, true),
+        None => (key, false),
+    };
+    if idx.is_empty() || !idx.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    // The specification allows up to 64 arguments.
+    match idx.parse::<u8>() {
+        Ok(idx) if idx < 64 => Some((idx, is_path)),
+        _ => None,
+    }
+}
+
+fn invalid<E: ToString>(e: E) -> fdo::Error {
+    fdo::Error::MatchRuleInvalid(e.to_string())
+}
@@ -0,0 +1,234 @@
+use std::{
+    ffi::OsString,
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+#[cfg(unix)]
+use std::{fs::Permissions, os::unix::fs::PermissionsExt};
+
+use anyhow::Result;
+use tokio::{
+    fs::{metadata, rename, File, OpenOptions},
+    io::{AsyncWriteExt, BufWriter},
+    sync::mpsc::{channel, error::TrySendError, Receiver, Sender},
+};
+use tracing::{debug, trace, warn};
+use zbus::Message;
+
+/// A capture of all the messages delivered by the bus, routed or its own.
+///
+/// Messages are captured as their recipients got them, so with the sender set by the bus, and
+/// those the bus dropped don't appear. The replies of the bus to calls to its own interfaces are
+/// sent by zbus directly, without going through the bus, so they don't appear either.
+///
+/// Messages are written in the pcap format by a separate task so that routing is never blocked
+/// on file I/O. If the writer can't keep up, messages are dropped from the capture.
+#[derive(Clone, Debug)]
+pub struct MessageLog {
+    tx: Sender<Record>,
+    // Since the writer last caught up, so that a full queue is reported once, not per message.
+    dropped: Arc<AtomicU64>,
+}
+
+/// Size-based rotation of the message log.
+///
+/// Once the log reaches `max_size` bytes, it's renamed to `<path>.1`, after `<path>.1` is
+/// renamed to `<path>.2` and so on, and a new log is started. Only `max_files` old logs are kept
+/// around.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rotation {
+    pub max_size: u64,
+    pub max_files: usize,
+}
+
+#[derive(Debug)]
+struct Record {
+    timestamp: u64,
+    msg: Arc<Message>,
+}
+
+/// Writes the records to the log file, rotating it as needed.
+#[derive(Debug)]
+struct Writer {
+    path: PathBuf,
+    rotation: Option<Rotation>,
+    file: BufWriter<File>,
+    // Of the current file, including the header.
+    size: u64,
+}
+
+impl MessageLog {
+    pub async fn create(path: &Path, rotation: Option<Rotation>) -> Result<Self> {
+        debug!("Capturing messages to `{}`.", path.display());
+        let writer = Writer::create(path.to_path_buf(), rotation).await?;
+
+        let (tx, rx) = channel(QUEUE_SIZE);
+        let dropped = Arc::new(AtomicU64::new(0));
+        tokio::spawn(write_records(writer, rx, dropped.clone()));
+
+        Ok(Self { tx, dropped })
+    }
+
+    /// Log the given message.
+    pub fn log(&self, msg: Arc<Message>) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        match self.tx.try_send(Record { timestamp, msg }) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => {
+                if self.dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+                    warn!("Message log queue full, dropping messages.");
+                }
+            }
+            Err(TrySendError::Closed(_)) => trace!("Message log closed, dropping message."),
+        }
+    }
+}
+
+async fn write_records(mut writer: Writer, mut rx: Receiver<Record>, dropped: Arc<AtomicU64>) {
+    while let Some(record) = rx.recv().await {
+        if let Err(e) = writer.write_queued_records(&mut rx, record).await {
+            warn!("Failed to write to message log: {}", e);
+
+            break;
+        }
+        let dropped = dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            warn!("Dropped {} messages from the message log.", dropped);
+        }
+    }
+}
+
+impl Writer {
+    async fn create(path: PathBuf, rotation: Option<Rotation>) -> Result<Self> {
+        let file = BufWriter::new(create_file(&path).await?);
+        let mut writer = Self {
+            path,
+            rotation,
+            file,
+            size: 0,
+        };
+        writer.write_header().await?;
+        writer.file.flush().await?;
+
+        Ok(writer)
+    }
+
+    /// Write the given record and all the records already queued, and then flush.
+    async fn write_queued_records(
+        &mut self,
+        rx: &mut Receiver<Record>,
+        record: Record,
+    ) -> Result<()> {
+        self.write_record(&record).await?;
+        while let Ok(record) = rx.try_recv() {
+            self.write_record(&record).await?;
+        }
+        self.file.flush().await?;
+
+        Ok(())
+    }
+
+    async fn write_header(&mut self) -> Result<()> {
+        self.file.write_all(&PCAP_MAGIC.to_le_bytes()).await?;
+        self.file.write_all(&2u16.to_le_bytes()).await?;
+        self.file.write_all(&4u16.to_le_bytes()).await?;
+        // Timezone offset and timestamp accuracy.
+        self.file.write_all(&0i32.to_le_bytes()).await?;
+        self.file.write_all(&0u32.to_le_bytes()).await?;
+        self.file.write_all(&MAX_MESSAGE_SIZE.to_le_bytes()).await?;
+        self.file.write_all(&LINKTYPE_DBUS.to_le_bytes()).await?;
+        self.size += PCAP_HEADER_SIZE;
+
+        Ok(())
+    }
+
+    async fn write_record(&mut self, record: &Record) -> Result<()> {
+        let bytes = record.msg.as_bytes();
+        let len = bytes.len() as u32;
+        let record_size = RECORD_HEADER_SIZE + bytes.len() as u64;
+        if let Some(rotation) = self.rotation {
+            // Always write at least one record per file, however large.
+            if self.size > PCAP_HEADER_SIZE && self.size + record_size > rotation.max_size {
+                self.rotate(rotation.max_files).await?;
+            }
+        }
+
+        self.file
+            .write_all(&((record.timestamp / 1_000_000) as u32).to_le_bytes())
+            .await?;
+        self.file
+            .write_all(&((record.timestamp % 1_000_000) as u32).to_le_bytes())
+            .await?;
+        // Captured and original lengths are the same as we never truncate.
+        self.file.write_all(&len.to_le_bytes()).await?;
+        self.file.write_all(&len.to_le_bytes()).await?;
+        self.file.write_all(bytes).await?;
+        self.size += record_size;
+
+        Ok(())
+    }
+
+    /// Move the current file out of the way, along with the older ones, and start a new one.
+    async fn rotate(&mut self, max_files: usize) -> Result<()> {
+        self.file.flush().await?;
+        if max_files > 0 {
+            // The oldest file, if there's one, gets overwritten.
+            for i in (1..max_files).rev() {
+                let from = self.rotated_path(i);
+                if metadata(&from).await.is_ok() {
+                    rename(from, self.rotated_path(i + 1)).await?;
+                }
+            }
+            rename(&self.path, self.rotated_path(1)).await?;
+        }
+        debug!("Rotated message log `{}`.", self.path.display());
+
+        self.file = BufWriter::new(create_file(&self.path).await?);
+        self.size = 0;
+        self.write_header().await
+    }
+
+    fn rotated_path(&self, i: usize) -> PathBuf {
+        let mut path = OsString::from(self.path.as_os_str());
+        path.push(format!(".{i}"));
+
+        path.into()
+    }
+}
+
+/// Create the log file at `path`, or truncate it, only readable by the bus user.
+///
+/// Messages carry whatever peers exchange, including secrets, so the umask doesn't get a say.
+async fn create_file(path: &Path) -> io::Result<File> {
+    let mut open_options = OpenOptions::new();
+    #[allow(unused_mut)]
+    let mut open_options = open_options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        open_options = open_options.mode(0o600);
+    }
+    let file = open_options.open(path).await?;
+    // The mode only applies to new files, not to an old log being overwritten.
+    #[cfg(unix)]
+    file.set_permissions(Permissions::from_mode(0o600)).await?;
+
+    Ok(file)
+}
+
+const PCAP_MAGIC: u32 = 0xa1b2c3d4;
+// https://www.tcpdump.org/linktypes/LINKTYPE_DBUS.html
+const LINKTYPE_DBUS: u32 = 231;
+// Maximum message size allowed by the D-Bus specification, used as the snapshot length.
+const MAX_MESSAGE_SIZE: u32 = 128 * 1024 * 1024;
+const QUEUE_SIZE: usize = 1024;
+const PCAP_HEADER_SIZE: u64 = 24;
+// Timestamp and the two lengths.
+const RECORD_HEADER_SIZE: u64 = 16;
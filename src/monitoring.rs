@@ -0,0 +1,127 @@
+use zbus::{dbus_interface, fdo, names::OwnedUniqueName, MessageHeader};
+
+use crate::{credentials::Credentials, match_rule, peers::Peers};
+
+/// The `BecomeMonitor` flag asking for a `NameAcquired` signal for each name owned at the time.
+///
+/// A busd extension, for monitors joining late, e.g. live dashboards of services, to learn the
+/// current owners without calling the bus, which they can't anymore. The signals are addressed to
+/// the owners, like those a monitor sees when names are acquired, and all sent before the reply.
+/// Kept clear of the low bits, which `dbus-daemon` might define flags in.
+pub const REPLAY_NAME_OWNERS: u32 = 1 << 31;
+
+/// The `BecomeMonitor` flag asking for the oldest copies to be dropped when the monitor falls
+/// behind, rather than for it to be disconnected.
+///
+/// A busd extension, for monitors that would rather lose old traffic than their view of the bus,
+/// e.g. live dashboards. See [`OverflowPolicy`].
+pub const DROP_OLDEST: u32 = 1 << 30;
+
+/// What happens once more copies are waiting to be sent to a monitor than
+/// [`BusBuilder::max_monitor_queue`](crate::bus_builder::BusBuilder::max_monitor_queue) allows.
+///
+/// Peers other than monitors aren't queued for: routing to them waits for them to read instead.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum OverflowPolicy {
+    /// Disconnect the monitor, the default.
+    Disconnect,
+    /// Drop the oldest copy to make room for the new one, if the monitor asked with
+    /// [`DROP_OLDEST`].
+    DropOldest,
+}
+
+/// The prefix of the `BecomeMonitor` rules of the messages a monitor doesn't want a copy of.
+///
+/// A busd extension, since match rules can only ever add messages: `!sender='org.freedesktop.DBus'`
+/// is all messages but those from the bus, on its own, or only the matching messages not from the
+/// bus, along with other rules. Exclusions win over any other rule.
+pub const EXCLUDE_PREFIX: char = '!';
+
+/// The `org.freedesktop.DBus.Monitoring` interface.
+#[derive(Debug)]
+pub(crate) struct Monitoring {
+    peers: Peers,
+    // Of the peer calling the methods.
+    unique_name: OwnedUniqueName,
+    credentials: Credentials,
+}
+
+impl Monitoring {
+    pub fn new(peers: Peers, unique_name: OwnedUniqueName, credentials: Credentials) -> Self {
+        Self {
+            peers,
+            unique_name,
+            credentials,
+        }
+    }
+}
+
+#[dbus_interface(interface = "org.freedesktop.DBus.Monitoring")]
+impl Monitoring {
+    /// Turn the caller into a monitor, receiving a copy of each message routed between peers that
+    /// matches any of `match_rules`, or of all of them if there are none.
+    ///
+    /// The copies are the messages as they were sent, with their original sender and destination.
+    /// The caller loses all its names, its unique name included, so it can't be addressed anymore,
+    /// and it's disconnected if it sends anything but this very call, even to the bus. Since
+    /// monitors see all traffic, only privileged peers (root or the user of the bus) are allowed
+    /// to call this. The only flags are [`REPLAY_NAME_OWNERS`] and [`DROP_OLDEST`], and `flags`
+    /// must be 0 otherwise.
+    /// Rules starting with [`EXCLUDE_PREFIX`] are of the messages not to copy instead.
+    async fn become_monitor(
+        &self,
+        match_rules: Vec<String>,
+        flags: u32,
+        #[zbus(header)] hdr: MessageHeader<'_>,
+    ) -> fdo::Result<()> {
+        if !self.credentials.is_privileged() {
+            return Err(fdo::Error::AccessDenied(
+                "Only privileged peers can become monitors".to_string(),
+            ));
+        }
+        if flags & !(REPLAY_NAME_OWNERS | DROP_OLDEST) != 0 {
+            return Err(fdo::Error::InvalidArgs(format!(
+                "Unsupported flags: {flags:#x}"
+            )));
+        }
+        let limits = self.peers.limits().get();
+        let max = limits.max_match_rules_per_connection;
+        if match_rules.len() > max {
+            return Err(fdo::Error::LimitsExceeded(format!(
+                "Monitors can't have more than {max} match rules"
+            )));
+        }
+        let max = limits.max_match_rule_length;
+        let (mut rules, mut excluded) = (vec![], vec![]);
+        for rule in &match_rules {
+            if rule.len() > max {
+                return Err(fdo::Error::MatchRuleInvalid(format!(
+                    "Match rule longer than {max} bytes"
+                )));
+            }
+            match rule.strip_prefix(EXCLUDE_PREFIX) {
+                Some(rule) => excluded.push(match_rule::parse(rule)?),
+                None => rules.push(match_rule::parse(rule)?),
+            }
+        }
+
+        let serial = hdr.primary().serial_num().copied();
+        let overflow = if flags & DROP_OLDEST != 0 {
+            OverflowPolicy::DropOldest
+        } else {
+            OverflowPolicy::Disconnect
+        };
+
+        self.peers
+            .become_monitor(
+                &self.unique_name,
+                rules,
+                excluded,
+                serial,
+                flags & REPLAY_NAME_OWNERS != 0,
+                overflow,
+            )
+            .await
+    }
+}
@@ -1,17 +1,41 @@
 use enumflags2::BitFlags;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use std::{
-    collections::{HashMap, VecDeque},
-    sync::Arc,
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
+use tokio::{sync::broadcast, time::sleep};
 use zbus::{
-    fdo::{ReleaseNameReply, RequestNameFlags, RequestNameReply},
+    fdo::{self, ReleaseNameReply, RequestNameFlags, RequestNameReply},
     names::{OwnedUniqueName, OwnedWellKnownName, UniqueName, WellKnownName},
 };
 
+use crate::{
+    event::{BusEvent, BusSignals},
+    limits::SharedLimits,
+};
+
+/// The owners of well-known names, and the peers queued for them.
+///
+/// Every operation holds the lock throughout, including while emitting its
+/// [`BusEvent::NameOwnerChanged`] events. So concurrent operations, even of the same peer, apply
+/// one after the other and their events come in that same order.
 #[derive(Clone, Debug)]
 pub struct NameRegistry {
     names: Arc<RwLock<HashMap<OwnedWellKnownName, NameEntry>>>,
+    peak_num_names: Arc<AtomicUsize>,
+    // Names peers can't request, only the bus itself.
+    reserved_names: Arc<HashSet<OwnedWellKnownName>>,
+    // Always locked after `names`.
+    orphans: Arc<Mutex<Orphans>>,
+    promotion_grace: Duration,
+    limits: Arc<SharedLimits>,
+    events: broadcast::Sender<BusEvent>,
+    bus_signals: BusSignals,
 }
 
 #[derive(Clone, Debug)]
@@ -20,6 +44,20 @@ pub struct NameEntry {
     waiting_list: VecDeque<NameOwner>,
 }
 
+/// The queues of names whose owner disconnected, until the next owner is promoted.
+#[derive(Debug, Default)]
+struct Orphans {
+    queues: HashMap<OwnedWellKnownName, Orphan>,
+    next_id: u64,
+}
+
+#[derive(Debug)]
+struct Orphan {
+    // So that the promotion of a name orphaned again in the meantime isn't brought forward.
+    id: u64,
+    waiting_list: VecDeque<NameOwner>,
+}
+
 #[derive(Clone, Debug)]
 pub struct NameOwner {
     unique_name: OwnedUniqueName,
@@ -27,62 +65,163 @@ pub struct NameOwner {
 }
 
 impl NameRegistry {
+    pub fn new(
+        events: broadcast::Sender<BusEvent>,
+        limits: Arc<SharedLimits>,
+        reserved_names: HashSet<OwnedWellKnownName>,
+        promotion_grace: Duration,
+    ) -> Self {
+        Self {
+            names: Arc::new(RwLock::new(HashMap::new())),
+            peak_num_names: Arc::default(),
+            reserved_names: Arc::new(reserved_names),
+            orphans: Arc::default(),
+            promotion_grace,
+            limits,
+            events,
+            bus_signals: BusSignals::default(),
+        }
+    }
+
+    /// Where the changes of owners go for the bus to signal them, along with other events.
+    pub(crate) fn bus_signals(&self) -> &BusSignals {
+        &self.bus_signals
+    }
+
+    /// Request `name` on behalf of a peer.
+    ///
+    /// Reserved names are refused, and so is the name of the bus, whatever the reserved names.
     pub fn request_name(
         &self,
         name: OwnedWellKnownName,
         unique_name: OwnedUniqueName,
         flags: BitFlags<RequestNameFlags>,
-    ) -> RequestNameReply {
-        // TODO: Emit all signals.
+    ) -> fdo::Result<RequestNameReply> {
+        // Same error as `dbus-daemon`.
+        if name.as_str() == "org.freedesktop.DBus" {
+            return Err(fdo::Error::InvalidArgs(format!(
+                "`{name}` is reserved for the bus itself"
+            )));
+        }
+        if self.reserved_names.contains(&name) {
+            return Err(fdo::Error::AccessDenied(format!(
+                "`{name}` is reserved by the bus"
+            )));
+        }
+
+        self.claim_name(name, unique_name, flags)
+    }
+
+    /// Request `name` on behalf of the bus itself, e.g. for a service it runs in-process.
+    ///
+    /// Unlike [`NameRegistry::request_name`], this works for reserved names too.
+    pub(crate) fn claim_name(
+        &self,
+        name: OwnedWellKnownName,
+        unique_name: OwnedUniqueName,
+        flags: BitFlags<RequestNameFlags>,
+    ) -> fdo::Result<RequestNameReply> {
         let owner = NameOwner {
             unique_name,
             allow_replacement: flags.contains(RequestNameFlags::AllowReplacement),
         };
         let mut names = self.names.write();
 
-        match names.get_mut(&name) {
+        let reply = match names.get_mut(&name) {
             Some(entry) => {
                 if entry.owner.unique_name == owner.unique_name {
                     RequestNameReply::AlreadyOwner
                 } else if flags.contains(RequestNameFlags::ReplaceExisting)
                     && entry.owner.allow_replacement
                 {
-                    entry.owner = owner;
+                    entry
+                        .waiting_list
+                        .retain(|waiting| waiting.unique_name != owner.unique_name);
+                    let old_owner = std::mem::replace(&mut entry.owner, owner);
+                    self.owner_changed(
+                        name,
+                        Some(old_owner.unique_name),
+                        Some(entry.owner.unique_name.clone()),
+                    );
 
                     RequestNameReply::PrimaryOwner
                 } else if !flags.contains(RequestNameFlags::DoNotQueue) {
-                    entry.waiting_list.push_back(owner);
+                    match entry
+                        .waiting_list
+                        .iter_mut()
+                        .find(|waiting| waiting.unique_name == owner.unique_name)
+                    {
+                        // Already queued, so only the flags change.
+                        Some(waiting) => *waiting = owner,
+                        None if entry.waiting_list.len()
+                            >= self.limits.max_queued_owners_per_name() =>
+                        {
+                            return Err(fdo::Error::LimitsExceeded(format!(
+                                "Too many peers waiting for `{name}`"
+                            )));
+                        }
+                        None => entry.waiting_list.push_back(owner),
+                    }
 
                     RequestNameReply::InQueue
                 } else {
+                    // Asking not to be queued anymore takes the peer out of the queue, if it was
+                    // in it.
+                    entry
+                        .waiting_list
+                        .retain(|waiting| waiting.unique_name != owner.unique_name);
+
                     RequestNameReply::Exists
                 }
             }
             None => {
+                let new_owner = owner.unique_name.clone();
+                // Taking an orphaned name takes over its queue too.
+                let mut waiting_list = self
+                    .orphans
+                    .lock()
+                    .queues
+                    .remove(&name)
+                    .map(|orphan| orphan.waiting_list)
+                    .unwrap_or_default();
+                waiting_list.retain(|waiting| waiting.unique_name != new_owner);
                 names.insert(
-                    name,
+                    name.clone(),
                     NameEntry {
                         owner,
-                        waiting_list: VecDeque::new(),
+                        waiting_list,
                     },
                 );
+                self.peak_num_names
+                    .fetch_max(names.len(), Ordering::Relaxed);
+                self.owner_changed(name, None, Some(new_owner));
 
                 RequestNameReply::PrimaryOwner
             }
-        }
+        };
+
+        Ok(reply)
     }
 
     pub fn release_name(&self, name: WellKnownName, owner: UniqueName) -> ReleaseNameReply {
-        // TODO: Emit all signals.
         let mut names = self.names.write();
         match names.get_mut(name.as_str()) {
             Some(entry) => {
                 if *entry.owner.unique_name == owner {
-                    if let Some(owner) = entry.waiting_list.pop_front() {
-                        entry.owner = owner;
-                    } else {
-                        names.remove(name.as_str());
-                    }
+                    let old_owner = entry.owner.unique_name.clone();
+                    let new_owner = match entry.waiting_list.pop_front() {
+                        Some(owner) => {
+                            entry.owner = owner;
+
+                            Some(entry.owner.unique_name.clone())
+                        }
+                        None => {
+                            names.remove(name.as_str());
+
+                            None
+                        }
+                    };
+                    self.owner_changed(name.into(), Some(old_owner), new_owner);
 
                     ReleaseNameReply::Released
                 } else {
@@ -97,22 +236,174 @@ impl NameRegistry {
                     ReleaseNameReply::NonExistent
                 }
             }
-            None => ReleaseNameReply::NonExistent,
+            None => match self.orphans.lock().queues.get_mut(name.as_str()) {
+                Some(orphan) if orphan.waiting_list.iter().any(|w| *w.unique_name == owner) => {
+                    orphan
+                        .waiting_list
+                        .retain(|waiting| *waiting.unique_name != owner);
+
+                    ReleaseNameReply::Released
+                }
+                _ => ReleaseNameReply::NonExistent,
+            },
         }
     }
 
+    /// Release all names owned by, or queued for, the given unique name.
+    ///
+    /// This is meant to be called when the owner disconnects. Its queued owners are only promoted
+    /// after the grace period the registry was created with, if any. In the meantime, the names
+    /// have no owner but go to whoever requests them first, along with their queues.
+    pub fn release_all(&self, owner: UniqueName<'_>) {
+        let mut names = self.names.write();
+        let mut orphans = self.orphans.lock();
+        for orphan in orphans.queues.values_mut() {
+            orphan
+                .waiting_list
+                .retain(|waiting| *waiting.unique_name != owner);
+        }
+        names.retain(|name, entry| {
+            entry
+                .waiting_list
+                .retain(|waiting| *waiting.unique_name != owner);
+            if *entry.owner.unique_name != owner {
+                return true;
+            }
+
+            let old_owner = entry.owner.unique_name.clone();
+            if !self.promotion_grace.is_zero() && !entry.waiting_list.is_empty() {
+                let id = orphans.next_id;
+                orphans.next_id += 1;
+                let waiting_list = std::mem::take(&mut entry.waiting_list);
+                orphans
+                    .queues
+                    .insert(name.clone(), Orphan { id, waiting_list });
+                self.owner_changed(name.clone(), Some(old_owner), None);
+                let registry = self.clone();
+                let name = name.clone();
+                tokio::spawn(async move {
+                    sleep(registry.promotion_grace).await;
+                    registry.promote_orphan(name, id);
+                });
+
+                return false;
+            }
+            let (new_owner, keep) = match entry.waiting_list.pop_front() {
+                Some(owner) => {
+                    entry.owner = owner;
+
+                    (Some(entry.owner.unique_name.clone()), true)
+                }
+                None => (None, false),
+            };
+            self.owner_changed(name.clone(), Some(old_owner), new_owner);
+
+            keep
+        });
+    }
+
     pub fn lookup(&self, name: WellKnownName) -> Option<OwnedUniqueName> {
         self.names
             .read()
             .get(name.as_str())
             .map(|e| e.owner.unique_name.clone())
     }
-}
 
-impl Default for NameRegistry {
-    fn default() -> Self {
-        Self {
-            names: Arc::new(RwLock::new(HashMap::new())),
-        }
+    /// The number of names currently owned.
+    pub fn num_names(&self) -> usize {
+        self.names.read().len()
+    }
+
+    /// The highest number of names owned at the same time so far.
+    pub fn peak_num_names(&self) -> usize {
+        self.peak_num_names.load(Ordering::Relaxed)
+    }
+
+    /// All the names that currently have an owner.
+    pub fn names(&self) -> Vec<OwnedWellKnownName> {
+        self.names.read().keys().cloned().collect()
+    }
+
+    /// All the names that currently have an owner, each along with its primary owner.
+    pub fn owners(&self) -> Vec<(OwnedWellKnownName, OwnedUniqueName)> {
+        self.names
+            .read()
+            .iter()
+            .map(|(name, entry)| (name.clone(), entry.owner.unique_name.clone()))
+            .collect()
+    }
+
+    /// The names whose primary owner is `owner`.
+    pub fn names_owned_by(&self, owner: UniqueName<'_>) -> Vec<OwnedWellKnownName> {
+        self.names
+            .read()
+            .iter()
+            .filter(|(_, entry)| *entry.owner.unique_name == owner)
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// The position of `unique_name` in the queue of owners of `name`, the primary owner being
+    /// at 0.
+    ///
+    /// Returns `None` if `name` has no owner, and `Some(None)` if `unique_name` isn't queued.
+    pub fn queue_position(
+        &self,
+        name: WellKnownName<'_>,
+        unique_name: UniqueName<'_>,
+    ) -> Option<Option<usize>> {
+        self.names.read().get(name.as_str()).map(|entry| {
+            std::iter::once(&entry.owner)
+                .chain(&entry.waiting_list)
+                .position(|owner| *owner.unique_name == unique_name)
+        })
+    }
+
+    /// Promote the first queued owner of `name`, if it's still the orphan `id`.
+    fn promote_orphan(&self, name: OwnedWellKnownName, id: u64) {
+        let mut names = self.names.write();
+        let mut orphans = self.orphans.lock();
+        let mut waiting_list = match orphans.queues.remove(&name) {
+            Some(orphan) if orphan.id == id => orphan.waiting_list,
+            // Orphaned again since, so it's up to the promotion of that.
+            Some(orphan) => {
+                orphans.queues.insert(name, orphan);
+
+                return;
+            }
+            None => return,
+        };
+        // Everyone queued may have left in the meantime.
+        let owner = match waiting_list.pop_front() {
+            Some(owner) => owner,
+            None => return,
+        };
+        let new_owner = owner.unique_name.clone();
+        names.insert(
+            name.clone(),
+            NameEntry {
+                owner,
+                waiting_list,
+            },
+        );
+        self.peak_num_names
+            .fetch_max(names.len(), Ordering::Relaxed);
+        self.owner_changed(name, None, Some(new_owner));
+    }
+
+    fn owner_changed(
+        &self,
+        name: OwnedWellKnownName,
+        old_owner: Option<OwnedUniqueName>,
+        new_owner: Option<OwnedUniqueName>,
+    ) {
+        let event = BusEvent::NameOwnerChanged {
+            name,
+            old_owner,
+            new_owner,
+        };
+        self.bus_signals.send(event.clone());
+        // It's fine if there are no subscribers.
+        let _ = self.events.send(event);
     }
 }
@@ -1,55 +1,139 @@
-use std::collections::HashSet;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{atomic::AtomicUsize, Arc},
+    time::{Duration, Instant},
+};
 
 use anyhow::Result;
 use enumflags2::BitFlags;
 use tracing::trace;
 use zbus::{
     dbus_interface,
-    fdo::{self, ReleaseNameReply, RequestNameFlags, RequestNameReply},
+    fdo::{self, ReleaseNameReply, RequestNameFlags, RequestNameReply, StartServiceReply},
     names::{BusName, OwnedBusName, OwnedUniqueName, OwnedWellKnownName},
-    AuthMechanism, Connection, ConnectionBuilder, Guid, MessageStream, OwnedMatchRule, Socket,
+    zvariant::{OwnedValue, Value},
+    AuthMechanism, Connection, ConnectionBuilder, Guid, InterfaceRef, MessageStream,
+    OwnedMatchRule, Socket,
 };
 
-use crate::name_registry::NameRegistry;
+use crate::{
+    credentials::Credentials, limits::LimitsInterface, match_rule, monitoring::Monitoring,
+    name_registry::NameRegistry, peers::Peers, stats::Stats,
+};
 
 /// A peer connection.
 #[derive(Debug)]
 pub struct Peer {
+    id: usize,
     conn: Connection,
     unique_name: OwnedUniqueName,
+    can_pass_unix_fd: bool,
+    credentials: Credentials,
+    accepted_at: Instant,
+    auth_latency: Duration,
+    auth_mechanism: AuthMechanism,
+    // Sends to the peer that haven't completed yet.
+    pending_sends: Arc<AtomicUsize>,
 }
 
 impl Peer {
+    /// Authenticate the peer on `socket`, accepted at `accepted_at`.
     pub async fn new(
         guid: &Guid,
         id: usize,
         socket: Box<dyn Socket + 'static>,
-        name_registry: NameRegistry,
+        credentials: Credentials,
+        accepted_at: Instant,
+        peers: Peers,
         auth_mechanism: AuthMechanism,
     ) -> Result<Self> {
         let unique_name = OwnedUniqueName::try_from(format!(":busd.{id}")).unwrap();
+        let can_pass_unix_fd = socket.can_pass_unix_fd();
+        let name_registry = peers.name_registry().clone();
 
         let conn = ConnectionBuilder::socket(socket)
             .server(guid)
             .p2p()
             .serve_at(
                 "/org/freedesktop/DBus",
-                DBus::new(unique_name.clone(), name_registry),
+                DBus::new(
+                    unique_name.clone(),
+                    name_registry.clone(),
+                    peers.clone(),
+                    guid.clone(),
+                    accepted_at,
+                ),
+            )?
+            .serve_at(
+                "/org/freedesktop/DBus",
+                Stats::new(
+                    name_registry,
+                    peers.clone(),
+                    unique_name.clone(),
+                    credentials.clone(),
+                ),
+            )?
+            .serve_at(
+                "/org/freedesktop/DBus",
+                Monitoring::new(peers.clone(), unique_name.clone(), credentials.clone()),
+            )?
+            .serve_at(
+                "/org/freedesktop/DBus",
+                LimitsInterface::new(peers, credentials.clone()),
             )?
             .name("org.freedesktop.DBus")?
             .unique_name("org.freedesktop.DBus")?
             .auth_mechanisms(&[auth_mechanism])
             .build()
             .await?;
+        let auth_latency = accepted_at.elapsed();
         trace!("created: {:?}", conn);
 
-        Ok(Self { conn, unique_name })
+        Ok(Self {
+            id,
+            conn,
+            unique_name,
+            can_pass_unix_fd,
+            credentials,
+            accepted_at,
+            auth_latency,
+            auth_mechanism,
+            pending_sends: Arc::default(),
+        })
     }
 
     pub fn unique_name(&self) -> &OwnedUniqueName {
         &self.unique_name
     }
 
+    /// If the underlying transport can carry Unix file descriptors.
+    pub fn can_pass_unix_fd(&self) -> bool {
+        self.can_pass_unix_fd
+    }
+
+    /// The credentials of the peer.
+    pub fn credentials(&self) -> &Credentials {
+        &self.credentials
+    }
+
+    /// The mechanism the peer authenticated through.
+    pub fn auth_mechanism(&self) -> AuthMechanism {
+        self.auth_mechanism
+    }
+
+    /// The ID of the peer, assigned when its connection was accepted.
+    ///
+    /// IDs are never reused and tag all the logs about the peer, so that its activity can be
+    /// followed through them.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// The number of sends to the peer that haven't completed yet, e.g. since it isn't reading.
+    pub(crate) fn pending_sends(&self) -> &Arc<AtomicUsize> {
+        &self.pending_sends
+    }
+
     pub fn conn(&self) -> &Connection {
         &self.conn
     }
@@ -58,112 +142,146 @@ impl Peer {
         MessageStream::from(&self.conn)
     }
 
-    /// # Panics
+    /// How long the peer has been connected, counting from the accept.
+    pub fn uptime(&self) -> Duration {
+        self.accepted_at.elapsed()
+    }
+
+    /// The current and peak number of match rules of the peer.
+    pub async fn match_rule_counts(&self) -> (usize, usize) {
+        let dbus_ref = self.dbus_ref().await;
+        let dbus = dbus_ref.get().await;
+
+        (dbus.match_rules.len(), dbus.peak_match_rules)
+    }
+
+    /// The time it took the peer to authenticate and to call `Hello`, counting from the accept.
     ///
-    /// if header, SENDER or DESTINATION is not set.
-    pub async fn interested(&self, msg: &zbus::Message) -> bool {
-        let dbus_ref = self
-            .conn
-            .object_server()
-            .interface::<_, DBus>("/org/freedesktop/DBus")
-            .await
-            .expect("DBus interface not found");
+    /// The latter is `None` if the peer didn't call `Hello` yet.
+    pub async fn latencies(&self) -> (Duration, Option<Duration>) {
+        let dbus_ref = self.dbus_ref().await;
         let dbus = dbus_ref.get().await;
-        let hdr = msg.header().expect("received message without header");
 
-        dbus.match_rules.iter().any(|rule| {
-            // First make use of zbus API
-            match rule.matches(msg) {
-                Ok(false) => return false,
-                Ok(true) => (),
-                Err(e) => {
-                    tracing::warn!("error matching rule: {}", e);
+        (self.auth_latency, dbus.hello_latency)
+    }
 
-                    return false;
-                }
-            }
+    /// The match rules of the peer.
+    pub async fn match_rules(&self) -> Vec<OwnedMatchRule> {
+        let dbus_ref = self.dbus_ref().await;
+        let dbus = dbus_ref.get().await;
 
-            // Then match sender and destination involving well-known names, manually.
-            if let Some(sender) = rule.sender().cloned().and_then(|name| match name {
-                BusName::WellKnown(name) => dbus.name_registry.lookup(name).as_deref().cloned(),
-                // Unique name is already taken care of by the zbus API.
-                BusName::Unique(_) => None,
-            }) {
-                if sender
-                    != hdr
-                        .sender()
-                        .expect("SENDER field unset")
-                        .expect("SENDER field unset")
-                        .clone()
-                {
-                    return false;
-                }
-            }
+        dbus.match_rules.iter().cloned().collect()
+    }
 
-            // The destination.
-            if let Some(destination) = rule.destination() {
-                match hdr
-                    .destination()
-                    .expect("DESTINATION field unset")
-                    .expect("DESTINATION field unset")
-                    .clone()
-                {
-                    BusName::WellKnown(name) => match dbus.name_registry.lookup(name) {
-                        Some(name) if name == *destination => (),
-                        Some(_) => return false,
-                        None => return false,
-                    },
-                    // Unique name is already taken care of by the zbus API.
-                    BusName::Unique(_) => {}
-                }
-            }
+    /// # Panics
+    ///
+    /// if the header is not set.
+    pub async fn interested(&self, msg: &zbus::Message) -> bool {
+        let dbus_ref = self.dbus_ref().await;
+        let dbus = dbus_ref.get().await;
 
-            true
-        })
+        dbus.match_rules
+            .iter()
+            .any(|rule| match_rule::matches(rule, msg, &dbus.name_registry))
+    }
+
+    async fn dbus_ref(&self) -> InterfaceRef<DBus> {
+        self.conn
+            .object_server()
+            .interface::<_, DBus>("/org/freedesktop/DBus")
+            .await
+            .expect("DBus interface not found")
     }
 }
 
 #[derive(Debug)]
 struct DBus {
-    greeted: bool,
+    accepted_at: Instant,
+    // Set once the peer calls `Hello`.
+    hello_latency: Option<Duration>,
     unique_name: OwnedUniqueName,
     name_registry: NameRegistry,
+    peers: Peers,
     match_rules: HashSet<OwnedMatchRule>,
+    peak_match_rules: usize,
+    guid: Guid,
 }
 
 impl DBus {
-    fn new(unique_name: OwnedUniqueName, name_registry: NameRegistry) -> Self {
+    fn new(
+        unique_name: OwnedUniqueName,
+        name_registry: NameRegistry,
+        peers: Peers,
+        guid: Guid,
+        accepted_at: Instant,
+    ) -> Self {
         Self {
-            greeted: false,
+            accepted_at,
+            hello_latency: None,
             unique_name,
             name_registry,
+            peers,
             match_rules: HashSet::new(),
+            peak_match_rules: 0,
+            guid,
         }
     }
+
+    /// The credentials of the owner of `name`, which is the bus itself for `org.freedesktop.DBus`.
+    async fn credentials_of(&self, name: OwnedBusName) -> fdo::Result<Credentials> {
+        let unique_name: OwnedUniqueName = match name.into_inner() {
+            BusName::WellKnown(name) if name.as_str() == "org.freedesktop.DBus" => {
+                return Ok(self.peers.bus_credentials().clone());
+            }
+            BusName::Unique(name) => name.into(),
+            BusName::WellKnown(name) => {
+                self.name_registry.lookup(name.clone()).ok_or_else(|| {
+                    fdo::Error::NameHasNoOwner(format!("Name `{name}` is not owned by anyone"))
+                })?
+            }
+        };
+
+        self.peers
+            .credentials((&*unique_name).into())
+            .await
+            .ok_or_else(|| {
+                fdo::Error::NameHasNoOwner(format!("No connection named `{unique_name}`"))
+            })
+    }
+}
+
+// The rules of a peer that disconnected no longer count against the global limit.
+impl Drop for DBus {
+    fn drop(&mut self) {
+        self.peers.remove_match_rules(self.match_rules.len());
+    }
 }
 
 #[dbus_interface(interface = "org.freedesktop.DBus")]
 impl DBus {
     /// Returns the unique name assigned to the connection.
     async fn hello(&mut self) -> fdo::Result<OwnedUniqueName> {
-        if self.greeted {
+        if self.hello_latency.is_some() {
             return Err(fdo::Error::Failed(
                 "Can only call `Hello` method once".to_string(),
             ));
         }
-        self.greeted = true;
+        let latency = self.accepted_at.elapsed();
+        self.hello_latency = Some(latency);
+        self.peers.greeted(latency);
 
         Ok(self.unique_name.clone())
     }
 
     /// Ask the message bus to assign the given name to the method caller.
-    fn request_name(
+    async fn request_name(
         &self,
         name: OwnedWellKnownName,
         flags: BitFlags<RequestNameFlags>,
-    ) -> RequestNameReply {
-        self.name_registry
+    ) -> fdo::Result<RequestNameReply> {
+        self.peers
             .request_name(name, self.unique_name.clone(), flags)
+            .await
     }
 
     /// Ask the message bus to release the method caller's claim to the given name.
@@ -173,29 +291,207 @@ impl DBus {
     }
 
     /// Returns the unique connection name of the primary owner of the name given.
-    fn get_name_owner(&self, name: OwnedBusName) -> fdo::Result<OwnedUniqueName> {
+    async fn get_name_owner(&self, name: OwnedBusName) -> fdo::Result<OwnedUniqueName> {
         match name.into_inner() {
             BusName::WellKnown(name) => self.name_registry.lookup(name).ok_or_else(|| {
                 fdo::Error::NameHasNoOwner("Name is not owned by anyone. Take it!".to_string())
             }),
-            // FIXME: Not good enough. We need to check if name is actually owned.
-            BusName::Unique(name) => Ok(name.into()),
+            BusName::Unique(name) => {
+                if self.peers.contains(name.clone()).await {
+                    Ok(name.into())
+                } else {
+                    Err(fdo::Error::NameHasNoOwner(format!(
+                        "No connection named `{name}`"
+                    )))
+                }
+            }
         }
     }
 
+    /// Checks if the specified name exists (currently has an owner).
+    async fn name_has_owner(&self, name: OwnedBusName) -> bool {
+        match name.into_inner() {
+            // The bus always owns its own name.
+            BusName::WellKnown(name) if name.as_str() == "org.freedesktop.DBus" => true,
+            BusName::WellKnown(name) => self.name_registry.lookup(name).is_some(),
+            // Unique names are owned by the connections they were assigned to, for as long as
+            // they're connected.
+            BusName::Unique(name) => self.peers.contains(name).await,
+        }
+    }
+
+    /// Returns a list of all currently-owned names on the bus.
+    ///
+    /// This includes the bus' own name and the unique names of all connected peers.
+    async fn list_names(&self) -> Vec<String> {
+        let mut names = vec!["org.freedesktop.DBus".to_string()];
+        names.extend(
+            self.peers
+                .unique_names()
+                .await
+                .into_iter()
+                .map(|name| name.to_string()),
+        );
+        names.extend(
+            self.name_registry
+                .names()
+                .into_iter()
+                .map(|name| name.to_string()),
+        );
+
+        names
+    }
+
     /// Adds a match rule to match messages going through the message bus
-    fn add_match(&mut self, rule: OwnedMatchRule) {
+    fn add_match(&mut self, rule: &str) -> fdo::Result<()> {
+        // All checked against the same limits, even if they're changed meanwhile.
+        let limits = self.peers.limits().get();
+        // Check before parsing so that huge rules don't cost us anything.
+        let max = limits.max_match_rule_length;
+        if rule.len() > max {
+            return Err(fdo::Error::MatchRuleInvalid(format!(
+                "Match rule longer than {max} bytes"
+            )));
+        }
+        let rule = match_rule::parse(rule)?;
+        if self.match_rules.contains(&rule) {
+            return Ok(());
+        }
+        // Peers already over a lowered limit keep their rules, but can't add any more.
+        let max = limits.max_match_rules_per_connection;
+        if self.match_rules.len() >= max {
+            return Err(fdo::Error::LimitsExceeded(format!(
+                "Connection has {max} or more match rules"
+            )));
+        }
+        let max = limits.max_match_rules;
+        if !self.peers.add_match_rule(max) {
+            return Err(fdo::Error::LimitsExceeded(format!(
+                "Bus has {max} or more match rules"
+            )));
+        }
+
         self.match_rules.insert(rule);
+        self.peak_match_rules = self.peak_match_rules.max(self.match_rules.len());
+
+        Ok(())
     }
 
     /// Removes the first rule that matches.
-    fn remove_match(&mut self, rule: OwnedMatchRule) -> fdo::Result<()> {
+    fn remove_match(&mut self, rule: &str) -> fdo::Result<()> {
+        let rule = match_rule::parse(rule)?;
         if !self.match_rules.remove(&rule) {
             return Err(fdo::Error::MatchRuleNotFound(
                 "No such match rule".to_string(),
             ));
         }
+        self.peers.remove_match_rules(1);
 
         Ok(())
     }
+
+    /// Tries to launch the executable associated with a name (service activation).
+    ///
+    /// No flags are currently defined so `flags` must be 0.
+    fn start_service_by_name(
+        &self,
+        name: OwnedWellKnownName,
+        flags: u32,
+    ) -> fdo::Result<StartServiceReply> {
+        if flags != 0 {
+            return Err(fdo::Error::InvalidArgs(format!(
+                "Unsupported flags: {flags:#x}"
+            )));
+        }
+
+        match self.name_registry.lookup(name.clone().into()) {
+            Some(_) => Ok(StartServiceReply::AlreadyRunning),
+            // TODO: Reply with `Success` once we support service activation.
+            None => Err(fdo::Error::ServiceUnknown(format!(
+                "The name `{name}` was not provided by any .service files"
+            ))),
+        }
+    }
+
+    /// Returns as many credentials as possible for the process connected to the server.
+    async fn get_connection_credentials(
+        &self,
+        name: OwnedBusName,
+    ) -> fdo::Result<HashMap<String, OwnedValue>> {
+        let credentials = self.credentials_of(name).await?;
+
+        let mut dict = HashMap::new();
+        if let Some(uid) = credentials.uid() {
+            dict.insert("UnixUserID".to_string(), Value::from(uid).into());
+        }
+        if !credentials.groups().is_empty() {
+            dict.insert(
+                "UnixGroupIDs".to_string(),
+                Value::from(credentials.groups().to_vec()).into(),
+            );
+        }
+        if let Some(pid) = credentials.pid() {
+            dict.insert("ProcessID".to_string(), Value::from(pid).into());
+        }
+        if let Some(label) = credentials.security_label() {
+            dict.insert(
+                "LinuxSecurityLabel".to_string(),
+                Value::from(label.to_vec()).into(),
+            );
+        }
+
+        Ok(dict)
+    }
+
+    /// Returns the Unix user ID of the process connected to the server.
+    async fn get_connection_unix_user(&self, name: OwnedBusName) -> fdo::Result<u32> {
+        let credentials = self.credentials_of(name.clone()).await?;
+
+        credentials
+            .uid()
+            .ok_or_else(|| fdo::Error::Failed(format!("Could not determine the user of `{name}`")))
+    }
+
+    /// Returns the Unix process ID of the process connected to the server.
+    ///
+    /// That's the process that connected, as captured when the connection was accepted, even if
+    /// it has exited or handed the connection over to another process since.
+    async fn get_connection_unix_process_id(&self, name: OwnedBusName) -> fdo::Result<u32> {
+        let credentials = self.credentials_of(name.clone()).await?;
+
+        credentials.pid().ok_or_else(|| {
+            fdo::Error::UnixProcessIdUnknown(format!("Could not determine the process of `{name}`"))
+        })
+    }
+
+    /// Returns the unique ID of the bus.
+    fn get_id(&self) -> String {
+        self.guid.as_str().to_string()
+    }
+
+    /// Optional features supported by this bus.
+    ///
+    /// Only features that are actually compiled in and enabled are advertised, so that clients
+    /// can fall back gracefully (e.g. to eavesdropping match rules in the absence of
+    /// `Monitoring`).
+    #[dbus_interface(property)]
+    fn features(&self) -> Vec<String> {
+        FEATURES.iter().map(|f| f.to_string()).collect()
+    }
+
+    /// Extra interfaces, besides `org.freedesktop.DBus`, supported by the bus object.
+    #[dbus_interface(property)]
+    fn interfaces(&self) -> Vec<String> {
+        INTERFACES.iter().map(|i| i.to_string()).collect()
+    }
 }
+
+/// The features we advertise through the `Features` property.
+const FEATURES: &[&str] = &["Monitoring"];
+
+/// The extra interfaces we advertise through the `Interfaces` property.
+const INTERFACES: &[&str] = &[
+    "org.freedesktop.DBus.Monitoring",
+    "org.freedesktop.DBus.Debug.Stats",
+    "org.busd.Limits",
+];
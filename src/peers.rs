@@ -1,30 +1,532 @@
 use anyhow::{anyhow, Context, Result};
+use enumflags2::BitFlags;
 use futures_util::{stream::StreamExt, SinkExt};
-use std::{collections::BTreeMap, sync::Arc};
-use tokio::sync::RwLock;
-use tracing::warn;
+use parking_lot::Mutex;
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    select,
+    sync::{broadcast, mpsc, Notify, OwnedSemaphorePermit, RwLock, Semaphore},
+    time::{interval_at, sleep},
+};
+use tracing::{debug, info, info_span, warn, Instrument};
 use zbus::{
-    names::{BusName, OwnedUniqueName, UniqueName},
-    MessageField, MessageFieldCode, MessageStream, MessageType,
+    fdo::{self, RequestNameFlags, RequestNameReply},
+    names::{BusName, OwnedBusName, OwnedUniqueName, OwnedWellKnownName, UniqueName},
+    zvariant::Signature,
+    AuthMechanism, DBusError, MessageBuilder, MessageField, MessageFieldCode, MessageFields,
+    MessageFlags, MessageStream, MessageType, OwnedMatchRule,
+};
+
+// They used to be defined here.
+pub use crate::protocol::{
+    MAX_HEADER_FIELDS, MAX_HEADER_FIELDS_LENGTH, MAX_NAME_LENGTH, MAX_PATH_LENGTH,
 };
 
-use crate::{name_registry::NameRegistry, peer::Peer};
+use crate::{
+    credentials::{Credentials, GroupsCache},
+    event::{BusEvent, BusSignals},
+    limits::SharedLimits,
+    match_rule,
+    message_log::MessageLog,
+    monitoring::OverflowPolicy,
+    name_registry::NameRegistry,
+    peer::Peer,
+    protocol::{serial, validate, ProtocolError},
+    rate_limiter::{RateLimit, RateLimiter, WindowedCount},
+    stats::{micros, BusStats},
+};
+
+/// The interface of the signals the bus emits as it starts and shuts down, if enabled through
+/// [`BusBuilder::lifecycle_signals`](crate::bus_builder::BusBuilder::lifecycle_signals).
+pub const LIFECYCLE_INTERFACE: &str = "org.busd.Lifecycle";
+
+// How often the per-destination rate limiters of a peer are looked through for those to drop.
+const RATE_LIMITERS_PRUNE_INTERVAL: Duration = Duration::from_secs(30);
 
 #[derive(Clone, Debug)]
 pub struct Peers {
-    peers: Arc<RwLock<BTreeMap<OwnedUniqueName, Peer>>>,
+    // Shared, so that sending to peers doesn't need the lock held.
+    peers: Arc<RwLock<BTreeMap<OwnedUniqueName, Arc<Peer>>>>,
+    // Not addressable like peers anymore, see `Peers::become_monitor`.
+    monitors: Arc<RwLock<BTreeMap<OwnedUniqueName, Monitor>>>,
     name_registry: NameRegistry,
+    message_log: Option<MessageLog>,
+    destination_rate_limit: Option<RateLimit>,
+    limits: Arc<SharedLimits>,
+    slow_routing_threshold: Option<Duration>,
+    events: broadcast::Sender<BusEvent>,
+    // Shared with the name registry, for the signals of the bus.
+    bus_signals: BusSignals,
+    counters: Arc<Counters>,
+    started_at: Instant,
+    connection_slots: Option<Arc<Semaphore>>,
+    pending_replies: Arc<Mutex<PendingReplies>>,
+    groups_cache: GroupsCache,
+    bus_credentials: Credentials,
+}
+
+/// A snapshot of a connected peer.
+///
+/// See [`Bus::peers`](crate::bus::Bus::peers).
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PeerInfo {
+    /// The ID assigned as the connection was accepted, see [`Peer::id`].
+    pub id: usize,
+    /// The unique name of the peer.
+    pub unique_name: OwnedUniqueName,
+    /// The remote address of peers connected over TCP, see
+    /// [`Credentials::remote_address`].
+    pub remote_address: Option<SocketAddr>,
+    /// How long the peer has been connected, counting from when its connection was accepted.
+    pub uptime: Duration,
+    /// If the peer became a monitor, which can't be addressed anymore.
+    pub is_monitor: bool,
+}
+
+/// A snapshot of the statistics of a connected peer, or of the monitor it became.
+///
+/// See [`Peers::connection_stats`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ConnectionStats {
+    /// The ID assigned as the connection was accepted, see [`Peer::id`].
+    pub id: usize,
+    /// The current number of match rules of the peer.
+    pub match_rules: usize,
+    /// The highest number of match rules the peer had at once.
+    pub peak_match_rules: usize,
+    /// How long the peer took to authenticate, see [`Peer::latencies`].
+    pub auth_latency: Duration,
+    /// How long the peer took to call `Hello`, if it did, see [`Peer::latencies`].
+    pub hello_latency: Option<Duration>,
+    /// How long the peer has been connected, counting from when its connection was accepted.
+    pub uptime: Duration,
+    /// If the peer became a monitor.
+    pub is_monitor: bool,
+}
+
+/// A peer turned into a monitor, along with the rules of the messages it gets a copy of.
+#[derive(Debug)]
+struct Monitor {
+    peer: Arc<Peer>,
+    // Empty for all messages.
+    rules: Vec<OwnedMatchRule>,
+    // Of the messages it doesn't get a copy of, whatever `rules`.
+    excluded: Vec<OwnedMatchRule>,
+    // Of the call that made the peer a monitor, until it's read from the peer.
+    become_monitor_serial: Option<u32>,
+    // Of the copies waiting to be sent to it.
+    queue: Arc<MonitorQueue>,
+}
+
+impl Monitor {
+    fn interested(&self, msg: &zbus::Message, name_registry: &NameRegistry) -> bool {
+        let matches = |rule: &OwnedMatchRule| match_rule::matches(rule, msg, name_registry);
+
+        !self.excluded.iter().any(matches)
+            && (self.rules.is_empty() || self.rules.iter().any(matches))
+    }
+}
+
+/// The copies waiting to be sent to a monitor, so that a monitor slow to read them can't hold up
+/// routing like other peers do.
+#[derive(Debug)]
+struct MonitorQueue {
+    // `None` once closed, for the monitor to be removed.
+    msgs: Mutex<Option<VecDeque<Arc<zbus::Message>>>>,
+    queued: Notify,
+    max: usize,
+    // Chosen by the monitor itself.
+    overflow: OverflowPolicy,
+}
+
+impl MonitorQueue {
+    fn new(max: usize, overflow: OverflowPolicy) -> Self {
+        Self {
+            msgs: Mutex::new(Some(VecDeque::new())),
+            queued: Notify::new(),
+            max,
+            overflow,
+        }
+    }
+
+    /// Queue `msg`, unless the queue is closed.
+    ///
+    /// Returns `true` if the queue was full and the monitor is to be disconnected, closing it.
+    fn push(&self, msg: Arc<zbus::Message>) -> bool {
+        let mut lock = self.msgs.lock();
+        let msgs = match &mut *lock {
+            Some(msgs) => msgs,
+            None => return false,
+        };
+        if msgs.len() >= self.max {
+            match self.overflow {
+                OverflowPolicy::Disconnect => {
+                    *lock = None;
+                    drop(lock);
+                    self.queued.notify_one();
+
+                    return true;
+                }
+                OverflowPolicy::DropOldest => {
+                    msgs.pop_front();
+                }
+            }
+        }
+        msgs.push_back(msg);
+        drop(lock);
+        self.queued.notify_one();
+
+        false
+    }
+
+    /// The oldest copy, waiting for one if need be, or `None` once the queue is closed.
+    async fn pop(&self) -> Option<Arc<zbus::Message>> {
+        loop {
+            let msg = self.msgs.lock().as_mut()?.pop_front();
+            if msg.is_some() {
+                return msg;
+            }
+            self.queued.notified().await;
+        }
+    }
+
+    fn close(&self) {
+        *self.msgs.lock() = None;
+        self.queued.notify_one();
+    }
+}
+
+/// A method call awaiting a reply, mapped to the peer the call was routed to.
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+struct PendingReply {
+    caller: OwnedUniqueName,
+    serial: u32,
+}
+
+/// Where a [`PendingReply`] was routed to, and until when a reply is expected.
+#[derive(Debug)]
+struct Callee {
+    unique_name: OwnedUniqueName,
+    // `None` if the timeout is too long to be represented, i.e. never.
+    deadline: Option<Instant>,
+}
+
+impl Callee {
+    fn timed_out(&self, now: Instant) -> bool {
+        self.deadline.map_or(false, |deadline| deadline <= now)
+    }
+}
+
+/// What a reply is to, as far as [`PendingReplies`] knows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ReplyStatus {
+    /// To a pending call, from the peer it was routed to.
+    Expected,
+    /// To a call that timed out, or whose caller is gone. Not the fault of the peer replying.
+    Stale,
+    /// To no call routed to the peer replying.
+    Unexpected,
+}
+
+/// The method calls awaiting a reply, each mapped to the peer it was routed to.
+///
+/// Calls that timed out are only dropped once their caller reaches the limit, or when a late
+/// reply arrives, rather than from a timer. No `org.freedesktop.DBus.Error.NoReply` error is sent
+/// for them, as their callers have their own timeouts, and have given up on them by then.
+#[derive(Debug, Default)]
+struct PendingReplies {
+    calls: HashMap<PendingReply, Callee>,
+    // The number of calls of each caller, against `Limits::max_replies_per_connection`.
+    per_caller: HashMap<OwnedUniqueName, usize>,
+    // The serials and callees of the calls of each caller dropped for timing out, oldest first,
+    // so that late replies to them aren't mistaken for unexpected ones. No more than the limit
+    // of pending calls is kept for each caller.
+    timed_out: HashMap<OwnedUniqueName, VecDeque<(u32, OwnedUniqueName)>>,
+}
+
+impl PendingReplies {
+    /// Add `call`, routed to `callee` and timing out after `timeout`, unless its caller has
+    /// `max` calls pending already.
+    fn insert(
+        &mut self,
+        call: PendingReply,
+        callee: OwnedUniqueName,
+        timeout: Duration,
+        max: usize,
+    ) -> bool {
+        let now = Instant::now();
+        // A reused serial is still the same number of calls.
+        if !self.calls.contains_key(&call) && self.count(&call.caller) >= max {
+            self.drop_timed_out(&call.caller, now, max);
+            if self.count(&call.caller) >= max {
+                return false;
+            }
+        }
+        let caller = call.caller.clone();
+        let callee = Callee {
+            unique_name: callee,
+            deadline: now.checked_add(timeout),
+        };
+        if self.calls.insert(call, callee).is_none() {
+            *self.per_caller.entry(caller).or_default() += 1;
+        }
+
+        true
+    }
+
+    /// Remove `call` if it was routed to `callee`, returning what a reply to it from `callee` is.
+    ///
+    /// Replies to calls of callers that are gone are [`ReplyStatus::Unexpected`] here, since their
+    /// calls are all removed already.
+    fn take(&mut self, call: &PendingReply, callee: &OwnedUniqueName) -> ReplyStatus {
+        match self.calls.get(call) {
+            Some(pending) if pending.unique_name == *callee => {
+                let timed_out = pending.timed_out(Instant::now());
+                self.remove(call);
+
+                if timed_out {
+                    ReplyStatus::Stale
+                } else {
+                    ReplyStatus::Expected
+                }
+            }
+            Some(_) => ReplyStatus::Unexpected,
+            None => {
+                let timed_out = match self.timed_out.get_mut(&call.caller) {
+                    Some(timed_out) => timed_out,
+                    None => return ReplyStatus::Unexpected,
+                };
+                let pos = timed_out.iter().position(|(serial, unique_name)| {
+                    *serial == call.serial && unique_name == callee
+                });
+                match pos {
+                    Some(pos) => {
+                        timed_out.remove(pos);
+                        if timed_out.is_empty() {
+                            self.timed_out.remove(&call.caller);
+                        }
+
+                        ReplyStatus::Stale
+                    }
+                    None => ReplyStatus::Unexpected,
+                }
+            }
+        }
+    }
+
+    fn count(&self, caller: &OwnedUniqueName) -> usize {
+        self.per_caller.get(caller).copied().unwrap_or_default()
+    }
+
+    fn drop_timed_out(&mut self, caller: &OwnedUniqueName, now: Instant, max: usize) {
+        let per_caller = &mut self.per_caller;
+        let mut dropped = vec![];
+        self.calls.retain(|call, callee| {
+            if call.caller != *caller || !callee.timed_out(now) {
+                return true;
+            }
+            decrement(per_caller, &call.caller);
+            dropped.push((call.serial, callee.unique_name.clone()));
+
+            false
+        });
+        if dropped.is_empty() {
+            return;
+        }
+        let timed_out = self.timed_out.entry(caller.clone()).or_default();
+        timed_out.extend(dropped);
+        while timed_out.len() > max {
+            timed_out.pop_front();
+        }
+    }
+
+    fn remove(&mut self, call: &PendingReply) {
+        if self.calls.remove(call).is_some() {
+            decrement(&mut self.per_caller, &call.caller);
+        }
+    }
+
+    /// Remove all the calls from and to `unique_name`.
+    fn remove_peer(&mut self, unique_name: &str) {
+        let per_caller = &mut self.per_caller;
+        per_caller.remove(unique_name);
+        self.timed_out.remove(unique_name);
+        self.calls.retain(|call, callee| {
+            if call.caller.as_str() == unique_name {
+                return false;
+            }
+            if callee.unique_name.as_str() != unique_name {
+                return true;
+            }
+            decrement(per_caller, &call.caller);
+
+            false
+        });
+    }
+}
+
+fn decrement(per_caller: &mut HashMap<OwnedUniqueName, usize>, caller: &OwnedUniqueName) {
+    if let Some(count) = per_caller.get_mut(caller) {
+        *count -= 1;
+        if *count == 0 {
+            per_caller.remove(caller);
+        }
+    }
+}
+
+/// Cumulative statistics, updated as we go.
+#[derive(Debug, Default)]
+struct Counters {
+    peak_connections: AtomicUsize,
+    messages_routed: AtomicU64,
+    bytes_routed: AtomicU64,
+    self_sends: AtomicU64,
+    // Of all peers, against the global limit.
+    match_rules: AtomicUsize,
+    connections_greeted: AtomicU64,
+    // In microseconds.
+    total_hello_latency: AtomicU64,
+    max_hello_latency: AtomicU64,
+    // Sends to peers that haven't completed yet, e.g. because the peer isn't reading.
+    pending_sends: AtomicUsize,
+    // Peers not read from until the routing backlog drains, woken up as it changes.
+    paused_peers: AtomicUsize,
+    backlog_changed: Notify,
+    // So that routing doesn't have to look for monitors if there are none.
+    monitors: AtomicUsize,
+}
+
+/// Counts a send to a peer as pending, for the bus and for the peer, for as long as it's alive.
+struct PendingSend<'a> {
+    counters: &'a Counters,
+    to_peer: Arc<AtomicUsize>,
+}
+
+impl<'a> PendingSend<'a> {
+    fn new(counters: &'a Counters, to_peer: &Arc<AtomicUsize>) -> Self {
+        counters.pending_sends.fetch_add(1, Ordering::SeqCst);
+        to_peer.fetch_add(1, Ordering::SeqCst);
+        // The peer might be paused, while we now wait on it.
+        counters.backlog_changed();
+
+        Self {
+            counters,
+            to_peer: to_peer.clone(),
+        }
+    }
+}
+
+impl Drop for PendingSend<'_> {
+    fn drop(&mut self) {
+        self.counters.pending_sends.fetch_sub(1, Ordering::SeqCst);
+        self.to_peer.fetch_sub(1, Ordering::SeqCst);
+        self.counters.backlog_changed();
+    }
+}
+
+impl Counters {
+    /// Wake up the paused peers, if any, to check the backlog again.
+    fn backlog_changed(&self) {
+        // Only paying for the notification when someone waits for it.
+        if self.paused_peers.load(Ordering::SeqCst) > 0 {
+            self.backlog_changed.notify_waiters();
+        }
+    }
 }
 
 impl Peers {
-    pub fn new(name_registry: NameRegistry) -> Self {
+    pub fn new(
+        name_registry: NameRegistry,
+        message_log: Option<MessageLog>,
+        destination_rate_limit: Option<RateLimit>,
+        limits: Arc<SharedLimits>,
+        max_connections: Option<usize>,
+        slow_routing_threshold: Option<Duration>,
+        events: broadcast::Sender<BusEvent>,
+    ) -> Self {
         Self {
             peers: Arc::new(RwLock::new(BTreeMap::new())),
+            monitors: Arc::new(RwLock::new(BTreeMap::new())),
+            bus_signals: name_registry.bus_signals().clone(),
             name_registry,
+            message_log,
+            destination_rate_limit,
+            limits,
+            slow_routing_threshold,
+            events,
+            counters: Arc::default(),
+            started_at: Instant::now(),
+            connection_slots: max_connections.map(|max| Arc::new(Semaphore::new(max))),
+            pending_replies: Arc::default(),
+            groups_cache: GroupsCache::default(),
+            // Only resolved once, as the bus starts and before any peer could be held up by it,
+            // since looking up the groups of its user can block.
+            bus_credentials: Credentials::of_bus(),
+        }
+    }
+
+    /// The credentials of the bus process itself, as of when the bus started.
+    pub fn bus_credentials(&self) -> &Credentials {
+        &self.bus_credentials
+    }
+
+    pub fn name_registry(&self) -> &NameRegistry {
+        &self.name_registry
+    }
+
+    /// Request `name` on behalf of the connected peer `unique_name`.
+    ///
+    /// Fails if the peer disconnected in the meantime, rather than granting it a name no one would
+    /// release anymore.
+    pub async fn request_name(
+        &self,
+        name: OwnedWellKnownName,
+        unique_name: OwnedUniqueName,
+        flags: BitFlags<RequestNameFlags>,
+    ) -> fdo::Result<RequestNameReply> {
+        // Held throughout, since peers release their names as they're removed.
+        let peers = self.peers.read().await;
+        if !peers.contains_key(&unique_name) {
+            return Err(fdo::Error::Failed(format!(
+                "`{unique_name}` is disconnected"
+            )));
+        }
+
+        self.name_registry.request_name(name, unique_name, flags)
+    }
+
+    /// The limits imposed on peers.
+    pub fn limits(&self) -> &SharedLimits {
+        &self.limits
+    }
+
+    /// Wait until there is room for another peer, if the number of peers is limited.
+    ///
+    /// The slot is taken by the peer passed to [`Peers::add`] along with it, until it's removed.
+    pub async fn reserve_slot(&self) -> Result<Option<OwnedSemaphorePermit>> {
+        let slots = match &self.connection_slots {
+            Some(slots) => slots.clone(),
+            None => return Ok(None),
+        };
+        if let Ok(slot) = slots.clone().try_acquire_owned() {
+            return Ok(Some(slot));
         }
+
+        info!("Maximum number of connections reached, waiting for a peer to disconnect..");
+        slots.acquire_owned().await.map(Some).map_err(Into::into)
     }
 
-    pub async fn add(&self, peer: Peer) {
+    pub async fn add(&self, peer: Peer, slot: Option<OwnedSemaphorePermit>) {
         let unique_name = peer.unique_name().clone();
         let mut peers = self.peers.write().await;
         match peers.get(&unique_name) {
@@ -34,57 +536,1199 @@ impl Peers {
             ),
             None => {
                 let peer_stream = peer.stream();
-                tokio::spawn(self.clone().serve_peer(peer_stream));
-                peers.insert(unique_name, peer);
+                let this = self.clone();
+                let credentials = matches!(peer.auth_mechanism(), AuthMechanism::External)
+                    .then(|| peer.credentials().clone());
+                let name = unique_name.clone();
+                let can_pass_unix_fd = peer.can_pass_unix_fd();
+                let pending_sends = peer.pending_sends().clone();
+                tokio::spawn(
+                    async move {
+                        // Policy rules on groups need the supplementary groups of the peer's user.
+                        // Resolving them can take NSS lookups, so it's not up to the accept loop.
+                        if let Some(credentials) = credentials {
+                            if let Err(e) = this.groups_cache.resolve(&credentials).await {
+                                warn!("Failed to resolve groups of the peer: {}", e);
+                            }
+                        }
+
+                        this.serve_peer(peer_stream, name, can_pass_unix_fd, pending_sends, slot)
+                            .await
+                    }
+                    .instrument(info_span!("peer", id = peer.id())),
+                );
+                peers.insert(unique_name.clone(), Arc::new(peer));
+                self.counters
+                    .peak_connections
+                    .fetch_max(peers.len(), Ordering::Relaxed);
+                self.send_event(BusEvent::PeerConnected(unique_name));
             }
         }
     }
 
-    async fn serve_peer(self, mut peer_stream: MessageStream) -> Result<()> {
-        while let Some(msg) = peer_stream.next().await {
-            match msg {
-                Ok(msg) => match msg.message_type() {
-                    MessageType::MethodCall
-                    | MessageType::MethodReturn
-                    | MessageType::Error
-                    | MessageType::Signal => {
-                        let fields = match msg.fields() {
-                            Ok(fields) => fields,
-                            Err(e) => {
-                                warn!("failed to parse message: {}", e);
-                                continue;
-                            }
-                        };
-                        match fields.get_field(MessageFieldCode::Destination) {
-                            Some(MessageField::Destination(dest)) => {
-                                if let Err(e) = self.send_msg(msg.clone(), dest.clone()).await {
-                                    warn!("{}", e);
-                                }
-                            }
-                            Some(_) => {
-                                warn!("failed to parse message: Missing destination");
-                            }
-                            None => {
-                                if msg.message_type() == MessageType::Signal {
-                                    // FIXME: should be based on match rules.
-                                    self.broadcast_msg(msg).await;
-                                } else {
-                                    warn!("missing destination field");
-                                }
-                            }
-                        };
+    /// Remove the peer with the given unique name, releasing all the names it owns.
+    ///
+    /// Match rules of the peer go away with it and its connection is closed. Monitors are removed
+    /// the same way.
+    pub async fn remove(&self, unique_name: UniqueName<'_>) {
+        let removed = {
+            let mut peers = self.peers.write().await;
+            match peers.remove(unique_name.as_str()) {
+                Some(peer) => {
+                    // While the peer can't be found anymore, so that a `RequestName` call of it
+                    // still being handled can't leave it owning a name. See
+                    // `Peers::request_name`.
+                    self.name_registry.release_all(unique_name.clone());
+
+                    Some((peer, false))
+                }
+                None => self
+                    .monitors
+                    .write()
+                    .await
+                    .remove(unique_name.as_str())
+                    .map(|monitor| {
+                        monitor.queue.close();
+
+                        (monitor.peer, true)
+                    }),
+            }
+        };
+        let (peer, was_monitor) = match removed {
+            Some(removed) => removed,
+            None => return,
+        };
+        if was_monitor {
+            self.counters.monitors.fetch_sub(1, Ordering::SeqCst);
+        }
+        // Flush whatever is still queued for the peer and shut the socket down in an orderly
+        // fashion, so it sees a clean EOF (and its client library can emit `Disconnected`)
+        // rather than a reset.
+        if let Err(e) = peer.conn().clone().close().await {
+            debug!("Failed to close connection to `{}`: {}", unique_name, e);
+        }
+        // No replies are coming from or going to the peer anymore.
+        self.pending_replies
+            .lock()
+            .remove_peer(unique_name.as_str());
+        debug!("Peer `{}` removed.", unique_name);
+        // Monitors were already gone, as far as peers are concerned.
+        if !was_monitor {
+            self.send_event(BusEvent::PeerDisconnected(unique_name.into()));
+        }
+    }
+
+    /// Turn peer `unique_name` into a monitor of the messages matching any of `rules`, or of all
+    /// messages if there are none, but for those matching any of `excluded`.
+    ///
+    /// The peer releases all its names and isn't addressable anymore, so it only gets the copies
+    /// and never receives a message twice. It's told it lost its unique name, and everyone else
+    /// is told the name is gone, as if it disconnected. Other than the `BecomeMonitor` call with
+    /// the given `serial`, any message it sends from now on gets it disconnected.
+    ///
+    /// If `replay_name_owners` is set, it's then sent a `NameAcquired` signal for each name owned,
+    /// addressed to its owner, as of it becoming a monitor. The copies are queued for it from
+    /// then on, with `overflow` applying once it falls behind.
+    pub async fn become_monitor(
+        &self,
+        unique_name: &OwnedUniqueName,
+        rules: Vec<OwnedMatchRule>,
+        excluded: Vec<OwnedMatchRule>,
+        serial: Option<u32>,
+        replay_name_owners: bool,
+        overflow: OverflowPolicy,
+    ) -> fdo::Result<()> {
+        let (peer, queue, owners) = {
+            let mut peers = self.peers.write().await;
+            let peer = peers
+                .remove(unique_name)
+                .ok_or_else(|| fdo::Error::Failed(format!("`{unique_name}` is disconnected")))?;
+            // Like in `Peers::remove`, while the peer can't be found anymore.
+            self.name_registry.release_all((&**unique_name).into());
+            // With the lock held, so that none is missed or already gone.
+            let owners = if replay_name_owners {
+                self.name_owners(peers.keys())
+            } else {
+                vec![]
+            };
+            let queue = Arc::new(MonitorQueue::new(self.limits.max_monitor_queue(), overflow));
+            let monitor = Monitor {
+                peer: peer.clone(),
+                rules,
+                excluded,
+                become_monitor_serial: serial,
+                queue: queue.clone(),
+            };
+            self.monitors
+                .write()
+                .await
+                .insert(unique_name.clone(), monitor);
+            self.counters.monitors.fetch_add(1, Ordering::SeqCst);
+
+            (peer, queue, owners)
+        };
+        let conn = peer.conn();
+        // No replies are coming from or going to the monitor anymore.
+        self.pending_replies
+            .lock()
+            .remove_peer(unique_name.as_str());
+        info!("Peer `{}` became a monitor.", unique_name);
+
+        let res = match bus_signal(Some(unique_name), "NameLost", &(unique_name.as_str(),)) {
+            Ok(msg) => self.send_bus_msg(conn, msg).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            warn!("Failed to send `NameLost` to `{}`: {}", unique_name, e);
+        }
+        for (name, owner) in owners {
+            let res = match bus_signal(Some(&owner), "NameAcquired", &(name.as_str(),)) {
+                Ok(msg) => self.send_bus_msg(conn, msg).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = res {
+                warn!("Failed to send `NameAcquired` to `{}`: {}", unique_name, e);
+
+                break;
+            }
+        }
+        // Only now, so that the copies queued meanwhile come after the signals.
+        tokio::spawn(
+            self.clone()
+                .send_to_monitor(peer.clone(), queue)
+                .instrument(info_span!("monitor", id = peer.id())),
+        );
+        self.send_event(BusEvent::PeerDisconnected(unique_name.clone()));
+
+        Ok(())
+    }
+
+    /// Send the copies queued for the monitor `peer` as it reads them, until it's removed.
+    async fn send_to_monitor(self, peer: Arc<Peer>, queue: Arc<MonitorQueue>) {
+        while let Some(msg) = queue.pop().await {
+            let _pending = PendingSend::new(&self.counters, peer.pending_sends());
+            if let Err(e) = peer
+                .conn()
+                .send(msg)
+                .await
+                .context("failed to send message")
+            {
+                warn!(
+                    "Error sending message to monitor `{}`: {}",
+                    peer.unique_name(),
+                    e
+                );
+            }
+        }
+    }
+
+    /// If peer `unique_name` is a monitor, whether `msg` from it is the `BecomeMonitor` call that
+    /// made it one, the only message monitors can send.
+    ///
+    /// That call is handled by the object server of the peer, so it may only be read here once
+    /// the peer is a monitor already. It's only let through once.
+    async fn sent_by_monitor(
+        &self,
+        unique_name: &OwnedUniqueName,
+        msg: &zbus::Message,
+    ) -> Option<bool> {
+        if self.counters.monitors.load(Ordering::SeqCst) == 0
+            || !self.monitors.read().await.contains_key(unique_name)
+        {
+            return None;
+        }
+
+        let mut monitors = self.monitors.write().await;
+        let monitor = monitors.get_mut(unique_name)?;
+        let allowed = is_to_bus(msg) && monitor.become_monitor_serial == Some(serial(msg));
+        if allowed {
+            monitor.become_monitor_serial = None;
+        }
+
+        Some(allowed)
+    }
+
+    /// Queue a copy of the message `msg`, untouched, for all the monitors interested in it.
+    async fn copy_to_monitors(&self, msg: &Arc<zbus::Message>, has_fds: bool) {
+        if self.counters.monitors.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+
+        // Queueing doesn't wait on the monitors, so it's fine with the lock held.
+        let mut overflowed = vec![];
+        for (name, monitor) in self.monitors.read().await.iter() {
+            if (!has_fds || monitor.peer.can_pass_unix_fd())
+                && monitor.interested(msg, &self.name_registry)
+                && monitor.queue.push(msg.clone())
+            {
+                overflowed.push(name.clone());
+            }
+        }
+        for name in overflowed {
+            warn!(
+                "Disconnecting monitor `{}`: more than {} messages behind.",
+                name,
+                self.limits.max_monitor_queue()
+            );
+            // Closing the connection waits for the monitor to read what zbus still has for it.
+            let this = self.clone();
+            tokio::spawn(async move { this.remove((&*name).into()).await });
+        }
+    }
+
+    /// Route all the messages from peer `unique_name`, until it disconnects or gets disconnected.
+    ///
+    /// Messages are routed one at a time, in the order they were sent, and each has been handed to
+    /// its destination's connection before the next one is looked at. So messages from a peer
+    /// reach any given destination in order, as the specification requires, even while the
+    /// destination is slow to read them.
+    async fn serve_peer(
+        self,
+        mut peer_stream: MessageStream,
+        unique_name: OwnedUniqueName,
+        can_pass_unix_fd: bool,
+        // Of the sends to the peer.
+        pending_sends: Arc<AtomicUsize>,
+        // Only freed once we're done with the peer.
+        _slot: Option<OwnedSemaphorePermit>,
+    ) -> Result<()> {
+        // Rate limiters for method calls from this peer, keyed by the destination.
+        let mut rate_limiters = HashMap::new();
+        let mut sender_rate_limiter = self.limits.sender_rate_limit().map(RateLimiter::new);
+        let mut violations = WindowedCount::new(self.limits.protocol_violation_window());
+        // Since when the peer has been held back by its rate limit, without a break.
+        let mut throttled_since = None;
+        // We're only called once the peer is authenticated.
+        let hello_deadline = sleep(self.limits.hello_timeout());
+        tokio::pin!(hello_deadline);
+        let mut hello_checked = false;
+        let mut prune_rate_limiters = interval_at(
+            tokio::time::Instant::now() + RATE_LIMITERS_PRUNE_INTERVAL,
+            RATE_LIMITERS_PRUNE_INTERVAL,
+        );
+
+        // Framing is taken care of by zbus: partial reads are buffered until a full message is
+        // available and `WouldBlock` just suspends us, so an idle peer costs nothing. The stream
+        // ends on a clean EOF at a message boundary, while an EOF in the middle of a message is
+        // an I/O error. Either way, there's no way for us to spin on a dead socket.
+        loop {
+            self.wait_for_backlog(&unique_name, &pending_sends).await;
+            let msg = select! {
+                msg = peer_stream.next() => match msg {
+                    Some(msg) => msg,
+                    None => break,
+                },
+                _ = &mut hello_deadline, if !hello_checked => {
+                    hello_checked = true;
+                    // Half-initialized peers would otherwise hold on to their slot forever.
+                    if let Some((_, None)) = self.latencies((&*unique_name).into()).await {
+                        warn!(
+                            "Disconnecting `{}`: no `Hello` within {:?}.",
+                            unique_name,
+                            self.limits.hello_timeout()
+                        );
+
+                        break;
+                    }
+
+                    continue;
+                }
+                _ = prune_rate_limiters.tick(), if !rate_limiters.is_empty() => {
+                    self.prune_rate_limiters(&mut rate_limiters).await;
+
+                    continue;
+                }
+            };
+            // Not reading from the peer in the meantime pushes back on it.
+            let throttled = match &mut sender_rate_limiter {
+                Some(limiter) => limiter.acquire().await,
+                None => false,
+            };
+            if throttled {
+                debug!("Throttled `{}` for exceeding its rate limit.", unique_name);
+                let since = *throttled_since.get_or_insert_with(Instant::now);
+                if since.elapsed() >= self.limits.sender_throttle_timeout() {
+                    warn!(
+                        "Disconnecting `{}`: over its rate limit for {:?}.",
+                        unique_name,
+                        since.elapsed()
+                    );
+
+                    break;
+                }
+            } else {
+                throttled_since = None;
+            }
+            if let Ok(msg) = &msg {
+                match self.sent_by_monitor(&unique_name, msg).await {
+                    Some(true) => continue,
+                    Some(false) => {
+                        warn!(
+                            "Disconnecting monitor `{}`: it sent a message.",
+                            unique_name
+                        );
+
+                        break;
+                    }
+                    None => (),
+                }
+            }
+            let valid = match msg {
+                Ok(msg) => match self
+                    .route_msg(msg, &unique_name, can_pass_unix_fd, &mut rate_limiters)
+                    .await
+                {
+                    Ok(valid) => valid,
+                    Err(e) => {
+                        warn!("Disconnecting `{}`: {}", unique_name, e);
+
+                        break;
                     }
-                    MessageType::Invalid => todo!(),
                 },
+                // An I/O error (including EOF in the middle of a message) means the peer is gone.
+                Err(zbus::Error::InputOutput(e)) => {
+                    debug!("Peer `{}` disconnected: {}", unique_name, e);
+
+                    break;
+                }
                 Err(e) => {
                     warn!("Error: {:?}", e);
+
+                    false
+                }
+            };
+            if !valid {
+                let violations = violations.increment();
+                if violations >= self.limits.max_protocol_violations() {
+                    warn!(
+                        "Disconnecting `{}` after {} protocol violations.",
+                        unique_name, violations
+                    );
+
+                    break;
                 }
             }
         }
+        // Ensure the stream doesn't keep the connection alive.
+        drop(peer_stream);
+        self.remove((&*unique_name).into()).await;
 
         Ok(())
     }
 
+    /// Wait until the routing backlog is within [`Limits::max_routing_backlog`], if limited,
+    /// before reading more from peer `unique_name`.
+    ///
+    /// Peers with sends to them pending aren't held up, since they could be blocked sending to us
+    /// in turn, which would never let the backlog drain.
+    ///
+    /// [`Limits::max_routing_backlog`]: crate::limits::Limits::max_routing_backlog
+    async fn wait_for_backlog(&self, unique_name: &OwnedUniqueName, pending_sends: &AtomicUsize) {
+        let max = match self.limits.max_routing_backlog() {
+            Some(max) => max,
+            None => return,
+        };
+        let within = || {
+            self.counters.pending_sends.load(Ordering::SeqCst) <= max
+                || pending_sends.load(Ordering::SeqCst) > 0
+        };
+        if within() {
+            return;
+        }
+
+        debug!("Routing backlogged, pausing reads from `{}`.", unique_name);
+        loop {
+            // Registered before checking, so that no change in between goes unnoticed.
+            let changed = self.counters.backlog_changed.notified();
+            self.counters.paused_peers.fetch_add(1, Ordering::SeqCst);
+            let resume = within();
+            if !resume {
+                changed.await;
+            }
+            self.counters.paused_peers.fetch_sub(1, Ordering::SeqCst);
+            if resume {
+                break;
+            }
+        }
+        debug!(
+            "Routing backlog drained, resuming reads from `{}`.",
+            unique_name
+        );
+    }
+
+    /// Route a message from peer `unique_name`.
+    ///
+    /// Returns `false` if the message was rejected for violating the protocol or a limit, and an
+    /// error if the violation is bad enough for the peer to be disconnected right away.
+    async fn route_msg(
+        &self,
+        msg: Arc<zbus::Message>,
+        unique_name: &OwnedUniqueName,
+        can_pass_unix_fd: bool,
+        rate_limiters: &mut HashMap<OwnedUniqueName, RateLimiter>,
+    ) -> Result<bool> {
+        let start = Instant::now();
+        let fields = match validate(&msg) {
+            Ok(fields) => fields,
+            Err(e) if e.is_fatal() => return Err(e.into()),
+            Err(e) => {
+                warn!("Invalid message from `{}`: {}", unique_name, e);
+                if e == ProtocolError::InvalidPath && msg.message_type() == MessageType::MethodCall
+                {
+                    let err = fdo::Error::InvalidArgs("Missing or invalid object path".to_string());
+                    self.reply_error(unique_name, &msg, err).await;
+                }
+
+                return Ok(false);
+            }
+        };
+        // Recipients and their match rules trust the sender field, e.g. to tell the signals of the
+        // bus itself, so peers can't claim to be anyone else. Most clients leave it to the bus to
+        // set, as the specification has it, and can't reply to messages without one.
+        let stamped = match fields.get_field(MessageFieldCode::Sender) {
+            Some(MessageField::Sender(sender)) if sender.as_str() != unique_name.as_str() => {
+                warn!(
+                    "Dropping message from `{}` claiming to be from `{}`",
+                    unique_name, sender
+                );
+
+                return Ok(false);
+            }
+            Some(_) => None,
+            None => match with_sender(&msg, unique_name) {
+                Ok(stamped) => Some(Arc::new(stamped)),
+                Err(e) => {
+                    warn!(
+                        "Failed to set the sender of a message from `{}`: {}",
+                        unique_name, e
+                    );
+
+                    return Ok(false);
+                }
+            },
+        };
+        // Not dropping the original, which `fields` borrows from and which owns the file
+        // descriptors of the stamped copy.
+        let msg = match stamped {
+            Some(stamped) => stamped,
+            None => msg.clone(),
+        };
+        let destination = match fields.get_field(MessageFieldCode::Destination) {
+            Some(MessageField::Destination(dest)) => Some(dest),
+            _ => None,
+        };
+        let num_fds = match fields.get_field(MessageFieldCode::UnixFDs) {
+            Some(MessageField::UnixFDs(num_fds)) => *num_fds as usize,
+            _ => 0,
+        };
+        let has_fds = num_fds > 0;
+        // Whenever a message is dropped, the file descriptors it came with are closed along with
+        // it, so none are left open in the bus.
+        let max_fds = self.limits.max_message_unix_fds();
+        if num_fds > max_fds {
+            debug!(
+                "Dropping message with {} Unix FDs from `{}`.",
+                num_fds, unique_name
+            );
+            let err = fdo::Error::LimitsExceeded(format!(
+                "Messages can't carry more than {max_fds} Unix FDs"
+            ));
+            self.deny(unique_name, &msg, has_fds, err).await;
+
+            return Ok(false);
+        }
+        if has_fds && !(can_pass_unix_fd && self.can_pass_unix_fd_to(destination).await) {
+            debug!("Dropping message with Unix FDs from `{}`.", unique_name);
+            let err =
+                fdo::Error::Failed("Unix fd passing not supported on this transport".to_string());
+            self.deny(unique_name, &msg, has_fds, err).await;
+
+            return Ok(false);
+        }
+        let fanout = match fields.get_field(MessageFieldCode::Destination) {
+            Some(MessageField::Destination(dest)) => {
+                if msg.message_type() == MessageType::MethodCall
+                    && !self.within_rate_limit(rate_limiters, dest)
+                {
+                    let err =
+                        fdo::Error::LimitsExceeded(format!("Too many method calls to `{}`", dest));
+                    self.deny(unique_name, &msg, has_fds, err).await;
+
+                    return Ok(false);
+                }
+                let pending_reply = match msg.message_type() {
+                    MessageType::MethodReturn | MessageType::Error => {
+                        match self.take_pending_reply(&fields, unique_name, dest).await {
+                            ReplyStatus::Expected => (),
+                            ReplyStatus::Stale => {
+                                debug!("Dropping stale reply from `{}` to `{}`", unique_name, dest);
+
+                                return Ok(true);
+                            }
+                            ReplyStatus::Unexpected => {
+                                warn!(
+                                    "Dropping unexpected reply from `{}` to `{}`",
+                                    unique_name, dest
+                                );
+
+                                return Ok(false);
+                            }
+                        }
+
+                        None
+                    }
+                    MessageType::MethodCall => {
+                        match self.add_pending_reply(&msg, unique_name, dest) {
+                            Ok(pending_reply) => pending_reply,
+                            Err(err) => {
+                                self.deny(unique_name, &msg, has_fds, err).await;
+
+                                return Ok(false);
+                            }
+                        }
+                    }
+                    _ => None,
+                };
+                // Monitors see messages before their recipients can act on them.
+                self.copy_to_monitors(&msg, has_fds).await;
+                // The message is forwarded untouched, so all its flags (e.g.
+                // `ALLOW_INTERACTIVE_AUTHORIZATION`) reach the destination intact.
+                match self.send_msg(msg.clone(), dest.clone()).await {
+                    Ok(()) => {
+                        self.log_msg(&msg);
+                        self.message_routed(&msg, unique_name, Some(dest));
+
+                        1
+                    }
+                    Err(e) => {
+                        warn!("{}", e);
+                        if let Some(pending_reply) = pending_reply {
+                            self.pending_replies.lock().remove(&pending_reply);
+                        }
+                        // Otherwise the caller would wait for a reply that's never coming. Calls
+                        // to the bus itself are replied to by its object server. Without service
+                        // activation, this is also what `NO_AUTO_START` calls get.
+                        if msg.message_type() == MessageType::MethodCall
+                            && dest.as_str() != "org.freedesktop.DBus"
+                            && !self.is_connected(dest).await
+                        {
+                            let err = fdo::Error::ServiceUnknown(format!(
+                                "The name `{}` is not owned by any connection",
+                                dest
+                            ));
+                            self.reply_error(unique_name, &msg, err).await;
+                        }
+
+                        0
+                    }
+                }
+            }
+            Some(_) => {
+                warn!("failed to parse message: Missing destination");
+
+                return Ok(false);
+            }
+            None => {
+                if msg.message_type() == MessageType::Signal {
+                    self.copy_to_monitors(&msg, has_fds).await;
+                    // FIXME: should be based on match rules.
+                    let fanout = self.broadcast_msg(msg.clone(), has_fds).await;
+                    if fanout > 0 {
+                        self.log_msg(&msg);
+                    }
+                    self.message_routed(&msg, unique_name, None);
+
+                    fanout
+                } else {
+                    warn!("missing destination field");
+
+                    return Ok(false);
+                }
+            }
+        };
+        if let Some(threshold) = self.slow_routing_threshold {
+            let elapsed = start.elapsed();
+            if elapsed > threshold {
+                warn!(
+                    "Routing `{}` from `{}` to {} peer(s) took {:?}",
+                    msg.member()
+                        .as_ref()
+                        .map(|m| m.as_str())
+                        .unwrap_or("<none>"),
+                    unique_name,
+                    fanout,
+                    elapsed,
+                );
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Emit the signals of the bus itself, as the name ownership changes they're about happen.
+    ///
+    /// `NameOwnerChanged` is broadcasted to all interested peers, while `NameLost` and
+    /// `NameAcquired` are sent to the old and new owner respectively. All have
+    /// `org.freedesktop.DBus` as the sender. `events` are from [`Peers::subscribe_bus_signals`],
+    /// so that none are skipped. Only returns once `events` is closed, i.e. subscribed to again.
+    pub(crate) async fn emit_bus_signals(&self, mut events: mpsc::UnboundedReceiver<BusEvent>) {
+        while let Some(event) = events.recv().await {
+            match event {
+                BusEvent::NameOwnerChanged {
+                    name,
+                    old_owner,
+                    new_owner,
+                } => {
+                    let name = name.as_str();
+                    self.name_owner_changed(name, old_owner.as_ref(), new_owner.as_ref())
+                        .await;
+                    if let Some(old_owner) = &old_owner {
+                        self.send_bus_signal(old_owner, "NameLost", name).await;
+                    }
+                    if let Some(new_owner) = &new_owner {
+                        self.send_bus_signal(new_owner, "NameAcquired", name).await;
+                    }
+                }
+                BusEvent::PeerConnected(name) => {
+                    self.name_owner_changed(name.as_str(), None, Some(&name))
+                        .await
+                }
+                BusEvent::PeerDisconnected(name) => {
+                    self.name_owner_changed(name.as_str(), Some(&name), None)
+                        .await
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Subscribe to the events the bus emits its signals about, for [`Peers::emit_bus_signals`].
+    pub(crate) fn subscribe_bus_signals(&self) -> mpsc::UnboundedReceiver<BusEvent> {
+        self.bus_signals.subscribe()
+    }
+
+    /// Send `event` to be signaled by the bus, and to the subscribers of bus events.
+    fn send_event(&self, event: BusEvent) {
+        self.bus_signals.send(event.clone());
+        // It's fine if there are no subscribers.
+        let _ = self.events.send(event);
+    }
+
+    /// The number of messages currently being sent to peers.
+    ///
+    /// Sends that take a while pile up once peers can't keep up, so this is a measure of how
+    /// congested the bus is.
+    pub fn pending_sends(&self) -> usize {
+        self.counters.pending_sends.load(Ordering::Relaxed)
+    }
+
+    /// The number of connected peers.
+    pub async fn count(&self) -> usize {
+        self.peers.read().await.len()
+    }
+
+    /// If a peer with the given unique name is connected.
+    pub async fn contains(&self, unique_name: UniqueName<'_>) -> bool {
+        self.peers.read().await.contains_key(unique_name.as_str())
+    }
+
+    /// The unique names of all connected peers.
+    pub async fn unique_names(&self) -> Vec<OwnedUniqueName> {
+        self.peers.read().await.keys().cloned().collect()
+    }
+
+    /// A snapshot of all connected peers, monitors included, ordered by ID.
+    pub async fn infos(&self) -> Vec<PeerInfo> {
+        let info = |peer: &Arc<Peer>, is_monitor| PeerInfo {
+            id: peer.id(),
+            unique_name: peer.unique_name().clone(),
+            remote_address: peer.credentials().remote_address(),
+            uptime: peer.uptime(),
+            is_monitor,
+        };
+        let peers = self.peers.read().await;
+        let monitors = self.monitors.read().await;
+        let mut infos: Vec<_> = peers
+            .values()
+            .map(|peer| info(peer, false))
+            .chain(monitors.values().map(|monitor| info(&monitor.peer, true)))
+            .collect();
+        infos.sort_unstable_by_key(|info| info.id);
+
+        infos
+    }
+
+    /// The statistics of the peer with the given unique name, or of the monitor it became, all
+    /// from a single lookup.
+    pub async fn connection_stats(&self, unique_name: UniqueName<'_>) -> Option<ConnectionStats> {
+        let peer = self.peers.read().await.get(unique_name.as_str()).cloned();
+        let (peer, is_monitor) = match peer {
+            Some(peer) => (peer, false),
+            None => {
+                let monitors = self.monitors.read().await;
+                (monitors.get(unique_name.as_str())?.peer.clone(), true)
+            }
+        };
+        let (match_rules, peak_match_rules) = peer.match_rule_counts().await;
+        let (auth_latency, hello_latency) = peer.latencies().await;
+
+        Some(ConnectionStats {
+            id: peer.id(),
+            match_rules,
+            peak_match_rules,
+            auth_latency,
+            hello_latency,
+            uptime: peer.uptime(),
+            is_monitor,
+        })
+    }
+
+    /// The credentials of the peer with the given unique name.
+    pub async fn credentials(&self, unique_name: UniqueName<'_>) -> Option<Credentials> {
+        self.peers
+            .read()
+            .await
+            .get(unique_name.as_str())
+            .map(|peer| peer.credentials().clone())
+    }
+
+    /// The time it took the peer with the given unique name, or the monitor it became, to
+    /// authenticate and to call `Hello`.
+    ///
+    /// See [`Peer::latencies`].
+    pub async fn latencies(
+        &self,
+        unique_name: UniqueName<'_>,
+    ) -> Option<(Duration, Option<Duration>)> {
+        if let Some(peer) = self.peers.read().await.get(unique_name.as_str()) {
+            return Some(peer.latencies().await);
+        }
+
+        match self.monitors.read().await.get(unique_name.as_str()) {
+            Some(monitor) => Some(monitor.peer.latencies().await),
+            None => None,
+        }
+    }
+
+    /// Record that a peer called `Hello`, `latency` after its connection was accepted.
+    pub fn greeted(&self, latency: Duration) {
+        let micros = micros(latency);
+        self.counters
+            .connections_greeted
+            .fetch_add(1, Ordering::Relaxed);
+        self.counters
+            .total_hello_latency
+            .fetch_add(micros, Ordering::Relaxed);
+        self.counters
+            .max_hello_latency
+            .fetch_max(micros, Ordering::Relaxed);
+    }
+
+    /// The match rules of all peers, along with the unique name of the peer owning each.
+    pub async fn all_match_rules(&self) -> Vec<(OwnedUniqueName, OwnedMatchRule)> {
+        let peers = self.peers.read().await;
+        let mut rules = vec![];
+        for (unique_name, peer) in peers.iter() {
+            for rule in peer.match_rules().await {
+                rules.push((unique_name.clone(), rule));
+            }
+        }
+
+        rules
+    }
+
+    /// Count a new match rule, unless the bus has `max` rules already.
+    ///
+    /// `max` is [`Limits::max_match_rules`], as the caller read it along with the other limits it
+    /// checks. Returns whether the rule was counted.
+    ///
+    /// [`Limits::max_match_rules`]: crate::limits::Limits::max_match_rules
+    pub fn add_match_rule(&self, max: usize) -> bool {
+        self.counters
+            .match_rules
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                (count < max).then(|| count + 1)
+            })
+            .is_ok()
+    }
+
+    /// Stop counting `count` match rules, previously counted by [`Peers::add_match_rule`].
+    pub fn remove_match_rules(&self, count: usize) {
+        self.counters
+            .match_rules
+            .fetch_sub(count, Ordering::Relaxed);
+    }
+
+    /// A snapshot of the statistics of the bus.
+    pub async fn stats(&self) -> BusStats {
+        let peers = self.peers.read().await;
+        let mut match_rules = 0;
+        for peer in peers.values() {
+            match_rules += peer.match_rule_counts().await.0;
+        }
+
+        let connections_greeted = self.counters.connections_greeted.load(Ordering::Relaxed);
+        let total_hello_latency = self.counters.total_hello_latency.load(Ordering::Relaxed);
+
+        BusStats {
+            connections: peers.len(),
+            monitors: self.counters.monitors.load(Ordering::Relaxed),
+            peak_connections: self.counters.peak_connections.load(Ordering::Relaxed),
+            names: self.name_registry.num_names(),
+            peak_names: self.name_registry.peak_num_names(),
+            match_rules,
+            messages_routed: self.counters.messages_routed.load(Ordering::Relaxed),
+            bytes_routed: self.counters.bytes_routed.load(Ordering::Relaxed),
+            self_sends: self.counters.self_sends.load(Ordering::Relaxed),
+            connections_greeted,
+            mean_hello_latency: Duration::from_micros(
+                total_hello_latency
+                    .checked_div(connections_greeted)
+                    .unwrap_or_default(),
+            ),
+            max_hello_latency: Duration::from_micros(
+                self.counters.max_hello_latency.load(Ordering::Relaxed),
+            ),
+            uptime: self.started_at.elapsed(),
+        }
+    }
+
+    fn message_routed(
+        &self,
+        msg: &zbus::Message,
+        sender: &OwnedUniqueName,
+        destination: Option<&BusName<'_>>,
+    ) {
+        self.counters
+            .messages_routed
+            .fetch_add(1, Ordering::Relaxed);
+        self.counters
+            .bytes_routed
+            .fetch_add(msg.as_bytes().len() as u64, Ordering::Relaxed);
+        // Legal and routed like any other message, since peers are read from and written to
+        // independently, but worth knowing about.
+        if destination.and_then(|dest| self.resolve(dest)).as_ref() == Some(sender) {
+            self.counters.self_sends.fetch_add(1, Ordering::Relaxed);
+        }
+
+        // Avoid the allocations if no one is listening.
+        if self.events.receiver_count() == 0 {
+            return;
+        }
+
+        let _ = self.events.send(BusEvent::MessageRouted {
+            sender: sender.clone(),
+            destination: destination.cloned().map(OwnedBusName::from),
+        });
+    }
+
+    /// Broadcast `NameOwnerChanged` for `name`, with empty strings standing in for missing owners.
+    async fn name_owner_changed(
+        &self,
+        name: &str,
+        old_owner: Option<&OwnedUniqueName>,
+        new_owner: Option<&OwnedUniqueName>,
+    ) {
+        let old_owner = old_owner.map(|o| o.as_str()).unwrap_or("");
+        let new_owner = new_owner.map(|o| o.as_str()).unwrap_or("");
+
+        self.broadcast_bus_signal("NameOwnerChanged", || {
+            bus_signal(None, "NameOwnerChanged", &(name, old_owner, new_owner))
+        })
+        .await
+    }
+
+    /// Broadcast the busd-specific `member` signal of the [`LIFECYCLE_INTERFACE`], about the bus
+    /// starting or shutting down.
+    pub async fn emit_lifecycle_signal(&self, member: &str) {
+        self.broadcast_bus_signal(member, || {
+            MessageBuilder::signal("/org/freedesktop/DBus", LIFECYCLE_INTERFACE, member)?
+                .sender("org.freedesktop.DBus")?
+                .build(&())
+        })
+        .await
+    }
+
+    /// Broadcast the signal `member` of the bus itself, as built by `signal`, to all interested
+    /// peers.
+    async fn broadcast_bus_signal<F>(&self, member: &str, signal: F)
+    where
+        F: Fn() -> zbus::Result<zbus::Message>,
+    {
+        for peer in self.snapshot().await {
+            // Each peer needs its own copy, since the serial is assigned on sending.
+            let msg = match signal() {
+                Ok(msg) => msg,
+                Err(e) => {
+                    warn!("Failed to create `{}` signal: {}", member, e);
+
+                    return;
+                }
+            };
+            if !peer.interested(&msg).await {
+                continue;
+            }
+
+            let _pending = PendingSend::new(&self.counters, peer.pending_sends());
+            if let Err(e) = self.send_bus_msg(peer.conn(), msg).await {
+                warn!("Error sending message: {}", e);
+            }
+        }
+    }
+
+    /// Send the bus signal `member`, with `name` as the only argument, to `destination`.
+    async fn send_bus_signal(&self, destination: &OwnedUniqueName, member: &str, name: &str) {
+        let conn = match self.peers.read().await.get(destination) {
+            Some(peer) => peer.conn().clone(),
+            // It's gone already.
+            None => return,
+        };
+        let res = match bus_signal(Some(destination), member, &(name,)) {
+            Ok(msg) => self.send_bus_msg(&conn, msg).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            warn!("Failed to send `{}` to `{}`: {}", member, destination, e);
+        }
+    }
+
+    /// Send `destination` a `NameOwnerChanged` signal for each name currently owned, as if they
+    /// had all just been acquired.
+    ///
+    /// The signals are sent regardless of the match rules of `destination`, which asked for them.
+    pub async fn send_name_owner_backlog(&self, destination: &OwnedUniqueName) {
+        let conn = match self.peers.read().await.get(destination) {
+            Some(peer) => peer.conn().clone(),
+            // It's gone already.
+            None => return,
+        };
+        let owners = self.name_owners(self.peers.read().await.keys());
+
+        for (name, owner) in owners {
+            let res = match bus_signal(
+                Some(destination),
+                "NameOwnerChanged",
+                &(name.as_str(), "", owner.as_str()),
+            ) {
+                Ok(msg) => self.send_bus_msg(&conn, msg).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = res {
+                warn!(
+                    "Failed to send `NameOwnerChanged` to `{}`: {}",
+                    destination, e
+                );
+
+                return;
+            }
+        }
+    }
+
+    /// All the names owned, the unique names of `peers` included, along with their owner.
+    fn name_owners<'a, I>(&self, peers: I) -> Vec<(String, OwnedUniqueName)>
+    where
+        I: Iterator<Item = &'a OwnedUniqueName>,
+    {
+        let mut owners: Vec<_> = peers.map(|name| (name.to_string(), name.clone())).collect();
+        owners.extend(
+            self.name_registry
+                .owners()
+                .into_iter()
+                .map(|(name, owner)| (name.to_string(), owner)),
+        );
+
+        owners
+    }
+
+    /// If the peer at `destination` can receive Unix file descriptors.
+    ///
+    /// A `None` destination means a broadcast, which is checked per-recipient.
+    async fn can_pass_unix_fd_to(&self, destination: Option<&BusName<'_>>) -> bool {
+        let destination = match destination {
+            Some(BusName::Unique(name)) => name.clone().into(),
+            Some(BusName::WellKnown(name)) => match self.name_registry.lookup(name.clone()) {
+                Some(name) => name,
+                None => return true,
+            },
+            None => return true,
+        };
+
+        self.peers
+            .read()
+            .await
+            .get(&destination)
+            .map(|peer| peer.can_pass_unix_fd())
+            .unwrap_or(true)
+    }
+
+    /// Check the rate limit for a method call to `destination`.
+    ///
+    /// Returns `false` if the limit has been exceeded.
+    fn within_rate_limit(
+        &self,
+        rate_limiters: &mut HashMap<OwnedUniqueName, RateLimiter>,
+        destination: &BusName<'_>,
+    ) -> bool {
+        let limit = match self.destination_rate_limit {
+            Some(limit) => limit,
+            None => return true,
+        };
+        let destination = match destination {
+            BusName::Unique(name) => name.clone().into(),
+            BusName::WellKnown(name) => match self.name_registry.lookup(name.clone()) {
+                Some(name) => name,
+                // Nowhere to send it to anyway.
+                None => return true,
+            },
+        };
+
+        rate_limiters
+            .entry(destination)
+            .or_insert_with(|| RateLimiter::new(limit))
+            .try_acquire()
+    }
+
+    /// Drop the rate limiters of destinations that disconnected, and of those not called for long
+    /// enough that their limiter is as good as new, so that they don't pile up over the lifetime
+    /// of the caller.
+    async fn prune_rate_limiters(&self, rate_limiters: &mut HashMap<OwnedUniqueName, RateLimiter>) {
+        let peers = self.peers.read().await;
+        rate_limiters
+            .retain(|destination, limiter| !limiter.is_idle() && peers.contains_key(destination));
+    }
+
+    /// Refuse to route the message `msg` from peer `unique_name`, replying with `err` if it's a
+    /// method call.
+    ///
+    /// Monitors still get a copy of the message, before the error reply, which they get a copy
+    /// of as well. So they see what was attempted, and that it was refused, in order.
+    async fn deny(
+        &self,
+        unique_name: &OwnedUniqueName,
+        msg: &Arc<zbus::Message>,
+        has_fds: bool,
+        err: fdo::Error,
+    ) {
+        self.copy_to_monitors(msg, has_fds).await;
+        if msg.message_type() == MessageType::MethodCall {
+            self.reply_error(unique_name, msg, err).await;
+        }
+    }
+
+    /// Reply to the method call `msg` from peer `unique_name` with an error.
+    ///
+    /// All errors the bus replies with while routing go through here, so that they all have a
+    /// canonical `org.freedesktop.DBus.Error` name, a message and the serial of `msg`. Monitors
+    /// get a copy of the reply. Nothing is sent if the peer asked for no reply.
+    async fn reply_error(
+        &self,
+        unique_name: &OwnedUniqueName,
+        msg: &zbus::Message,
+        err: fdo::Error,
+    ) {
+        if no_reply_expected(msg) {
+            return;
+        }
+        let conn = match self.peers.read().await.get(unique_name.as_str()) {
+            Some(peer) => peer.conn().clone(),
+            None => return,
+        };
+        let res = match msg.header().and_then(|hdr| err.create_reply(&hdr)) {
+            Ok(reply) => self.send_bus_msg(&conn, reply).await,
+            Err(e) => Err(e),
+        };
+        match res {
+            Ok(reply) => self.copy_to_monitors(&reply, false).await,
+            Err(e) => warn!("Failed to send error reply to `{}`: {}", unique_name, e),
+        }
+    }
+
+    /// Record that `caller` awaits a reply to the method call `msg`, from the current owner of
+    /// `destination`.
+    ///
+    /// This must happen before the call is sent, since the reply can arrive right after. Fails
+    /// if `caller` has [`Limits::max_replies_per_connection`] calls pending already.
+    ///
+    /// [`Limits::max_replies_per_connection`]: crate::limits::Limits::max_replies_per_connection
+    fn add_pending_reply(
+        &self,
+        msg: &zbus::Message,
+        caller: &OwnedUniqueName,
+        destination: &BusName<'_>,
+    ) -> fdo::Result<Option<PendingReply>> {
+        if no_reply_expected(msg) {
+            return Ok(None);
+        }
+        // Calls to the bus itself are replied to directly, not routed.
+        let callee = match self.resolve(destination) {
+            Some(callee) => callee,
+            None => return Ok(None),
+        };
+        let pending_reply = PendingReply {
+            caller: caller.clone(),
+            serial: serial(msg),
+        };
+        let max = self.limits.max_replies_per_connection();
+        let timeout = self.limits.reply_timeout();
+        if !self
+            .pending_replies
+            .lock()
+            .insert(pending_reply.clone(), callee, timeout, max)
+        {
+            return Err(fdo::Error::LimitsExceeded(format!(
+                "Connections can't have more than {max} calls awaiting a reply"
+            )));
+        }
+
+        Ok(Some(pending_reply))
+    }
+
+    /// Check that `sender` is the peer a call from `destination` was routed to, for the reply
+    /// with the given header `fields`, and that the call didn't time out. The call is not pending
+    /// anymore afterwards.
+    ///
+    /// This keeps peers from spoofing replies to calls made to others, without blaming them for
+    /// replying late or to callers that are gone.
+    async fn take_pending_reply(
+        &self,
+        fields: &MessageFields<'_>,
+        sender: &OwnedUniqueName,
+        destination: &BusName<'_>,
+    ) -> ReplyStatus {
+        let serial = match fields.get_field(MessageFieldCode::ReplySerial) {
+            Some(MessageField::ReplySerial(serial)) => *serial,
+            _ => return ReplyStatus::Unexpected,
+        };
+        let caller = match self.resolve(destination) {
+            Some(caller) => caller,
+            // The caller owned the name, but doesn't anymore.
+            None => return ReplyStatus::Stale,
+        };
+        let call = PendingReply { caller, serial };
+        let status = self.pending_replies.lock().take(&call, sender);
+        if status == ReplyStatus::Unexpected && !self.contains((&*call.caller).into()).await {
+            return ReplyStatus::Stale;
+        }
+
+        status
+    }
+
+    /// If `name` refers to a connected peer.
+    async fn is_connected(&self, name: &BusName<'_>) -> bool {
+        match self.resolve(name) {
+            Some(unique_name) => self.contains((&*unique_name).into()).await,
+            None => false,
+        }
+    }
+
+    /// The unique name `name` refers to, if any.
+    fn resolve(&self, name: &BusName<'_>) -> Option<OwnedUniqueName> {
+        match name {
+            BusName::Unique(name) => Some(name.to_owned().into()),
+            BusName::WellKnown(name) => self.name_registry.lookup(name.clone()),
+        }
+    }
+
     async fn send_msg(&self, msg: Arc<zbus::Message>, destination: BusName<'_>) -> Result<()> {
         match destination {
             BusName::Unique(dest) => self.send_msg_to_unique_name(msg, dest.clone()).await,
@@ -110,19 +1754,67 @@ impl Peers {
             .read()
             .await
             .get(destination.as_str())
-            .map(|peer| peer.conn().clone());
+            .map(|peer| (peer.conn().clone(), peer.pending_sends().clone()));
         match conn {
-            Some(mut conn) => conn.send(msg).await.context("failed to send message"),
+            Some((mut conn, pending_sends)) => {
+                let _pending = PendingSend::new(&self.counters, &pending_sends);
+
+                conn.send(msg).await.context("failed to send message")
+            }
             None => Err(anyhow!("no peer for destination `{}`", destination)),
         }
     }
 
-    async fn broadcast_msg(&self, msg: Arc<zbus::Message>) {
-        for peer in self.peers.read().await.values() {
+    /// Send `msg`, from the bus itself, to the peer at `conn`.
+    ///
+    /// All the messages of the bus go through here, but for the replies of its own interfaces,
+    /// which zbus sends on its own.
+    ///
+    /// Returns the message as sent, with its serial.
+    async fn send_bus_msg(
+        &self,
+        conn: &zbus::Connection,
+        mut msg: zbus::Message,
+    ) -> zbus::Result<Arc<zbus::Message>> {
+        // Not left to sending, so that the logged copy has it too.
+        conn.assign_serial_num(&mut msg)?;
+        let msg = Arc::new(msg);
+        conn.clone().send(msg.clone()).await?;
+        self.log_msg(&msg);
+
+        Ok(msg)
+    }
+
+    /// Capture `msg` in the message log, if there's one, once it reached a recipient.
+    ///
+    /// Only delivered messages are logged, as delivered, so dropped ones don't show up, and the
+    /// sender set by the bus does.
+    fn log_msg(&self, msg: &Arc<zbus::Message>) {
+        if let Some(message_log) = &self.message_log {
+            message_log.log(msg.clone());
+        }
+    }
+
+    /// The peers connected right now.
+    ///
+    /// Broadcasting goes through this rather than the lock, since otherwise a single peer that
+    /// isn't reading would hold up peers being added and removed, and all routing behind that.
+    async fn snapshot(&self) -> Vec<Arc<Peer>> {
+        self.peers.read().await.values().cloned().collect()
+    }
+
+    /// Send the signal `msg` to all interested peers, returning how many it was sent to.
+    async fn broadcast_msg(&self, msg: Arc<zbus::Message>, has_fds: bool) -> usize {
+        let mut fanout = 0;
+        for peer in self.snapshot().await {
+            if has_fds && !peer.can_pass_unix_fd() {
+                continue;
+            }
             if !peer.interested(&msg).await {
                 continue;
             }
 
+            let _pending = PendingSend::new(&self.counters, peer.pending_sends());
             if let Err(e) = peer
                 .conn()
                 .send(msg.clone())
@@ -130,7 +1822,67 @@ impl Peers {
                 .context("failed to send message")
             {
                 warn!("Error sending message: {}", e);
+            } else {
+                fanout += 1;
             }
         }
+
+        fanout
     }
 }
+
+/// Create a signal of the `org.freedesktop.DBus` interface, sent by the bus itself.
+fn bus_signal<B>(
+    destination: Option<&OwnedUniqueName>,
+    member: &str,
+    body: &B,
+) -> zbus::Result<zbus::Message>
+where
+    B: serde::ser::Serialize + zbus::zvariant::DynamicType,
+{
+    let mut builder =
+        MessageBuilder::signal("/org/freedesktop/DBus", "org.freedesktop.DBus", member)?
+            .sender("org.freedesktop.DBus")?;
+    if let Some(destination) = destination {
+        builder = builder.destination(destination.as_str())?;
+    }
+
+    builder.build(body)
+}
+
+/// A copy of `msg`, with `sender` as its sender.
+///
+/// Only the header changes: the serial, the flags and the body are the ones of `msg`, and so are
+/// the file descriptors, which `msg` still owns. So it has to outlive the copy.
+fn with_sender(msg: &zbus::Message, sender: &OwnedUniqueName) -> zbus::Result<zbus::Message> {
+    let body = msg.body_as_bytes()?;
+    // Only missing for empty bodies.
+    let signature = msg
+        .body_signature()
+        .unwrap_or_else(|_| Signature::from_static_str_unchecked(""));
+    let builder = MessageBuilder::from(msg.header()?).sender(sender.as_str())?;
+
+    // SAFETY: the body is the one of a valid message, with the same signature.
+    #[cfg(unix)]
+    return unsafe { builder.build_raw_body(body, signature, msg.fds()) };
+    #[cfg(not(unix))]
+    return unsafe { builder.build_raw_body(body, signature) };
+}
+
+/// If `msg` is addressed to the bus itself.
+fn is_to_bus(msg: &zbus::Message) -> bool {
+    match msg.header() {
+        Ok(hdr) => matches!(
+            hdr.destination(),
+            Ok(Some(BusName::WellKnown(name))) if name.as_str() == "org.freedesktop.DBus"
+        ),
+        Err(_) => false,
+    }
+}
+
+/// If the sender of `msg` asked for no reply.
+fn no_reply_expected(msg: &zbus::Message) -> bool {
+    msg.primary_header()
+        .flags()
+        .contains(MessageFlags::NoReplyExpected)
+}
@@ -0,0 +1,310 @@
+//! Validation of the messages peers send, isolated from the socket I/O.
+//!
+//! Everything here works on untrusted input, so it never panics, whatever the bytes. See
+//! [`parse_message`] for fuzzing it.
+
+use std::fmt;
+
+use zbus::{
+    zvariant::ObjectPath, Message, MessageField, MessageFieldCode, MessageFields, MessageType,
+};
+
+/// The maximum length of interface, member, error and bus names, as per the D-Bus specification.
+pub const MAX_NAME_LENGTH: usize = 255;
+
+/// The maximum length of object paths.
+///
+/// The D-Bus specification doesn't limit them but real-world paths are nowhere near this long.
+pub const MAX_PATH_LENGTH: usize = 4096;
+
+/// The maximum number of header fields.
+///
+/// The D-Bus specification defines 10 of them, each appearing at most once, but the ones it may
+/// define later have to be let through.
+pub const MAX_HEADER_FIELDS: usize = 32;
+
+/// The maximum length of the header fields array, in bytes.
+///
+/// The D-Bus specification only limits it to 64 MiB, like any array, which zbus enforces. This is
+/// plenty for all the fields at their maximum lengths.
+pub const MAX_HEADER_FIELDS_LENGTH: usize = 16 * 1024;
+
+/// The maximum length of signatures, as per the D-Bus specification.
+pub const MAX_SIGNATURE_LENGTH: usize = 255;
+
+/// The maximum nesting depth of arrays in a signature, and separately of structs, as per the
+/// D-Bus specification.
+pub const MAX_SIGNATURE_DEPTH: usize = 32;
+
+/// The maximum length of a message, as per the D-Bus specification.
+pub const MAX_MESSAGE_LENGTH: usize = 128 * 1024 * 1024;
+
+// Endianness, type, flags, version, body length and serial, then the length of the fields array.
+const PRIMARY_HEADER_LENGTH: usize = 16;
+
+/// A way in which a message violates the protocol.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ProtocolError {
+    /// The message is shorter than its primary header.
+    Truncated,
+    /// The lengths in the primary header don't add up to the length of the message.
+    LengthMismatch { expected: usize, actual: usize },
+    /// The message is longer than [`MAX_MESSAGE_LENGTH`].
+    TooLong(usize),
+    /// The endianness byte is neither `l` nor `B`.
+    InvalidEndianness(u8),
+    /// The message type is none of the four the specification defines.
+    InvalidType(u8),
+    /// Replies refer to calls by serial so 0 is reserved as invalid.
+    ZeroSerial,
+    /// The header fields array is longer than [`MAX_HEADER_FIELDS_LENGTH`].
+    HeaderFieldsTooLong(usize),
+    /// There are more than [`MAX_HEADER_FIELDS`] header fields.
+    TooManyHeaderFields(usize),
+    /// A string header field is longer than it can legitimately be.
+    FieldTooLong {
+        field: &'static str,
+        len: usize,
+        max: usize,
+    },
+    /// The signature of the body nests arrays or structs deeper than [`MAX_SIGNATURE_DEPTH`].
+    SignatureTooDeep(String),
+    /// A method call or signal without an object path, or any message with an invalid one.
+    InvalidPath,
+    /// zbus failed to parse the message, e.g. because of an invalid signature.
+    Malformed(String),
+}
+
+impl ProtocolError {
+    /// If the violation is bad enough for the peer to be disconnected right away.
+    ///
+    /// Other violations only count towards
+    /// [`Limits::max_protocol_violations`](crate::limits::Limits::max_protocol_violations).
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self,
+            Self::Truncated
+                | Self::LengthMismatch { .. }
+                | Self::TooLong(_)
+                | Self::InvalidEndianness(_)
+                | Self::HeaderFieldsTooLong(_)
+                | Self::TooManyHeaderFields(_)
+                | Self::FieldTooLong { .. }
+                | Self::SignatureTooDeep(_)
+        )
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncated => write!(f, "message is shorter than its header"),
+            Self::LengthMismatch { expected, actual } => write!(
+                f,
+                "message is {actual} bytes long, its header says {expected}"
+            ),
+            Self::TooLong(len) => write!(
+                f,
+                "message is {len} bytes long, maximum is {MAX_MESSAGE_LENGTH}"
+            ),
+            Self::InvalidEndianness(byte) => write!(f, "invalid endianness `{byte:#04x}`"),
+            Self::InvalidType(byte) => write!(f, "invalid message type {byte}"),
+            Self::ZeroSerial => write!(f, "message with a zero serial"),
+            Self::HeaderFieldsTooLong(len) => write!(
+                f,
+                "header fields are {len} bytes long, maximum is {MAX_HEADER_FIELDS_LENGTH}"
+            ),
+            Self::TooManyHeaderFields(count) => {
+                write!(f, "{count} header fields, maximum is {MAX_HEADER_FIELDS}")
+            }
+            Self::FieldTooLong { field, len, max } => {
+                write!(f, "{field} is {len} bytes long, maximum is {max}")
+            }
+            Self::SignatureTooDeep(signature) => write!(
+                f,
+                "signature `{signature}` nests deeper than {MAX_SIGNATURE_DEPTH} levels"
+            ),
+            Self::InvalidPath => write!(f, "missing or invalid object path"),
+            Self::Malformed(e) => write!(f, "failed to parse message: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// Parse and validate a complete message, as received from a peer.
+///
+/// This checks everything the bus does before routing a message, starting with the primary
+/// header so that zbus only gets to parse messages whose lengths add up. The message can't carry
+/// any file descriptors.
+pub fn parse_message(bytes: &[u8]) -> Result<Message, ProtocolError> {
+    check_primary_header(bytes)?;
+    let msg =
+        Message::from_bytes(bytes.to_vec()).map_err(|e| ProtocolError::Malformed(e.to_string()))?;
+    validate(&msg)?;
+
+    Ok(msg)
+}
+
+/// Validate a message zbus already parsed, returning its header fields.
+pub(crate) fn validate(msg: &Message) -> Result<MessageFields<'_>, ProtocolError> {
+    let bytes = msg.as_bytes();
+    // zbus refuses to parse messages in any other byte order but we don't want to rely on that,
+    // since everything reading the header ourselves depends on it.
+    if !matches!(bytes[0], b'l' | b'B') {
+        return Err(ProtocolError::InvalidEndianness(bytes[0]));
+    }
+    if msg.message_type() == MessageType::Invalid {
+        return Err(ProtocolError::InvalidType(bytes[1]));
+    }
+    if serial(msg) == 0 {
+        return Err(ProtocolError::ZeroSerial);
+    }
+    // Before parsing the fields, so that we don't bother with huge ones.
+    let fields_len = header_u32(bytes, 12) as usize;
+    if fields_len > MAX_HEADER_FIELDS_LENGTH {
+        return Err(ProtocolError::HeaderFieldsTooLong(fields_len));
+    }
+    let fields = msg
+        .fields()
+        .map_err(|e| ProtocolError::Malformed(e.to_string()))?;
+    if !has_valid_path(msg.message_type(), &fields) {
+        return Err(ProtocolError::InvalidPath);
+    }
+    check_fields(&fields)?;
+
+    Ok(fields)
+}
+
+/// The serial of `msg`, read straight from its primary header.
+pub(crate) fn serial(msg: &Message) -> u32 {
+    header_u32(msg.as_bytes(), 8)
+}
+
+/// Ensure the primary header of the message in `bytes` is consistent with its length.
+fn check_primary_header(bytes: &[u8]) -> Result<(), ProtocolError> {
+    if bytes.len() < PRIMARY_HEADER_LENGTH {
+        return Err(ProtocolError::Truncated);
+    }
+    if !matches!(bytes[0], b'l' | b'B') {
+        return Err(ProtocolError::InvalidEndianness(bytes[0]));
+    }
+    if !(1..=4).contains(&bytes[1]) {
+        return Err(ProtocolError::InvalidType(bytes[1]));
+    }
+    if bytes.len() > MAX_MESSAGE_LENGTH {
+        return Err(ProtocolError::TooLong(bytes.len()));
+    }
+    let fields_len = header_u32(bytes, 12) as usize;
+    if fields_len > MAX_HEADER_FIELDS_LENGTH {
+        return Err(ProtocolError::HeaderFieldsTooLong(fields_len));
+    }
+    // The body starts 8-byte aligned after the fields.
+    let header_len = (PRIMARY_HEADER_LENGTH + fields_len + 7) & !7;
+    let expected = header_len.saturating_add(header_u32(bytes, 4) as usize);
+    if expected != bytes.len() {
+        return Err(ProtocolError::LengthMismatch {
+            expected,
+            actual: bytes.len(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Read the `u32` at `offset` of the primary header in `bytes`, in the endianness of the message.
+///
+/// `bytes` must be at least as long as the primary header.
+fn header_u32(bytes: &[u8], offset: usize) -> u32 {
+    let value = [
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ];
+
+    match bytes[0] {
+        b'B' => u32::from_be_bytes(value),
+        _ => u32::from_le_bytes(value),
+    }
+}
+
+/// Ensure there aren't too many header fields, none of the string ones are longer than they can
+/// legitimately be and the signature isn't nested too deeply.
+fn check_fields(fields: &MessageFields<'_>) -> Result<(), ProtocolError> {
+    let count = fields.get().len();
+    if count > MAX_HEADER_FIELDS {
+        return Err(ProtocolError::TooManyHeaderFields(count));
+    }
+    for field in fields.get() {
+        let (name, len, max) = match field {
+            MessageField::Path(path) => ("object path", path.len(), MAX_PATH_LENGTH),
+            MessageField::Interface(iface) => ("interface", iface.len(), MAX_NAME_LENGTH),
+            MessageField::Member(member) => ("member", member.len(), MAX_NAME_LENGTH),
+            MessageField::ErrorName(error) => ("error name", error.len(), MAX_NAME_LENGTH),
+            MessageField::Destination(dest) => ("destination", dest.len(), MAX_NAME_LENGTH),
+            MessageField::Sender(sender) => ("sender", sender.len(), MAX_NAME_LENGTH),
+            MessageField::Signature(signature) => {
+                check_signature_depth(signature.as_str())?;
+
+                ("signature", signature.len(), MAX_SIGNATURE_LENGTH)
+            }
+            _ => continue,
+        };
+        if len > max {
+            return Err(ProtocolError::FieldTooLong {
+                field: name,
+                len,
+                max,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Ensure neither arrays nor structs are nested deeper than [`MAX_SIGNATURE_DEPTH`] in
+/// `signature`.
+///
+/// Dict entries count as structs. This doesn't validate the signature otherwise, zbus does.
+fn check_signature_depth(signature: &str) -> Result<(), ProtocolError> {
+    // The containers enclosing the current position, arrays only until their element type ends.
+    let mut containers = vec![];
+    for c in signature.bytes() {
+        match c {
+            b'a' | b'(' | b'{' => {
+                containers.push(c);
+                let depth = containers
+                    .iter()
+                    .filter(|&&container| (container == b'a') == (c == b'a'))
+                    .count();
+                if depth > MAX_SIGNATURE_DEPTH {
+                    return Err(ProtocolError::SignatureTooDeep(signature.to_string()));
+                }
+
+                continue;
+            }
+            b')' | b'}' => {
+                containers.pop();
+            }
+            _ => (),
+        }
+        // A complete type, which ends the arrays it's the element type of.
+        while containers.last() == Some(&b'a') {
+            containers.pop();
+        }
+    }
+
+    Ok(())
+}
+
+/// If the message has a valid object path, in case its type requires one.
+fn has_valid_path(msg_type: MessageType, fields: &MessageFields<'_>) -> bool {
+    match (msg_type, fields.get_field(MessageFieldCode::Path)) {
+        // zbus validates the path when parsing the header but we don't want to rely on that.
+        (_, Some(MessageField::Path(path))) => ObjectPath::try_from(path.as_str()).is_ok(),
+        (MessageType::MethodCall | MessageType::Signal, _) => false,
+        _ => true,
+    }
+}
@@ -0,0 +1,105 @@
+use std::time::Duration;
+
+use tokio::time::{sleep, Instant};
+
+/// A rate limit, expressed as a sustained rate with an allowance for bursts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    /// The sustained number of messages allowed per second.
+    pub messages_per_second: u32,
+    /// The maximum number of messages allowed in a burst.
+    pub burst: u32,
+}
+
+/// A token bucket enforcing a [`RateLimit`].
+#[derive(Debug)]
+pub struct RateLimiter {
+    limit: RateLimit,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst.max(1) as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Take a token from the bucket, if possible.
+    ///
+    /// Returns `false` if the limit has been exceeded.
+    pub fn try_acquire(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.last_refill = now;
+        let max_tokens = self.limit.burst.max(1) as f64;
+        self.tokens =
+            (self.tokens + elapsed * self.limit.messages_per_second as f64).min(max_tokens);
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+
+        true
+    }
+
+    /// If the bucket has refilled since it was last used, making the limiter no different from a
+    /// new one.
+    pub fn is_idle(&self) -> bool {
+        let elapsed = self.last_refill.elapsed().as_secs_f64();
+        let tokens = self.tokens + elapsed * self.limit.messages_per_second as f64;
+
+        tokens >= self.limit.burst.max(1) as f64
+    }
+
+    /// Take a token from the bucket, waiting for one to become available if needed.
+    ///
+    /// Returns `true` if it had to wait.
+    pub async fn acquire(&mut self) -> bool {
+        let mut waited = false;
+        while !self.try_acquire() {
+            let rate = self.limit.messages_per_second.max(1) as f64;
+            sleep(Duration::from_secs_f64((1.0 - self.tokens) / rate)).await;
+            waited = true;
+        }
+
+        waited
+    }
+}
+
+/// A count of events, only keeping those of the current window.
+///
+/// The count starts over once the window the first event of it happened in is over, so that only
+/// events close together add up.
+#[derive(Debug)]
+pub(crate) struct WindowedCount {
+    window: Duration,
+    count: u32,
+    window_start: Instant,
+}
+
+impl WindowedCount {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            count: 0,
+            window_start: Instant::now(),
+        }
+    }
+
+    /// Count an event, returning the number of events in the current window, this one included.
+    pub fn increment(&mut self) -> u32 {
+        let now = Instant::now();
+        if now.duration_since(self.window_start) >= self.window {
+            self.count = 0;
+            self.window_start = now;
+        }
+        self.count = self.count.saturating_add(1);
+
+        self.count
+    }
+}
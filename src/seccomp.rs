@@ -0,0 +1,214 @@
+//! Restricting the syscalls of the bus process itself, through a seccomp filter.
+
+use std::io;
+
+use anyhow::{anyhow, Context, Result};
+use nix::libc::{self, c_long, sock_filter, sock_fprog};
+use tracing::info;
+
+// The classic BPF instructions and seccomp constants the filter is made of, from
+// `linux/filter.h` and `linux/seccomp.h`: `BPF_LD | BPF_W | BPF_ABS`, `BPF_JMP | BPF_JEQ | BPF_K`
+// and `BPF_RET | BPF_K`.
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JMP_JEQ_K: u16 = 0x15;
+const BPF_RET_K: u16 = 0x06;
+const SECCOMP_SET_MODE_FILTER: libc::c_ulong = 1;
+const SECCOMP_FILTER_FLAG_TSYNC: libc::c_ulong = 1;
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+
+// The offsets of the fields of `struct seccomp_data`.
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+
+// The architecture the syscall numbers below are for, from `linux/audit.h`.
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+compile_error!("The `seccomp` feature is only supported on x86-64 and AArch64.");
+
+/// The syscalls the bus needs once it's listening: socket and file I/O, polling, passing file
+/// descriptors, spawning worker threads and memory management.
+const ALLOWED_SYSCALLS: &[c_long] = &[
+    // Reading and writing connections, file descriptors passing included.
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_recvfrom,
+    libc::SYS_recvmsg,
+    libc::SYS_sendto,
+    libc::SYS_sendmsg,
+    libc::SYS_accept,
+    libc::SYS_accept4,
+    libc::SYS_shutdown,
+    libc::SYS_close,
+    libc::SYS_fcntl,
+    libc::SYS_ioctl,
+    libc::SYS_getsockopt,
+    libc::SYS_setsockopt,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    // Notifying systemd, through a new datagram socket each time, and connecting in-process peers.
+    libc::SYS_socket,
+    libc::SYS_socketpair,
+    // Polling.
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_eventfd2,
+    libc::SYS_pipe2,
+    libc::SYS_ppoll,
+    // Files: the message log, the cookie keyring, the peers' `/proc` entries and cleaning up the
+    // socket files.
+    libc::SYS_openat,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_lseek,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_getdents64,
+    libc::SYS_fsync,
+    libc::SYS_fdatasync,
+    libc::SYS_ftruncate,
+    libc::SYS_fchmod,
+    libc::SYS_fchmodat,
+    libc::SYS_mkdirat,
+    libc::SYS_renameat,
+    libc::SYS_renameat2,
+    libc::SYS_unlinkat,
+    // Threads and synchronization.
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_futex,
+    libc::SYS_set_robust_list,
+    libc::SYS_rseq,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_sched_yield,
+    libc::SYS_prctl,
+    libc::SYS_sigaltstack,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    // Memory.
+    libc::SYS_brk,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    // Signals, time, identity and randomness.
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_nanosleep,
+    libc::SYS_getpid,
+    libc::SYS_gettid,
+    libc::SYS_getuid,
+    libc::SYS_geteuid,
+    libc::SYS_getgid,
+    libc::SYS_getegid,
+    libc::SYS_getrandom,
+    // The legacy syscalls the C library still uses on x86-64.
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_open,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_stat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_lstat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_poll,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_epoll_wait,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_pipe,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_chmod,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_mkdir,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_rename,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_unlink,
+];
+
+/// Restrict the whole process, all its threads included, to the syscalls the bus needs once it's
+/// listening.
+///
+/// Any other syscall fails with `EPERM`, e.g. running programs or listening on new sockets, so
+/// this is meant to be applied once all the listeners are bound. It can't be undone and is
+/// inherited by children.
+pub fn apply() -> Result<()> {
+    let mut filter = filter(AUDIT_ARCH);
+    let prog = sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_mut_ptr(),
+    };
+
+    // Required to install a filter without `CAP_SYS_ADMIN`.
+    // SAFETY: `PR_SET_NO_NEW_PRIVS` takes no pointers.
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(io::Error::last_os_error()).context("Failed to set `no_new_privs`");
+    }
+    // Synchronizing all threads, since the tokio runtime has spawned its own already.
+    // SAFETY: `prog` and the instructions it points to outlive the call.
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            SECCOMP_SET_MODE_FILTER,
+            SECCOMP_FILTER_FLAG_TSYNC,
+            &prog as *const sock_fprog,
+        )
+    };
+    match ret {
+        0 => (),
+        -1 => {
+            return Err(io::Error::last_os_error()).context("Failed to install the seccomp filter")
+        }
+        tid => {
+            return Err(anyhow!(
+                "Failed to apply the seccomp filter to thread {tid}"
+            ))
+        }
+    }
+    info!(
+        "Restricted the bus to {} syscalls through seccomp.",
+        ALLOWED_SYSCALLS.len()
+    );
+
+    Ok(())
+}
+
+/// The filter allowing [`ALLOWED_SYSCALLS`] of `arch` and failing everything else.
+fn filter(arch: u32) -> Vec<sock_filter> {
+    // Jump offsets are a byte, from the instruction following each comparison.
+    assert!(ALLOWED_SYSCALLS.len() < u8::MAX as usize);
+    let instruction = |code, jt, jf, k| sock_filter { code, jt, jf, k };
+
+    // The syscall numbers are only meaningful for the architecture they're for.
+    let mut filter = vec![
+        instruction(BPF_LD_W_ABS, 0, 0, SECCOMP_DATA_ARCH),
+        instruction(BPF_JMP_JEQ_K, 1, 0, arch),
+        instruction(BPF_RET_K, 0, 0, SECCOMP_RET_KILL_PROCESS),
+        instruction(BPF_LD_W_ABS, 0, 0, SECCOMP_DATA_NR),
+    ];
+    for (i, nr) in ALLOWED_SYSCALLS.iter().enumerate() {
+        // Past the remaining comparisons and the failure, to the success.
+        let to_allow = (ALLOWED_SYSCALLS.len() - i) as u8;
+        filter.push(instruction(BPF_JMP_JEQ_K, to_allow, 0, *nr as u32));
+    }
+    filter.push(instruction(
+        BPF_RET_K,
+        0,
+        0,
+        SECCOMP_RET_ERRNO | libc::EPERM as u32,
+    ));
+    filter.push(instruction(BPF_RET_K, 0, 0, SECCOMP_RET_ALLOW));
+
+    filter
+}
@@ -0,0 +1,49 @@
+//! Labeling the files the bus creates on SELinux systems.
+
+use std::{fs::OpenOptions, io, os::unix::io::AsRawFd, path::Path};
+
+use nix::unistd::write;
+use tracing::warn;
+
+// Where the kernel takes the context of the files the calling thread creates, as
+// `setfscreatecon(3)` does.
+const FSCREATE_PATH: &str = "/proc/thread-self/attr/fscreate";
+
+/// Run `create`, with the files it creates on the current thread labeled with `context`.
+///
+/// Does nothing but run `create` if SELinux is disabled.
+pub(crate) fn with_fscreate_context<T>(
+    context: &str,
+    create: impl FnOnce() -> io::Result<T>,
+) -> io::Result<T> {
+    if !is_enabled() {
+        return create();
+    }
+
+    set_fscreate_context(context.as_bytes()).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Failed to set SELinux file context `{context}`: {e}"),
+        )
+    })?;
+    let res = create();
+    // An empty context goes back to the default labeling.
+    if let Err(e) = set_fscreate_context(b"") {
+        warn!("Failed to reset the SELinux file context: {}", e);
+    }
+
+    res
+}
+
+/// If SELinux is enabled, i.e. its filesystem is mounted.
+fn is_enabled() -> bool {
+    Path::new("/sys/fs/selinux/enforce").exists()
+}
+
+fn set_fscreate_context(context: &[u8]) -> io::Result<()> {
+    let file = OpenOptions::new().write(true).open(FSCREATE_PATH)?;
+    // Not `write_all`, which wouldn't write anything for an empty context.
+    write(file.as_raw_fd(), context)?;
+
+    Ok(())
+}
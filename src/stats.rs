@@ -0,0 +1,235 @@
+use std::{collections::HashMap, time::Duration};
+
+use zbus::{
+    dbus_interface, fdo,
+    names::{BusName, OwnedBusName, OwnedUniqueName, OwnedWellKnownName},
+    zvariant::{OwnedValue, Value},
+};
+
+use crate::{credentials::Credentials, name_registry::NameRegistry, peers::Peers};
+
+/// A snapshot of the statistics of the bus.
+///
+/// See [`Bus::stats`](crate::bus::Bus::stats).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct BusStats {
+    /// The number of connected peers, not counting monitors.
+    pub connections: usize,
+    /// The number of peers that became monitors.
+    pub monitors: usize,
+    /// The highest number of peers connected at the same time so far.
+    pub peak_connections: usize,
+    /// The number of well-known names owned.
+    pub names: usize,
+    /// The highest number of well-known names owned at the same time so far.
+    pub peak_names: usize,
+    /// The number of match rules of all peers.
+    pub match_rules: usize,
+    /// The number of messages routed so far, counting each broadcast once.
+    pub messages_routed: u64,
+    /// The number of bytes routed so far, counting each broadcast once.
+    pub bytes_routed: u64,
+    /// The number of messages routed back to their sender so far, e.g. method calls a peer made
+    /// to a name it owns, and the replies to them.
+    pub self_sends: u64,
+    /// The number of peers that called `Hello` so far.
+    pub connections_greeted: u64,
+    /// The mean time from accepting a connection to its peer calling `Hello`.
+    pub mean_hello_latency: Duration,
+    /// The longest time from accepting a connection to its peer calling `Hello` so far.
+    pub max_hello_latency: Duration,
+    /// How long the bus has been up, counting from when it was built.
+    pub uptime: Duration,
+}
+
+/// The `org.freedesktop.DBus.Debug.Stats` interface.
+#[derive(Debug)]
+pub(crate) struct Stats {
+    name_registry: NameRegistry,
+    peers: Peers,
+    // Of the peer calling the methods.
+    unique_name: OwnedUniqueName,
+    credentials: Credentials,
+}
+
+impl Stats {
+    pub fn new(
+        name_registry: NameRegistry,
+        peers: Peers,
+        unique_name: OwnedUniqueName,
+        credentials: Credentials,
+    ) -> Self {
+        Self {
+            name_registry,
+            peers,
+            unique_name,
+            credentials,
+        }
+    }
+}
+
+#[dbus_interface(interface = "org.freedesktop.DBus.Debug.Stats")]
+impl Stats {
+    /// Get statistics about the connection owning `name`.
+    ///
+    /// Monitors can't own names anymore, but can still be looked up by their unique name.
+    async fn get_connection_stats(
+        &self,
+        name: OwnedBusName,
+    ) -> fdo::Result<HashMap<String, OwnedValue>> {
+        let unique_name: OwnedUniqueName = match name.into_inner() {
+            BusName::Unique(name) => name.into(),
+            BusName::WellKnown(name) => {
+                self.name_registry.lookup(name.clone()).ok_or_else(|| {
+                    fdo::Error::NameHasNoOwner(format!("Name `{name}` is not owned by anyone"))
+                })?
+            }
+        };
+        let connection = self
+            .peers
+            .connection_stats((&*unique_name).into())
+            .await
+            .ok_or_else(|| {
+                fdo::Error::NameHasNoOwner(format!("No connection named `{unique_name}`"))
+            })?;
+        let names: Vec<String> = self
+            .name_registry
+            .names_owned_by((&*unique_name).into())
+            .into_iter()
+            .map(|name| name.to_string())
+            .collect();
+
+        let mut stats = HashMap::new();
+        stats.insert(
+            "UniqueName".to_string(),
+            Value::from(unique_name.to_string()).into(),
+        );
+        // Tagging all the logs about the connection.
+        stats.insert(
+            "ConnectionId".to_string(),
+            Value::from(u64::try_from(connection.id).unwrap_or(u64::MAX)).into(),
+        );
+        stats.insert(
+            "MatchRules".to_string(),
+            Value::from(connection.match_rules as u32).into(),
+        );
+        stats.insert(
+            "PeakMatchRules".to_string(),
+            Value::from(connection.peak_match_rules as u32).into(),
+        );
+        stats.insert("Names".to_string(), Value::from(names).into());
+        stats.insert(
+            "IsMonitor".to_string(),
+            Value::from(connection.is_monitor).into(),
+        );
+        // Both counting from when the connection was accepted.
+        stats.insert(
+            "AuthenticationMicroseconds".to_string(),
+            Value::from(micros(connection.auth_latency)).into(),
+        );
+        if let Some(latency) = connection.hello_latency {
+            stats.insert(
+                "HelloMicroseconds".to_string(),
+                Value::from(micros(latency)).into(),
+            );
+        }
+        stats.insert(
+            "ConnectionUptimeSeconds".to_string(),
+            Value::from(connection.uptime.as_secs()).into(),
+        );
+
+        Ok(stats)
+    }
+
+    /// Get statistics about the whole bus, the same as [`Bus::stats`](crate::bus::Bus::stats).
+    async fn get_stats(&self) -> HashMap<String, OwnedValue> {
+        let stats = self.peers.stats().await;
+
+        [
+            ("ActiveConnections", stats.connections as u64),
+            ("MonitorCount", stats.monitors as u64),
+            ("PeakConnections", stats.peak_connections as u64),
+            ("BusNames", stats.names as u64),
+            ("PeakBusNames", stats.peak_names as u64),
+            ("MatchRules", stats.match_rules as u64),
+            ("MessagesRouted", stats.messages_routed),
+            ("BytesRouted", stats.bytes_routed),
+            ("SelfSends", stats.self_sends),
+            ("ConnectionsGreeted", stats.connections_greeted),
+            ("MeanHelloMicroseconds", micros(stats.mean_hello_latency)),
+            ("MaxHelloMicroseconds", micros(stats.max_hello_latency)),
+            ("UptimeSeconds", stats.uptime.as_secs()),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), Value::from(value).into()))
+        .collect()
+    }
+
+    /// Get all the match rules on the bus, each along with the unique name of its owner.
+    ///
+    /// Since rules reveal what peers are interested in, only privileged peers (root or the user
+    /// of the bus) are allowed to call this.
+    async fn get_all_match_rules(&self) -> fdo::Result<Vec<(String, String)>> {
+        if !self.credentials.is_privileged() {
+            return Err(fdo::Error::AccessDenied(
+                "Only privileged peers can get all match rules".to_string(),
+            ));
+        }
+
+        Ok(self
+            .peers
+            .all_match_rules()
+            .await
+            .into_iter()
+            .map(|(unique_name, rule)| (unique_name.to_string(), rule.to_string()))
+            .collect())
+    }
+
+    /// Send the caller a `NameOwnerChanged` signal for each name currently owned, with an empty
+    /// old owner, as if they had all just been acquired.
+    ///
+    /// This is a busd extension for peers joining late, e.g. live dashboards of services, to
+    /// learn the current owners from the same signals they then watch for changes. The signals
+    /// are all sent before the reply. Monitors can't call this, and ask for `NameAcquired`
+    /// signals through the [`REPLAY_NAME_OWNERS`] flag of `BecomeMonitor` instead.
+    ///
+    /// [`REPLAY_NAME_OWNERS`]: crate::monitoring::REPLAY_NAME_OWNERS
+    async fn replay_name_owners(&self) {
+        self.peers.send_name_owner_backlog(&self.unique_name).await
+    }
+
+    /// Get the position of `unique_name` in the queue of owners of `name`.
+    ///
+    /// Positions count from 0 for the primary owner, like the list `ListQueuedOwners` returns.
+    /// Since queues reveal which services peers are waiting to provide, only privileged peers are
+    /// allowed to call this.
+    async fn get_name_queue_position(
+        &self,
+        name: OwnedWellKnownName,
+        unique_name: OwnedUniqueName,
+    ) -> fdo::Result<u32> {
+        if !self.credentials.is_privileged() {
+            return Err(fdo::Error::AccessDenied(
+                "Only privileged peers can get queue positions".to_string(),
+            ));
+        }
+
+        match self
+            .name_registry
+            .queue_position((&*name).into(), (&*unique_name).into())
+        {
+            Some(Some(position)) => Ok(u32::try_from(position).unwrap_or(u32::MAX)),
+            Some(None) => Err(fdo::Error::Failed(format!(
+                "`{unique_name}` is not queued for `{name}`"
+            ))),
+            None => Err(fdo::Error::NameHasNoOwner(format!(
+                "Name `{name}` is not owned by anyone"
+            ))),
+        }
+    }
+}
+
+pub(crate) fn micros(duration: Duration) -> u64 {
+    u64::try_from(duration.as_micros()).unwrap_or(u64::MAX)
+}
@@ -0,0 +1,141 @@
+use std::{
+    env,
+    os::unix::{ffi::OsStrExt, io::RawFd},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use nix::{
+    fcntl::{fcntl, FcntlArg, FdFlag},
+    sys::socket::{sendto, socket, AddressFamily, MsgFlags, SockFlag, SockType, UnixAddr},
+    unistd::close,
+};
+use parking_lot::{const_mutex, Mutex};
+use tokio::{sync::watch, time::interval};
+use tracing::warn;
+
+/// The first file descriptor passed through socket activation, as per `sd_listen_fds(3)`.
+const LISTEN_FDS_START: RawFd = 3;
+
+// The socket-activated file descriptors already taken over by a listener.
+static TAKEN_FDS: Mutex<Vec<RawFd>> = const_mutex(Vec::new());
+
+/// Tell systemd that the bus is ready to accept connections.
+///
+/// This is meant for services with `Type=notify` and does nothing if `NOTIFY_SOCKET` isn't set.
+pub fn notify_ready() -> Result<()> {
+    notify("READY=1")
+}
+
+/// The interval at which systemd expects us to ping the watchdog, if it's enabled.
+///
+/// This is half the `WATCHDOG_USEC` timeout, as recommended by systemd, so that a late ping
+/// doesn't get us killed.
+pub fn watchdog_interval() -> Option<Duration> {
+    // The watchdog might be meant for another process (e.g. our parent before we forked).
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    if usec == 0 {
+        return None;
+    }
+
+    Some(Duration::from_micros(usec) / 2)
+}
+
+/// Ping the watchdog every `period`, for as long as the bus is alive.
+///
+/// `liveness` is the one of the bus, see [`Bus::liveness`](crate::bus::Bus::liveness). Pings are
+/// skipped while it wasn't seen alive within the whole watchdog timeout (twice `period`), so
+/// that systemd gets to restart a bus that's stuck, rather than the pings going on regardless.
+pub async fn run_watchdog(period: Duration, liveness: watch::Receiver<Instant>) {
+    let mut interval = interval(period);
+    loop {
+        interval.tick().await;
+        let last_seen = liveness.borrow().elapsed();
+        if last_seen > period * 2 {
+            warn!(
+                "Not pinging the systemd watchdog, the bus was last seen alive {:?} ago.",
+                last_seen
+            );
+
+            continue;
+        }
+        if let Err(e) = notify("WATCHDOG=1") {
+            warn!("Failed to ping the systemd watchdog: {}", e);
+        }
+    }
+}
+
+/// Take over the listening sockets systemd passed to us, along with their names.
+///
+/// Only the sockets named `name` (through `FileDescriptorName=` in the socket unit) are taken, if
+/// it's given, or all of them otherwise. Each socket can only be taken once. Fails if none are
+/// left, e.g. because we weren't socket-activated or the sockets are meant for another process.
+pub(crate) fn take_listen_fds(name: Option<&str>) -> Result<Vec<(RawFd, String)>> {
+    let pid = env::var("LISTEN_PID")
+        .map_err(|_| anyhow!("`LISTEN_PID` is not set, busd wasn't socket-activated"))?;
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+        return Err(anyhow!(
+            "`LISTEN_PID` is {}, the sockets are not meant for us ({})",
+            pid,
+            std::process::id()
+        ));
+    }
+    let count = env::var("LISTEN_FDS")
+        .map_err(|_| anyhow!("`LISTEN_FDS` is not set, busd wasn't socket-activated"))?;
+    let count = count
+        .parse::<RawFd>()
+        .map_err(|_| anyhow!("Invalid `LISTEN_FDS` `{}`", count))?;
+    // Names default to `unknown`, like with `sd_listen_fds_with_names(3)`.
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+    let mut names = names.split(':').filter(|name| !name.is_empty());
+
+    let mut taken = TAKEN_FDS.lock();
+    let mut fds = vec![];
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START.saturating_add(count) {
+        let fd_name = names.next().unwrap_or("unknown");
+        if taken.contains(&fd) || name.map_or(false, |name| name != fd_name) {
+            continue;
+        }
+        // They're passed on to our children otherwise.
+        fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+        taken.push(fd);
+        fds.push((fd, fd_name.to_string()));
+    }
+    if fds.is_empty() {
+        return Err(match name {
+            Some(name) => anyhow!("No socket named `{}` was passed by systemd", name),
+            None => anyhow!("No sockets were passed by systemd"),
+        });
+    }
+
+    Ok(fds)
+}
+
+fn notify(state: &str) -> Result<()> {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let addr = match path.as_bytes() {
+        // A socket in the abstract namespace.
+        [b'@', name @ ..] => UnixAddr::new_abstract(name)?,
+        _ => UnixAddr::new(Path::new(&path))?,
+    };
+    let fd = socket(
+        AddressFamily::Unix,
+        SockType::Datagram,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    )?;
+    let res = sendto(fd, state.as_bytes(), &addr, MsgFlags::empty());
+    let _ = close(fd);
+    res?;
+
+    Ok(())
+}
@@ -0,0 +1,100 @@
+//! Helpers for tests of the bus and of its clients, e.g. downstream integration tests.
+
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use futures_util::{Stream, StreamExt};
+use zbus::{
+    fdo::DBusProxy, names::UniqueName, CacheProperties, Connection, MatchRule, Message,
+    MessageBuilder, MessageStream, MessageType,
+};
+
+use crate::event::BusEvent;
+
+/// Collects the signals a client receives, so that tests can assert on all of them at once.
+///
+/// Collecting completes once the messages expected from a sender were routed, as the bus events
+/// tell, so tests don't have to wait an arbitrary time for signals that might still be on their
+/// way, nor for ones that should never come.
+pub struct SignalCollector {
+    conn: Connection,
+    stream: MessageStream,
+    rule: MatchRule<'static>,
+    events: Box<dyn Stream<Item = BusEvent> + Send + Unpin>,
+}
+
+impl SignalCollector {
+    /// Start collecting the signals `conn` receives that match `rule`, adding `rule` on the bus.
+    ///
+    /// `events` are the events of the bus `conn` is connected to, e.g. from
+    /// [`BusHandle::event_stream`](crate::bus::BusHandle::event_stream), subscribed to before any
+    /// of the messages to be collected are sent.
+    pub async fn new<E>(conn: &Connection, rule: MatchRule<'_>, events: E) -> Result<Self>
+    where
+        E: Stream<Item = BusEvent> + Send + Unpin + 'static,
+    {
+        // Before adding the rule, so that no signal it lets through is missed.
+        let stream = MessageStream::from(conn);
+        let rule = rule.into_owned();
+        DBusProxy::builder(conn)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?
+            .add_match_rule(rule.clone())
+            .await?;
+
+        Ok(Self {
+            conn: conn.clone(),
+            stream,
+            rule,
+            events: Box::new(events),
+        })
+    }
+
+    /// The signals received since the collector was created, in order, once `count` messages
+    /// from `sender` were routed, e.g. the signals it emits.
+    ///
+    /// Every message `sender` sends to other peers counts, broadcasted signals no one receives
+    /// included, but not the calls it makes to the bus itself.
+    pub async fn collect(
+        mut self,
+        sender: &UniqueName<'_>,
+        count: usize,
+    ) -> Result<Vec<Arc<Message>>> {
+        let mut routed = 0;
+        while routed < count {
+            match self.events.next().await {
+                Some(BusEvent::MessageRouted { sender: s, .. })
+                    if s.as_str() == sender.as_str() =>
+                {
+                    routed += 1
+                }
+                Some(_) => (),
+                None => return Err(anyhow!("The bus is gone.")),
+            }
+        }
+
+        // The bus only routes a message once it's sent to all its recipients, so our own call
+        // gets its reply after everything routed to us so far.
+        let sync = MessageBuilder::method_call("/org/freedesktop/DBus", "GetId")?
+            .destination("org.freedesktop.DBus")?
+            .interface("org.freedesktop.DBus")?
+            .build(&())?;
+        let serial = self.conn.send_message(sync).await?;
+        let mut signals = vec![];
+        while let Some(msg) = self.stream.next().await {
+            let msg = msg?;
+            match msg.message_type() {
+                MessageType::Signal if self.rule.matches(&msg)? => signals.push(msg),
+                MessageType::MethodReturn | MessageType::Error
+                    if msg.header()?.reply_serial()? == Some(serial) =>
+                {
+                    return Ok(signals)
+                }
+                _ => (),
+            }
+        }
+
+        Err(anyhow!("The connection to the bus was closed."))
+    }
+}
@@ -12,3 +12,98 @@ pub fn init() {
     #[cfg(feature = "console-subscriber")]
     console_subscriber::init();
 }
+
+/// Initialize logging to the system log, with the given facility.
+///
+/// Unlike [`init`], which logs to stderr, this is meant for traditional system daemons.
+#[cfg(feature = "syslog")]
+pub fn init_syslog(facility: syslog::Facility) -> anyhow::Result<()> {
+    use std::sync::Arc;
+
+    use anyhow::anyhow;
+    use parking_lot::Mutex;
+    use tracing_subscriber::{util::SubscriberInitExt, EnvFilter, FmtSubscriber};
+
+    let formatter = syslog::Formatter3164 {
+        facility,
+        hostname: None,
+        process: "busd".into(),
+        pid: std::process::id(),
+    };
+    let logger =
+        syslog::unix(formatter).map_err(|e| anyhow!("Failed to connect to syslog: {e}"))?;
+
+    FmtSubscriber::builder()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_writer(syslog_writer::MakeSyslogWriter {
+            logger: Arc::new(Mutex::new(logger)),
+        })
+        // syslog takes care of these.
+        .with_ansi(false)
+        .without_time()
+        .finish()
+        .try_init()?;
+
+    Ok(())
+}
+
+#[cfg(feature = "syslog")]
+mod syslog_writer {
+    use std::{io, sync::Arc};
+
+    use parking_lot::Mutex;
+    use syslog::{Formatter3164, Logger, LoggerBackend};
+    use tracing::{Level, Metadata};
+    use tracing_subscriber::fmt::MakeWriter;
+
+    type SyslogLogger = Logger<LoggerBackend, Formatter3164>;
+
+    pub struct MakeSyslogWriter {
+        pub logger: Arc<Mutex<SyslogLogger>>,
+    }
+
+    impl<'a> MakeWriter<'a> for MakeSyslogWriter {
+        type Writer = SyslogWriter;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            SyslogWriter {
+                logger: self.logger.clone(),
+                level: Level::INFO,
+            }
+        }
+
+        fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+            SyslogWriter {
+                logger: self.logger.clone(),
+                level: *meta.level(),
+            }
+        }
+    }
+
+    /// Writes each formatted event as a syslog message with the severity of the event's level.
+    pub struct SyslogWriter {
+        logger: Arc<Mutex<SyslogLogger>>,
+        level: Level,
+    }
+
+    impl io::Write for SyslogWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let msg = String::from_utf8_lossy(buf);
+            let msg = msg.trim_end();
+            let mut logger = self.logger.lock();
+            match self.level {
+                Level::ERROR => logger.err(msg),
+                Level::WARN => logger.warning(msg),
+                Level::INFO => logger.info(msg),
+                Level::DEBUG | Level::TRACE => logger.debug(msg),
+            }
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
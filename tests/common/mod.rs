@@ -0,0 +1,53 @@
+// Not all tests use all the helpers.
+#![allow(dead_code)]
+
+use std::future::Future;
+
+use busd::bus::Bus;
+use tokio::select;
+
+/// Run `bus` on its own task while `client` runs, and then hand it back for inspection and
+/// cleanup, along with the output of `client`.
+///
+/// Panics if the bus stops before `client` is done.
+pub async fn run_until<F>(mut bus: Bus, client: F) -> (Bus, F::Output)
+where
+    F: Future,
+{
+    let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+    let handle = tokio::spawn(async move {
+        select! {
+            _ = rx => (),
+            res = bus.run() => match res {
+                Ok(()) => panic!("Bus exited unexpectedly"),
+                Err(e) => panic!("Bus exited with an error: {}", e),
+            }
+        }
+
+        bus
+    });
+
+    let output = client.await;
+    tx.send(()).unwrap();
+
+    (handle.await.unwrap(), output)
+}
+
+/// The address of the first TCP listener in `address`, as [`Bus::address`] lists them.
+pub fn tcp_address(address: &str) -> String {
+    address
+        .split(';')
+        .find(|address| address.starts_with("tcp:"))
+        .unwrap()
+        .to_string()
+}
+
+/// The port of the TCP `address`.
+pub fn tcp_port(address: &str) -> u16 {
+    address
+        .split(',')
+        .find_map(|kv| kv.strip_prefix("port="))
+        .unwrap()
+        .parse()
+        .unwrap()
+}
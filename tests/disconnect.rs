@@ -0,0 +1,391 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::{env::temp_dir, path::Path, time::Duration};
+
+use anyhow::ensure;
+use busd::{
+    bus::{Bus, MEMORY_ADDRESS},
+    bus_builder::BusBuilder,
+};
+use futures_util::stream::StreamExt;
+use ntest::timeout;
+use rand::{
+    distributions::{Alphanumeric, DistString},
+    thread_rng,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::UnixStream,
+    time::sleep,
+};
+use tracing::instrument;
+use zbus::{
+    fdo::{self, DBusProxy, RequestNameReply},
+    names::{BusName, WellKnownName},
+    AuthMechanism, CacheProperties, ConnectionBuilder, MatchRule, MessageBuilder, MessageStream,
+};
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn disconnect_mid_message() {
+    busd::tracing_subscriber::init();
+
+    let s = Alphanumeric.sample_string(&mut thread_rng(), 10);
+    let path = temp_dir().join(s);
+    let address = format!("unix:path={}", path.display());
+    let bus = Bus::for_address(Some(&address), AuthMechanism::External)
+        .await
+        .unwrap();
+
+    let (bus, ret) = common::run_until(bus, disconnect_mid_message_client(&address, &path)).await;
+    // All the clients went away, the one that disconnected mid-message included.
+    if ret.is_ok() {
+        while !bus.peer_names().await.is_empty() {
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[instrument]
+async fn disconnect_mid_message_client(address: &str, socket_path: &Path) -> anyhow::Result<()> {
+    let name: WellKnownName = "org.busd.Disconnect".try_into()?;
+
+    // Watching the names come and go.
+    let observer = ConnectionBuilder::address(address)?.build().await?;
+    let mut signals = MessageStream::from(&observer);
+    let observer_proxy = DBusProxy::builder(&observer)
+        .cache_properties(CacheProperties::No)
+        .build()
+        .await?;
+    observer_proxy
+        .add_match_rule(MatchRule::builder().member("NameOwnerChanged")?.build())
+        .await?;
+
+    // A well-behaving client that owns a name and then goes away.
+    let conn = ConnectionBuilder::address(address)?.build().await?;
+    let owner = conn.unique_name().unwrap().to_string();
+    let dbus_proxy = DBusProxy::builder(&conn)
+        .cache_properties(CacheProperties::No)
+        .build()
+        .await?;
+    let ret = dbus_proxy
+        .request_name(name.clone(), Default::default())
+        .await?;
+    ensure!(
+        ret == RequestNameReply::PrimaryOwner,
+        "expected to become primary owner"
+    );
+    drop(dbus_proxy);
+    drop(conn);
+
+    // Both its names are released, and everyone is told.
+    let mut released = vec![];
+    while released.len() < 2 {
+        let msg = signals.next().await.unwrap()?;
+        if msg.member().as_deref() != Some("NameOwnerChanged") {
+            continue;
+        }
+        let (released_name, old_owner, new_owner): (String, String, String) = msg.body()?;
+        if old_owner == owner {
+            ensure!(
+                new_owner.is_empty(),
+                "`{released_name}` passed on to `{new_owner}`"
+            );
+            released.push(released_name);
+        }
+    }
+    released.sort();
+    let mut expected = vec![name.to_string(), owner.clone()];
+    expected.sort();
+    ensure!(released == expected, "released {released:?}");
+    ensure!(
+        matches!(
+            observer_proxy.get_name_owner(name.clone().into()).await,
+            Err(fdo::Error::NameHasNoOwner(_))
+        ),
+        "`{name}` still owned"
+    );
+    // And it's no peer of the bus anymore.
+    let names = observer_proxy.list_names().await?;
+    ensure!(
+        !names.iter().any(|listed| listed.as_str() == owner),
+        "`{owner}` still listed"
+    );
+
+    // A client that authenticates and then closes the socket in the middle of a message.
+    let mut stream = UnixStream::connect(socket_path).await?;
+    let uid = hex::encode(nix::unistd::Uid::current().to_string());
+    stream
+        .write_all(format!("\0AUTH EXTERNAL {uid}\r\n").as_bytes())
+        .await?;
+    let mut reader = BufReader::new(&mut stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    ensure!(line.starts_with("OK "), "authentication failed: {line}");
+    stream.write_all(b"BEGIN\r\n").await?;
+    // Only the fixed part of a method call header, no header fields and no body.
+    stream
+        .write_all(b"l\x01\x00\x01\x00\x00\x00\x00\x01\x00\x00\x00")
+        .await?;
+    drop(stream);
+
+    // The bus should be still healthy.
+    let conn = ConnectionBuilder::address(address)?.build().await?;
+    DBusProxy::builder(&conn)
+        .cache_properties(CacheProperties::No)
+        .build()
+        .await?
+        .get_id()
+        .await?;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn exit_on_idle() {
+    busd::tracing_subscriber::init();
+
+    let mut bus = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .exit_on_idle(true)
+        .build()
+        .await
+        .unwrap();
+    let connector = bus.memory_connector().unwrap();
+    let handle = tokio::spawn(async move {
+        bus.run().await.unwrap();
+
+        bus
+    });
+
+    // The bus shouldn't exit before the first peer connects.
+    sleep(Duration::from_millis(100)).await;
+    assert!(!handle.is_finished());
+
+    let conn = connector.connect().await.unwrap();
+    let conn2 = connector.connect().await.unwrap();
+    drop(conn);
+    sleep(Duration::from_millis(100)).await;
+    assert!(!handle.is_finished());
+
+    drop(conn2);
+    let bus = handle.await.unwrap();
+    bus.cleanup().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn reader_gone_mid_write() {
+    busd::tracing_subscriber::init();
+
+    let bus = Bus::for_address(Some(MEMORY_ADDRESS), AuthMechanism::External)
+        .await
+        .unwrap();
+    let connector = bus.memory_connector().unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let reader = connector.connect().await?;
+        let rule = MatchRule::builder().member("Flood")?.build();
+        DBusProxy::new(&reader).await?.add_match_rule(rule).await?;
+
+        // Keep the bus busy writing large signals to the reader, while it goes away.
+        let writer = connector.connect().await?;
+        let payload = vec![0u8; 64 * 1024];
+        let mut reader = Some(reader);
+        for i in 0..64 {
+            writer
+                .emit_signal(
+                    None::<BusName<'_>>,
+                    "/org/busd/Flood",
+                    "org.busd.Flood",
+                    "Flood",
+                    &payload,
+                )
+                .await?;
+            if i == 8 {
+                drop(reader.take());
+            }
+        }
+
+        // The bus should still be healthy.
+        DBusProxy::builder(&writer)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?
+            .get_id()
+            .await?;
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn disconnect_peer() {
+    busd::tracing_subscriber::init();
+
+    let bus = Bus::for_address(Some(MEMORY_ADDRESS), AuthMechanism::External)
+        .await
+        .unwrap();
+    let connector = bus.memory_connector().unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let conn = connector.connect().await?;
+        DBusProxy::builder(&conn)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?
+            .request_name("org.busd.DisconnectPeer".try_into()?, Default::default())
+            .await?;
+
+        Ok::<_, anyhow::Error>(conn)
+    })
+    .await;
+    let conn = ret.unwrap();
+
+    let mut stream = MessageStream::from(&conn);
+    let unique_name = conn.unique_name().unwrap().to_owned();
+    assert_eq!(bus.peer_names().await, [unique_name.clone()]);
+    bus.disconnect_peer((&*unique_name).into()).await;
+    assert!(bus.peer_names().await.is_empty());
+    assert_eq!(bus.stats().await.names, 0);
+    // The peer is already gone.
+    bus.disconnect_peer((&*unique_name).into()).await;
+
+    // The client should see the connection going away.
+    while let Some(msg) = stream.next().await {
+        if msg.is_err() {
+            break;
+        }
+    }
+
+    bus.cleanup().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn partial_reads_and_eof() {
+    busd::tracing_subscriber::init();
+
+    let s = Alphanumeric.sample_string(&mut thread_rng(), 10);
+    let path = temp_dir().join(s);
+    let address = format!("unix:path={}", path.display());
+    let bus = Bus::for_address(Some(&address), AuthMechanism::External)
+        .await
+        .unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        // A peer that authenticates and then stays idle.
+        let idle = authenticated_stream(&path).await?;
+
+        // A peer that trickles a `Hello` call in, a few bytes at a time.
+        let mut stream = authenticated_stream(&path).await?;
+        let hello = MessageBuilder::method_call("/org/freedesktop/DBus", "Hello")?
+            .destination("org.freedesktop.DBus")?
+            .interface("org.freedesktop.DBus")?
+            .build(&())?;
+        let mut bytes = hello.as_bytes().to_vec();
+        bytes[8..12].copy_from_slice(&1u32.to_ne_bytes());
+        for chunk in bytes.chunks(5) {
+            stream.write_all(chunk).await?;
+            sleep(Duration::from_millis(5)).await;
+        }
+        let mut reply = [0u8; 16];
+        stream.read_exact(&mut reply).await?;
+        ensure!(
+            reply[1] == 2,
+            "expected a method return, got type {}",
+            reply[1]
+        );
+
+        // Half a header and then EOF.
+        stream
+            .write_all(b"l\x01\x00\x01\x00\x00\x00\x00\x02\x00")
+            .await?;
+        stream.shutdown().await?;
+        let mut buf = [0u8; 1024];
+        while stream.read(&mut buf).await? > 0 {}
+
+        // Only the idle peer should be left and the bus should still be healthy.
+        let conn = ConnectionBuilder::address(address.as_str())?
+            .build()
+            .await?;
+        DBusProxy::builder(&conn)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?
+            .get_id()
+            .await?;
+        drop(idle);
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn hello_timeout() {
+    busd::tracing_subscriber::init();
+
+    let s = Alphanumeric.sample_string(&mut thread_rng(), 10);
+    let path = temp_dir().join(s);
+    let address = format!("unix:path={}", path.display());
+    let bus = BusBuilder::new()
+        .address(&address)
+        .hello_timeout(Duration::from_millis(200))
+        .build()
+        .await
+        .unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let conn = ConnectionBuilder::address(address.as_str())?
+            .build()
+            .await?;
+
+        // A peer that authenticates but never says `Hello` gets disconnected.
+        let mut idle = authenticated_stream(&path).await?;
+        let mut buf = [0u8; 1024];
+        ensure!(idle.read(&mut buf).await? == 0, "expected EOF");
+
+        // Unlike those that did.
+        DBusProxy::builder(&conn)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?
+            .get_id()
+            .await?;
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+async fn authenticated_stream(path: &Path) -> anyhow::Result<UnixStream> {
+    let mut stream = UnixStream::connect(path).await?;
+    let uid = hex::encode(nix::unistd::Uid::current().to_string());
+    stream
+        .write_all(format!("\0AUTH EXTERNAL {uid}\r\n").as_bytes())
+        .await?;
+    let mut reader = BufReader::new(&mut stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    ensure!(line.starts_with("OK "), "authentication failed: {line}");
+    stream.write_all(b"BEGIN\r\n").await?;
+
+    Ok(stream)
+}
@@ -1,18 +1,30 @@
-use std::env::temp_dir;
+mod common;
+
+use std::{collections::HashMap, env::temp_dir, time::Duration};
 
 use anyhow::ensure;
-use busd::bus::Bus;
+use busd::{
+    bus::{Bus, MEMORY_ADDRESS},
+    bus_builder::BusBuilder,
+    event::{BusEvent, NameOwnerChange},
+};
+use enumflags2::BitFlags;
+use futures_util::stream::StreamExt;
 use ntest::timeout;
 use rand::{
     distributions::{Alphanumeric, DistString},
     thread_rng,
 };
-use tokio::{select, sync::oneshot::Sender};
+use tokio::{select, sync::oneshot::Sender, time::sleep};
 use tracing::instrument;
 use zbus::{
-    fdo::{DBusProxy, ReleaseNameReply, RequestNameFlags, RequestNameReply},
-    names::WellKnownName,
-    AuthMechanism, CacheProperties, ConnectionBuilder,
+    fdo::{
+        self, DBusProxy, PropertiesProxy, ReleaseNameReply, RequestNameFlags, RequestNameReply,
+        StartServiceReply,
+    },
+    names::{BusName, WellKnownName},
+    zvariant::{OwnedValue, Value},
+    AuthMechanism, CacheProperties, ConnectionBuilder, Guid, MatchRule, MessageStream, MessageType,
 };
 
 #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
@@ -117,3 +129,2221 @@ async fn name_ownership_changes_client(address: &str, tx: Sender<()>) -> anyhow:
 
     Ok(())
 }
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn name_ownership_events() {
+    busd::tracing_subscriber::init();
+
+    let s = Alphanumeric.sample_string(&mut thread_rng(), 10);
+    let path = temp_dir().join(s);
+    let address = format!("unix:path={}", path.display());
+    let bus = Bus::for_address(Some(&address), AuthMechanism::External)
+        .await
+        .unwrap();
+    let mut events = bus.event_stream();
+    let (bus, ret) = common::run_until(bus, async {
+        let conn = ConnectionBuilder::address(&*address)?.build().await?;
+        let unique_name = conn.unique_name().unwrap().to_owned();
+        let name: WellKnownName = "org.blah".try_into()?;
+        let dbus_proxy = DBusProxy::builder(&conn)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?;
+        dbus_proxy
+            .request_name(name.clone(), Default::default())
+            .await?;
+        while let Some(event) = events.next().await {
+            if let BusEvent::NameOwnerChanged {
+                name: changed_name,
+                old_owner,
+                new_owner,
+            } = event
+            {
+                ensure!(*changed_name == name, "unexpected name");
+                ensure!(old_owner.is_none(), "unexpected old owner");
+                ensure!(
+                    new_owner.as_deref() == Some(&*unique_name),
+                    "unexpected new owner"
+                );
+
+                break;
+            }
+        }
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn watch_name() {
+    busd::tracing_subscriber::init();
+
+    let bus = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .build()
+        .await
+        .unwrap();
+    let name: WellKnownName = "org.busd.Watched".try_into().unwrap();
+    let watchers = [bus.watch_name(name.clone()), bus.watch_name(name.clone())];
+    let handle = bus.spawn();
+
+    let ret = async {
+        let connector = handle.memory_connector().unwrap();
+        let mut proxies = vec![];
+        for _ in 0..2 {
+            let conn = connector.connect().await?;
+            let proxy = DBusProxy::builder(&conn)
+                .cache_properties(CacheProperties::No)
+                .build()
+                .await?;
+            proxies.push((conn.unique_name().unwrap().to_owned(), proxy));
+        }
+        let (first, first_proxy) = &proxies[0];
+        let (second, second_proxy) = &proxies[1];
+
+        first_proxy
+            .request_name(
+                name.clone(),
+                RequestNameFlags::AllowReplacement | RequestNameFlags::DoNotQueue,
+            )
+            .await?;
+        // Changes of other names aren't yielded.
+        first_proxy
+            .request_name("org.busd.Unwatched".try_into()?, Default::default())
+            .await?;
+        second_proxy
+            .request_name(name.clone(), RequestNameFlags::ReplaceExisting.into())
+            .await?;
+        second_proxy.release_name(name.clone()).await?;
+
+        let expected = vec![
+            NameOwnerChange {
+                old_owner: None,
+                new_owner: Some(first.clone()),
+            },
+            NameOwnerChange {
+                old_owner: Some(first.clone()),
+                new_owner: Some(second.clone()),
+            },
+            NameOwnerChange {
+                old_owner: Some(second.clone()),
+                new_owner: None,
+            },
+        ];
+        for watcher in watchers {
+            let changes: Vec<_> = watcher.take(3).collect().await;
+            ensure!(changes == expected, "unexpected changes: {changes:?}");
+        }
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    handle.shutdown().await.unwrap();
+    ret.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn pinned_guid() {
+    busd::tracing_subscriber::init();
+
+    let s = Alphanumeric.sample_string(&mut thread_rng(), 10);
+    let path = temp_dir().join(s);
+    let address = format!("unix:path={}", path.display());
+    let guid = Guid::try_from("0123456789abcdef0123456789abcdef").unwrap();
+    let bus = BusBuilder::new()
+        .address(&address)
+        .guid(guid.clone())
+        .build()
+        .await
+        .unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let conn = ConnectionBuilder::address(&*address)?.build().await?;
+        let dbus_proxy = DBusProxy::builder(&conn)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?;
+        let id = dbus_proxy.get_id().await?;
+        ensure!(id == guid.as_str(), "unexpected bus ID");
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn memory_transport() {
+    busd::tracing_subscriber::init();
+
+    let bus = Bus::for_address(Some(MEMORY_ADDRESS), AuthMechanism::External)
+        .await
+        .unwrap();
+    let connector = bus.memory_connector().unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let name: WellKnownName = "org.blah".try_into()?;
+        let conn = connector.connect().await?;
+        let dbus_proxy = DBusProxy::builder(&conn)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?;
+        let ret = dbus_proxy
+            .request_name(name.clone(), Default::default())
+            .await?;
+        ensure!(
+            ret == RequestNameReply::PrimaryOwner,
+            "expected to become primary owner"
+        );
+
+        let conn2 = connector.connect().await?;
+        let dbus_proxy2 = DBusProxy::builder(&conn2)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?;
+        let owner = dbus_proxy2.get_name_owner(name.into()).await?;
+        ensure!(owner == *conn.unique_name().unwrap(), "unexpected owner");
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn connection_credentials() {
+    busd::tracing_subscriber::init();
+
+    let bus = Bus::for_address(Some(MEMORY_ADDRESS), AuthMechanism::External)
+        .await
+        .unwrap();
+    let connector = bus.memory_connector().unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let conn = connector.connect().await?;
+        let reply = conn
+            .call_method(
+                Some("org.freedesktop.DBus"),
+                "/org/freedesktop/DBus",
+                Some("org.freedesktop.DBus"),
+                "GetConnectionCredentials",
+                &(conn.unique_name().unwrap().as_str(),),
+            )
+            .await?;
+        let credentials: HashMap<String, OwnedValue> = reply.body()?;
+
+        // The client is in the same process as the bus.
+        let uid = u32::try_from(Value::clone(&credentials["UnixUserID"]))?;
+        ensure!(
+            uid == nix::unistd::Uid::current().as_raw(),
+            "unexpected uid: {uid}"
+        );
+        let pid = u32::try_from(Value::clone(&credentials["ProcessID"]))?;
+        ensure!(pid == std::process::id(), "unexpected pid: {pid}");
+        // Only there if an LSM providing labels is active.
+        if let Some(label) = credentials.get("LinuxSecurityLabel") {
+            let label = Vec::<u8>::try_from(Value::clone(label))?;
+            ensure!(!label.is_empty(), "empty security label");
+        }
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn accept_filter() {
+    busd::tracing_subscriber::init();
+
+    let mut bus = Bus::for_address(Some(MEMORY_ADDRESS), AuthMechanism::External)
+        .await
+        .unwrap();
+    let connector = bus.memory_connector().unwrap();
+    // Clients of the memory transport run in our process, so this refuses them all.
+    bus.set_accept_filter(|credentials| credentials.pid() != Some(std::process::id()));
+    let (mut bus, ret) = common::run_until(bus, connector.connect()).await;
+    assert!(ret.is_err(), "refused peer connected");
+    assert!(bus.peer_names().await.is_empty());
+
+    bus.set_accept_filter(|_| true);
+    let (bus, ret) = common::run_until(bus, connector.connect()).await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn error_names() {
+    busd::tracing_subscriber::init();
+
+    let bus = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .max_match_rules_per_connection(1)
+        .build()
+        .await
+        .unwrap();
+    let connector = bus.memory_connector().unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let conn = connector.connect().await?;
+        // Takes the only match rule allowed.
+        DBusProxy::builder(&conn)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?
+            .add_match_rule(MatchRule::builder().member("One")?.build())
+            .await?;
+
+        let cases = [
+            // Routed by the bus.
+            ("org.busd.Nobody", "Call", "", "ServiceUnknown"),
+            // Handled by the bus.
+            (
+                "org.freedesktop.DBus",
+                "GetNameOwner",
+                "org.busd.Nobody",
+                "NameHasNoOwner",
+            ),
+            (
+                "org.freedesktop.DBus",
+                "AddMatch",
+                "type='nonsense'",
+                "MatchRuleInvalid",
+            ),
+            (
+                "org.freedesktop.DBus",
+                "AddMatch",
+                "member='Two'",
+                "LimitsExceeded",
+            ),
+            ("org.freedesktop.DBus", "NoSuchMethod", "", "UnknownMethod"),
+        ];
+        for (destination, member, arg, expected) in cases {
+            let res = conn
+                .call_method(
+                    Some(destination),
+                    "/org/freedesktop/DBus",
+                    Some(destination),
+                    member,
+                    &(arg,),
+                )
+                .await;
+            // Matched to the call through its serial, or we wouldn't get it.
+            match res {
+                Err(zbus::Error::MethodError(name, Some(message), _)) => {
+                    ensure!(
+                        name.as_str() == format!("org.freedesktop.DBus.Error.{expected}"),
+                        "unexpected error for `{member}`: {name}"
+                    );
+                    ensure!(!message.is_empty(), "no message for `{name}`");
+                }
+                res => panic!("expected a `{expected}` error for `{member}`, got {res:?}"),
+            }
+        }
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn unknown_bus_methods() {
+    busd::tracing_subscriber::init();
+
+    let handle = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .build()
+        .await
+        .unwrap()
+        .spawn();
+
+    let ret = async {
+        let conn = handle.memory_connector().unwrap().connect().await?;
+        let cases = [
+            (
+                "/org/freedesktop/DBus",
+                "org.freedesktop.DBus",
+                "NoSuchMethod",
+                "UnknownMethod",
+            ),
+            (
+                "/org/freedesktop/DBus",
+                "org.freedesktop.DBus.Properties",
+                "NoSuchMethod",
+                "UnknownMethod",
+            ),
+            (
+                "/org/freedesktop/DBus",
+                "org.busd.NoSuchInterface",
+                "Hello",
+                "UnknownInterface",
+            ),
+            (
+                "/org/busd/NoSuchObject",
+                "org.freedesktop.DBus",
+                "Hello",
+                "UnknownObject",
+            ),
+        ];
+        for (path, interface, member, expected) in cases {
+            // Neither dropped nor forwarded, or we'd never get a reply.
+            let res = conn
+                .call_method(
+                    Some("org.freedesktop.DBus"),
+                    path,
+                    Some(interface),
+                    member,
+                    &(),
+                )
+                .await;
+            match res {
+                Err(zbus::Error::MethodError(name, _, _)) => ensure!(
+                    name.as_str() == format!("org.freedesktop.DBus.Error.{expected}"),
+                    "unexpected error for `{interface}.{member}` at `{path}`: {name}"
+                ),
+                res => {
+                    panic!("expected a `{expected}` error for `{interface}.{member}`, got {res:?}")
+                }
+            }
+        }
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    handle.shutdown().await.unwrap();
+    ret.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn bus_credentials() {
+    busd::tracing_subscriber::init();
+
+    let bus = Bus::for_address(Some(MEMORY_ADDRESS), AuthMechanism::External)
+        .await
+        .unwrap();
+    let connector = bus.memory_connector().unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let conn = connector.connect().await?;
+        let dbus_proxy = DBusProxy::builder(&conn)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?;
+        let bus_name: BusName = "org.freedesktop.DBus".try_into()?;
+
+        // The bus runs in this process.
+        let uid = dbus_proxy
+            .get_connection_unix_user(bus_name.clone())
+            .await?;
+        ensure!(
+            uid == nix::unistd::Uid::current().as_raw(),
+            "unexpected uid: {uid}"
+        );
+        let pid = dbus_proxy
+            .get_connection_unix_process_id(bus_name.clone())
+            .await?;
+        ensure!(pid == std::process::id(), "unexpected pid: {pid}");
+        let reply = conn
+            .call_method(
+                Some("org.freedesktop.DBus"),
+                "/org/freedesktop/DBus",
+                Some("org.freedesktop.DBus"),
+                "GetConnectionCredentials",
+                &(bus_name.as_str(),),
+            )
+            .await?;
+        let credentials: HashMap<String, OwnedValue> = reply.body()?;
+        let pid = u32::try_from(Value::clone(&credentials["ProcessID"]))?;
+        ensure!(pid == std::process::id(), "unexpected pid: {pid}");
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn bus_properties() {
+    busd::tracing_subscriber::init();
+
+    let bus = Bus::for_address(Some(MEMORY_ADDRESS), AuthMechanism::External)
+        .await
+        .unwrap();
+    let connector = bus.memory_connector().unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let conn = connector.connect().await?;
+        let proxy = PropertiesProxy::builder(&conn)
+            .destination("org.freedesktop.DBus")?
+            .path("/org/freedesktop/DBus")?
+            .build()
+            .await?;
+
+        let properties = proxy.get_all("org.freedesktop.DBus".try_into()?).await?;
+        let features = Vec::<String>::try_from(Value::clone(&properties["Features"]))?;
+        ensure!(
+            features.contains(&"Monitoring".to_string()),
+            "unexpected features: {features:?}"
+        );
+        let interfaces = Vec::<String>::try_from(Value::clone(&properties["Interfaces"]))?;
+        ensure!(
+            interfaces.contains(&"org.freedesktop.DBus.Debug.Stats".to_string()),
+            "unexpected interfaces: {interfaces:?}"
+        );
+
+        // An interface without properties.
+        let properties = proxy
+            .get_all("org.freedesktop.DBus.Debug.Stats".try_into()?)
+            .await?;
+        ensure!(
+            properties.is_empty(),
+            "unexpected properties {properties:?}"
+        );
+
+        let res = proxy.get_all("org.busd.NoSuchInterface".try_into()?).await;
+        ensure!(res.is_err(), "got properties of an unknown interface");
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn custom_machine_id() {
+    busd::tracing_subscriber::init();
+
+    let s = Alphanumeric.sample_string(&mut thread_rng(), 10);
+    let path = temp_dir().join(s);
+    let machine_id = "0123456789abcdef0123456789abcdef";
+    tokio::fs::write(&path, format!("{machine_id}\n"))
+        .await
+        .unwrap();
+    let bus = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .machine_id_path(&path)
+        .build()
+        .await
+        .unwrap();
+    assert_eq!(bus.machine_id(), machine_id);
+    bus.cleanup().await.unwrap();
+
+    tokio::fs::write(&path, "not-a-machine-id\n").await.unwrap();
+    let res = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .machine_id_path(&path)
+        .build()
+        .await;
+    tokio::fs::remove_file(&path).await.unwrap();
+    assert!(res.is_err());
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn missing_machine_id() {
+    busd::tracing_subscriber::init();
+
+    // Ephemeral, and not saved.
+    let s = Alphanumeric.sample_string(&mut thread_rng(), 10);
+    let path = temp_dir().join(s);
+    let bus = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .machine_id_path(&path)
+        .build()
+        .await
+        .unwrap();
+    let machine_id = bus.machine_id().to_string();
+    bus.cleanup().await.unwrap();
+    assert_eq!(machine_id.len(), 32);
+    assert!(machine_id.chars().all(|c| c.is_ascii_hexdigit()));
+    assert!(!path.exists());
+    let bus = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .machine_id_path(&path)
+        .build()
+        .await
+        .unwrap();
+    assert_ne!(bus.machine_id(), machine_id);
+    bus.cleanup().await.unwrap();
+
+    // Even where it can't be saved.
+    let path = temp_dir().join(Alphanumeric.sample_string(&mut thread_rng(), 10));
+    let bus = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .machine_id_path(path.join("machine-id"))
+        .build()
+        .await
+        .unwrap();
+    assert_eq!(bus.machine_id().len(), 32);
+    assert!(!path.exists());
+    bus.cleanup().await.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn unix_dir_address() {
+    busd::tracing_subscriber::init();
+
+    let dir = temp_dir();
+    let bus = Bus::for_address(
+        Some(&format!("unix:dir={}", dir.display())),
+        AuthMechanism::External,
+    )
+    .await
+    .unwrap();
+    let address = bus.address().to_string();
+    let path = std::path::PathBuf::from(address.strip_prefix("unix:path=").unwrap());
+    assert_eq!(path.parent(), Some(dir.as_path()));
+    let (bus, ret) = common::run_until(bus, async {
+        let conn = ConnectionBuilder::address(&*address)?.build().await?;
+        ensure!(conn.unique_name().is_some(), "no unique name assigned");
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    assert!(!path.exists());
+    ret.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn per_listener_auth_mechanism() {
+    busd::tracing_subscriber::init();
+
+    let s = Alphanumeric.sample_string(&mut thread_rng(), 10);
+    let path = temp_dir().join(s);
+    let unix_address = format!("unix:path={}", path.display());
+    // Any free port.
+    let tcp_address = "tcp:host=127.0.0.1,port=0";
+    let bus = BusBuilder::new()
+        .address(&unix_address)
+        .listen_on(tcp_address, AuthMechanism::Anonymous)
+        .build()
+        .await
+        .unwrap();
+    let tcp_address = common::tcp_address(bus.address());
+    let (bus, ret) = common::run_until(bus, async {
+        // ANONYMOUS is allowed on the TCP address..
+        ConnectionBuilder::address(&*tcp_address)?
+            .auth_mechanisms(&[AuthMechanism::Anonymous])
+            .build()
+            .await?;
+        // ..but not on the unix one, which still allows EXTERNAL.
+        let res = ConnectionBuilder::address(&*unix_address)?
+            .auth_mechanisms(&[AuthMechanism::Anonymous])
+            .build()
+            .await;
+        ensure!(
+            res.is_err(),
+            "ANONYMOUS authentication allowed on unix socket"
+        );
+        ConnectionBuilder::address(&*unix_address)?.build().await?;
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn listener_failure() {
+    busd::tracing_subscriber::init();
+
+    let s = Alphanumeric.sample_string(&mut thread_rng(), 10);
+    let path = temp_dir().join(s);
+    let unix_address = format!("unix:path={}", path.display());
+    // Any free port.
+    let tcp_address = "tcp:host=127.0.0.1,port=0";
+    let bus = BusBuilder::new()
+        .address(&unix_address)
+        .listen_on(tcp_address, AuthMechanism::Anonymous)
+        .build()
+        .await
+        .unwrap();
+    let tcp_address = common::tcp_address(bus.address());
+    let mut events = bus.event_stream();
+    let (bus, ret) = common::run_until(bus, async {
+        std::fs::remove_file(&path)?;
+        while let Some(event) = events.next().await {
+            if let BusEvent::ListenerRemoved { address } = event {
+                ensure!(
+                    address == unix_address,
+                    "unexpected listener removed: {address}"
+                );
+                break;
+            }
+        }
+
+        // The bus keeps serving on the TCP listener.
+        let conn = ConnectionBuilder::address(&*tcp_address)?
+            .auth_mechanisms(&[AuthMechanism::Anonymous])
+            .build()
+            .await?;
+        DBusProxy::builder(&conn)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?
+            .get_id()
+            .await?;
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    // Only the remaining listener is advertised.
+    assert_eq!(bus.address(), tcp_address);
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn add_and_remove_listener() {
+    busd::tracing_subscriber::init();
+
+    let bus = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .build()
+        .await
+        .unwrap();
+    let mut events = bus.event_stream();
+    let mut handle = bus.spawn();
+
+    let ret = async {
+        let s = Alphanumeric.sample_string(&mut thread_rng(), 10);
+        let path = temp_dir().join(s);
+        let unix_address = format!("unix:path={}", path.display());
+        let added = handle
+            .add_listener(&unix_address, AuthMechanism::External)
+            .await?;
+        // Advertised with the GUID of the bus, unlike the in-memory one.
+        let advertised = format!("{unix_address},guid={}", handle.guid());
+        ensure!(added == advertised, "unexpected address: {added}");
+        ensure!(
+            handle.address() == format!("{MEMORY_ADDRESS};{advertised}"),
+            "unexpected bus address: {}",
+            handle.address()
+        );
+        ensure!(
+            events.next().await
+                == Some(BusEvent::ListenerAdded {
+                    address: unix_address.clone()
+                }),
+            "no event for the new listener"
+        );
+
+        // Served by the same bus, which the client checks against the advertised GUID.
+        let conn = ConnectionBuilder::address(&*added)?.build().await?;
+        let proxy = DBusProxy::builder(&conn)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?;
+        let id = proxy.get_id().await?;
+        ensure!(id == handle.guid().as_str(), "unexpected GUID: {id}");
+
+        handle.remove_listener(&unix_address).await?;
+        ensure!(!path.exists(), "socket file left behind");
+        ensure!(
+            handle.address() == MEMORY_ADDRESS,
+            "unexpected bus address: {}",
+            handle.address()
+        );
+        // Peers that connected through it stay connected.
+        proxy.get_id().await?;
+
+        ensure!(
+            handle.remove_listener(&added).await.is_err(),
+            "removed a listener twice"
+        );
+        ensure!(
+            handle.remove_listener(MEMORY_ADDRESS).await.is_err(),
+            "removed the last listener"
+        );
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    handle.shutdown().await.unwrap();
+    ret.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn listener_guid() {
+    busd::tracing_subscriber::init();
+
+    let mut handle = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .build()
+        .await
+        .unwrap()
+        .spawn();
+
+    let ret = async {
+        let s = Alphanumeric.sample_string(&mut thread_rng(), 10);
+        let unix_address = format!("unix:path={}", temp_dir().join(s).display());
+        let guid = Guid::generate();
+        let added = handle
+            .add_listener_with_guid(&unix_address, AuthMechanism::External, guid.clone())
+            .await?;
+        ensure!(
+            added == format!("{unix_address},guid={guid}"),
+            "advertised with the wrong GUID: {added}"
+        );
+
+        // Peers of the listener only ever see its own GUID.
+        let conn = ConnectionBuilder::address(&*unix_address)?.build().await?;
+        ensure!(
+            conn.server_guid() == guid.as_str(),
+            "unexpected GUID in the handshake: {}",
+            conn.server_guid()
+        );
+        let id = DBusProxy::builder(&conn)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?
+            .get_id()
+            .await?;
+        ensure!(id == guid.as_str(), "unexpected GUID: {id}");
+
+        // The others still see the bus' one.
+        let conn = handle.memory_connector().unwrap().connect().await?;
+        ensure!(
+            conn.server_guid() == handle.guid().as_str(),
+            "unexpected GUID in the handshake: {}",
+            conn.server_guid()
+        );
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    handle.shutdown().await.unwrap();
+    ret.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn escaped_address() {
+    busd::tracing_subscriber::init();
+
+    let dir = temp_dir();
+    let s = Alphanumeric.sample_string(&mut thread_rng(), 10);
+    let path = dir.join(format!("busd {s}"));
+    let escaped = format!("unix:path={}/busd%20{s}", dir.display());
+
+    // Unescaped values that should have been escaped are accepted, but we escape ours.
+    let bus = Bus::for_address(
+        Some(&format!("unix:path={}", path.display())),
+        AuthMechanism::External,
+    )
+    .await
+    .unwrap();
+    assert_eq!(bus.address(), format!("{escaped},guid={}", bus.guid()));
+    assert!(path.exists());
+    bus.cleanup().await.unwrap();
+
+    let bus = Bus::for_address(Some(&escaped), AuthMechanism::External)
+        .await
+        .unwrap();
+    assert!(path.exists());
+    let (bus, ret) = common::run_until(bus, async {
+        let conn = ConnectionBuilder::address(&*escaped)?.build().await?;
+        ensure!(conn.unique_name().is_some(), "no unique name assigned");
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    assert!(!path.exists());
+    ret.unwrap();
+
+    // Truncated escapes are rejected.
+    assert!(
+        Bus::for_address(Some("unix:path=/tmp/busd%2"), AuthMechanism::External)
+            .await
+            .is_err()
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn abstract_socket_in_use() {
+    busd::tracing_subscriber::init();
+
+    let s = Alphanumeric.sample_string(&mut thread_rng(), 10);
+    let address = format!("unix:abstract=busd-{s}");
+    let bus = Bus::for_address(Some(&address), AuthMechanism::External)
+        .await
+        .unwrap();
+    assert_eq!(bus.address(), format!("{address},guid={}", bus.guid()));
+
+    // There's no file to tell, so we try connecting to find out that the other bus is alive.
+    let err = Bus::for_address(Some(&address), AuthMechanism::External)
+        .await
+        .unwrap_err();
+    assert!(
+        err.to_string().contains("Another bus"),
+        "unexpected error: {err}"
+    );
+    let (bus, ret) = common::run_until(bus, async {
+        let conn = ConnectionBuilder::address(&*address)?.build().await?;
+        ensure!(conn.unique_name().is_some(), "no unique name assigned");
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+
+    // The name is free again once the bus is gone.
+    let bus = Bus::for_address(Some(&address), AuthMechanism::External)
+        .await
+        .unwrap();
+    bus.cleanup().await.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn replace_stale_socket() {
+    busd::tracing_subscriber::init();
+
+    let s = Alphanumeric.sample_string(&mut thread_rng(), 10);
+    let path = temp_dir().join(s);
+    let address = format!("unix:path={}", path.display());
+
+    // A socket no one listens on anymore is left behind.
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+    assert!(BusBuilder::new().address(&address).build().await.is_err());
+    let bus = BusBuilder::new()
+        .address(&address)
+        .replace_stale_socket(true)
+        .build()
+        .await
+        .unwrap();
+    bus.cleanup().await.unwrap();
+
+    // Sockets still in use are left alone.
+    let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+    let e = BusBuilder::new()
+        .address(&address)
+        .replace_stale_socket(true)
+        .build()
+        .await
+        .unwrap_err();
+    assert!(e.to_string().contains("already in use"), "{e}");
+    drop(listener);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn socket_mode() {
+    use std::os::unix::fs::PermissionsExt;
+
+    busd::tracing_subscriber::init();
+
+    let s = Alphanumeric.sample_string(&mut thread_rng(), 10);
+    let path = temp_dir().join(s);
+    let dir = temp_dir().join(Alphanumeric.sample_string(&mut thread_rng(), 10));
+    std::fs::create_dir(&dir).unwrap();
+    let address = format!("unix:path={}", path.display());
+    let dir_address = format!("unix:dir={}", dir.display());
+    // Not something a umask would give.
+    let bus = BusBuilder::new()
+        .address(&address)
+        .listen_on(&dir_address, AuthMechanism::External)
+        .socket_mode(0o640)
+        .build()
+        .await
+        .unwrap();
+    let mut socket_paths = vec![path];
+    socket_paths.extend(std::fs::read_dir(&dir).unwrap().map(|e| e.unwrap().path()));
+    assert_eq!(socket_paths.len(), 2);
+    for socket_path in socket_paths {
+        let mode = std::fs::metadata(&socket_path)
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o640, "{}", socket_path.display());
+    }
+    bus.cleanup().await.unwrap();
+    std::fs::remove_dir(&dir).unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn name_has_owner() {
+    busd::tracing_subscriber::init();
+
+    let bus = Bus::for_address(Some(MEMORY_ADDRESS), AuthMechanism::External)
+        .await
+        .unwrap();
+    let connector = bus.memory_connector().unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let conn = connector.connect().await?;
+        let unique_name = conn.unique_name().unwrap().to_owned();
+        let conn2 = connector.connect().await?;
+        let dbus_proxy = DBusProxy::builder(&conn2)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?;
+        ensure!(
+            dbus_proxy
+                .name_has_owner(BusName::Unique(unique_name.clone().into_inner()))
+                .await?,
+            "connected peer has no owner"
+        );
+        ensure!(
+            dbus_proxy
+                .name_has_owner("org.freedesktop.DBus".try_into()?)
+                .await?,
+            "bus doesn't own its name"
+        );
+        ensure!(
+            !dbus_proxy
+                .name_has_owner("org.busd.Nobody".try_into()?)
+                .await?,
+            "unowned name has an owner"
+        );
+
+        drop(conn);
+        while dbus_proxy
+            .name_has_owner(BusName::Unique(unique_name.clone().into_inner()))
+            .await?
+        {
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn list_names() {
+    busd::tracing_subscriber::init();
+
+    let bus = Bus::for_address(Some(MEMORY_ADDRESS), AuthMechanism::External)
+        .await
+        .unwrap();
+    let connector = bus.memory_connector().unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let conn = connector.connect().await?;
+        let dbus_proxy = DBusProxy::builder(&conn)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?;
+        dbus_proxy
+            .request_name("org.busd.ListNames".try_into()?, Default::default())
+            .await?;
+
+        let names = dbus_proxy.list_names().await?;
+        let names: Vec<&str> = names.iter().map(|name| name.as_str()).collect();
+        for expected in [
+            conn.unique_name().unwrap().as_str(),
+            "org.busd.ListNames",
+            "org.freedesktop.DBus",
+        ] {
+            ensure!(names.contains(&expected), "`{expected}` not in {names:?}");
+        }
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn request_name_while_queued() {
+    busd::tracing_subscriber::init();
+
+    let bus = Bus::for_address(Some(MEMORY_ADDRESS), AuthMechanism::External)
+        .await
+        .unwrap();
+    let connector = bus.memory_connector().unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let name: WellKnownName = "org.busd.Requeue".try_into()?;
+        let mut proxies = vec![];
+        for _ in 0..3 {
+            let conn = connector.connect().await?;
+            let proxy = DBusProxy::builder(&conn)
+                .cache_properties(CacheProperties::No)
+                .build()
+                .await?;
+            proxies.push((conn, proxy));
+        }
+        let request = |i: usize, flags: BitFlags<RequestNameFlags>| {
+            proxies[i].1.request_name(name.clone(), flags)
+        };
+        let owner = || proxies[0].1.get_name_owner(name.clone().into());
+        let unique_name = |i: usize| proxies[i].0.unique_name().unwrap().to_string();
+
+        ensure!(
+            request(0, BitFlags::empty()).await? == RequestNameReply::PrimaryOwner,
+            "expected to own the name"
+        );
+        for i in 1..3 {
+            ensure!(
+                request(i, BitFlags::empty()).await? == RequestNameReply::InQueue,
+                "expected to be queued"
+            );
+        }
+        // New flags, same place in the queue.
+        ensure!(
+            request(1, RequestNameFlags::AllowReplacement.into()).await?
+                == RequestNameReply::InQueue,
+            "expected to be queued"
+        );
+        proxies[0].1.release_name(name.clone()).await?;
+        ensure!(
+            owner().await?.as_str() == unique_name(1),
+            "expected the first queued peer to own the name"
+        );
+
+        // Asking not to be queued anymore leaves the queue.
+        ensure!(
+            request(2, RequestNameFlags::DoNotQueue.into()).await? == RequestNameReply::Exists,
+            "expected the name to exist"
+        );
+        proxies[1].1.release_name(name.clone()).await?;
+        match owner().await {
+            Err(fdo::Error::NameHasNoOwner(_)) => (),
+            res => panic!("expected no owner, got {res:?}"),
+        }
+
+        // The name of the bus can't be requested, with whatever flags.
+        let bus_name: WellKnownName = "org.freedesktop.DBus".try_into()?;
+        for flags in [BitFlags::empty(), RequestNameFlags::ReplaceExisting.into()] {
+            match proxies[0].1.request_name(bus_name.clone(), flags).await {
+                Err(fdo::Error::InvalidArgs(_)) => (),
+                res => panic!("expected the bus name to be refused, got {res:?}"),
+            }
+        }
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn request_name_flags() {
+    busd::tracing_subscriber::init();
+
+    let bus = Bus::for_address(Some(MEMORY_ADDRESS), AuthMechanism::External)
+        .await
+        .unwrap();
+    let connector = bus.memory_connector().unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let name: WellKnownName = "org.busd.Flags".try_into()?;
+        let mut proxies = vec![];
+        for _ in 0..2 {
+            let conn = connector.connect().await?;
+            let proxy = DBusProxy::builder(&conn)
+                .cache_properties(CacheProperties::No)
+                .build()
+                .await?;
+            proxies.push((conn, proxy));
+        }
+        let request = |i: usize, flags: BitFlags<RequestNameFlags>| {
+            proxies[i].1.request_name(name.clone(), flags)
+        };
+        let owner = || proxies[0].1.get_name_owner(name.clone().into());
+        let unique_name = |i: usize| proxies[i].0.unique_name().unwrap().to_string();
+
+        ensure!(
+            request(0, BitFlags::empty()).await? == RequestNameReply::PrimaryOwner,
+            "expected to own the name"
+        );
+        ensure!(
+            request(0, RequestNameFlags::ReplaceExisting.into()).await?
+                == RequestNameReply::AlreadyOwner,
+            "expected to already own the name"
+        );
+
+        // Not replacing an owner that doesn't allow it.
+        ensure!(
+            request(1, RequestNameFlags::ReplaceExisting.into()).await?
+                == RequestNameReply::InQueue,
+            "expected to be queued"
+        );
+        ensure!(
+            request(
+                1,
+                RequestNameFlags::ReplaceExisting | RequestNameFlags::DoNotQueue
+            )
+            .await?
+                == RequestNameReply::Exists,
+            "expected the name to exist"
+        );
+        ensure!(
+            owner().await?.as_str() == unique_name(0),
+            "expected the name not to be replaced"
+        );
+
+        // Replacing an owner that does.
+        proxies[0].1.release_name(name.clone()).await?;
+        ensure!(
+            request(0, RequestNameFlags::AllowReplacement.into()).await?
+                == RequestNameReply::PrimaryOwner,
+            "expected to own the name"
+        );
+        ensure!(
+            request(1, RequestNameFlags::ReplaceExisting.into()).await?
+                == RequestNameReply::PrimaryOwner,
+            "expected to replace the owner"
+        );
+        ensure!(
+            owner().await?.as_str() == unique_name(1),
+            "expected the name to be replaced"
+        );
+
+        // Flags nobody defined.
+        let res = proxies[0]
+            .0
+            .call_method(
+                Some("org.freedesktop.DBus"),
+                "/org/freedesktop/DBus",
+                Some("org.freedesktop.DBus"),
+                "RequestName",
+                &(name.as_str(), 0x10u32),
+            )
+            .await;
+        ensure!(res.is_err(), "unknown flags accepted: {res:?}");
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn start_service_by_name() {
+    busd::tracing_subscriber::init();
+
+    let bus = Bus::for_address(Some(MEMORY_ADDRESS), AuthMechanism::External)
+        .await
+        .unwrap();
+    let connector = bus.memory_connector().unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let service = connector.connect().await?;
+        service.request_name("org.busd.Running").await?;
+        let conn = connector.connect().await?;
+        let start = |name: &'static str, flags: u32| {
+            conn.call_method(
+                Some("org.freedesktop.DBus"),
+                "/org/freedesktop/DBus",
+                Some("org.freedesktop.DBus"),
+                "StartServiceByName",
+                &(name, flags),
+            )
+        };
+
+        let reply: u32 = start("org.busd.Running", 0).await?.body()?;
+        ensure!(
+            reply == StartServiceReply::AlreadyRunning as u32,
+            "expected the service to be already running, got {reply}"
+        );
+
+        // Nothing to activate, and no flags to ask for anything else.
+        for (name, flags, expected) in [
+            ("org.busd.Nobody", 0, "ServiceUnknown"),
+            ("org.busd.Running", 1, "InvalidArgs"),
+        ] {
+            match start(name, flags).await {
+                Err(zbus::Error::MethodError(error, _, _)) => ensure!(
+                    error.as_str() == format!("org.freedesktop.DBus.Error.{expected}"),
+                    "unexpected error for `{name}`: {error}"
+                ),
+                res => panic!("expected a `{expected}` error for `{name}`, got {res:?}"),
+            }
+        }
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn max_queued_owners_per_name() {
+    busd::tracing_subscriber::init();
+
+    let bus = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .max_queued_owners_per_name(1)
+        .build()
+        .await
+        .unwrap();
+    let connector = bus.memory_connector().unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let name: WellKnownName = "org.busd.Queue".try_into()?;
+        let mut proxies = vec![];
+        for _ in 0..3 {
+            let conn = connector.connect().await?;
+            let proxy = DBusProxy::builder(&conn)
+                .cache_properties(CacheProperties::No)
+                .build()
+                .await?;
+            proxies.push((conn, proxy));
+        }
+        let request = |i: usize| proxies[i].1.request_name(name.clone(), Default::default());
+
+        ensure!(
+            request(0).await? == RequestNameReply::PrimaryOwner,
+            "expected to own the name"
+        );
+        ensure!(
+            request(1).await? == RequestNameReply::InQueue,
+            "expected to be queued"
+        );
+        // Asking again doesn't take another place in the queue.
+        ensure!(
+            request(1).await? == RequestNameReply::InQueue,
+            "expected to be queued"
+        );
+        match request(2).await {
+            Err(fdo::Error::LimitsExceeded(_)) => (),
+            res => panic!("expected the queue to be full, got {res:?}"),
+        }
+
+        // Once someone leaves the queue, there's room again.
+        proxies[1].1.release_name(name.clone()).await?;
+        ensure!(
+            request(2).await? == RequestNameReply::InQueue,
+            "expected to be queued"
+        );
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn name_replacement_signals() {
+    busd::tracing_subscriber::init();
+
+    let bus = Bus::for_address(Some(MEMORY_ADDRESS), AuthMechanism::External)
+        .await
+        .unwrap();
+    let connector = bus.memory_connector().unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let name: WellKnownName = "org.busd.Replacement".try_into()?;
+        let conn_a = connector.connect().await?;
+        let mut stream_a = MessageStream::from(&conn_a);
+        DBusProxy::builder(&conn_a)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?
+            .request_name(name.clone(), RequestNameFlags::AllowReplacement.into())
+            .await?;
+        let conn_b = connector.connect().await?;
+        let mut stream_b = MessageStream::from(&conn_b);
+        let proxy_b = DBusProxy::builder(&conn_b)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?;
+        let rule = MatchRule::builder()
+            .msg_type(MessageType::Signal)
+            .member("NameOwnerChanged")?
+            .arg(0, name.as_str())?
+            .build();
+        proxy_b.add_match_rule(rule).await?;
+        let ret = proxy_b
+            .request_name(name.clone(), RequestNameFlags::ReplaceExisting.into())
+            .await?;
+        ensure!(
+            ret == RequestNameReply::PrimaryOwner,
+            "expected to replace the owner"
+        );
+
+        // B sees the ownership change before it's told it acquired the name.
+        let unique_a = conn_a.unique_name().unwrap().to_string();
+        let unique_b = conn_b.unique_name().unwrap().to_string();
+        let mut owner_changed = false;
+        while let Some(msg) = stream_b.next().await {
+            let msg = msg?;
+            match msg.member().as_deref() {
+                Some("NameOwnerChanged") => {
+                    let args: (String, String, String) = msg.body()?;
+                    ensure!(
+                        args == (name.to_string(), unique_a.clone(), unique_b.clone()),
+                        "unexpected NameOwnerChanged: {args:?}"
+                    );
+                    owner_changed = true;
+                }
+                Some("NameAcquired") if msg.body::<String>()? == name.as_str() => break,
+                _ => (),
+            }
+        }
+        ensure!(owner_changed, "NameAcquired before NameOwnerChanged");
+
+        // A is told it lost the name.
+        while let Some(msg) = stream_a.next().await {
+            let msg = msg?;
+            if msg.member().as_deref() == Some("NameLost") {
+                let lost = msg.body::<String>()?;
+                ensure!(lost == name.as_str(), "lost unexpected name `{lost}`");
+
+                break;
+            }
+        }
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn queued_owner_promotion() {
+    busd::tracing_subscriber::init();
+
+    let handle = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .build()
+        .await
+        .unwrap()
+        .spawn();
+
+    let ret = async {
+        let connector = handle.memory_connector().unwrap();
+        let name: WellKnownName = "org.busd.Promotion".try_into()?;
+        let conn_a = connector.connect().await?;
+        let proxy_a = DBusProxy::builder(&conn_a)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?;
+        proxy_a
+            .request_name(name.clone(), Default::default())
+            .await?;
+        let conn_b = connector.connect().await?;
+        let mut stream_b = MessageStream::from(&conn_b);
+        let ret = DBusProxy::builder(&conn_b)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?
+            .request_name(name.clone(), Default::default())
+            .await?;
+        ensure!(ret == RequestNameReply::InQueue, "expected to be queued");
+        let conn_c = connector.connect().await?;
+        let mut stream_c = MessageStream::from(&conn_c);
+        let rule = MatchRule::builder()
+            .msg_type(MessageType::Signal)
+            .member("NameOwnerChanged")?
+            .arg(0, name.as_str())?
+            .build();
+        DBusProxy::builder(&conn_c)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?
+            .add_match_rule(rule)
+            .await?;
+
+        let ret = proxy_a.release_name(name.clone()).await?;
+        ensure!(
+            ret == ReleaseNameReply::Released,
+            "expected to release the name"
+        );
+
+        // Everyone interested sees B taking over from A.
+        let unique_a = conn_a.unique_name().unwrap().to_string();
+        let unique_b = conn_b.unique_name().unwrap().to_string();
+        while let Some(msg) = stream_c.next().await {
+            let msg = msg?;
+            if msg.member().as_deref() == Some("NameOwnerChanged") {
+                let args: (String, String, String) = msg.body()?;
+                ensure!(
+                    args == (name.to_string(), unique_a.clone(), unique_b.clone()),
+                    "unexpected NameOwnerChanged: {args:?}"
+                );
+
+                break;
+            }
+        }
+        let owner = DBusProxy::builder(&conn_c)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?
+            .get_name_owner(name.clone().into())
+            .await?;
+        ensure!(owner.as_str() == unique_b, "unexpected owner `{owner}`");
+
+        // B is told it acquired the name, then gets what's sent to it from now on.
+        while let Some(msg) = stream_b.next().await {
+            let msg = msg?;
+            if msg.member().as_deref() == Some("NameAcquired") {
+                let acquired = msg.body::<String>()?;
+                ensure!(
+                    acquired == name.as_str(),
+                    "acquired unexpected name `{acquired}`"
+                );
+
+                break;
+            }
+        }
+        conn_c
+            .emit_signal(
+                Some(name.clone()),
+                "/org/busd/Promotion",
+                "org.busd.Promotion",
+                "Promoted",
+                &(),
+            )
+            .await?;
+        while let Some(msg) = stream_b.next().await {
+            let msg = msg?;
+            if msg.member().as_deref() == Some("Promoted") {
+                let sender = msg.header()?.sender()?.map(|sender| sender.to_string());
+                ensure!(
+                    sender.as_deref() == conn_c.unique_name().map(|name| name.as_str()),
+                    "unexpected sender {sender:?}"
+                );
+
+                break;
+            }
+        }
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    handle.shutdown().await.unwrap();
+    ret.unwrap();
+}
+
+#[cfg(all(unix, feature = "test-util"))]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn authenticated_peer() {
+    busd::tracing_subscriber::init();
+
+    let mut bus = Bus::for_address(Some(MEMORY_ADDRESS), AuthMechanism::External)
+        .await
+        .unwrap();
+    let credentials = busd::credentials::Credentials::new(4242, 4343, 42);
+    let conn = bus.add_authenticated_peer(credentials).await.unwrap();
+
+    let reply = conn
+        .call_method(
+            Some("org.freedesktop.DBus"),
+            "/org/freedesktop/DBus",
+            Some("org.freedesktop.DBus"),
+            "GetConnectionCredentials",
+            &(conn.unique_name().unwrap().as_str(),),
+        )
+        .await
+        .unwrap();
+    let credentials: HashMap<String, OwnedValue> = reply.body().unwrap();
+    let uid = u32::try_from(Value::clone(&credentials["UnixUserID"])).unwrap();
+    assert_eq!(uid, 4242);
+    let groups = Vec::<u32>::try_from(Value::clone(&credentials["UnixGroupIDs"])).unwrap();
+    assert_eq!(groups, [4343]);
+    let pid = u32::try_from(Value::clone(&credentials["ProcessID"])).unwrap();
+    assert_eq!(pid, 42);
+
+    drop(conn);
+    bus.cleanup().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn tcp_bind_address() {
+    busd::tracing_subscriber::init();
+
+    let bus = Bus::for_address(
+        Some("tcp:host=localhost,bind=127.0.0.1,port=0"),
+        AuthMechanism::Anonymous,
+    )
+    .await
+    .unwrap();
+    // Clients are told to connect to `host`, not `bind`.
+    assert!(bus.address().starts_with("tcp:host=localhost,port="));
+    assert_ne!(common::tcp_port(bus.address()), 0);
+    let address = bus.address().to_string();
+    let (bus, ret) = common::run_until(bus, async {
+        let conn = ConnectionBuilder::address(address.as_str())?
+            .auth_mechanisms(&[AuthMechanism::Anonymous])
+            .build()
+            .await?;
+        DBusProxy::builder(&conn)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?
+            .get_id()
+            .await?;
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn deny_anonymous() {
+    busd::tracing_subscriber::init();
+
+    let s = Alphanumeric.sample_string(&mut thread_rng(), 10);
+    let path = temp_dir().join(s);
+    let unix_address = format!("unix:path={}", path.display());
+    // Any free port.
+    let tcp_address = "tcp:host=127.0.0.1,port=0";
+    let bus = BusBuilder::new()
+        .address(&unix_address)
+        .listen_on(tcp_address, AuthMechanism::Anonymous)
+        .deny_anonymous(true)
+        .build()
+        .await
+        .unwrap();
+    let tcp_address = common::tcp_address(bus.address());
+    let (bus, ret) = common::run_until(bus, async {
+        // The listener allows `ANONYMOUS`, but the switch takes precedence.
+        let res = ConnectionBuilder::address(&*tcp_address)?
+            .auth_mechanisms(&[AuthMechanism::Anonymous])
+            .build()
+            .await;
+        ensure!(res.is_err(), "anonymous peer accepted");
+
+        // Other mechanisms are unaffected.
+        let conn = ConnectionBuilder::address(unix_address.as_str())?
+            .build()
+            .await?;
+        ensure!(conn.unique_name().is_some(), "no unique name assigned");
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn remote_address() {
+    busd::tracing_subscriber::init();
+
+    // Any free port.
+    let tcp_address = "tcp:host=127.0.0.1,port=0";
+    let bus = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .listen_on(tcp_address, AuthMechanism::Anonymous)
+        .build()
+        .await
+        .unwrap();
+    let tcp_address = common::tcp_address(bus.address());
+    let connector = bus.memory_connector().unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let memory_conn = connector.connect().await?;
+        let tcp_conn = ConnectionBuilder::address(&*tcp_address)?
+            .auth_mechanisms(&[AuthMechanism::Anonymous])
+            .build()
+            .await?;
+
+        Ok::<_, anyhow::Error>((memory_conn, tcp_conn))
+    })
+    .await;
+    let (memory_conn, tcp_conn) = ret.unwrap();
+    let peers = bus.peers().await;
+    let remote_address = |conn: &zbus::Connection| {
+        let unique_name = conn.unique_name().unwrap();
+        peers
+            .iter()
+            .find(|peer| peer.unique_name == *unique_name)
+            .map(|peer| peer.remote_address)
+            .unwrap()
+    };
+    assert_eq!(remote_address(&memory_conn), None);
+    let tcp_remote_address = remote_address(&tcp_conn).unwrap();
+    assert_eq!(tcp_remote_address.ip().to_string(), "127.0.0.1");
+    // The client's port, not the bus'.
+    assert_ne!(tcp_remote_address.port(), common::tcp_port(&tcp_address));
+    // Ordered by ID, so the oldest connection comes first.
+    assert!(peers[0].uptime >= peers[1].uptime);
+    bus.cleanup().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn tcp_all_interfaces() {
+    busd::tracing_subscriber::init();
+
+    let bus = Bus::for_address(Some("tcp:host=*,port=0"), AuthMechanism::Anonymous)
+        .await
+        .unwrap();
+    // One address per socket, in the usual `;`-separated list.
+    let addresses: Vec<String> = bus.address().split(';').map(String::from).collect();
+    assert!(addresses
+        .iter()
+        .any(|address| address.starts_with("tcp:host=0.0.0.0,port=")));
+    let (bus, ret) = common::run_until(bus, async {
+        // IPv6 might not be available, but if it is, we're listening on it as well.
+        for address in &addresses {
+            let conn = ConnectionBuilder::address(address.as_str())?
+                .auth_mechanisms(&[AuthMechanism::Anonymous])
+                .build()
+                .await?;
+            DBusProxy::builder(&conn)
+                .cache_properties(CacheProperties::No)
+                .build()
+                .await?
+                .get_id()
+                .await?;
+        }
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+#[instrument]
+#[timeout(15000)]
+async fn concurrent_name_requests() {
+    busd::tracing_subscriber::init();
+
+    const PEERS: usize = 4;
+    const ROUNDS: usize = 25;
+
+    let handle = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .build()
+        .await
+        .unwrap()
+        .spawn();
+
+    let ret = async {
+        let connector = handle.memory_connector().unwrap();
+        let names: [WellKnownName; 2] = [
+            "org.busd.Contended1".try_into()?,
+            "org.busd.Contended2".try_into()?,
+        ];
+        let observer = connector.connect().await?;
+        let mut stream = MessageStream::from(&observer);
+        let observer_proxy = DBusProxy::builder(&observer)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?;
+        for name in &names {
+            let rule = MatchRule::builder()
+                .msg_type(MessageType::Signal)
+                .member("NameOwnerChanged")?
+                .arg(0, name.as_str())?
+                .build();
+            observer_proxy.add_match_rule(rule).await?;
+        }
+
+        // Each peer requests and releases both names, concurrently, over and over.
+        let mut peers = vec![];
+        for _ in 0..PEERS {
+            let conn = connector.connect().await?;
+            let names = names.clone();
+            peers.push(tokio::spawn(async move {
+                let proxy = DBusProxy::builder(&conn)
+                    .cache_properties(CacheProperties::No)
+                    .build()
+                    .await?;
+                for _ in 0..ROUNDS {
+                    let (first, second) = futures_util::try_join!(
+                        proxy.request_name(names[0].clone(), Default::default()),
+                        proxy.request_name(names[1].clone(), Default::default()),
+                    )?;
+                    for reply in [first, second] {
+                        ensure!(
+                            matches!(
+                                reply,
+                                RequestNameReply::PrimaryOwner | RequestNameReply::InQueue
+                            ),
+                            "unexpected reply to requesting a name: {reply:?}"
+                        );
+                    }
+                    let (first, second) = futures_util::try_join!(
+                        proxy.release_name(names[0].clone()),
+                        proxy.release_name(names[1].clone()),
+                    )?;
+                    for reply in [first, second] {
+                        ensure!(
+                            reply == ReleaseNameReply::Released,
+                            "unexpected reply to releasing a name: {reply:?}"
+                        );
+                    }
+                }
+
+                Ok::<_, anyhow::Error>(conn)
+            }));
+        }
+        // Kept connected until the end, so that only `ReleaseName` changes the owners.
+        let mut conns = vec![];
+        for peer in peers {
+            conns.push(peer.await??);
+        }
+        for name in &names {
+            match observer_proxy.get_name_owner(name.clone().into()).await {
+                Err(fdo::Error::NameHasNoOwner(_)) => (),
+                res => anyhow::bail!("unexpected owner of `{name}`: {res:?}"),
+            }
+        }
+
+        // Taking the names last marks the end of the signals.
+        let observer_name = observer.unique_name().unwrap().to_string();
+        for name in &names {
+            observer_proxy
+                .request_name(name.clone(), Default::default())
+                .await?;
+        }
+        let mut owners: HashMap<String, String> = HashMap::new();
+        let mut acquired: HashMap<(String, String), usize> = HashMap::new();
+        let mut lost: HashMap<(String, String), usize> = HashMap::new();
+        let mut done = 0;
+        while done < names.len() {
+            let msg = stream.next().await.unwrap()?;
+            if msg.member().as_deref() != Some("NameOwnerChanged") {
+                continue;
+            }
+            let (name, old_owner, new_owner): (String, String, String) = msg.body()?;
+            // Each change starts from where the previous one of the same name left off.
+            let owner = owners.entry(name.clone()).or_default();
+            ensure!(
+                *owner == old_owner,
+                "`{name}` changed from `{old_owner}` while owned by `{owner}`"
+            );
+            *owner = new_owner.clone();
+            if !old_owner.is_empty() {
+                *lost.entry((name.clone(), old_owner)).or_default() += 1;
+            }
+            if new_owner == observer_name {
+                done += 1;
+            } else if !new_owner.is_empty() {
+                *acquired.entry((name, new_owner)).or_default() += 1;
+            }
+        }
+        ensure!(
+            acquired == lost,
+            "names acquired {acquired:?} and lost {lost:?} don't add up"
+        );
+        drop(conns);
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    handle.shutdown().await.unwrap();
+    ret.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn name_promotion_grace() {
+    busd::tracing_subscriber::init();
+
+    let handle = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .name_promotion_grace(Duration::from_millis(200))
+        .build()
+        .await
+        .unwrap()
+        .spawn();
+
+    let ret = async {
+        let connector = handle.memory_connector().unwrap();
+        let name: WellKnownName = "org.busd.Failover".try_into()?;
+        let observer = connector.connect().await?;
+        let mut stream = MessageStream::from(&observer);
+        let observer_proxy = DBusProxy::builder(&observer)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?;
+        let rule = MatchRule::builder()
+            .msg_type(MessageType::Signal)
+            .member("NameOwnerChanged")?
+            .arg(0, name.as_str())?
+            .build();
+        observer_proxy.add_match_rule(rule).await?;
+
+        let mut conns = vec![];
+        let mut unique_names = vec![];
+        for _ in 0..3 {
+            let conn = connector.connect().await?;
+            DBusProxy::builder(&conn)
+                .cache_properties(CacheProperties::No)
+                .build()
+                .await?
+                .request_name(name.clone(), Default::default())
+                .await?;
+            unique_names.push(conn.unique_name().unwrap().to_string());
+            conns.push(conn);
+        }
+        let [a, b, c] = <[String; 3]>::try_from(unique_names).unwrap();
+        assert_eq!(
+            next_owner_change(&mut stream).await?,
+            (String::new(), a.clone())
+        );
+
+        // The name is held unowned rather than going to B, so the observer can take it over.
+        drop(conns.remove(0));
+        assert_eq!(next_owner_change(&mut stream).await?, (a, String::new()));
+        let ret = observer_proxy
+            .request_name(name.clone(), RequestNameFlags::DoNotQueue.into())
+            .await?;
+        ensure!(
+            ret == RequestNameReply::PrimaryOwner,
+            "expected to own the name"
+        );
+        let observer_name = observer.unique_name().unwrap().to_string();
+        assert_eq!(
+            next_owner_change(&mut stream).await?,
+            (String::new(), observer_name.clone())
+        );
+
+        // Along with the queue.
+        observer_proxy.release_name(name.clone()).await?;
+        assert_eq!(
+            next_owner_change(&mut stream).await?,
+            (observer_name, b.clone())
+        );
+
+        // Unless someone shows up, the next owner is promoted once the grace period is over.
+        drop(conns.remove(0));
+        assert_eq!(next_owner_change(&mut stream).await?, (b, String::new()));
+        assert_eq!(
+            next_owner_change(&mut stream).await?,
+            (String::new(), c.clone())
+        );
+        let owner = observer_proxy.get_name_owner(name.clone().into()).await?;
+        ensure!(owner.as_str() == c, "unexpected owner `{owner}`");
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    handle.shutdown().await.unwrap();
+    ret.unwrap();
+}
+
+// The old and new owner in the next `NameOwnerChanged` of `stream`.
+async fn next_owner_change(stream: &mut MessageStream) -> anyhow::Result<(String, String)> {
+    while let Some(msg) = stream.next().await {
+        let msg = msg?;
+        if msg.member().as_deref() == Some("NameOwnerChanged") {
+            let (_, old_owner, new_owner): (String, String, String) = msg.body()?;
+
+            return Ok((old_owner, new_owner));
+        }
+    }
+
+    anyhow::bail!("stream ended")
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn process_id() {
+    use nix::{
+        sys::{
+            socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags},
+            wait::waitpid,
+        },
+        unistd::{fork, ForkResult},
+    };
+    use std::{
+        io::{IoSlice, IoSliceMut},
+        os::unix::{
+            io::{AsRawFd, FromRawFd, RawFd},
+            net::UnixStream,
+        },
+    };
+
+    busd::tracing_subscriber::init();
+
+    let s = Alphanumeric.sample_string(&mut thread_rng(), 10);
+    let path = temp_dir().join(s);
+    let address = format!("unix:path={}", path.display());
+    // Any free port.
+    let tcp_address = "tcp:host=127.0.0.1,port=0";
+    let handle = BusBuilder::new()
+        .address(&address)
+        .listen_on(tcp_address, AuthMechanism::Anonymous)
+        .build()
+        .await
+        .unwrap()
+        .spawn();
+    let tcp_address = common::tcp_address(handle.address());
+
+    let ret = async {
+        // A child process connects and hands the connection over to us before exiting.
+        let (ours, theirs) = UnixStream::pair()?;
+        let child = match unsafe { fork() }? {
+            ForkResult::Child => {
+                let sent = match UnixStream::connect(&path) {
+                    Ok(stream) => sendmsg::<()>(
+                        theirs.as_raw_fd(),
+                        &[IoSlice::new(b"\0")],
+                        &[ControlMessage::ScmRights(&[stream.as_raw_fd()])],
+                        MsgFlags::empty(),
+                        None,
+                    )
+                    .is_ok(),
+                    Err(_) => false,
+                };
+                unsafe { nix::libc::_exit(if sent { 0 } else { 1 }) };
+            }
+            ForkResult::Parent { child } => child,
+        };
+        let mut byte = [0];
+        let mut cmsg = nix::cmsg_space!(RawFd);
+        let msg = recvmsg::<()>(
+            ours.as_raw_fd(),
+            &mut [IoSliceMut::new(&mut byte)],
+            Some(&mut cmsg),
+            MsgFlags::empty(),
+        )?;
+        let fd = match msg.cmsgs().next() {
+            Some(ControlMessageOwned::ScmRights(fds)) if fds.len() == 1 => fds[0],
+            cmsg => anyhow::bail!("unexpected control message: {cmsg:?}"),
+        };
+        waitpid(child, None)?;
+
+        let stream = unsafe { UnixStream::from_raw_fd(fd) };
+        stream.set_nonblocking(true)?;
+        let conn = ConnectionBuilder::socket(tokio::net::UnixStream::from_std(stream)?)
+            .build()
+            .await?;
+        let pid: u32 = conn
+            .call_method(
+                Some("org.freedesktop.DBus"),
+                "/org/freedesktop/DBus",
+                Some("org.freedesktop.DBus"),
+                "GetConnectionUnixProcessID",
+                &(conn.unique_name().unwrap().as_str(),),
+            )
+            .await?
+            .body()?;
+        ensure!(
+            i32::try_from(pid)? == child.as_raw(),
+            "unexpected process ID {pid}, not the one of the child that connected"
+        );
+
+        // There's no process to tell over TCP.
+        let tcp_conn = ConnectionBuilder::address(&*tcp_address)?
+            .auth_mechanisms(&[AuthMechanism::Anonymous])
+            .build()
+            .await?;
+        let res = tcp_conn
+            .call_method(
+                Some("org.freedesktop.DBus"),
+                "/org/freedesktop/DBus",
+                Some("org.freedesktop.DBus"),
+                "GetConnectionUnixProcessID",
+                &(tcp_conn.unique_name().unwrap().as_str(),),
+            )
+            .await;
+        match res {
+            Err(zbus::Error::MethodError(name, _, _)) => ensure!(
+                name.as_str() == "org.freedesktop.DBus.Error.UnixProcessIdUnknown",
+                "unexpected error `{name}`"
+            ),
+            res => anyhow::bail!("unexpected result over TCP: {res:?}"),
+        }
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    handle.shutdown().await.unwrap();
+    ret.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn expanded_unix_path() {
+    busd::tracing_subscriber::init();
+
+    std::env::set_var("BUSD_TEST_EXPANDED_DIR", temp_dir());
+    let s = Alphanumeric.sample_string(&mut thread_rng(), 10);
+    let bus = Bus::for_address(
+        Some(&format!("unix:path=${{BUSD_TEST_EXPANDED_DIR}}/{s}")),
+        AuthMechanism::External,
+    )
+    .await
+    .unwrap();
+    let path = temp_dir().join(&s);
+    // Recorded expanded, for clients and for cleaning up.
+    assert_eq!(
+        bus.address(),
+        format!("unix:path={},guid={}", path.display(), bus.guid())
+    );
+    assert!(path.exists());
+    bus.cleanup().await.unwrap();
+    assert!(!path.exists());
+
+    match Bus::for_address(
+        Some("unix:path=$BUSD_TEST_UNSET_DIR/bus"),
+        AuthMechanism::External,
+    )
+    .await
+    {
+        Err(busd::error::BusError::Address { .. }) => (),
+        res => panic!("unexpected result for an unset variable: {res:?}"),
+    }
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn address_list() {
+    busd::tracing_subscriber::init();
+
+    let s = Alphanumeric.sample_string(&mut thread_rng(), 10);
+    let path = temp_dir().join(s);
+    let unix_address = format!("unix:path={}", path.display());
+    // Any free port.
+    let tcp_address = "tcp:host=127.0.0.1,port=0";
+    let handle = BusBuilder::new()
+        .address(&format!("{unix_address};{tcp_address};"))
+        .auth_mechanism(AuthMechanism::Anonymous)
+        .build()
+        .await
+        .unwrap()
+        .spawn();
+    let tcp_address = common::tcp_address(handle.address());
+    assert_eq!(
+        handle.address(),
+        format!("{unix_address},guid={};{tcp_address}", handle.guid())
+    );
+
+    let ret = async {
+        for address in [unix_address.as_str(), tcp_address.as_str()] {
+            let conn = ConnectionBuilder::address(address)?
+                .auth_mechanisms(&[AuthMechanism::Anonymous])
+                .build()
+                .await?;
+            ensure!(
+                conn.unique_name().is_some(),
+                "no unique name assigned through `{address}`"
+            );
+        }
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    handle.shutdown().await.unwrap();
+    assert!(!path.exists());
+    ret.unwrap();
+
+    // Escaped, a semicolon is part of the path rather than a separator.
+    let s = Alphanumeric.sample_string(&mut thread_rng(), 10);
+    let path = temp_dir().join(format!("{s};bus"));
+    let bus = Bus::for_address(
+        Some(&format!("unix:path={}/{s}%3bbus", temp_dir().display())),
+        AuthMechanism::External,
+    )
+    .await
+    .unwrap();
+    assert!(path.exists());
+    bus.cleanup().await.unwrap();
+    assert!(!path.exists());
+}
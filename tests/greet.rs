@@ -161,12 +161,35 @@ async fn greet_client(socket_addr: &str) -> anyhow::Result<()> {
         .build();
     DBusProxy::new(&conn)
         .await?
-        .add_match_rule(match_rule)
+        .add_match_rule(match_rule.clone())
         .await?;
     let _ = proxy.say_hello("Maria").await?;
     let signal = msg_stream.next().await.unwrap();
     let args = signal.args()?;
     assert_eq!(args.name, "Maria");
 
+    // A rule with a destination shouldn't match broadcast signals. Signals come in order, so
+    // the next one being the one matching another rule tells that the first didn't match.
+    let dbus_proxy = DBusProxy::new(&conn).await?;
+    dbus_proxy.remove_match_rule(match_rule).await?;
+    let unique_name = conn.unique_name().unwrap().to_owned();
+    let match_rule = MatchRule::builder()
+        .interface("org.zbus.MyGreeter1")?
+        .member("Greeted")?
+        .destination(unique_name.as_str())?
+        .build();
+    dbus_proxy.add_match_rule(match_rule).await?;
+    let match_rule = MatchRule::builder()
+        .interface("org.zbus.MyGreeter1")?
+        .member("Greeted")?
+        .add_arg("Joao")?
+        .build();
+    dbus_proxy.add_match_rule(match_rule).await?;
+    let _ = proxy.say_hello("Maria").await?;
+    let _ = proxy.say_hello("Joao").await?;
+    let signal = msg_stream.next().await.unwrap();
+    let args = signal.args()?;
+    assert_eq!(args.name, "Joao");
+
     Ok(())
 }
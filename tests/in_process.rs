@@ -0,0 +1,318 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::{env::temp_dir, time::Duration};
+
+use anyhow::ensure;
+use busd::{
+    bus::{Bus, MEMORY_ADDRESS},
+    bus_builder::BusBuilder,
+    error::BusError,
+    peers::LIFECYCLE_INTERFACE,
+};
+use futures_util::stream::StreamExt;
+use ntest::timeout;
+use rand::{
+    distributions::{Alphanumeric, DistString},
+    thread_rng,
+};
+use tracing::instrument;
+use zbus::{
+    dbus_interface,
+    fdo::{self, DBusProxy},
+    names::WellKnownName,
+    AuthMechanism, CacheProperties, Connection, MatchRule, MessageStream, MessageType,
+};
+
+struct Greeter;
+
+#[dbus_interface(name = "org.busd.Greeter1")]
+impl Greeter {
+    fn greet(&self, name: &str) -> String {
+        format!("Hello {name}!")
+    }
+
+    async fn slow_greet(&self, name: &str) -> String {
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        format!("Hello {name}!")
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn in_process_service() {
+    busd::tracing_subscriber::init();
+
+    let bus = Bus::for_address(Some(MEMORY_ADDRESS), AuthMechanism::External)
+        .await
+        .unwrap();
+    let connector = bus.memory_connector().unwrap();
+    let service = bus
+        .serve_at("org.busd.Greeter", "/org/busd/Greeter", Greeter)
+        .await
+        .unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let conn = connector.connect().await?;
+        let reply = conn
+            .call_method(
+                Some("org.busd.Greeter"),
+                "/org/busd/Greeter",
+                Some("org.busd.Greeter1"),
+                "Greet",
+                &("Maria",),
+            )
+            .await?;
+        let greeting: String = reply.body()?;
+        ensure!(greeting == "Hello Maria!", "unexpected reply: {greeting}");
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    drop(service);
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn replies_with_overlapping_serials() {
+    busd::tracing_subscriber::init();
+
+    let bus = Bus::for_address(Some(MEMORY_ADDRESS), AuthMechanism::External)
+        .await
+        .unwrap();
+    let connector = bus.memory_connector().unwrap();
+    let service = bus
+        .serve_at("org.busd.Greeter", "/org/busd/Greeter", Greeter)
+        .await
+        .unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        // Both connections are fresh so their calls get the same serial, and the calls overlap.
+        let conn1 = connector.connect().await?;
+        let conn2 = connector.connect().await?;
+        let (greeting1, greeting2) =
+            futures_util::try_join!(slow_greet(&conn1, "Maria"), slow_greet(&conn2, "Zeeshan"))?;
+        ensure!(greeting1 == "Hello Maria!", "unexpected reply: {greeting1}");
+        ensure!(
+            greeting2 == "Hello Zeeshan!",
+            "unexpected reply: {greeting2}"
+        );
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    drop(service);
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+async fn slow_greet(conn: &Connection, name: &str) -> anyhow::Result<String> {
+    let reply = conn
+        .call_method(
+            Some("org.busd.Greeter"),
+            "/org/busd/Greeter",
+            Some("org.busd.Greeter1"),
+            "SlowGreet",
+            &(name,),
+        )
+        .await?;
+
+    reply.body().map_err(Into::into)
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn ready() {
+    busd::tracing_subscriber::init();
+
+    let bus = Bus::for_address(Some(MEMORY_ADDRESS), AuthMechanism::External)
+        .await
+        .unwrap();
+    let connector = bus.memory_connector().unwrap();
+    // Not accepting connections before it runs.
+    assert!(
+        tokio::time::timeout(Duration::from_millis(100), bus.ready())
+            .await
+            .is_err()
+    );
+    let ready = bus.ready();
+    let (bus, ret) = common::run_until(bus, async {
+        ready.await;
+        connector.connect().await
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn spawn() {
+    busd::tracing_subscriber::init();
+
+    let bus = Bus::for_address(Some(MEMORY_ADDRESS), AuthMechanism::External)
+        .await
+        .unwrap();
+    let guid = bus.guid().clone();
+    let handle = bus.spawn();
+    assert_eq!(handle.address(), MEMORY_ADDRESS);
+
+    let conn = handle.memory_connector().unwrap().connect().await.unwrap();
+    let id = DBusProxy::builder(&conn)
+        .cache_properties(CacheProperties::No)
+        .build()
+        .await
+        .unwrap()
+        .get_id()
+        .await
+        .unwrap();
+    assert_eq!(id, guid.as_str());
+    handle.shutdown().await.unwrap();
+
+    // Dropping the handle stops the bus as well, cleaning up after it.
+    let s = Alphanumeric.sample_string(&mut thread_rng(), 10);
+    let path = temp_dir().join(s);
+    let address = format!("unix:path={}", path.display());
+    let handle = Bus::for_address(Some(&address), AuthMechanism::External)
+        .await
+        .unwrap()
+        .spawn();
+    assert!(path.exists());
+    drop(handle);
+    while path.exists() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn reserved_names() {
+    busd::tracing_subscriber::init();
+
+    let greeter: WellKnownName = "org.busd.Greeter".try_into().unwrap();
+    let unclaimed: WellKnownName = "org.busd.Reserved".try_into().unwrap();
+    let mut bus = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .reserve_name(greeter.clone())
+        .reserve_name(unclaimed.clone())
+        .build()
+        .await
+        .unwrap();
+    // The bus' own services can still own them.
+    let service = bus
+        .serve_at(greeter.as_str(), "/org/busd/Greeter", Greeter)
+        .await
+        .unwrap();
+    let handle = bus.spawn();
+
+    let ret = async {
+        let conn = handle.memory_connector().unwrap().connect().await?;
+        let proxy = DBusProxy::builder(&conn)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?;
+        for name in [&greeter, &unclaimed] {
+            match proxy.request_name(name.clone(), Default::default()).await {
+                Err(fdo::Error::AccessDenied(_)) => (),
+                res => anyhow::bail!("unexpected reply to requesting `{name}`: {res:?}"),
+            }
+        }
+        let owner = proxy.get_name_owner(greeter.clone().into()).await?;
+        ensure!(
+            owner.as_str() == service.unique_name().unwrap().as_str(),
+            "`{greeter}` is owned by `{owner}`"
+        );
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    handle.shutdown().await.unwrap();
+    drop(service);
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn lifecycle_signals() {
+    busd::tracing_subscriber::init();
+
+    let mut bus = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .lifecycle_signals(true)
+        .build()
+        .await
+        .unwrap();
+    // In-process services are the only peers connected before the bus starts.
+    let service = bus
+        .serve_at("org.busd.Greeter", "/org/busd/Greeter", Greeter)
+        .await
+        .unwrap();
+    let rule = MatchRule::builder()
+        .msg_type(MessageType::Signal)
+        .interface(LIFECYCLE_INTERFACE)
+        .unwrap()
+        .build();
+    DBusProxy::builder(&service)
+        .cache_properties(CacheProperties::No)
+        .build()
+        .await
+        .unwrap()
+        .add_match_rule(rule)
+        .await
+        .unwrap();
+    // Boxed so that it can be moved to a task once pinned.
+    let mut stream = Box::pin(MessageStream::from(&service).filter_map(|msg| async {
+        let msg = msg.ok()?;
+        let header = msg.header().ok()?;
+        match header.interface().ok()?? {
+            iface if iface.as_str() == LIFECYCLE_INTERFACE => {
+                Some(header.member().ok()??.to_string())
+            }
+            _ => None,
+        }
+    }));
+    let handle = bus.spawn();
+
+    assert_eq!(stream.next().await.unwrap(), "Starting");
+    let shutting_down = tokio::spawn(async move { stream.next().await });
+    handle.shutdown().await.unwrap();
+    assert_eq!(shutting_down.await.unwrap().unwrap(), "ShuttingDown");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn errors() {
+    busd::tracing_subscriber::init();
+
+    for address in [
+        "bananas:",
+        "tcp:port=4242",
+        "tcp:host=localhost,port=bananas",
+    ] {
+        match Bus::for_address(Some(address), AuthMechanism::Anonymous).await {
+            Err(BusError::Address { address: a, .. }) => assert_eq!(a, address),
+            res => panic!("unexpected result for `{address}`: {res:?}"),
+        }
+    }
+
+    // Taken by another bus.
+    let bus = Bus::for_address(Some("tcp:host=127.0.0.1"), AuthMechanism::Anonymous)
+        .await
+        .unwrap();
+    let port = common::tcp_port(bus.address());
+    let address = format!("tcp:host=127.0.0.1,port={port}");
+    match Bus::for_address(Some(&address), AuthMechanism::Anonymous).await {
+        Err(BusError::Bind { address: a, .. }) => assert_eq!(a, address),
+        res => panic!("unexpected result for `{address}`: {res:?}"),
+    }
+    bus.cleanup().await.unwrap();
+}
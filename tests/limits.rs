@@ -0,0 +1,694 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::ensure;
+use busd::{
+    bus::MEMORY_ADDRESS, bus_builder::BusBuilder, event::BusEvent, limits::Limit,
+    rate_limiter::RateLimit,
+};
+use futures_util::{future::join_all, stream::StreamExt};
+use ntest::timeout;
+use tokio::time::timeout as tokio_timeout;
+use tracing::instrument;
+use zbus::{
+    dbus_interface,
+    fdo::{self, DBusProxy},
+    names::BusName,
+    zvariant::{OwnedValue, Value},
+    CacheProperties, MatchRule, Message, MessageBuilder, MessageStream, MessageType,
+};
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn max_completed_connections() {
+    busd::tracing_subscriber::init();
+
+    let bus = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .max_completed_connections(1)
+        .build()
+        .await
+        .unwrap();
+    let connector = bus.memory_connector().unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let conn = connector.connect().await?;
+
+        // The bus is full so the second connection should have to wait for the first one to go.
+        let connector2 = connector.clone();
+        let mut pending = tokio::spawn(async move { connector2.connect().await });
+        ensure!(
+            tokio_timeout(Duration::from_millis(100), &mut pending)
+                .await
+                .is_err(),
+            "connection accepted beyond the limit"
+        );
+        drop(conn);
+        let _conn2 = pending.await??;
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+struct Service;
+
+#[dbus_interface(name = "org.busd.Service")]
+impl Service {
+    fn ping(&self) {}
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn max_completed_connections_in_process() {
+    busd::tracing_subscriber::init();
+
+    let mut bus = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .max_completed_connections(1)
+        .build()
+        .await
+        .unwrap();
+    // Takes the only slot.
+    let service = bus
+        .serve_at("org.busd.Service", "/org/busd/Service", Service)
+        .await
+        .unwrap();
+    let connector = bus.memory_connector().unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let connector = connector.clone();
+        let mut pending = tokio::spawn(async move { connector.connect().await });
+        ensure!(
+            tokio_timeout(Duration::from_millis(100), &mut pending)
+                .await
+                .is_err(),
+            "connection accepted beyond the limit"
+        );
+        drop(service);
+        let _conn = pending.await??;
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn sender_rate_limit() {
+    busd::tracing_subscriber::init();
+
+    let bus = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .sender_rate_limit(RateLimit {
+            messages_per_second: 10,
+            burst: 1,
+        })
+        .build()
+        .await
+        .unwrap();
+    let connector = bus.memory_connector().unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let receiver = connector.connect().await?;
+        let rule = MatchRule::builder().member("Flood")?.build();
+        DBusProxy::new(&receiver)
+            .await?
+            .add_match_rule(rule)
+            .await?;
+        let mut stream = MessageStream::from(&receiver);
+
+        let sender = connector.connect().await?;
+        for _ in 0..5 {
+            sender
+                .emit_signal(
+                    None::<BusName<'_>>,
+                    "/org/busd/Flood",
+                    "org.busd.Flood",
+                    "Flood",
+                    &(),
+                )
+                .await?;
+        }
+
+        // The signals trickle in at the limited rate.
+        let mut first = None;
+        let mut received = 0;
+        while let Some(msg) = stream.next().await {
+            if msg?.member().as_deref() != Some("Flood") {
+                continue;
+            }
+            first.get_or_insert_with(Instant::now);
+            received += 1;
+            if received == 5 {
+                break;
+            }
+        }
+        let elapsed = first.unwrap().elapsed();
+        ensure!(
+            elapsed >= Duration::from_millis(300),
+            "signals delivered too fast: {elapsed:?}"
+        );
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn sender_throttle_timeout() {
+    busd::tracing_subscriber::init();
+
+    let handle = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .sender_rate_limit(RateLimit {
+            messages_per_second: 10,
+            burst: 1,
+        })
+        .sender_throttle_timeout(Duration::from_millis(300))
+        .build()
+        .await
+        .unwrap()
+        .spawn();
+    let mut events = handle.event_stream();
+
+    let ret = async {
+        let sender = handle.memory_connector().unwrap().connect().await?;
+        let unique_name = sender.unique_name().unwrap().to_owned();
+
+        // Held back on every message, for far longer than the timeout all together.
+        let flood = tokio::spawn(async move {
+            for _ in 0..50 {
+                sender
+                    .emit_signal(
+                        None::<BusName<'_>>,
+                        "/org/busd/Flood",
+                        "org.busd.Flood",
+                        "Flood",
+                        &(),
+                    )
+                    .await?;
+            }
+
+            Ok::<_, zbus::Error>(())
+        });
+        while let Some(event) = events.next().await {
+            if event == BusEvent::PeerDisconnected(unique_name.clone()) {
+                break;
+            }
+        }
+        flood.abort();
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    handle.shutdown().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn set_limit_at_runtime() {
+    busd::tracing_subscriber::init();
+
+    let bus = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .build()
+        .await
+        .unwrap();
+    let connector = bus.memory_connector().unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let conn = connector.connect().await?;
+        let proxy = DBusProxy::new(&conn).await?;
+        let rule = |member: &'static str| MatchRule::builder().member(member).unwrap().build();
+        proxy.add_match_rule(rule("One")).await?;
+        proxy.add_match_rule(rule("Two")).await?;
+
+        // Through the memory transport, we run as the same user as the bus.
+        let set_limit = |name: &'static str, value: u64| {
+            let conn = conn.clone();
+            async move {
+                conn.call_method(
+                    Some("org.freedesktop.DBus"),
+                    "/org/freedesktop/DBus",
+                    Some("org.busd.Limits"),
+                    "SetLimit",
+                    &(name, value),
+                )
+                .await
+                .map_err(fdo::Error::from)
+            }
+        };
+        set_limit("max_match_rules_per_connection", 1).await?;
+        match set_limit("max_bananas", 1).await {
+            Err(fdo::Error::InvalidArgs(_)) => (),
+            res => panic!("unexpected result for an unknown limit: {res:?}"),
+        }
+
+        // Existing rules are kept, but no more can be added until we're below the limit.
+        match proxy.add_match_rule(rule("Three")).await {
+            Err(fdo::Error::LimitsExceeded(_)) => (),
+            res => panic!("expected the limit to apply, got {res:?}"),
+        }
+        proxy.remove_match_rule(rule("One")).await?;
+        match proxy.add_match_rule(rule("Three")).await {
+            Err(fdo::Error::LimitsExceeded(_)) => (),
+            res => panic!("expected the limit to apply, got {res:?}"),
+        }
+        proxy.remove_match_rule(rule("Two")).await?;
+        proxy.add_match_rule(rule("Three")).await?;
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    // The change is visible, and can be made, through the API too.
+    assert_eq!(bus.limits().max_match_rules_per_connection, 1);
+    bus.set_limit(Limit::MaxMatchRulesPerConnection, 10);
+    assert_eq!(bus.limits().max_match_rules_per_connection, 10);
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn get_limits() {
+    busd::tracing_subscriber::init();
+
+    let bus = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .max_match_rules_per_connection(42)
+        .build()
+        .await
+        .unwrap();
+    assert_eq!(bus.limits().max_match_rules_per_connection, 42);
+    assert_eq!(bus.limits().sender_rate_limit, None);
+    let connector = bus.memory_connector().unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let conn = connector.connect().await?;
+        let reply = conn
+            .call_method(
+                Some("org.freedesktop.DBus"),
+                "/org/freedesktop/DBus",
+                Some("org.busd.Limits"),
+                "GetLimits",
+                &(),
+            )
+            .await?;
+        let limits: HashMap<String, OwnedValue> = reply.body()?;
+        let max = limits
+            .get("max_match_rules_per_connection")
+            .and_then(|value| u64::try_from(Value::clone(value)).ok());
+        ensure!(max == Some(42), "unexpected limits: {limits:?}");
+        ensure!(
+            !limits.contains_key("sender_messages_per_second"),
+            "unexpected limits: {limits:?}"
+        );
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn max_match_rules() {
+    busd::tracing_subscriber::init();
+
+    let handle = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .max_match_rules(2)
+        .build()
+        .await
+        .unwrap()
+        .spawn();
+
+    let ret = async {
+        let connector = handle.memory_connector().unwrap();
+        let rule = |member: &'static str| MatchRule::builder().member(member).unwrap().build();
+        let mut proxies = vec![];
+        for _ in 0..2 {
+            let conn = connector.connect().await?;
+            let proxy = DBusProxy::builder(&conn)
+                .cache_properties(CacheProperties::No)
+                .build()
+                .await?;
+            proxies.push(proxy);
+        }
+        proxies[0].add_match_rule(rule("One")).await?;
+        proxies[0].add_match_rule(rule("Two")).await?;
+
+        // Whatever the number of rules of the peer adding it.
+        match proxies[1].add_match_rule(rule("Three")).await {
+            Err(fdo::Error::LimitsExceeded(_)) => (),
+            res => anyhow::bail!("unexpected result over the limit: {res:?}"),
+        }
+        // Adding a rule the peer already has doesn't count.
+        proxies[0].add_match_rule(rule("One")).await?;
+
+        // Removed rules stop counting.
+        proxies[0].remove_match_rule(rule("Two")).await?;
+        proxies[1].add_match_rule(rule("Three")).await?;
+
+        // And so do the rules of peers that disconnected, once the bus notices.
+        drop(proxies.remove(0));
+        loop {
+            match proxies[0].add_match_rule(rule("Four")).await {
+                Ok(()) => break,
+                Err(fdo::Error::LimitsExceeded(_)) => {
+                    tokio::time::sleep(Duration::from_millis(10)).await
+                }
+                res => anyhow::bail!("unexpected result after disconnecting: {res:?}"),
+            }
+        }
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    handle.shutdown().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn max_replies_per_connection() {
+    busd::tracing_subscriber::init();
+
+    let handle = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .max_replies_per_connection(1)
+        .build()
+        .await
+        .unwrap()
+        .spawn();
+
+    let ret = async {
+        let connector = handle.memory_connector().unwrap();
+        // Never replying on its own.
+        let service = connector.connect().await?;
+        let mut stream = MessageStream::from(&service);
+        let service_name = service.unique_name().unwrap().to_owned();
+        let client = connector.connect().await?;
+        let call = |member: &'static str| {
+            let client = client.clone();
+            let service_name = service_name.clone();
+            async move {
+                client
+                    .call_method(
+                        Some(service_name.as_str()),
+                        "/org/busd/Limits",
+                        Some("org.busd.Limits"),
+                        member,
+                        &(),
+                    )
+                    .await
+                    .map_err(fdo::Error::from)
+            }
+        };
+
+        let pending = tokio::spawn(call("One"));
+        let one = loop {
+            let msg = stream.next().await.unwrap()?;
+            if msg.message_type() == MessageType::MethodCall
+                && msg.member().as_deref() == Some("One")
+            {
+                break msg;
+            }
+        };
+        match call("Two").await {
+            Err(fdo::Error::LimitsExceeded(_)) => (),
+            res => anyhow::bail!("unexpected result over the limit: {res:?}"),
+        }
+
+        // Replied calls stop counting.
+        let reply = MessageBuilder::method_return(&one.header()?)?.build(&())?;
+        service.send_message(reply).await?;
+        pending.await??;
+        let pending = tokio::spawn(call("Three"));
+        let three = loop {
+            let msg = stream.next().await.unwrap()?;
+            if msg.message_type() == MessageType::MethodCall
+                && msg.member().as_deref() == Some("Three")
+            {
+                break msg;
+            }
+        };
+        let reply = MessageBuilder::method_return(&three.header()?)?.build(&())?;
+        service.send_message(reply).await?;
+        pending.await??;
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    handle.shutdown().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn reply_timeout() {
+    busd::tracing_subscriber::init();
+
+    let handle = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .max_replies_per_connection(1)
+        .reply_timeout(Duration::from_millis(200))
+        .build()
+        .await
+        .unwrap()
+        .spawn();
+
+    let ret = async {
+        let connector = handle.memory_connector().unwrap();
+        // Never replying on its own.
+        let service = connector.connect().await?;
+        let mut stream = MessageStream::from(&service);
+        let service_name = service.unique_name().unwrap().to_owned();
+        let client = connector.connect().await?;
+        let call = |member: &'static str| {
+            let client = client.clone();
+            let service_name = service_name.clone();
+            async move {
+                client
+                    .call_method(
+                        Some(service_name.as_str()),
+                        "/org/busd/Limits",
+                        Some("org.busd.Limits"),
+                        member,
+                        &(),
+                    )
+                    .await
+                    .map_err(fdo::Error::from)
+            }
+        };
+        async fn next_call(stream: &mut MessageStream) -> zbus::Result<Arc<Message>> {
+            loop {
+                let msg = stream.next().await.unwrap()?;
+                if msg.message_type() == MessageType::MethodCall
+                    && msg.interface().as_deref() == Some("org.busd.Limits")
+                {
+                    return Ok(msg);
+                }
+            }
+        }
+
+        // Never replied to.
+        let abandoned = tokio::spawn(call("One"));
+        next_call(&mut stream).await?;
+
+        // Once it timed out, it doesn't count against the limit anymore.
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        let pending = tokio::spawn(call("Two"));
+        let two = next_call(&mut stream).await?;
+        ensure!(
+            two.member().as_deref() == Some("Two"),
+            "unexpected call {:?}",
+            two.member()
+        );
+        let reply = MessageBuilder::method_return(&two.header()?)?.build(&())?;
+        service.send_message(reply).await?;
+        pending.await??;
+        abandoned.abort();
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    handle.shutdown().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn set_limit_during_requests() {
+    busd::tracing_subscriber::init();
+
+    let handle = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .build()
+        .await
+        .unwrap()
+        .spawn();
+
+    let ret = async {
+        let connector = handle.memory_connector().unwrap();
+        let mut proxies = vec![];
+        for _ in 0..20 {
+            let conn = connector.connect().await?;
+            let proxy = DBusProxy::builder(&conn)
+                .cache_properties(CacheProperties::No)
+                .build()
+                .await?;
+            proxies.push(proxy);
+        }
+
+        // Through the memory transport, we run as the same user as the bus.
+        let admin = connector.connect().await?;
+        let (done_tx, mut done_rx) = tokio::sync::oneshot::channel::<()>();
+        let toggle = tokio::spawn(async move {
+            for max in [1u64, 1000].into_iter().cycle() {
+                admin
+                    .call_method(
+                        Some("org.freedesktop.DBus"),
+                        "/org/freedesktop/DBus",
+                        Some("org.busd.Limits"),
+                        "SetLimit",
+                        &("max_queued_owners_per_name", max),
+                    )
+                    .await?;
+                if done_rx.try_recv().is_ok() {
+                    break;
+                }
+            }
+
+            Ok::<_, zbus::Error>(())
+        });
+
+        let replies = join_all(proxies.iter().map(|proxy| {
+            proxy.request_name("org.busd.Burst".try_into().unwrap(), Default::default())
+        }))
+        .await;
+        let _ = done_tx.send(());
+        toggle.await??;
+        let mut queued = 0;
+        for reply in replies {
+            match reply {
+                Ok(fdo::RequestNameReply::PrimaryOwner) => (),
+                Ok(fdo::RequestNameReply::InQueue) => queued += 1,
+                // Requests seeing the lower limit.
+                Err(fdo::Error::LimitsExceeded(_)) => (),
+                reply => anyhow::bail!("unexpected reply: {reply:?}"),
+            }
+        }
+        let owners = proxies[0]
+            .list_queued_owners("org.busd.Burst".try_into()?)
+            .await?;
+        ensure!(
+            owners.len() == queued + 1,
+            "{} owners queued, but {queued} requests were queued",
+            owners.len() - 1
+        );
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    handle.shutdown().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn max_routing_backlog() {
+    busd::tracing_subscriber::init();
+
+    let handle = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .max_routing_backlog(1)
+        .build()
+        .await
+        .unwrap()
+        .spawn();
+
+    let ret = async {
+        let connector = handle.memory_connector().unwrap();
+        let subscriber = connector.connect().await?;
+        let mut stream = MessageStream::from(&subscriber);
+        DBusProxy::builder(&subscriber)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?
+            .add_match_rule(MatchRule::builder().member("Tick")?.build())
+            .await?;
+        let mut emitters = vec![];
+        for _ in 0..10 {
+            emitters.push(connector.connect().await?);
+        }
+
+        // Bursts from all the emitters at once keep the backlog at its limit, but all go through
+        // as the subscriber reads, even though it isn't read from while paused.
+        let emitted = join_all(emitters.iter().map(|emitter| async move {
+            for _ in 0..50 {
+                emitter
+                    .emit_signal(
+                        None::<BusName<'_>>,
+                        "/org/busd/Test",
+                        "org.busd.Test",
+                        "Tick",
+                        &(),
+                    )
+                    .await?;
+            }
+
+            Ok::<_, zbus::Error>(())
+        }));
+        let received = async {
+            let mut ticks = 0;
+            while ticks < 500 {
+                let msg = stream.next().await.unwrap()?;
+                if msg.member().as_deref() == Some("Tick") {
+                    ticks += 1;
+                }
+            }
+
+            Ok::<_, zbus::Error>(())
+        };
+        let (emitted, received) = tokio::join!(emitted, received);
+        for res in emitted {
+            res?;
+        }
+        received?;
+        DBusProxy::builder(&subscriber)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?
+            .get_id()
+            .await?;
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    handle.shutdown().await.unwrap();
+    ret.unwrap();
+}
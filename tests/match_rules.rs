@@ -0,0 +1,463 @@
+#![cfg(unix)]
+
+mod common;
+
+use busd::{
+    bus::{Bus, MEMORY_ADDRESS},
+    bus_builder::BusBuilder,
+};
+use futures_util::stream::StreamExt;
+use ntest::timeout;
+use tracing::instrument;
+use zbus::{
+    fdo::{self, DBusProxy},
+    names::{BusName, WellKnownName},
+    AuthMechanism, CacheProperties, MatchRule, MessageBuilder, MessageStream,
+};
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn arg_matching_only_matches_strings() {
+    busd::tracing_subscriber::init();
+
+    let bus = Bus::for_address(Some(MEMORY_ADDRESS), AuthMechanism::External)
+        .await
+        .unwrap();
+    let connector = bus.memory_connector().unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let listener = connector.connect().await?;
+        let mut stream = MessageStream::from(&listener);
+        let rule = MatchRule::builder()
+            .interface("org.busd.MatchRules")?
+            .member("Signal")?
+            .add_arg("42")?
+            .build();
+        DBusProxy::new(&listener)
+            .await?
+            .add_match_rule(rule)
+            .await?;
+
+        let emitter = connector.connect().await?;
+        // Neither an integer arg0, nor a missing one, should match.
+        emitter
+            .emit_signal(
+                None::<BusName<'_>>,
+                "/org/busd/MatchRules",
+                "org.busd.MatchRules",
+                "Signal",
+                &(42i32,),
+            )
+            .await?;
+        emitter
+            .emit_signal(
+                None::<BusName<'_>>,
+                "/org/busd/MatchRules",
+                "org.busd.MatchRules",
+                "Signal",
+                &(),
+            )
+            .await?;
+        emitter
+            .emit_signal(
+                None::<BusName<'_>>,
+                "/org/busd/MatchRules",
+                "org.busd.MatchRules",
+                "Signal",
+                &("42",),
+            )
+            .await?;
+
+        // Signals are delivered in order so the first one we get must be the string one.
+        loop {
+            let msg = stream.next().await.unwrap()?;
+            if msg.member().as_deref() != Some("Signal") {
+                continue;
+            }
+            let (arg0,): (String,) = msg.body()?;
+            assert_eq!(arg0, "42");
+
+            break;
+        }
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn bus_signals_sender() {
+    busd::tracing_subscriber::init();
+
+    let bus = Bus::for_address(Some(MEMORY_ADDRESS), AuthMechanism::External)
+        .await
+        .unwrap();
+    let connector = bus.memory_connector().unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let name: WellKnownName = "org.busd.BusSignals".try_into()?;
+        let listener = connector.connect().await?;
+        let mut stream = MessageStream::from(&listener);
+        let rule = MatchRule::builder()
+            .sender("org.freedesktop.DBus")?
+            .interface("org.freedesktop.DBus")?
+            .member("NameOwnerChanged")?
+            .add_arg(name.as_str())?
+            .build();
+        DBusProxy::new(&listener)
+            .await?
+            .add_match_rule(rule)
+            .await?;
+
+        // A peer pretending to be the bus shouldn't match.
+        let owner = connector.connect().await?;
+        owner
+            .emit_signal(
+                None::<BusName<'_>>,
+                "/org/freedesktop/DBus",
+                "org.freedesktop.DBus",
+                "NameOwnerChanged",
+                &(name.as_str(), "", ":busd.fake"),
+            )
+            .await?;
+        DBusProxy::builder(&owner)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?
+            .request_name(name.clone(), Default::default())
+            .await?;
+
+        loop {
+            let msg = stream.next().await.unwrap()?;
+            if msg.member().as_deref() != Some("NameOwnerChanged") {
+                continue;
+            }
+            let hdr = msg.header()?;
+            assert_eq!(hdr.sender()?.unwrap().as_str(), "org.freedesktop.DBus");
+            let (arg0, old_owner, new_owner): (String, String, String) = msg.body()?;
+            assert_eq!(arg0, name.as_str());
+            assert_eq!(old_owner, "");
+            assert_eq!(new_owner, owner.unique_name().unwrap().as_str());
+
+            break;
+        }
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn forged_bus_signals() {
+    busd::tracing_subscriber::init();
+
+    let bus = Bus::for_address(Some(MEMORY_ADDRESS), AuthMechanism::External)
+        .await
+        .unwrap();
+    let connector = bus.memory_connector().unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let name: WellKnownName = "org.busd.Forged".try_into()?;
+        let listener = connector.connect().await?;
+        let forger = connector.connect().await?;
+        let forger_name = forger.unique_name().unwrap().to_owned();
+        let mut stream = MessageStream::from(&listener);
+        let proxy = DBusProxy::new(&listener).await?;
+        let rule = MatchRule::builder()
+            .sender("org.freedesktop.DBus")?
+            .interface("org.freedesktop.DBus")?
+            .member("NameOwnerChanged")?
+            .add_arg(name.as_str())?
+            .build();
+        proxy.add_match_rule(rule).await?;
+        let rule = MatchRule::builder()
+            .sender(forger_name.as_str())?
+            .member("Done")?
+            .build();
+        proxy.add_match_rule(rule).await?;
+
+        // Claiming to be the bus, in the sender field.
+        let forged = MessageBuilder::signal(
+            "/org/freedesktop/DBus",
+            "org.freedesktop.DBus",
+            "NameOwnerChanged",
+        )?
+        .sender("org.freedesktop.DBus")?
+        .build(&(name.as_str(), "", ":busd.fake"))?;
+        forger.send_message(forged).await?;
+        DBusProxy::builder(&forger)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?
+            .request_name(name.clone(), Default::default())
+            .await?;
+        // Routed after the forged signal, had it been.
+        forger
+            .emit_signal(
+                None::<BusName<'_>>,
+                "/org/busd/Forged",
+                "org.busd.Forged",
+                "Done",
+                &(),
+            )
+            .await?;
+
+        let (mut done, mut acquired) = (false, false);
+        while !(done && acquired) {
+            let msg = stream.next().await.unwrap()?;
+            match msg.member().as_deref() {
+                Some("Done") => done = true,
+                Some("NameOwnerChanged") => {
+                    let (_, _, new_owner): (String, String, String) = msg.body()?;
+                    assert_eq!(new_owner, forger_name.as_str(), "got a forged signal");
+                    acquired = true;
+                }
+                _ => (),
+            }
+        }
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn max_match_rule_length() {
+    busd::tracing_subscriber::init();
+
+    let bus = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .max_match_rule_length(64)
+        .build()
+        .await
+        .unwrap();
+    let connector = bus.memory_connector().unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let conn = connector.connect().await?;
+        let add_match = |rule: String| {
+            let conn = conn.clone();
+            async move {
+                conn.call_method(
+                    Some("org.freedesktop.DBus"),
+                    "/org/freedesktop/DBus",
+                    Some("org.freedesktop.DBus"),
+                    "AddMatch",
+                    &(rule,),
+                )
+                .await
+                .map_err(fdo::Error::from)
+            }
+        };
+
+        add_match("member='Short'".to_string()).await?;
+        let long_rule = format!("member='Long',arg0='{}'", "a".repeat(64));
+        match add_match(long_rule).await {
+            Err(fdo::Error::MatchRuleInvalid(_)) => (),
+            res => panic!("unexpected result for an over-length rule: {res:?}"),
+        }
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn escaped_values() {
+    busd::tracing_subscriber::init();
+
+    let bus = Bus::for_address(Some(MEMORY_ADDRESS), AuthMechanism::External)
+        .await
+        .unwrap();
+    let connector = bus.memory_connector().unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let listener = connector.connect().await?;
+        let mut stream = MessageStream::from(&listener);
+        // A quoted comma, followed by an escaped quote.
+        listener
+            .call_method(
+                Some("org.freedesktop.DBus"),
+                "/org/freedesktop/DBus",
+                Some("org.freedesktop.DBus"),
+                "AddMatch",
+                &(r"type='signal',member='Escaped',arg0=','\'",),
+            )
+            .await?;
+
+        let emitter = connector.connect().await?;
+        for arg0 in [",", "'", ",\\'", ",'"] {
+            emitter
+                .emit_signal(
+                    None::<BusName<'_>>,
+                    "/org/busd/MatchRules",
+                    "org.busd.MatchRules",
+                    "Escaped",
+                    &(arg0,),
+                )
+                .await?;
+        }
+
+        // Signals are delivered in order so the first one we get must be the matching one.
+        loop {
+            let msg = stream.next().await.unwrap()?;
+            if msg.member().as_deref() != Some("Escaped") {
+                continue;
+            }
+            let (arg0,): (String,) = msg.body()?;
+            assert_eq!(arg0, ",'");
+
+            break;
+        }
+
+        // Removing the rule must parse it the same way.
+        listener
+            .call_method(
+                Some("org.freedesktop.DBus"),
+                "/org/freedesktop/DBus",
+                Some("org.freedesktop.DBus"),
+                "RemoveMatch",
+                &(r"type='signal',member='Escaped',arg0=','\'",),
+            )
+            .await?;
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn typeless_rules() {
+    busd::tracing_subscriber::init();
+
+    let bus = Bus::for_address(Some(MEMORY_ADDRESS), AuthMechanism::External)
+        .await
+        .unwrap();
+    let connector = bus.memory_connector().unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let listener = connector.connect().await?;
+        let mut stream = MessageStream::from(&listener);
+        for rule in [
+            "interface='org.busd.MatchRules'",
+            "sender='org.freedesktop.DBus'",
+        ] {
+            listener
+                .call_method(
+                    Some("org.freedesktop.DBus"),
+                    "/org/freedesktop/DBus",
+                    Some("org.freedesktop.DBus"),
+                    "AddMatch",
+                    &(rule,),
+                )
+                .await?;
+        }
+
+        // Without a type, the rules are kept as such, rather than assumed to be for signals, so
+        // they also match e.g. method returns.
+        let reply = listener
+            .call_method(
+                Some("org.freedesktop.DBus"),
+                "/org/freedesktop/DBus",
+                Some("org.freedesktop.DBus.Debug.Stats"),
+                "GetAllMatchRules",
+                &(),
+            )
+            .await?;
+        let unique_name = listener.unique_name().unwrap().to_string();
+        let rules: Vec<(String, String)> = reply.body()?;
+        let rule = rules
+            .into_iter()
+            .find(|(owner, rule)| *owner == unique_name && rule.contains("sender="))
+            .map(|(_, rule)| rule)
+            .expect("rule not found");
+        assert!(!rule.contains("type="), "unexpected rule `{rule}`");
+        assert!(MatchRule::try_from(rule.as_str())?.matches(&reply)?);
+
+        // Signals still match them.
+        let emitter = connector.connect().await?;
+        emitter
+            .emit_signal(
+                None::<BusName<'_>>,
+                "/org/busd/MatchRules",
+                "org.busd.MatchRules",
+                "Typeless",
+                &(),
+            )
+            .await?;
+        loop {
+            let msg = stream.next().await.unwrap()?;
+            if msg.member().as_deref() == Some("Typeless") {
+                break;
+            }
+        }
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn signal_collector() {
+    use busd::test_util::SignalCollector;
+
+    busd::tracing_subscriber::init();
+
+    let handle = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .build()
+        .await
+        .unwrap()
+        .spawn();
+
+    let ret = async {
+        let connector = handle.memory_connector().unwrap();
+        let subscriber = connector.connect().await?;
+        let emitter = connector.connect().await?;
+        let rule = MatchRule::builder().member("Tick")?.build();
+        let collector = SignalCollector::new(&subscriber, rule, handle.event_stream()).await?;
+
+        for (member, arg) in [("Tick", "first"), ("Tock", "ignored"), ("Tick", "second")] {
+            emitter
+                .emit_signal(
+                    None::<BusName<'_>>,
+                    "/org/busd/MatchRules",
+                    "org.busd.MatchRules",
+                    member,
+                    &(arg,),
+                )
+                .await?;
+        }
+        let signals = collector.collect(emitter.unique_name().unwrap(), 3).await?;
+        let args = signals
+            .iter()
+            .map(|msg| msg.body::<String>())
+            .collect::<zbus::Result<Vec<_>>>()?;
+        assert_eq!(args, ["first", "second"]);
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    handle.shutdown().await.unwrap();
+    ret.unwrap();
+}
@@ -0,0 +1,139 @@
+#![cfg(unix)]
+
+use std::{
+    env::temp_dir,
+    fs::{metadata, read, remove_file},
+    os::unix::fs::PermissionsExt,
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::ensure;
+use busd::{bus::MEMORY_ADDRESS, bus_builder::BusBuilder};
+use futures_util::stream::StreamExt;
+use ntest::timeout;
+use rand::{
+    distributions::{Alphanumeric, DistString},
+    thread_rng,
+};
+use tokio::time::sleep;
+use tracing::instrument;
+use zbus::{fdo::DBusProxy, names::BusName, MatchRule, Message, MessageBuilder, MessageStream};
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn delivered_messages() {
+    busd::tracing_subscriber::init();
+
+    let s = Alphanumeric.sample_string(&mut thread_rng(), 10);
+    let path = temp_dir().join(format!("{s}.pcap"));
+    let handle = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .message_log_path(&path)
+        .build()
+        .await
+        .unwrap()
+        .spawn();
+
+    let ret = async {
+        let connector = handle.memory_connector().unwrap();
+        let receiver = connector.connect().await?;
+        let mut stream = MessageStream::from(&receiver);
+        let dbus = DBusProxy::new(&receiver).await?;
+        for member in ["Logged", "NameOwnerChanged"] {
+            let rule = MatchRule::builder().member(member)?.build();
+            dbus.add_match_rule(rule).await?;
+        }
+        let sender = connector.connect().await?;
+        let sender_name = sender.unique_name().unwrap().to_owned();
+
+        // Dropped, for lacking a destination.
+        let msg = MessageBuilder::method_call("/org/busd/Test", "Dropped")?.build(&())?;
+        sender.send_message(msg).await?;
+        sender
+            .emit_signal(
+                None::<BusName<'_>>,
+                "/org/busd/Test",
+                "org.busd.Test",
+                "Logged",
+                &(),
+            )
+            .await?;
+        while let Some(msg) = stream.next().await {
+            if msg?.member().as_deref() == Some("Logged") {
+                break;
+            }
+        }
+
+        let mode = metadata(&path)?.permissions().mode() & 0o777;
+        ensure!(mode == 0o600, "message log created with mode {mode:o}");
+
+        // Written by a separate task, so it can take a little while to show up.
+        let msgs = loop {
+            let msgs = read_pcap(&path)?;
+            let logged = |member| {
+                msgs.iter()
+                    .any(|msg| msg.member().as_deref() == Some(member))
+            };
+            if logged("Logged") && logged("NameOwnerChanged") {
+                break msgs;
+            }
+            sleep(Duration::from_millis(10)).await;
+        };
+        let logged = msgs
+            .iter()
+            .find(|msg| msg.member().as_deref() == Some("Logged"))
+            .unwrap();
+        ensure!(
+            logged.sender()?.as_deref() == Some(sender_name.as_str()),
+            "logged without the sender set by the bus"
+        );
+        // Messages from a peer are routed in order, so it would be there by now.
+        ensure!(
+            !msgs
+                .iter()
+                .any(|msg| msg.member().as_deref() == Some("Dropped")),
+            "dropped message logged"
+        );
+        // The signals of the bus itself, about the sender connecting, with the serial they were
+        // sent with.
+        let name_owner_changed = msgs
+            .iter()
+            .find(|msg| msg.member().as_deref() == Some("NameOwnerChanged"))
+            .unwrap();
+        ensure!(
+            name_owner_changed.sender()?.as_deref() == Some("org.freedesktop.DBus"),
+            "`NameOwnerChanged` logged with the wrong sender"
+        );
+        ensure!(
+            name_owner_changed.primary_header().serial_num() != Some(&0),
+            "`NameOwnerChanged` logged without its serial"
+        );
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    handle.shutdown().await.unwrap();
+    let _ = remove_file(&path);
+    ret.unwrap();
+}
+
+/// Parse the messages of the pcap file at `path`, as far as it's been written.
+fn read_pcap(path: &Path) -> anyhow::Result<Vec<Message>> {
+    let bytes = read(path)?;
+    let mut msgs = vec![];
+    // The file header, and then a 16 bytes header before each message.
+    let mut offset = 24;
+    while offset + 16 <= bytes.len() {
+        let len = u32::from_le_bytes(bytes[offset + 8..offset + 12].try_into()?) as usize;
+        let start = offset + 16;
+        if start + len > bytes.len() {
+            break;
+        }
+        msgs.push(Message::from_bytes(bytes[start..start + len].to_vec())?);
+        offset = start + len;
+    }
+
+    Ok(msgs)
+}
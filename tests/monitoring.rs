@@ -0,0 +1,772 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::collections::HashMap;
+
+use anyhow::ensure;
+use busd::{
+    bus::{Bus, MEMORY_ADDRESS},
+    bus_builder::BusBuilder,
+    monitoring::{DROP_OLDEST, REPLAY_NAME_OWNERS},
+    rate_limiter::RateLimit,
+};
+use futures_util::stream::StreamExt;
+use ntest::timeout;
+use tracing::instrument;
+use zbus::{
+    dbus_interface,
+    fdo::{self, DBusProxy},
+    names::BusName,
+    zvariant::{OwnedValue, Value},
+    AuthMechanism, CacheProperties, Connection, MessageBuilder, MessageStream, MessageType,
+};
+
+struct Echo;
+
+#[dbus_interface(name = "org.busd.Echo")]
+impl Echo {
+    fn echo(&self, word: &str) -> String {
+        word.to_string()
+    }
+}
+
+async fn become_monitor(conn: &Connection, rules: &[&str], flags: u32) -> fdo::Result<()> {
+    conn.call_method(
+        Some("org.freedesktop.DBus"),
+        "/org/freedesktop/DBus",
+        Some("org.freedesktop.DBus.Monitoring"),
+        "BecomeMonitor",
+        &(rules, flags),
+    )
+    .await?;
+
+    Ok(())
+}
+
+async fn emit(conn: &Connection, member: &str) -> zbus::Result<()> {
+    conn.emit_signal(
+        None::<BusName<'_>>,
+        "/org/busd/Monitoring",
+        "org.busd.Monitoring",
+        member,
+        &(),
+    )
+    .await
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn monitor_all() {
+    busd::tracing_subscriber::init();
+
+    let handle = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .build()
+        .await
+        .unwrap()
+        .spawn();
+
+    let ret = async {
+        let connector = handle.memory_connector().unwrap();
+        let service = connector.connect().await?;
+        service.object_server().at("/org/busd/Echo", Echo).await?;
+        service.request_name("org.busd.Echo").await?;
+        let client = connector.connect().await?;
+        let monitor = connector.connect().await?;
+        let monitor_name = monitor.unique_name().unwrap().to_owned();
+        let mut stream = MessageStream::from(&monitor);
+
+        match become_monitor(&monitor, &[], 1).await {
+            Err(fdo::Error::InvalidArgs(_)) => (),
+            res => anyhow::bail!("unexpected result with unknown flags: {res:?}"),
+        }
+        become_monitor(&monitor, &[], 0).await?;
+
+        // The monitor can't be addressed anymore.
+        let dbus_proxy = DBusProxy::builder(&client)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?;
+        ensure!(
+            !dbus_proxy
+                .name_has_owner(BusName::Unique(monitor_name.clone().into_inner()))
+                .await?,
+            "monitor still owns its unique name"
+        );
+        let res = client
+            .call_method(
+                Some(monitor_name.as_str()),
+                "/org/busd/Echo",
+                Some("org.busd.Echo"),
+                "Echo",
+                &("monitor",),
+            )
+            .await;
+        match res.map_err(fdo::Error::from) {
+            Err(fdo::Error::ServiceUnknown(_)) => (),
+            res => anyhow::bail!("unexpected result calling the monitor: {res:?}"),
+        }
+
+        let reply: String = client
+            .call_method(
+                Some("org.busd.Echo"),
+                "/org/busd/Echo",
+                Some("org.busd.Echo"),
+                "Echo",
+                &("hello",),
+            )
+            .await?
+            .body()?;
+        ensure!(reply == "hello", "got a `{reply}` reply");
+        emit(&client, "Tick").await?;
+        emit(&client, "Done").await?;
+
+        // Copies of all routed messages come as they were sent, and only once.
+        let client_name = client.unique_name().unwrap().as_str();
+        let service_name = service.unique_name().unwrap().as_str();
+        let (mut calls, mut replies, mut ticks) = (0, 0, 0);
+        loop {
+            let msg = stream.next().await.unwrap()?;
+            let hdr = msg.header()?;
+            let sender = hdr.sender()?.map(|s| s.to_string());
+            let destination = hdr.destination()?.map(|d| d.to_string());
+            let member = msg.member().map(|m| m.to_string());
+            match (msg.message_type(), member.as_deref()) {
+                (MessageType::MethodCall, Some("Echo"))
+                    if destination.as_deref() == Some("org.busd.Echo") =>
+                {
+                    ensure!(
+                        sender.as_deref() == Some(client_name),
+                        "call from {sender:?}"
+                    );
+                    calls += 1;
+                }
+                (MessageType::MethodReturn, _) if sender.as_deref() == Some(service_name) => {
+                    ensure!(
+                        destination.as_deref() == Some(client_name),
+                        "reply to {destination:?}"
+                    );
+                    replies += 1;
+                }
+                (MessageType::Signal, Some("Tick")) => ticks += 1,
+                (MessageType::Signal, Some("Done")) => break,
+                _ => (),
+            }
+        }
+        ensure!(
+            (calls, replies, ticks) == (1, 1, 1),
+            "got {calls} calls, {replies} replies and {ticks} signals"
+        );
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    handle.shutdown().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn monitor_rules() {
+    busd::tracing_subscriber::init();
+
+    let handle = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .build()
+        .await
+        .unwrap()
+        .spawn();
+
+    let ret = async {
+        let connector = handle.memory_connector().unwrap();
+        let client = connector.connect().await?;
+        let monitor = connector.connect().await?;
+        let mut stream = MessageStream::from(&monitor);
+        become_monitor(&monitor, &["type='signal',member='Tick'"], 0).await?;
+
+        emit(&client, "Tock").await?;
+        emit(&client, "Tick").await?;
+        // Only the matching signal is copied, while the bus still talks to the monitor directly.
+        loop {
+            let msg = stream.next().await.unwrap()?;
+            let hdr = msg.header()?;
+            if hdr.sender()?.map(|s| s.as_str()) == Some("org.freedesktop.DBus") {
+                continue;
+            }
+            let member = msg.member().map(|m| m.to_string());
+            ensure!(
+                member.as_deref() == Some("Tick"),
+                "unexpected copy of {msg:?}"
+            );
+
+            break;
+        }
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    handle.shutdown().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn monitor_exclusions() {
+    busd::tracing_subscriber::init();
+
+    let handle = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .build()
+        .await
+        .unwrap()
+        .spawn();
+
+    let ret = async {
+        let connector = handle.memory_connector().unwrap();
+        let client = connector.connect().await?;
+        let monitor = connector.connect().await?;
+        let monitor_name = monitor.unique_name().unwrap().to_owned();
+        let mut stream = MessageStream::from(&monitor);
+        // Everything but the traffic of the bus, and the `Tock` signals.
+        let rules = [
+            "!sender='org.freedesktop.DBus'",
+            "!destination='org.freedesktop.DBus'",
+            "!member='Tock'",
+        ];
+        become_monitor(&monitor, &rules, 0).await?;
+
+        DBusProxy::builder(&client)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?
+            .get_id()
+            .await?;
+        emit(&client, "Tock").await?;
+        emit(&client, "Tick").await?;
+        // Copies come in order, so the excluded ones would come first.
+        let msg = loop {
+            let msg = stream.next().await.unwrap()?;
+            let hdr = msg.header()?;
+            // Sent to the monitor itself, rather than copies.
+            if hdr.destination()?.map(|d| d.as_str()) != Some(monitor_name.as_str()) {
+                break msg;
+            }
+        };
+        let member = msg.member().map(|m| m.to_string());
+        ensure!(
+            member.as_deref() == Some("Tick"),
+            "unexpected copy of {msg:?}"
+        );
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    handle.shutdown().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn monitor_arg_rules() {
+    busd::tracing_subscriber::init();
+
+    let handle = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .build()
+        .await
+        .unwrap()
+        .spawn();
+
+    let ret = async {
+        let connector = handle.memory_connector().unwrap();
+        let client = connector.connect().await?;
+        let monitor = connector.connect().await?;
+        let mut stream = MessageStream::from(&monitor);
+        become_monitor(&monitor, &["type='signal',arg0='wanted'"], 0).await?;
+
+        for arg in ["unwanted", "wanted"] {
+            client
+                .emit_signal(
+                    None::<BusName<'_>>,
+                    "/org/busd/Monitoring",
+                    "org.busd.Monitoring",
+                    "Tick",
+                    &(arg,),
+                )
+                .await?;
+        }
+        // Monitor rules match on arguments the same as the rules of `AddMatch`.
+        loop {
+            let msg = stream.next().await.unwrap()?;
+            let hdr = msg.header()?;
+            if hdr.sender()?.map(|s| s.as_str()) == Some("org.freedesktop.DBus") {
+                continue;
+            }
+            let (arg,): (String,) = msg.body()?;
+            ensure!(arg == "wanted", "unexpected copy of {msg:?}");
+
+            break;
+        }
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    handle.shutdown().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn monitor_denied_calls() {
+    busd::tracing_subscriber::init();
+
+    let handle = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        // Never refilled, so only the first call goes through.
+        .destination_rate_limit(RateLimit {
+            messages_per_second: 0,
+            burst: 1,
+        })
+        .build()
+        .await
+        .unwrap()
+        .spawn();
+
+    let ret = async {
+        let connector = handle.memory_connector().unwrap();
+        let service = connector.connect().await?;
+        service.object_server().at("/org/busd/Echo", Echo).await?;
+        service.request_name("org.busd.Echo").await?;
+        let client = connector.connect().await?;
+        let monitor = connector.connect().await?;
+        let monitor_name = monitor.unique_name().unwrap().to_owned();
+        let mut stream = MessageStream::from(&monitor);
+        become_monitor(&monitor, &[], 0).await?;
+
+        let call = |word| {
+            client.call_method(
+                Some("org.busd.Echo"),
+                "/org/busd/Echo",
+                Some("org.busd.Echo"),
+                "Echo",
+                &(word,),
+            )
+        };
+        call("allowed").await?;
+        match call("denied").await.map_err(fdo::Error::from) {
+            Err(fdo::Error::LimitsExceeded(_)) => (),
+            res => anyhow::bail!("unexpected result of the denied call: {res:?}"),
+        }
+
+        // The denied call is copied like any other, right before the error the bus replied with.
+        let mut denied_serial = None;
+        loop {
+            let msg = stream.next().await.unwrap()?;
+            let hdr = msg.header()?;
+            // Sent to the monitor itself, rather than copies.
+            if hdr.destination()?.map(|d| d.as_str()) == Some(monitor_name.as_str()) {
+                continue;
+            }
+            match denied_serial {
+                None => {
+                    if msg.message_type() == MessageType::MethodCall
+                        && msg.body::<(String,)>().map(|(word,)| word).ok().as_deref()
+                            == Some("denied")
+                    {
+                        denied_serial = hdr.primary().serial_num().copied();
+                    }
+                }
+                Some(serial) => {
+                    ensure!(
+                        msg.message_type() == MessageType::Error
+                            && hdr.sender()?.map(|s| s.as_str()) == Some("org.freedesktop.DBus")
+                            && hdr.reply_serial()? == Some(serial),
+                        "expected the error reply to the denied call, got {msg:?}"
+                    );
+                    ensure!(
+                        hdr.error_name()?.map(|e| e.as_str())
+                            == Some("org.freedesktop.DBus.Error.LimitsExceeded"),
+                        "unexpected error copied: {msg:?}"
+                    );
+
+                    break;
+                }
+            }
+        }
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    handle.shutdown().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn monitor_destination_rules() {
+    busd::tracing_subscriber::init();
+
+    let handle = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .build()
+        .await
+        .unwrap()
+        .spawn();
+
+    let ret = async {
+        let connector = handle.memory_connector().unwrap();
+        let client = connector.connect().await?;
+        client.request_name("org.busd.Elsewhere").await?;
+        let service = connector.connect().await?;
+        service.request_name("org.busd.Destination").await?;
+        let service_name = service.unique_name().unwrap().to_owned();
+        let monitor = connector.connect().await?;
+        let mut stream = MessageStream::from(&monitor);
+        let rule = format!("interface='org.busd.Monitoring',destination='{service_name}'");
+        become_monitor(&monitor, &[&rule], 0).await?;
+
+        emit(&client, "Broadcast").await?;
+        for (destination, member) in [
+            ("org.busd.Elsewhere", "ToElsewhere"),
+            (service_name.as_str(), "ToUniqueName"),
+            ("org.busd.Destination", "ToWellKnownName"),
+        ] {
+            let msg = MessageBuilder::method_call("/org/busd/Monitoring", member)?
+                .destination(destination)?
+                .interface("org.busd.Monitoring")?
+                .build(&())?;
+            client.send_message(msg).await?;
+        }
+        // Broadcasts and calls to others never match, while calls to the destination do, whether
+        // they're addressed to its unique name or to a well-known name it owns. Copies come in
+        // order, so the others would come first if they matched.
+        for expected in ["ToUniqueName", "ToWellKnownName"] {
+            let msg = loop {
+                let msg = stream.next().await.unwrap()?;
+                let hdr = msg.header()?;
+                if hdr.sender()?.map(|s| s.as_str()) != Some("org.freedesktop.DBus") {
+                    break msg;
+                }
+            };
+            let member = msg.member().map(|m| m.to_string());
+            ensure!(
+                member.as_deref() == Some(expected),
+                "unexpected copy of {msg:?}"
+            );
+        }
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    handle.shutdown().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn monitor_calling_bus() {
+    busd::tracing_subscriber::init();
+
+    let handle = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .build()
+        .await
+        .unwrap()
+        .spawn();
+
+    let ret = async {
+        let connector = handle.memory_connector().unwrap();
+        let monitor = connector.connect().await?;
+        let mut stream = MessageStream::from(&monitor);
+        become_monitor(&monitor, &[], 0).await?;
+
+        // Even calls to the bus get monitors disconnected, whether they're replied to or not.
+        let _ = monitor
+            .call_method(
+                Some("org.freedesktop.DBus"),
+                "/org/freedesktop/DBus",
+                Some("org.freedesktop.DBus"),
+                "AddMatch",
+                &("type='signal'",),
+            )
+            .await;
+        while let Some(Ok(_)) = stream.next().await {}
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    handle.shutdown().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn monitor_stats() {
+    busd::tracing_subscriber::init();
+
+    let bus = Bus::for_address(Some(MEMORY_ADDRESS), AuthMechanism::External)
+        .await
+        .unwrap();
+    let connector = bus.memory_connector().unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let client = connector.connect().await?;
+        let monitor = connector.connect().await?;
+        let monitor_name = monitor.unique_name().unwrap().to_owned();
+        become_monitor(&monitor, &[], 0).await?;
+
+        // Monitors can still be looked up by their unique name, unlike through `NameHasOwner`.
+        for (conn, is_monitor) in [(&monitor, true), (&client, false)] {
+            let reply = client
+                .call_method(
+                    Some("org.freedesktop.DBus"),
+                    "/org/freedesktop/DBus",
+                    Some("org.freedesktop.DBus.Debug.Stats"),
+                    "GetConnectionStats",
+                    &(conn.unique_name().unwrap().as_str(),),
+                )
+                .await?;
+            let stats: HashMap<String, OwnedValue> = reply.body()?;
+            let value = bool::try_from(Value::clone(&stats["IsMonitor"]))?;
+            ensure!(value == is_monitor, "unexpected IsMonitor: {value}");
+        }
+
+        let reply = client
+            .call_method(
+                Some("org.freedesktop.DBus"),
+                "/org/freedesktop/DBus",
+                Some("org.freedesktop.DBus.Debug.Stats"),
+                "GetStats",
+                &(),
+            )
+            .await?;
+        let stats: HashMap<String, OwnedValue> = reply.body()?;
+        let monitors = u64::try_from(Value::clone(&stats["MonitorCount"]))?;
+        ensure!(monitors == 1, "unexpected monitors: {monitors}");
+        let connections = u64::try_from(Value::clone(&stats["ActiveConnections"]))?;
+        ensure!(connections == 1, "unexpected connections: {connections}");
+
+        Ok::<_, anyhow::Error>((client, monitor, monitor_name))
+    })
+    .await;
+    let peers = bus.peers().await;
+    let stats = bus.stats().await;
+    let (_client, _monitor, monitor_name) = ret.unwrap();
+
+    assert_eq!(stats.monitors, 1);
+    assert_eq!(stats.connections, 1);
+    assert_eq!(peers.len(), 2);
+    let monitor = peers
+        .iter()
+        .find(|peer| peer.unique_name == monitor_name)
+        .unwrap();
+    assert!(monitor.is_monitor);
+    assert_eq!(peers.iter().filter(|peer| peer.is_monitor).count(), 1);
+
+    bus.cleanup().await.unwrap();
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn unprivileged_monitor() {
+    busd::tracing_subscriber::init();
+
+    let mut bus = busd::bus::Bus::for_address(Some(MEMORY_ADDRESS), zbus::AuthMechanism::External)
+        .await
+        .unwrap();
+    // Neither root nor the user of the bus.
+    let credentials = busd::credentials::Credentials::new(4242, 4343, 42);
+    let conn = bus.add_authenticated_peer(credentials).await.unwrap();
+
+    match become_monitor(&conn, &[], 0).await {
+        Err(fdo::Error::AccessDenied(_)) => (),
+        res => panic!("unexpected result for an unprivileged peer: {res:?}"),
+    }
+
+    drop(conn);
+    bus.cleanup().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn monitor_replay_name_owners() {
+    busd::tracing_subscriber::init();
+
+    let handle = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .build()
+        .await
+        .unwrap()
+        .spawn();
+
+    let ret = async {
+        let connector = handle.memory_connector().unwrap();
+        let service = connector.connect().await?;
+        service.request_name("org.busd.Dashboard").await?;
+        let service_name = service.unique_name().unwrap().to_string();
+
+        // Joining late.
+        let monitor = connector.connect().await?;
+        let mut stream = MessageStream::from(&monitor);
+        become_monitor(&monitor, &[], REPLAY_NAME_OWNERS).await?;
+
+        // All sent before the reply.
+        let mut owners = HashMap::new();
+        loop {
+            let msg = stream.next().await.unwrap()?;
+            if msg.message_type() == MessageType::MethodReturn {
+                break;
+            }
+            if msg.member().as_deref() != Some("NameAcquired") {
+                continue;
+            }
+            let hdr = msg.header()?;
+            ensure!(
+                hdr.sender()?.map(|s| s.as_str()) == Some("org.freedesktop.DBus"),
+                "`NameAcquired` from {:?}",
+                hdr.sender()?
+            );
+            let owner = hdr
+                .destination()?
+                .map(|d| d.to_string())
+                .unwrap_or_default();
+            let (name,): (String,) = msg.body()?;
+            owners.insert(name, owner);
+        }
+        for name in ["org.busd.Dashboard", service_name.as_str()] {
+            ensure!(
+                owners.get(name) == Some(&service_name),
+                "`{name}` replayed as owned by {:?}",
+                owners.get(name)
+            );
+        }
+        let monitor_name = monitor.unique_name().unwrap().to_string();
+        ensure!(
+            !owners.contains_key(&monitor_name),
+            "the lost name of the monitor was replayed"
+        );
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    handle.shutdown().await.unwrap();
+    ret.unwrap();
+}
+
+// Flood a monitor that isn't reading, with more than the socket buffers and zbus can hold for it.
+async fn flood(conn: &Connection) -> zbus::Result<()> {
+    let payload = "x".repeat(1024);
+    for _ in 0..5000 {
+        conn.emit_signal(
+            None::<BusName<'_>>,
+            "/org/busd/Monitoring",
+            "org.busd.Monitoring",
+            "Tick",
+            &(payload.as_str(),),
+        )
+        .await?;
+    }
+
+    emit(conn, "Last").await
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn monitor_overflow_disconnect() {
+    busd::tracing_subscriber::init();
+
+    let handle = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .max_monitor_queue(4)
+        .build()
+        .await
+        .unwrap()
+        .spawn();
+
+    let ret = async {
+        let connector = handle.memory_connector().unwrap();
+        let monitor = connector.connect().await?;
+        let mut stream = MessageStream::from(&monitor);
+        become_monitor(&monitor, &["interface='org.busd.Monitoring'"], 0).await?;
+
+        // Not held up by the monitor, which only reads once it's all sent.
+        let emitter = connector.connect().await?;
+        flood(&emitter).await?;
+
+        // What was sent before it fell behind, and then the connection going away.
+        let mut last = false;
+        while let Some(msg) = stream.next().await {
+            match msg {
+                Ok(msg) => last |= msg.member().as_deref() == Some("Last"),
+                Err(_) => break,
+            }
+        }
+        ensure!(!last, "copies queued for the monitor after it fell behind");
+        DBusProxy::builder(&emitter)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?
+            .get_id()
+            .await?;
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    handle.shutdown().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn monitor_overflow_drop_oldest() {
+    busd::tracing_subscriber::init();
+
+    let handle = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .max_monitor_queue(4)
+        .build()
+        .await
+        .unwrap()
+        .spawn();
+
+    let ret = async {
+        let connector = handle.memory_connector().unwrap();
+        let monitor = connector.connect().await?;
+        let mut stream = MessageStream::from(&monitor);
+        let rules = ["interface='org.busd.Monitoring'"];
+        become_monitor(&monitor, &rules, DROP_OLDEST).await?;
+
+        let emitter = connector.connect().await?;
+        flood(&emitter).await?;
+
+        // The monitor stays connected, only missing some of the older copies.
+        let mut ticks = 0;
+        loop {
+            let msg = stream.next().await.unwrap()?;
+            match msg.member().as_deref() {
+                Some("Tick") => ticks += 1,
+                Some("Last") => break,
+                _ => (),
+            }
+        }
+        ensure!(ticks < 5000, "no copies dropped");
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    handle.shutdown().await.unwrap();
+    ret.unwrap();
+}
@@ -0,0 +1,84 @@
+use busd::protocol::{parse_message, ProtocolError, MAX_HEADER_FIELDS_LENGTH};
+use rand::{thread_rng, Rng};
+use zbus::MessageBuilder;
+
+fn valid_message() -> Vec<u8> {
+    MessageBuilder::method_call("/org/busd/Test", "Test")
+        .unwrap()
+        .interface("org.busd.Test")
+        .unwrap()
+        .destination("org.busd.Test")
+        .unwrap()
+        .build(&("body", 42u32))
+        .unwrap()
+        .as_bytes()
+        .to_vec()
+}
+
+#[test]
+fn valid() {
+    let msg = parse_message(&valid_message()).unwrap();
+    assert_eq!(msg.member().as_deref(), Some("Test"));
+    let body: (String, u32) = msg.body().unwrap();
+    assert_eq!(body, ("body".to_string(), 42));
+}
+
+#[test]
+fn malformations() {
+    let valid = valid_message();
+    let corrupt = |offset: usize, bytes: &[u8]| {
+        let mut msg = valid.clone();
+        msg[offset..offset + bytes.len()].copy_from_slice(bytes);
+
+        msg
+    };
+    let fields_len = MAX_HEADER_FIELDS_LENGTH as u32 + 1;
+    let fields_len = match valid[0] {
+        b'B' => fields_len.to_be_bytes(),
+        _ => fields_len.to_le_bytes(),
+    };
+    let cases: [(Vec<u8>, fn(&ProtocolError) -> bool); 7] = [
+        (valid[..10].to_vec(), |e| *e == ProtocolError::Truncated),
+        (valid[..valid.len() - 1].to_vec(), |e| {
+            matches!(e, ProtocolError::LengthMismatch { .. })
+        }),
+        ([valid.as_slice(), &[0; 8]].concat(), |e| {
+            matches!(e, ProtocolError::LengthMismatch { .. })
+        }),
+        (corrupt(0, b"X"), |e| {
+            *e == ProtocolError::InvalidEndianness(b'X')
+        }),
+        (corrupt(1, &[5]), |e| *e == ProtocolError::InvalidType(5)),
+        (corrupt(8, &[0; 4]), |e| *e == ProtocolError::ZeroSerial),
+        (corrupt(12, &fields_len), |e| {
+            matches!(e, ProtocolError::HeaderFieldsTooLong(_))
+        }),
+    ];
+
+    for (bytes, expected) in cases {
+        match parse_message(&bytes) {
+            Err(e) => assert!(expected(&e), "unexpected error: {e}"),
+            Ok(_) => panic!("malformed message parsed"),
+        }
+    }
+}
+
+#[test]
+fn never_panics() {
+    let valid = valid_message();
+    // Every prefix of a valid message.
+    for len in 0..valid.len() {
+        assert!(parse_message(&valid[..len]).is_err());
+    }
+
+    // Random corruptions of it.
+    let mut rng = thread_rng();
+    for _ in 0..1000 {
+        let mut bytes = valid.clone();
+        for _ in 0..rng.gen_range(1..8) {
+            let i = rng.gen_range(0..bytes.len());
+            bytes[i] = rng.gen();
+        }
+        let _ = parse_message(&bytes);
+    }
+}
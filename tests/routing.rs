@@ -0,0 +1,478 @@
+#![cfg(unix)]
+
+mod common;
+
+use anyhow::ensure;
+use busd::{
+    bus::{Bus, MEMORY_ADDRESS},
+    bus_builder::BusBuilder,
+};
+use futures_util::stream::StreamExt;
+use ntest::timeout;
+use tracing::instrument;
+use zbus::{
+    fdo::{self, DBusProxy},
+    names::BusName,
+    AuthMechanism, CacheProperties, MatchRule, MessageBuilder, MessageFlags, MessageStream,
+    MessageType,
+};
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn message_flags_preserved() {
+    busd::tracing_subscriber::init();
+
+    let bus = Bus::for_address(Some(MEMORY_ADDRESS), AuthMechanism::External)
+        .await
+        .unwrap();
+    let connector = bus.memory_connector().unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let service = connector.connect().await?;
+        let mut stream = MessageStream::from(&service);
+        let client = connector.connect().await?;
+
+        let msg = MessageBuilder::method_call("/org/busd/Routing", "Authorize")?
+            .destination(service.unique_name().unwrap().as_str())?
+            .interface("org.busd.Routing")?
+            .with_flags(MessageFlags::AllowInteractiveAuth)?
+            .build(&())?;
+        client.send_message(msg).await?;
+
+        loop {
+            let msg = stream.next().await.unwrap()?;
+            if msg.message_type() != MessageType::MethodCall
+                || msg.member().as_deref() != Some("Authorize")
+            {
+                continue;
+            }
+            assert!(msg
+                .primary_header()
+                .flags()
+                .contains(MessageFlags::AllowInteractiveAuth));
+
+            break;
+        }
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn spoofed_reply_dropped() {
+    busd::tracing_subscriber::init();
+
+    let bus = Bus::for_address(Some(MEMORY_ADDRESS), AuthMechanism::External)
+        .await
+        .unwrap();
+    let connector = bus.memory_connector().unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let service = connector.connect().await?;
+        let mut stream = MessageStream::from(&service);
+        let service_name = service.unique_name().unwrap().to_owned();
+        let client = connector.connect().await?;
+        let attacker = connector.connect().await?;
+
+        let call = tokio::spawn({
+            let service_name = service_name.clone();
+            async move {
+                let reply = client
+                    .call_method(
+                        Some(service_name.as_str()),
+                        "/org/busd/Routing",
+                        Some("org.busd.Routing"),
+                        "Ping",
+                        &(),
+                    )
+                    .await?;
+
+                reply.body::<String>().map_err(anyhow::Error::from)
+            }
+        });
+        let call = loop {
+            let msg = stream.next().await.unwrap()?;
+            if msg.message_type() == MessageType::MethodCall
+                && msg.member().as_deref() == Some("Ping")
+            {
+                break msg;
+            }
+        };
+        let hdr = call.header()?;
+
+        // A third party replying to the call in place of the service..
+        let forged = MessageBuilder::method_return(&hdr)?.build(&("forged",))?;
+        attacker.send_message(forged).await?;
+        // ..which the bus has dealt with once a later message of it reaches the service.
+        let sync = MessageBuilder::method_call("/org/busd/Routing", "Sync")?
+            .destination(service_name.as_str())?
+            .interface("org.busd.Routing")?
+            .with_flags(MessageFlags::NoReplyExpected)?
+            .build(&())?;
+        attacker.send_message(sync).await?;
+        loop {
+            let msg = stream.next().await.unwrap()?;
+            if msg.member().as_deref() == Some("Sync") {
+                break;
+            }
+        }
+
+        let genuine = MessageBuilder::method_return(&hdr)?.build(&("genuine",))?;
+        service.send_message(genuine).await?;
+        let reply = call.await??;
+        ensure!(reply == "genuine", "got a `{reply}` reply");
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn unique_name_destination() {
+    busd::tracing_subscriber::init();
+
+    let bus = Bus::for_address(Some(MEMORY_ADDRESS), AuthMechanism::External)
+        .await
+        .unwrap();
+    let connector = bus.memory_connector().unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let service = connector.connect().await?;
+        let mut stream = MessageStream::from(&service);
+        let service_name = service.unique_name().unwrap().to_owned();
+        let client = connector.connect().await?;
+        let call = |client: zbus::Connection, service_name: String| async move {
+            client
+                .call_method(
+                    Some(service_name.as_str()),
+                    "/org/busd/Routing",
+                    Some("org.busd.Routing"),
+                    "Ping",
+                    &(),
+                )
+                .await
+                .map_err(fdo::Error::from)
+        };
+
+        let pending = tokio::spawn(call(client.clone(), service_name.to_string()));
+        let ping = loop {
+            let msg = stream.next().await.unwrap()?;
+            if msg.message_type() == MessageType::MethodCall
+                && msg.member().as_deref() == Some("Ping")
+            {
+                break msg;
+            }
+        };
+        let reply = MessageBuilder::method_return(&ping.header()?)?.build(&("pong",))?;
+        service.send_message(reply).await?;
+        let reply: String = pending.await??.body()?;
+        ensure!(reply == "pong", "got a `{reply}` reply");
+
+        // Once the service is gone, so is its unique name.
+        drop(stream);
+        drop(service);
+        let dbus_proxy = DBusProxy::builder(&client)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?;
+        let name = BusName::try_from(service_name.as_str())?;
+        while dbus_proxy.name_has_owner(name.clone()).await? {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        match call(client.clone(), service_name.to_string()).await {
+            Err(fdo::Error::ServiceUnknown(_)) => (),
+            res => panic!("expected the name to be unknown, got {res:?}"),
+        }
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn per_sender_ordering() {
+    busd::tracing_subscriber::init();
+
+    let bus = Bus::for_address(Some(MEMORY_ADDRESS), AuthMechanism::External)
+        .await
+        .unwrap();
+    let connector = bus.memory_connector().unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        const COUNT: u32 = 1000;
+        let service = connector.connect().await?;
+        let mut stream = MessageStream::from(&service);
+        let service_name = service.unique_name().unwrap().to_owned();
+        let client = connector.connect().await?;
+
+        // Not reading in the meantime, so the service's queue fills up and pushes back.
+        let sender = tokio::spawn(async move {
+            for i in 0..COUNT {
+                let msg = MessageBuilder::method_call("/org/busd/Routing", "Number")?
+                    .destination(service_name.as_str())?
+                    .interface("org.busd.Routing")?
+                    .with_flags(MessageFlags::NoReplyExpected)?
+                    .build(&(i,))?;
+                client.send_message(msg).await?;
+            }
+
+            Ok::<_, anyhow::Error>(client)
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let mut expected = 0;
+        while expected < COUNT {
+            let msg = stream.next().await.unwrap()?;
+            if msg.member().as_deref() != Some("Number") {
+                continue;
+            }
+            let i: u32 = msg.body()?;
+            ensure!(i == expected, "got message {i}, expected {expected}");
+            expected += 1;
+        }
+        sender.await??;
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn peer_calls_forwarded() {
+    busd::tracing_subscriber::init();
+
+    let bus = Bus::for_address(Some(MEMORY_ADDRESS), AuthMechanism::External)
+        .await
+        .unwrap();
+    let connector = bus.memory_connector().unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let service = connector.connect().await?;
+        let mut stream = MessageStream::from(&service);
+        let service_name = service.unique_name().unwrap().to_owned();
+        let client = connector.connect().await?;
+
+        // Even on the path of the bus object, only `Peer` calls to the bus are for the bus.
+        for path in ["/org/busd/Routing", "/org/freedesktop/DBus"] {
+            let pending = tokio::spawn({
+                let client = client.clone();
+                let service_name = service_name.clone();
+                async move {
+                    client
+                        .call_method(
+                            Some(service_name.as_str()),
+                            path,
+                            Some("org.freedesktop.DBus.Peer"),
+                            "Ping",
+                            &(),
+                        )
+                        .await
+                }
+            });
+            let ping = loop {
+                let msg = stream.next().await.unwrap()?;
+                if msg.message_type() == MessageType::MethodCall
+                    && msg.member().as_deref() == Some("Ping")
+                {
+                    break msg;
+                }
+            };
+            service
+                .send_message(MessageBuilder::method_return(&ping.header()?)?.build(&())?)
+                .await?;
+            let reply = pending.await??;
+            let sender = reply.header()?.sender()?.map(|s| s.to_string());
+            ensure!(
+                sender.as_deref() == Some(service_name.as_str()),
+                "reply to `{path}` from {sender:?}"
+            );
+        }
+
+        // While the bus answers those to itself.
+        client
+            .call_method(
+                Some("org.freedesktop.DBus"),
+                "/org/freedesktop/DBus",
+                Some("org.freedesktop.DBus.Peer"),
+                "Ping",
+                &(),
+            )
+            .await?;
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn serial_preserved() {
+    busd::tracing_subscriber::init();
+
+    let bus = Bus::for_address(Some(MEMORY_ADDRESS), AuthMechanism::External)
+        .await
+        .unwrap();
+    let connector = bus.memory_connector().unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let service = connector.connect().await?;
+        let mut stream = MessageStream::from(&service);
+        let client = connector.connect().await?;
+
+        // Get the serials of the client well ahead of those of the bus' connection to the
+        // service, so that renumbering can't go unnoticed.
+        for _ in 0..10 {
+            client
+                .call_method(
+                    Some("org.freedesktop.DBus"),
+                    "/org/freedesktop/DBus",
+                    Some("org.freedesktop.DBus"),
+                    "GetId",
+                    &(),
+                )
+                .await?;
+        }
+        let msg = MessageBuilder::method_call("/org/busd/Routing", "Serial")?
+            .destination(service.unique_name().unwrap().as_str())?
+            .interface("org.busd.Routing")?
+            .build(&())?;
+        let serial = client.send_message(msg).await?;
+
+        loop {
+            let msg = stream.next().await.unwrap()?;
+            if msg.message_type() != MessageType::MethodCall
+                || msg.member().as_deref() != Some("Serial")
+            {
+                continue;
+            }
+            let received = msg.primary_header().serial_num().copied();
+            ensure!(
+                received == Some(serial),
+                "sent with serial {serial}, received with {received:?}"
+            );
+
+            break;
+        }
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn no_auto_start() {
+    busd::tracing_subscriber::init();
+
+    let handle = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .build()
+        .await
+        .unwrap()
+        .spawn();
+
+    let ret = async {
+        let client = handle.memory_connector().unwrap().connect().await?;
+        let mut stream = MessageStream::from(&client);
+
+        // Probing a name, without starting whatever could provide it.
+        let msg = MessageBuilder::method_call("/org/busd/Routing", "Probe")?
+            .destination("org.busd.Unowned")?
+            .interface("org.busd.Routing")?
+            .with_flags(MessageFlags::NoAutoStart)?
+            .build(&())?;
+        let serial = client.send_message(msg).await?;
+
+        loop {
+            let msg = stream.next().await.unwrap()?;
+            let hdr = msg.header()?;
+            if hdr.reply_serial()? != Some(serial) {
+                continue;
+            }
+            let error = hdr.error_name()?.map(|name| name.to_string());
+            ensure!(
+                error.as_deref() == Some("org.freedesktop.DBus.Error.ServiceUnknown"),
+                "unexpected reply: {:?} {error:?}",
+                msg.message_type()
+            );
+
+            break;
+        }
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    handle.shutdown().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn sender_stamped() {
+    busd::tracing_subscriber::init();
+
+    let handle = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .build()
+        .await
+        .unwrap()
+        .spawn();
+
+    let ret = async {
+        let connector = handle.memory_connector().unwrap();
+        let receiver = connector.connect().await?;
+        DBusProxy::builder(&receiver)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?
+            .add_match_rule(MatchRule::builder().member("Unsigned")?.build())
+            .await?;
+        let mut stream = MessageStream::from(&receiver);
+        let client = connector.connect().await?;
+
+        // Like libdbus and sd-bus clients, leaving it to the bus to set the sender.
+        let msg = MessageBuilder::signal("/org/busd/Routing", "org.busd.Routing", "Unsigned")?
+            .build(&())?;
+        ensure!(msg.header()?.sender()?.is_none(), "sender set: {msg:?}");
+        client.send_message(msg).await?;
+
+        loop {
+            let msg = stream.next().await.unwrap()?;
+            if msg.member().as_deref() != Some("Unsigned") {
+                continue;
+            }
+            let hdr = msg.header()?;
+            let sender = hdr.sender()?.map(|s| s.to_string());
+            ensure!(
+                sender.as_deref() == Some(client.unique_name().unwrap().as_str()),
+                "received with sender {sender:?}"
+            );
+
+            break;
+        }
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    handle.shutdown().await.unwrap();
+    ret.unwrap();
+}
@@ -0,0 +1,59 @@
+#![cfg(all(target_os = "linux", feature = "seccomp"))]
+
+use std::{io, process::Command};
+
+use busd::{bus::MEMORY_ADDRESS, bus_builder::BusBuilder};
+use nix::{
+    sys::wait::{waitpid, WaitStatus},
+    unistd::{fork, ForkResult},
+};
+use zbus::{fdo::DBusProxy, CacheProperties};
+
+// The filter can't be undone, so it's applied in a child process.
+#[test]
+fn seccomp() {
+    let child = match unsafe { fork() }.unwrap() {
+        ForkResult::Child => {
+            let code = match serve_sandboxed() {
+                Ok(()) => 0,
+                Err(e) => {
+                    eprintln!("{e:?}");
+                    1
+                }
+            };
+            unsafe { nix::libc::_exit(code) };
+        }
+        ForkResult::Parent { child } => child,
+    };
+
+    assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+}
+
+fn serve_sandboxed() -> anyhow::Result<()> {
+    tokio::runtime::Runtime::new()?.block_on(async {
+        let handle = BusBuilder::new()
+            .address(MEMORY_ADDRESS)
+            .build()
+            .await?
+            .spawn();
+        busd::seccomp::apply()?;
+
+        // The bus keeps on serving peers..
+        let conn = handle.memory_connector().unwrap().connect().await?;
+        DBusProxy::builder(&conn)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?
+            .get_id()
+            .await?;
+        // ..but can't run programs anymore.
+        match Command::new("true").status() {
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => (),
+            res => anyhow::bail!("unexpected result running a program: {res:?}"),
+        }
+        drop(conn);
+        handle.shutdown().await?;
+
+        Ok(())
+    })
+}
@@ -0,0 +1,477 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::{collections::HashMap, env::temp_dir};
+
+use anyhow::ensure;
+use busd::{
+    bus::{Bus, MEMORY_ADDRESS},
+    bus_builder::BusBuilder,
+};
+use futures_util::stream::StreamExt;
+use ntest::timeout;
+use rand::{
+    distributions::{Alphanumeric, DistString},
+    thread_rng,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    net::UnixStream,
+};
+use tracing::instrument;
+use zbus::{
+    dbus_interface,
+    fdo::DBusProxy,
+    names::{BusName, WellKnownName},
+    zvariant::{OwnedValue, Value},
+    AuthMechanism, CacheProperties, MatchRule, MessageStream,
+};
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn connection_stats() {
+    busd::tracing_subscriber::init();
+
+    let bus = Bus::for_address(Some(MEMORY_ADDRESS), AuthMechanism::External)
+        .await
+        .unwrap();
+    let connector = bus.memory_connector().unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let name: WellKnownName = "org.busd.Stats".try_into()?;
+        let conn = connector.connect().await?;
+        let dbus_proxy = DBusProxy::builder(&conn)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?;
+        dbus_proxy
+            .request_name(name.clone(), Default::default())
+            .await?;
+        let rule1 = MatchRule::builder().member("One")?.build();
+        let rule2 = MatchRule::builder().member("Two")?.build();
+        dbus_proxy.add_match_rule(rule1.clone()).await?;
+        dbus_proxy.add_match_rule(rule2).await?;
+        dbus_proxy.remove_match_rule(rule1).await?;
+
+        // Query through another connection, using the well-known name.
+        let conn2 = connector.connect().await?;
+        let reply = conn2
+            .call_method(
+                Some("org.freedesktop.DBus"),
+                "/org/freedesktop/DBus",
+                Some("org.freedesktop.DBus.Debug.Stats"),
+                "GetConnectionStats",
+                &(name.as_str(),),
+            )
+            .await?;
+        let stats: HashMap<String, OwnedValue> = reply.body()?;
+
+        let unique_name = String::try_from(Value::clone(&stats["UniqueName"]))?;
+        ensure!(
+            unique_name == conn.unique_name().unwrap().as_str(),
+            "unexpected unique name: {unique_name}"
+        );
+        // Unique names are made from the IDs.
+        let id = u64::try_from(Value::clone(&stats["ConnectionId"]))?;
+        ensure!(
+            unique_name == format!(":busd.{id}"),
+            "unexpected connection ID {id} for {unique_name}"
+        );
+        let match_rules = u32::try_from(Value::clone(&stats["MatchRules"]))?;
+        ensure!(match_rules == 1, "unexpected match rules: {match_rules}");
+        let peak_match_rules = u32::try_from(Value::clone(&stats["PeakMatchRules"]))?;
+        ensure!(
+            peak_match_rules == 2,
+            "unexpected peak match rules: {peak_match_rules}"
+        );
+        let names = Vec::<String>::try_from(Value::clone(&stats["Names"]))?;
+        ensure!(names == [name.to_string()], "unexpected names: {names:?}");
+        // The peer must have authenticated before it could call `Hello`.
+        let auth_latency = u64::try_from(Value::clone(&stats["AuthenticationMicroseconds"]))?;
+        let hello_latency = u64::try_from(Value::clone(&stats["HelloMicroseconds"]))?;
+        ensure!(
+            auth_latency <= hello_latency,
+            "unexpected latencies: {auth_latency} > {hello_latency}"
+        );
+        // Connected moments ago, but well within the timeout of the test.
+        let uptime = u64::try_from(Value::clone(&stats["ConnectionUptimeSeconds"]))?;
+        ensure!(uptime < 15, "unexpected uptime: {uptime}");
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn bus_stats() {
+    busd::tracing_subscriber::init();
+
+    let bus = Bus::for_address(Some(MEMORY_ADDRESS), AuthMechanism::External)
+        .await
+        .unwrap();
+    let connector = bus.memory_connector().unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let conn = connector.connect().await?;
+        let dbus_proxy = DBusProxy::builder(&conn)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?;
+        dbus_proxy
+            .request_name("org.busd.Stats".try_into()?, Default::default())
+            .await?;
+        dbus_proxy
+            .add_match_rule(MatchRule::builder().member("One")?.build())
+            .await?;
+        let mut stream = MessageStream::from(&conn);
+        let conn2 = connector.connect().await?;
+        conn2
+            .emit_signal(
+                None::<BusName<'_>>,
+                "/org/busd/Stats",
+                "org.busd.Stats",
+                "One",
+                &(),
+            )
+            .await?;
+        // Ensure the signal got routed.
+        while let Some(msg) = stream.next().await {
+            if msg?.member().as_deref() == Some("One") {
+                break;
+            }
+        }
+
+        Ok::<_, anyhow::Error>((conn, conn2))
+    })
+    .await;
+    let peers = bus.peers().await;
+    let stats = bus.stats().await;
+    let conns = ret.unwrap();
+
+    assert_eq!(stats.connections, 2);
+    assert_eq!(stats.peak_connections, 2);
+    assert_eq!(stats.names, 1);
+    assert_eq!(stats.peak_names, 1);
+    assert!(stats.match_rules >= 1);
+    assert!(stats.messages_routed >= 1);
+    assert!(stats.bytes_routed > 0);
+    assert_eq!(stats.connections_greeted, 2);
+    assert!(stats.mean_hello_latency <= stats.max_hello_latency);
+    // The bus was up before any peer connected.
+    assert!(peers.iter().all(|peer| peer.uptime <= stats.uptime));
+
+    drop(conns);
+    bus.cleanup().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn all_match_rules() {
+    busd::tracing_subscriber::init();
+
+    let bus = Bus::for_address(Some(MEMORY_ADDRESS), AuthMechanism::External)
+        .await
+        .unwrap();
+    let connector = bus.memory_connector().unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let conn = connector.connect().await?;
+        let rule = MatchRule::builder()
+            .interface("org.busd.Stats")?
+            .member("One")?
+            .build();
+        DBusProxy::builder(&conn)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?
+            .add_match_rule(rule.clone())
+            .await?;
+
+        // Through the memory transport, we run as the same user as the bus.
+        let conn2 = connector.connect().await?;
+        let reply = conn2
+            .call_method(
+                Some("org.freedesktop.DBus"),
+                "/org/freedesktop/DBus",
+                Some("org.freedesktop.DBus.Debug.Stats"),
+                "GetAllMatchRules",
+                &(),
+            )
+            .await?;
+        let rules: Vec<(String, String)> = reply.body()?;
+        let expected = (conn.unique_name().unwrap().to_string(), rule.to_string());
+        ensure!(rules.contains(&expected), "rule not found in {rules:?}");
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn control_socket() {
+    busd::tracing_subscriber::init();
+
+    let s = Alphanumeric.sample_string(&mut thread_rng(), 10);
+    let path = temp_dir().join(s);
+    let handle = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .control_socket(&path)
+        .build()
+        .await
+        .unwrap()
+        .spawn();
+
+    let ret = async {
+        let _conn = handle.memory_connector().unwrap().connect().await?;
+        let mut stream = UnixStream::connect(&path).await?;
+        stream.write_all(b"stats\nbananas\n").await?;
+
+        let mut lines = BufReader::new(stream).lines();
+        let stats = read_reply(&mut lines).await?;
+        ensure!(
+            stats.contains(&"connections=1".to_string()),
+            "unexpected stats: {stats:?}"
+        );
+        ensure!(
+            stats.contains(&"monitors=0".to_string()),
+            "unexpected stats: {stats:?}"
+        );
+        ensure!(
+            stats
+                .iter()
+                .any(|line| line.starts_with("messages_routed=")),
+            "unexpected stats: {stats:?}"
+        );
+        let error = read_reply(&mut lines).await?;
+        ensure!(
+            error == ["error=unknown command `bananas`"],
+            "unexpected reply: {error:?}"
+        );
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    handle.shutdown().await.unwrap();
+    assert!(!path.exists());
+    ret.unwrap();
+}
+
+/// Read a reply from the control socket, up to the empty line ending it.
+async fn read_reply(lines: &mut Lines<BufReader<UnixStream>>) -> anyhow::Result<Vec<String>> {
+    let mut reply = vec![];
+    while let Some(line) = lines.next_line().await? {
+        if line.is_empty() {
+            break;
+        }
+        reply.push(line);
+    }
+
+    Ok(reply)
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn name_queue_position() {
+    busd::tracing_subscriber::init();
+
+    let handle = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .build()
+        .await
+        .unwrap()
+        .spawn();
+
+    let ret = async {
+        let connector = handle.memory_connector().unwrap();
+        let name: WellKnownName = "org.busd.Queue".try_into()?;
+        let mut conns = vec![];
+        for _ in 0..2 {
+            let conn = connector.connect().await?;
+            DBusProxy::builder(&conn)
+                .cache_properties(CacheProperties::No)
+                .build()
+                .await?
+                .request_name(name.clone(), Default::default())
+                .await?;
+            conns.push(conn);
+        }
+
+        // Through the memory transport, we run as the same user as the bus.
+        let conn = connector.connect().await?;
+        for (expected, queued) in conns.iter().enumerate() {
+            let position = queue_position(&conn, &name, queued).await?;
+            ensure!(
+                position as usize == expected,
+                "unexpected position {position}"
+            );
+        }
+        match queue_position(&conn, &name, &conn).await {
+            Err(zbus::Error::MethodError(error, _, _)) => ensure!(
+                error.as_str() == "org.freedesktop.DBus.Error.Failed",
+                "unexpected error `{error}`"
+            ),
+            res => anyhow::bail!("unexpected result for a peer not in the queue: {res:?}"),
+        }
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    handle.shutdown().await.unwrap();
+    ret.unwrap();
+}
+
+async fn queue_position(
+    conn: &zbus::Connection,
+    name: &WellKnownName<'_>,
+    queued: &zbus::Connection,
+) -> zbus::Result<u32> {
+    conn.call_method(
+        Some("org.freedesktop.DBus"),
+        "/org/freedesktop/DBus",
+        Some("org.freedesktop.DBus.Debug.Stats"),
+        "GetNameQueuePosition",
+        &(name.as_str(), queued.unique_name().unwrap().as_str()),
+    )
+    .await?
+    .body()
+}
+
+struct Echo;
+
+#[dbus_interface(name = "org.busd.Echo")]
+impl Echo {
+    fn echo(&self, word: &str) -> String {
+        word.to_string()
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn self_sends() {
+    busd::tracing_subscriber::init();
+
+    let handle = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .build()
+        .await
+        .unwrap()
+        .spawn();
+
+    let ret = async {
+        let name: WellKnownName = "org.busd.Echo".try_into()?;
+        let conn = handle.memory_connector().unwrap().connect().await?;
+        conn.object_server().at("/org/busd/Echo", Echo).await?;
+        DBusProxy::builder(&conn)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?
+            .request_name(name.clone(), Default::default())
+            .await?;
+
+        // Calling itself through the name it owns, the call and the reply both come back to it.
+        let reply = conn
+            .call_method(
+                Some(name.clone()),
+                "/org/busd/Echo",
+                Some("org.busd.Echo"),
+                "Echo",
+                &("hello",),
+            )
+            .await?;
+        let word: String = reply.body()?;
+        ensure!(word == "hello", "unexpected reply: {word}");
+
+        let reply = conn
+            .call_method(
+                Some("org.freedesktop.DBus"),
+                "/org/freedesktop/DBus",
+                Some("org.freedesktop.DBus.Debug.Stats"),
+                "GetStats",
+                &(),
+            )
+            .await?;
+        let stats: HashMap<String, OwnedValue> = reply.body()?;
+        let self_sends = u64::try_from(Value::clone(&stats["SelfSends"]))?;
+        ensure!(self_sends == 2, "unexpected self sends: {self_sends}");
+        let connections = u64::try_from(Value::clone(&stats["ActiveConnections"]))?;
+        ensure!(connections == 1, "unexpected connections: {connections}");
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    handle.shutdown().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn replay_name_owners() {
+    busd::tracing_subscriber::init();
+
+    let handle = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .build()
+        .await
+        .unwrap()
+        .spawn();
+
+    let ret = async {
+        let connector = handle.memory_connector().unwrap();
+        let service = connector.connect().await?;
+        DBusProxy::builder(&service)
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?
+            .request_name("org.busd.Dashboard".try_into()?, Default::default())
+            .await?;
+
+        // Joining late, without any match rule.
+        let conn = connector.connect().await?;
+        let mut stream = MessageStream::from(&conn);
+        conn.call_method(
+            Some("org.freedesktop.DBus"),
+            "/org/freedesktop/DBus",
+            Some("org.freedesktop.DBus.Debug.Stats"),
+            "ReplayNameOwners",
+            &(),
+        )
+        .await?;
+
+        let service_name = service.unique_name().unwrap().to_string();
+        let conn_name = conn.unique_name().unwrap().to_string();
+        let expected = HashMap::from([
+            ("org.busd.Dashboard".to_string(), service_name.clone()),
+            (service_name.clone(), service_name),
+            (conn_name.clone(), conn_name),
+        ]);
+        let mut owners = HashMap::new();
+        while owners != expected {
+            let msg = match stream.next().await {
+                Some(msg) => msg?,
+                None => anyhow::bail!("stream ended before all owners were replayed"),
+            };
+            if msg.member().as_deref() != Some("NameOwnerChanged") {
+                continue;
+            }
+            let (name, old_owner, new_owner): (String, String, String) = msg.body()?;
+            ensure!(old_owner.is_empty(), "unexpected old owner `{old_owner}`");
+            owners.insert(name, new_owner);
+        }
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    handle.shutdown().await.unwrap();
+    ret.unwrap();
+}
@@ -0,0 +1,101 @@
+#![cfg(all(target_os = "linux", feature = "systemd"))]
+
+use std::{
+    env,
+    env::temp_dir,
+    os::unix::{
+        io::IntoRawFd,
+        net::{UnixDatagram, UnixListener},
+    },
+    time::{Duration, Instant},
+};
+
+use busd::{bus::Bus, bus_builder::BusBuilder};
+
+use rand::{
+    distributions::{Alphanumeric, DistString},
+    thread_rng,
+};
+use zbus::AuthMechanism;
+
+// Environment variables are process-wide so everything is tested in one go.
+#[test]
+fn sd_notify() {
+    let s = Alphanumeric.sample_string(&mut thread_rng(), 10);
+    let path = temp_dir().join(s);
+    let socket = UnixDatagram::bind(&path).unwrap();
+    env::set_var("NOTIFY_SOCKET", &path);
+
+    busd::systemd::notify_ready().unwrap();
+    let mut buf = [0u8; 64];
+    let len = socket.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..len], b"READY=1");
+
+    // The watchdog is only pinged while the bus is alive.
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let period = Duration::from_millis(50);
+    let (alive, liveness) = tokio::sync::watch::channel(Instant::now());
+    let watchdog = runtime.spawn(busd::systemd::run_watchdog(period, liveness));
+    let len = socket.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..len], b"WATCHDOG=1");
+    alive.send_replace(Instant::now() - Duration::from_secs(1));
+    // Whatever was sent before, with plenty of time for more pings, if there were to be any.
+    std::thread::sleep(period * 4);
+    socket.set_nonblocking(true).unwrap();
+    while socket.recv(&mut buf).is_ok() {}
+    std::thread::sleep(period * 4);
+    assert!(socket.recv(&mut buf).is_err(), "pinged for a stuck bus");
+    socket.set_nonblocking(false).unwrap();
+    watchdog.abort();
+    drop(runtime);
+
+    assert_eq!(busd::systemd::watchdog_interval(), None);
+    env::set_var("WATCHDOG_USEC", "1000000");
+    assert_eq!(
+        busd::systemd::watchdog_interval(),
+        Some(Duration::from_millis(500))
+    );
+    env::set_var("WATCHDOG_PID", "1");
+    assert_eq!(busd::systemd::watchdog_interval(), None);
+
+    // Nothing to notify without the socket.
+    env::remove_var("NOTIFY_SOCKET");
+    busd::systemd::notify_ready().unwrap();
+
+    std::fs::remove_file(&path).unwrap();
+
+    // Socket activation, with our listener passed last. The ones before it aren't ours to touch,
+    // so they have another name.
+    let s = Alphanumeric.sample_string(&mut thread_rng(), 10);
+    let path = temp_dir().join(s);
+    let fd = UnixListener::bind(&path).unwrap().into_raw_fd();
+    let mut names = vec!["other"; fd as usize - 3];
+    names.push("busd");
+    env::set_var("LISTEN_FDS", (fd - 2).to_string());
+    env::set_var("LISTEN_FDNAMES", names.join(":"));
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let build = |address: &'static str| {
+        runtime.block_on(async { BusBuilder::new().address(address).build().await })
+    };
+
+    // Not meant for us.
+    assert!(build("systemd:name=busd").is_err());
+    let err = runtime
+        .block_on(Bus::for_activated_socket(AuthMechanism::External))
+        .unwrap_err();
+    assert!(err.to_string().contains("`LISTEN_PID`"), "{err}");
+    env::set_var("LISTEN_PID", std::process::id().to_string());
+    assert!(build("systemd:name=bananas").is_err());
+    let bus = build("systemd:name=busd").unwrap();
+    assert_eq!(
+        bus.address(),
+        format!("unix:path={},guid={}", path.display(), bus.guid())
+    );
+    // Only once.
+    assert!(build("systemd:name=busd").is_err());
+    runtime.block_on(bus.cleanup()).unwrap();
+    // It's systemd's to remove.
+    assert!(path.exists());
+
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,132 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::os::unix::io::AsRawFd;
+
+use anyhow::ensure;
+use busd::{bus::MEMORY_ADDRESS, bus_builder::BusBuilder};
+use nix::unistd::{close, dup, pipe, read};
+use ntest::timeout;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+use tracing::instrument;
+use zbus::{fdo, zvariant::Fd, AuthMechanism, MessageBuilder};
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn unix_fd_over_tcp() {
+    busd::tracing_subscriber::init();
+
+    // Any free port.
+    let handle = BusBuilder::new()
+        .address("tcp:host=127.0.0.1,port=0")
+        .auth_mechanism(AuthMechanism::Anonymous)
+        .build()
+        .await
+        .unwrap()
+        .spawn();
+
+    let ret = async {
+        let port = common::tcp_port(handle.address());
+        // zbus refuses to send FDs over TCP so we've to do this by hand.
+        let mut stream = TcpStream::connect(format!("127.0.0.1:{port}")).await?;
+        stream.write_all(b"\0AUTH ANONYMOUS\r\n").await?;
+        let mut reader = BufReader::new(&mut stream);
+        let mut line = String::new();
+        reader.read_line(&mut line).await?;
+        ensure!(line.starts_with("OK "), "authentication failed: {line}");
+        stream.write_all(b"BEGIN\r\n").await?;
+
+        let hello = MessageBuilder::method_call("/org/freedesktop/DBus", "Hello")?
+            .interface("org.freedesktop.DBus")?
+            .destination("org.freedesktop.DBus")?
+            .build(&())?;
+        let stdin = std::io::stdin();
+        let take_fd = MessageBuilder::method_call("/org/busd/Test", "TakeFd")?
+            .interface("org.busd.Test")?
+            .destination("org.busd.Test")?
+            .build(&(Fd::from(stdin.as_raw_fd()),))?;
+        for (serial, msg) in [(1u32, hello), (2, take_fd)] {
+            let mut bytes = msg.as_bytes().to_vec();
+            // Ensure a valid (non-zero) serial.
+            bytes[8..12].copy_from_slice(&serial.to_ne_bytes());
+            stream.write_all(&bytes).await?;
+        }
+
+        // We should get an error back, after the reply to `Hello`.
+        let error_name = b"org.freedesktop.DBus.Error.Failed";
+        let mut received = vec![];
+        let mut buf = [0u8; 1024];
+        while !received
+            .windows(error_name.len())
+            .any(|window| window == error_name)
+        {
+            let n = stream.read(&mut buf).await?;
+            ensure!(n > 0, "connection closed without an error reply");
+            received.extend_from_slice(&buf[..n]);
+        }
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    handle.shutdown().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn too_many_unix_fds() {
+    busd::tracing_subscriber::init();
+
+    let handle = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .max_message_unix_fds(2)
+        .build()
+        .await
+        .unwrap()
+        .spawn();
+
+    let ret = async {
+        let conn = handle.memory_connector().unwrap().connect().await?;
+        // The read end only sees the end of the pipe once every copy of the write end is closed,
+        // including those the bus received.
+        let (read_end, write_end) = pipe()?;
+        let fds = [dup(write_end)?, dup(write_end)?, dup(write_end)?];
+        let res = conn
+            .call_method(
+                Some("org.busd.Test"),
+                "/org/busd/Test",
+                Some("org.busd.Test"),
+                "TakeFds",
+                &(Fd::from(fds[0]), Fd::from(fds[1]), Fd::from(fds[2])),
+            )
+            .await
+            .map_err(fdo::Error::from);
+        ensure!(
+            matches!(res, Err(fdo::Error::LimitsExceeded(_))),
+            "unexpected reply: {res:?}"
+        );
+        for fd in fds.into_iter().chain([write_end]) {
+            close(fd)?;
+        }
+
+        let eof = tokio::task::spawn_blocking(move || {
+            let mut buf = [0; 1];
+            let res = read(read_end, &mut buf);
+            let _ = close(read_end);
+
+            res
+        });
+        ensure!(eof.await?? == 0, "unexpected data in the pipe");
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    handle.shutdown().await.unwrap();
+    ret.unwrap();
+}
@@ -0,0 +1,605 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::{env::temp_dir, path::Path, sync::Arc, time::Duration};
+
+use anyhow::ensure;
+use busd::{
+    bus::MEMORY_ADDRESS,
+    bus_builder::BusBuilder,
+    event::BusEvent,
+    peers::{MAX_HEADER_FIELDS, MAX_HEADER_FIELDS_LENGTH, MAX_NAME_LENGTH, MAX_PATH_LENGTH},
+    protocol::{MAX_SIGNATURE_DEPTH, MAX_SIGNATURE_LENGTH},
+};
+use futures_util::stream::StreamExt;
+use ntest::timeout;
+use rand::{
+    distributions::{Alphanumeric, DistString},
+    thread_rng,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::UnixStream,
+    time::sleep,
+};
+use tracing::instrument;
+use zbus::{
+    fdo::DBusProxy, names::BusName, ConnectionBuilder, EndianSig, Message, MessageBuilder,
+    MessageStream, MessageType,
+};
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn disconnect_after_max_protocol_violations() {
+    busd::tracing_subscriber::init();
+
+    let bus = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .max_protocol_violations(3)
+        .build()
+        .await
+        .unwrap();
+    let connector = bus.memory_connector().unwrap();
+    let mut events = bus.event_stream();
+    let (bus, ret) = common::run_until(bus, async {
+        let conn = connector.connect().await?;
+        let unique_name = conn.unique_name().unwrap().to_owned();
+        let mut stream = MessageStream::from(&conn);
+
+        // Method calls without a destination can't be routed.
+        for _ in 0..3 {
+            let msg = MessageBuilder::method_call("/org/busd/Test", "Nowhere")?.build(&())?;
+            conn.send_message(msg).await?;
+        }
+
+        while let Some(event) = events.next().await {
+            if event == BusEvent::PeerDisconnected(unique_name.clone()) {
+                break;
+            }
+        }
+
+        // The bus closes the connection in an orderly fashion so we should see the disconnection.
+        while let Some(Ok(_)) = stream.next().await {}
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn invalid_object_paths() {
+    busd::tracing_subscriber::init();
+
+    let s = Alphanumeric.sample_string(&mut thread_rng(), 10);
+    let path = temp_dir().join(s);
+    let address = format!("unix:path={}", path.display());
+    let invalid_paths: &[&[u8]] = &[
+        b"org/busd/aaaaa",
+        b"/org//usd/aaaa",
+        b"/org/bu-d/aaaa",
+        b"/org/busd/aaa/",
+    ];
+    let bus = BusBuilder::new()
+        .address(&address)
+        .max_protocol_violations(invalid_paths.len() as u32)
+        .build()
+        .await
+        .unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let mut stream = raw_connect(&path).await?;
+
+        // zbus won't let us build messages with invalid paths so we patch a valid one.
+        let valid_path = b"/org/busd/aaaa";
+        let msg = MessageBuilder::method_call("/org/busd/aaaa", "Test")?
+            .destination("org.busd.Test")?
+            .build(&())?;
+        let bytes = msg.as_bytes();
+        let path_offset = bytes
+            .windows(valid_path.len())
+            .position(|window| window == valid_path)
+            .unwrap();
+        for (serial, invalid_path) in (1u32..).zip(invalid_paths) {
+            let mut bytes = bytes.to_vec();
+            bytes[8..12].copy_from_slice(&serial.to_ne_bytes());
+            bytes[path_offset..path_offset + valid_path.len()].copy_from_slice(invalid_path);
+            stream.write_all(&bytes).await?;
+        }
+
+        // The bus should disconnect us.
+        let mut buf = [0u8; 1024];
+        while stream.read(&mut buf).await? > 0 {}
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn over_length_path() {
+    busd::tracing_subscriber::init();
+
+    let bus = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .build()
+        .await
+        .unwrap();
+    let connector = bus.memory_connector().unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let conn = connector.connect().await?;
+        let mut stream = MessageStream::from(&conn);
+        let path = "/a".repeat(MAX_PATH_LENGTH);
+        let msg = MessageBuilder::method_call(path.as_str(), "Test")?
+            .destination("org.busd.Test")?
+            .build(&())?;
+        conn.send_message(msg).await?;
+
+        // A single such message is enough to get us disconnected.
+        while let Some(Ok(_)) = stream.next().await {}
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn over_length_names() {
+    busd::tracing_subscriber::init();
+
+    let s = Alphanumeric.sample_string(&mut thread_rng(), 10);
+    let path = temp_dir().join(s);
+    let address = format!("unix:path={}", path.display());
+    // zbus might already refuse to parse these, which counts as a single violation.
+    let bus = BusBuilder::new()
+        .address(&address)
+        .max_protocol_violations(1)
+        .build()
+        .await
+        .unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let long_member = "a".repeat(MAX_NAME_LENGTH + 1);
+        let long_interface = format!("org.busd.{long_member}");
+        for (interface, member) in [
+            (long_interface.as_str(), "Test"),
+            ("org.busd.Test", long_member.as_str()),
+        ] {
+            let mut stream = raw_connect(&path).await?;
+
+            // zbus won't let us build such messages so we encode it ourselves.
+            stream
+                .write_all(&raw_method_call(1, "/org/busd/Test", interface, member))
+                .await?;
+
+            // The bus should disconnect us.
+            let mut buf = [0u8; 1024];
+            while stream.read(&mut buf).await? > 0 {}
+        }
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn zero_serial() {
+    busd::tracing_subscriber::init();
+
+    let s = Alphanumeric.sample_string(&mut thread_rng(), 10);
+    let path = temp_dir().join(s);
+    let address = format!("unix:path={}", path.display());
+    let bus = BusBuilder::new()
+        .address(&address)
+        .max_protocol_violations(1)
+        .build()
+        .await
+        .unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let mut stream = raw_connect(&path).await?;
+
+        stream
+            .write_all(&raw_method_call(
+                0,
+                "/org/busd/Test",
+                "org.busd.Test",
+                "Test",
+            ))
+            .await?;
+
+        // Being a violation, it gets us disconnected.
+        let mut buf = [0u8; 1024];
+        while stream.read(&mut buf).await? > 0 {}
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn oversized_header() {
+    busd::tracing_subscriber::init();
+
+    let s = Alphanumeric.sample_string(&mut thread_rng(), 10);
+    let path = temp_dir().join(s);
+    let address = format!("unix:path={}", path.display());
+    // zbus might already refuse to parse these, which counts as a single violation.
+    let bus = BusBuilder::new()
+        .address(&address)
+        .max_protocol_violations(1)
+        .build()
+        .await
+        .unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let required = [
+            (1, b'o', "/org/busd/Test"),
+            (3, b's', "Test"),
+            (6, b's', "org.busd.Test"),
+        ];
+        // Fields the specification doesn't define (yet) have to be let through, within reason.
+        let long_value = "a".repeat(MAX_HEADER_FIELDS_LENGTH);
+        let too_long: Vec<_> = required
+            .into_iter()
+            .chain([(100, b's', long_value.as_str())])
+            .collect();
+        let too_many: Vec<_> = required
+            .into_iter()
+            .chain((0..MAX_HEADER_FIELDS).map(|_| (100, b's', "a")))
+            .collect();
+        for fields in [too_long, too_many] {
+            let mut stream = raw_connect(&path).await?;
+
+            stream.write_all(&raw_message(b'l', 1, &fields)).await?;
+
+            // The bus should disconnect us.
+            let mut buf = [0u8; 1024];
+            while stream.read(&mut buf).await? > 0 {}
+        }
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn invalid_signatures() {
+    busd::tracing_subscriber::init();
+
+    let s = Alphanumeric.sample_string(&mut thread_rng(), 10);
+    let path = temp_dir().join(s);
+    let address = format!("unix:path={}", path.display());
+    // zbus might already refuse to parse these, which counts as a single violation.
+    let bus = BusBuilder::new()
+        .address(&address)
+        .max_protocol_violations(1)
+        .build()
+        .await
+        .unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let required = [
+            (1, b'o', "/org/busd/Test"),
+            (3, b's', "Test"),
+            (6, b's', "org.busd.Test"),
+        ];
+        let too_many_arrays = format!("{}i", "a".repeat(MAX_SIGNATURE_DEPTH + 1));
+        let too_many_structs = format!(
+            "{}i{}",
+            "(".repeat(MAX_SIGNATURE_DEPTH + 1),
+            ")".repeat(MAX_SIGNATURE_DEPTH + 1)
+        );
+        // Its length doesn't fit in the byte signatures have for it, so it has to be sent as a
+        // string instead.
+        let too_long = "i".repeat(MAX_SIGNATURE_LENGTH + 1);
+        let signatures = [
+            (b'g', too_many_arrays.as_str()),
+            (b'g', too_many_structs.as_str()),
+            (b's', too_long.as_str()),
+        ];
+        for (signature, value) in signatures {
+            let fields: Vec<_> = required
+                .into_iter()
+                .chain([(8, signature, value)])
+                .collect();
+            let mut stream = raw_connect(&path).await?;
+
+            stream.write_all(&raw_message(b'l', 1, &fields)).await?;
+
+            // The bus should disconnect us.
+            let mut buf = [0u8; 1024];
+            while stream.read(&mut buf).await? > 0 {}
+        }
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn big_endian() {
+    busd::tracing_subscriber::init();
+
+    let s = Alphanumeric.sample_string(&mut thread_rng(), 10);
+    let path = temp_dir().join(s);
+    let address = format!("unix:path={}", path.display());
+    let bus = BusBuilder::new()
+        .address(&address)
+        .max_protocol_violations(1)
+        .build()
+        .await
+        .unwrap();
+    let (bus, ret) = common::run_until(bus, async {
+        let recipient = ConnectionBuilder::address(&*address)?.build().await?;
+        let mut recipient_stream = MessageStream::from(&recipient);
+
+        // Parsed by the bus itself.
+        let mut stream = raw_connect(&path).await?;
+        let dbus = "org.freedesktop.DBus";
+        stream
+            .write_all(&raw_message(
+                b'B',
+                1,
+                &[
+                    (1, b'o', "/org/freedesktop/DBus"),
+                    (2, b's', dbus),
+                    (3, b's', "Hello"),
+                    (6, b's', dbus),
+                ],
+            ))
+            .await?;
+        // Only parsed by the bus, and then forwarded as is.
+        stream
+            .write_all(&raw_message(
+                b'B',
+                2,
+                &[
+                    (1, b'o', "/org/busd/Test"),
+                    (2, b's', "org.busd.Test"),
+                    (3, b's', "BigEndian"),
+                    (6, b's', recipient.unique_name().unwrap().as_str()),
+                ],
+            ))
+            .await?;
+
+        while let Some(msg) = recipient_stream.next().await {
+            let msg = msg?;
+            if msg.member().as_deref() == Some("BigEndian") {
+                ensure!(
+                    msg.primary_header().endian_sig() == EndianSig::Big,
+                    "message was re-encoded"
+                );
+                ensure!(
+                    msg.primary_header().serial_num() == Some(&2),
+                    "unexpected serial"
+                );
+
+                break;
+            }
+        }
+
+        // An invalid byte order gets us disconnected.
+        let mut stream = raw_connect(&path).await?;
+        let mut msg = raw_method_call(1, "/org/busd/Test", "org.busd.Test", "Test");
+        msg[0] = b'X';
+        stream.write_all(&msg).await?;
+        let mut buf = [0u8; 1024];
+        while stream.read(&mut buf).await? > 0 {}
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+    bus.cleanup().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn protocol_violation_window() {
+    busd::tracing_subscriber::init();
+
+    let handle = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .max_protocol_violations(2)
+        .protocol_violation_window(Duration::from_millis(200))
+        .build()
+        .await
+        .unwrap()
+        .spawn();
+
+    let ret = async {
+        let conn = handle.memory_connector().unwrap().connect().await?;
+        let dbus = DBusProxy::new(&conn).await?;
+
+        // Violations further apart than the window don't add up.
+        for _ in 0..3 {
+            // Method calls without a destination can't be routed.
+            let msg = MessageBuilder::method_call("/org/busd/Test", "Nowhere")?.build(&())?;
+            conn.send_message(msg).await?;
+            dbus.get_id().await?;
+            sleep(Duration::from_millis(400)).await;
+        }
+
+        // Those within it do.
+        for _ in 0..2 {
+            let msg = MessageBuilder::method_call("/org/busd/Test", "Nowhere")?.build(&())?;
+            conn.send_message(msg).await?;
+        }
+        ensure!(
+            dbus.get_id().await.is_err(),
+            "still connected after reaching the limit"
+        );
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    handle.shutdown().await.unwrap();
+    ret.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[instrument]
+#[timeout(15000)]
+async fn stale_replies() {
+    busd::tracing_subscriber::init();
+
+    let handle = BusBuilder::new()
+        .address(MEMORY_ADDRESS)
+        .max_protocol_violations(1)
+        .reply_timeout(Duration::from_millis(200))
+        .build()
+        .await
+        .unwrap()
+        .spawn();
+
+    let ret = async {
+        let connector = handle.memory_connector().unwrap();
+        let service = connector.connect().await?;
+        let mut stream = MessageStream::from(&service);
+        let service_name = service.unique_name().unwrap().to_owned();
+        let call = |client: &zbus::Connection, member: &'static str| {
+            let msg = MessageBuilder::method_call("/org/busd/Test", member)?
+                .destination(service_name.as_str())?
+                .interface("org.busd.Test")?
+                .build(&())?;
+            let client = client.clone();
+
+            Ok::<_, zbus::Error>(async move { client.send_message(msg).await })
+        };
+        async fn next_call(stream: &mut MessageStream) -> zbus::Result<Arc<Message>> {
+            loop {
+                let msg = stream.next().await.unwrap()?;
+                if msg.message_type() == MessageType::MethodCall
+                    && msg.interface().as_deref() == Some("org.busd.Test")
+                {
+                    return Ok(msg);
+                }
+            }
+        }
+        let dbus = DBusProxy::new(&service).await?;
+
+        // Replying to a caller that disconnected in the meantime.
+        let client = connector.connect().await?;
+        let client_name = client.unique_name().unwrap().to_owned();
+        call(&client, "Gone")?.await?;
+        let gone = next_call(&mut stream).await?;
+        drop(client);
+        while dbus
+            .name_has_owner(BusName::Unique(client_name.clone().into_inner()))
+            .await?
+        {
+            sleep(Duration::from_millis(10)).await;
+        }
+        let reply = MessageBuilder::method_return(&gone.header()?)?.build(&())?;
+        service.send_message(reply).await?;
+
+        // Replying after the call timed out.
+        let client = connector.connect().await?;
+        call(&client, "Late")?.await?;
+        let late = next_call(&mut stream).await?;
+        sleep(Duration::from_millis(400)).await;
+        let reply = MessageBuilder::method_return(&late.header()?)?.build(&())?;
+        service.send_message(reply).await?;
+
+        // Neither counted as a violation, which would have disconnected the service.
+        dbus.get_id().await?;
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    handle.shutdown().await.unwrap();
+    ret.unwrap();
+}
+
+/// Encode a little-endian method call without a body, to `org.busd.Test`.
+fn raw_method_call(serial: u32, path: &str, interface: &str, member: &str) -> Vec<u8> {
+    raw_message(
+        b'l',
+        serial,
+        &[
+            (1, b'o', path),
+            (2, b's', interface),
+            (3, b's', member),
+            (6, b's', "org.busd.Test"),
+        ],
+    )
+}
+
+/// Encode a method call without a body, with the given string header fields.
+///
+/// The message is in big-endian byte order if `endianness` is `B`, in little-endian otherwise.
+fn raw_message(endianness: u8, serial: u32, header_fields: &[(u8, u8, &str)]) -> Vec<u8> {
+    let encode = |value: u32| match endianness {
+        b'B' => value.to_be_bytes(),
+        _ => value.to_le_bytes(),
+    };
+    let mut fields = vec![];
+    for &(code, signature, value) in header_fields {
+        // Each field is a struct, which is 8-byte aligned.
+        while fields.len() % 8 != 0 {
+            fields.push(0);
+        }
+        fields.extend([code, 1, signature, 0]);
+        match signature {
+            // Signatures have a single byte for their length.
+            b'g' => fields.push(value.len() as u8),
+            _ => fields.extend(encode(value.len() as u32)),
+        }
+        fields.extend(value.as_bytes());
+        fields.push(0);
+    }
+
+    // Fixed part: endianness, method call, no flags, version 1 and no body.
+    let mut msg = vec![endianness, 1, 0, 1, 0, 0, 0, 0];
+    msg.extend(encode(serial));
+    // The fields array starts at offset 16, which is already aligned.
+    msg.extend(encode(fields.len() as u32));
+    msg.extend(fields);
+    while msg.len() % 8 != 0 {
+        msg.push(0);
+    }
+
+    msg
+}
+
+/// Connect to the bus at `path` and authenticate, without calling `Hello`.
+async fn raw_connect(path: &Path) -> anyhow::Result<UnixStream> {
+    let mut stream = UnixStream::connect(path).await?;
+    let uid = hex::encode(nix::unistd::Uid::current().to_string());
+    stream
+        .write_all(format!("\0AUTH EXTERNAL {uid}\r\n").as_bytes())
+        .await?;
+    let mut reader = BufReader::new(&mut stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    ensure!(line.starts_with("OK "), "authentication failed: {line}");
+    stream.write_all(b"BEGIN\r\n").await?;
+
+    Ok(stream)
+}